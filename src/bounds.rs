@@ -0,0 +1,341 @@
+use nalgebra::{Matrix4, Point3, Vector3, Vector4};
+use specs::{Component, Entities, Join, ReadStorage, System, VecStorage, WriteStorage};
+
+use crate::render::component::Mesh;
+use crate::transform::GlobalTransform;
+
+/// Axis aligned bounding box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Vector3<f32>>>(points: I) -> Option<Aabb> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+
+        let mut aabb = Aabb::new(first, first);
+        for point in points {
+            aabb.min = aabb.min.zip_map(&point, f32::min);
+            aabb.max = aabb.max.zip_map(&point, f32::max);
+        }
+
+        Some(aabb)
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn contains(&self, point: &Vector3<f32>) -> bool {
+        point.x >= self.min.x
+            && point.y >= self.min.y
+            && point.z >= self.min.z
+            && point.x <= self.max.x
+            && point.y <= self.max.y
+            && point.z <= self.max.z
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.min.y <= other.max.y
+            && self.min.z <= other.max.z
+            && other.min.x <= self.max.x
+            && other.min.y <= self.max.y
+            && other.min.z <= self.max.z
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(
+            self.min.zip_map(&other.min, f32::min),
+            self.max.zip_map(&other.max, f32::max),
+        )
+    }
+
+    /// The box enclosing this box after transforming it by `matrix`.
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> Aabb {
+        let center = matrix.transform_point(&Point3::from_coordinates(self.center()));
+        let half = self.half_extents();
+
+        let mut extents = Vector3::zeros();
+        for row in 0..3 {
+            for col in 0..3 {
+                extents[row] += matrix[(row, col)].abs() * half[col];
+            }
+        }
+
+        Aabb::new(center.coords - extents, center.coords + extents)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// A sphere around the center of the box that still encloses every point.
+    /// Tighter than the sphere around the box itself.
+    pub fn from_points(aabb: &Aabb, points: &[Vector3<f32>]) -> BoundingSphere {
+        let center = aabb.center();
+        let radius = points
+            .iter()
+            .map(|point| (point - center).norm())
+            .fold(0., f32::max);
+
+        BoundingSphere { center, radius }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> BoundingSphere {
+        let center = matrix.transform_point(&Point3::from_coordinates(self.center));
+
+        let max_scale = (0..3)
+            .map(|col| matrix.fixed_slice::<nalgebra::U3, nalgebra::U1>(0, col).norm())
+            .fold(0., f32::max);
+
+        BoundingSphere {
+            center: center.coords,
+            radius: self.radius * max_scale,
+        }
+    }
+}
+
+/// Bounding volumes of a mesh in its own space, computed from its vertices.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LocalBounds {
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+}
+
+impl LocalBounds {
+    pub fn from_points(points: &[Vector3<f32>]) -> Option<LocalBounds> {
+        let aabb = Aabb::from_points(points.iter().cloned())?;
+        let sphere = BoundingSphere::from_points(&aabb, points);
+
+        Some(LocalBounds { aabb, sphere })
+    }
+
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> Bounds {
+        Bounds {
+            aabb: self.aabb.transformed(matrix),
+            sphere: self.sphere.transformed(matrix),
+        }
+    }
+}
+
+/// World space bounding volumes of an entity, kept up to date by the
+/// `BoundsSystem`.
+#[derive(Component, Copy, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+}
+
+/// A plane `normal . p + d = 0`, with the normal pointing inside the frustum.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Plane {
+    normal: Vector3<f32>,
+    d: f32,
+}
+
+impl Plane {
+    fn from_vector(v: Vector4<f32>) -> Plane {
+        let normal = Vector3::new(v.x, v.y, v.z);
+        let len = normal.norm();
+
+        Plane {
+            normal: normal / len,
+            d: v.w / len,
+        }
+    }
+
+    fn distance(&self, point: &Vector3<f32>) -> f32 {
+        self.normal.dot(point) + self.d
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frustum {
+    planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Extract the six clip planes from a combined projection * view matrix.
+    pub fn from_matrix(m: &Matrix4<f32>) -> Frustum {
+        let row = |i: usize| Vector4::new(m[(i, 0)], m[(i, 1)], m[(i, 2)], m[(i, 3)]);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+
+        Frustum {
+            planes: [
+                Plane::from_vector(r3 + r0),
+                Plane::from_vector(r3 - r0),
+                Plane::from_vector(r3 + r1),
+                Plane::from_vector(r3 - r1),
+                Plane::from_vector(r3 + r2),
+                Plane::from_vector(r3 - r2),
+            ],
+        }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.distance(&sphere.center) >= -sphere.radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane normal
+            let positive = Vector3::new(
+                if plane.normal.x >= 0. { aabb.max.x } else { aabb.min.x },
+                if plane.normal.y >= 0. { aabb.max.y } else { aabb.min.y },
+                if plane.normal.z >= 0. { aabb.max.z } else { aabb.min.z },
+            );
+            plane.distance(&positive) >= 0.
+        })
+    }
+
+    /// Cheap sphere test first, the box only for what survives it.
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        self.intersects_sphere(&bounds.sphere) && self.intersects_aabb(&bounds.aabb)
+    }
+}
+
+/// Refits the local bounds of meshes edited since the last frame and moves
+/// them into world space. Runs after the `TransformPropagationSystem`.
+pub struct BoundsSystem;
+
+impl<'a> System<'a> for BoundsSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Mesh>,
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, Bounds>,
+    );
+
+    fn run(&mut self, (entities, mut mesh, transform, mut bounds): Self::SystemData) {
+        for (entity, mesh, transform) in (&entities, &mut mesh, transform.maybe()).join() {
+            mesh.refresh_bounds();
+            let local = match mesh.local_bounds() {
+                Some(local) => local,
+                None => continue,
+            };
+
+            let world = match transform {
+                Some(transform) => local.transformed(&transform.0),
+                None => local.transformed(&Matrix4::identity()),
+            };
+
+            bounds.insert(entity, world).expect("Insert bounds");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chal_engine::shader::ShaderKind;
+    use nalgebra::Perspective3;
+
+    fn frustum() -> Frustum {
+        // At the origin looking down -Z, with the view matrix the identity
+        let projection = Perspective3::new(1., std::f32::consts::FRAC_PI_2, 0.1, 100.);
+        Frustum::from_matrix(&projection.to_homogeneous())
+    }
+
+    fn sphere(x: f32, y: f32, z: f32, radius: f32) -> BoundingSphere {
+        BoundingSphere {
+            center: Vector3::new(x, y, z),
+            radius,
+        }
+    }
+
+    #[test]
+    fn aabb_from_points_and_union() {
+        let aabb = Aabb::from_points(vec![
+            Vector3::new(1., -2., 3.),
+            Vector3::new(-1., 4., 0.),
+            Vector3::new(0., 0., 5.),
+        ])
+        .unwrap();
+        assert_eq!(aabb.min, Vector3::new(-1., -2., 0.));
+        assert_eq!(aabb.max, Vector3::new(1., 4., 5.));
+
+        let other = Aabb::new(Vector3::new(-3., 0., 1.), Vector3::new(0., 1., 7.));
+        let union = aabb.union(&other);
+        assert_eq!(union.min, Vector3::new(-3., -2., 0.));
+        assert_eq!(union.max, Vector3::new(1., 4., 7.));
+
+        assert!(Aabb::from_points(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn aabb_transformed_encloses_the_rotated_box() {
+        let aabb = Aabb::new(Vector3::new(-1., -2., -3.), Vector3::new(1., 2., 3.));
+        let rotation = Matrix4::new_rotation(Vector3::z() * std::f32::consts::FRAC_PI_2);
+        let matrix = Matrix4::new_translation(&Vector3::new(10., 0., 0.)) * rotation;
+
+        let moved = aabb.transformed(&matrix);
+        assert!((moved.min - Vector3::new(8., -1., -3.)).norm() < 1.0e-5);
+        assert!((moved.max - Vector3::new(12., 1., 3.)).norm() < 1.0e-5);
+    }
+
+    #[test]
+    fn frustum_keeps_spheres_in_front_of_the_camera() {
+        let frustum = frustum();
+
+        assert!(frustum.intersects_sphere(&sphere(0., 0., -10., 1.)));
+        // Straddling the left plane
+        assert!(frustum.intersects_sphere(&sphere(-10.5, 0., -10., 1.)));
+    }
+
+    #[test]
+    fn frustum_culls_spheres_outside() {
+        let frustum = frustum();
+
+        assert!(!frustum.intersects_sphere(&sphere(0., 0., 10., 1.)));
+        assert!(!frustum.intersects_sphere(&sphere(30., 0., -10., 1.)));
+        assert!(!frustum.intersects_sphere(&sphere(0., 0., -200., 1.)));
+    }
+
+    #[test]
+    fn frustum_tests_boxes_against_every_plane() {
+        let frustum = frustum();
+        let aabb = |min: [f32; 3], max: [f32; 3]| {
+            Aabb::new(
+                Vector3::new(min[0], min[1], min[2]),
+                Vector3::new(max[0], max[1], max[2]),
+            )
+        };
+
+        assert!(frustum.intersects_aabb(&aabb([-1., -1., -6.], [1., 1., -4.])));
+        assert!(frustum.intersects_aabb(&aabb([-50., -50., -60.], [50., 50., -40.])));
+        assert!(!frustum.intersects_aabb(&aabb([-1., -1., 1.], [1., 1., 2.])));
+        assert!(!frustum.intersects_aabb(&aabb([20., -1., -6.], [22., 1., -4.])));
+    }
+
+    #[test]
+    fn mesh_bounds_follow_edits_after_a_refresh() {
+        let max = |mesh: &Mesh| mesh.local_bounds().unwrap().aabb.max;
+        let mut mesh = Mesh::new("quad", vec![0., 0., 1., 1.], ShaderKind::NonSkinnedMesh);
+        assert_eq!(max(&mesh), Vector3::new(1., 1., 0.));
+
+        mesh.update_vertices(2, &[4., 2.]);
+        assert_eq!(max(&mesh), Vector3::new(1., 1., 0.));
+
+        mesh.refresh_bounds();
+        assert_eq!(max(&mesh), Vector3::new(4., 2., 0.));
+    }
+}
@@ -0,0 +1,506 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Matrix4, UnitQuaternion, Vector3};
+use specs::{Component, Entity, Join, Read, ReadStorage, System, VecStorage, WriteStorage};
+
+use crate::engine::RealDeltaTime;
+use crate::input::Input;
+use crate::transform::{GlobalTransform, Transform};
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Projection {
+    Perspective {
+        /// Vertical field of view in radians
+        fov: f32,
+        near: f32,
+        far: f32,
+    },
+    Orthographic {
+        /// Visible height in world units
+        height: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+/// Part of the canvas a camera renders to, in fractions of its size.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for Viewport {
+    fn default() -> Viewport {
+        Viewport {
+            x: 0.,
+            y: 0.,
+            width: 1.,
+            height: 1.,
+        }
+    }
+}
+
+impl Viewport {
+    /// The rectangle in pixels for a canvas of the given size.
+    pub fn pixels(&self, canvas_width: i32, canvas_height: i32) -> [i32; 4] {
+        [
+            (self.x * canvas_width as f32) as i32,
+            (self.y * canvas_height as f32) as i32,
+            (self.width * canvas_width as f32) as i32,
+            (self.height * canvas_height as f32) as i32,
+        ]
+    }
+}
+
+/// Looks down its local -Z axis from wherever its transform puts it.
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct Camera {
+    pub projection: Projection,
+    #[serde(default)]
+    pub viewport: Viewport,
+}
+
+impl Camera {
+    pub fn perspective(fov: f32, near: f32, far: f32) -> Camera {
+        Camera {
+            projection: Projection::Perspective { fov, near, far },
+            viewport: Viewport::default(),
+        }
+    }
+
+    pub fn orthographic(height: f32, near: f32, far: f32) -> Camera {
+        Camera {
+            projection: Projection::Orthographic { height, near, far },
+            viewport: Viewport::default(),
+        }
+    }
+
+    pub fn projection_matrix(&self, aspect: f32) -> Matrix4<f32> {
+        match self.projection {
+            Projection::Perspective { fov, near, far } => {
+                Matrix4::new_perspective(aspect, fov, near, far)
+            }
+            Projection::Orthographic { height, near, far } => {
+                let half_height = height * 0.5;
+                let half_width = half_height * aspect;
+                Matrix4::new_orthographic(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    near,
+                    far,
+                )
+            }
+        }
+    }
+}
+
+/// The camera entity the scene is rendered from.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ActiveCamera(pub Option<Entity>);
+
+/// Everything the renderer needs to know about the point of view.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraView {
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    pub eye: Vector3<f32>,
+    /// x, y, width and height in pixels
+    pub viewport: [i32; 4],
+}
+
+impl CameraView {
+    /// The view of the active camera, `None` when there is no usable one.
+    pub fn active(
+        active: &ActiveCamera,
+        camera: &ReadStorage<Camera>,
+        global: &ReadStorage<GlobalTransform>,
+        canvas_size: (i32, i32),
+    ) -> Option<CameraView> {
+        let entity = active.0?;
+        let camera = camera.get(entity)?;
+        let world = global.get(entity)?.0;
+
+        let viewport = camera.viewport.pixels(canvas_size.0, canvas_size.1);
+        let aspect = viewport[2] as f32 / viewport[3].max(1) as f32;
+
+        Some(CameraView {
+            view: world.try_inverse().unwrap_or_else(Matrix4::identity),
+            projection: camera.projection_matrix(aspect),
+            eye: Vector3::new(world[(0, 3)], world[(1, 3)], world[(2, 3)]),
+            viewport,
+        })
+    }
+
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        self.projection * self.view
+    }
+}
+
+/// Orientation looking along yaw (around Y) and pitch (positive looks down).
+fn look_rotation(yaw: f32, pitch: f32) -> UnitQuaternion<f32> {
+    UnitQuaternion::from_axis_angle(&Vector3::y_axis(), yaw)
+        * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), -pitch)
+}
+
+/// The inverse of `look_rotation`, ignoring any roll.
+fn yaw_pitch(rotation: &UnitQuaternion<f32>) -> (f32, f32) {
+    let forward = rotation * Vector3::new(0., 0., -1.);
+    ((-forward.x).atan2(-forward.z), (-forward.y).max(-1.).min(1.).asin())
+}
+
+const MAX_PITCH: f32 = PI * 0.49;
+
+/// Circles around a point, dragging with the mouse rotates and the wheel
+/// zooms.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrbitController {
+    pub target: Vector3<f32>,
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// Radians per pixel dragged
+    pub sensitivity: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+}
+
+impl Default for OrbitController {
+    fn default() -> OrbitController {
+        OrbitController {
+            target: Vector3::zeros(),
+            distance: 10.,
+            yaw: 0.,
+            pitch: 0.5,
+            sensitivity: 0.01,
+            min_distance: 1.,
+            max_distance: 100.,
+        }
+    }
+}
+
+impl OrbitController {
+    fn update(&mut self, input: &Input, transform: &mut Transform) {
+        if input.mouse_down {
+            self.yaw -= input.mouse_delta.0 * self.sensitivity;
+            self.pitch += input.mouse_delta.1 * self.sensitivity;
+            self.pitch = self.pitch.max(-MAX_PITCH).min(MAX_PITCH);
+        }
+
+        self.distance = (self.distance + input.wheel)
+            .max(self.min_distance)
+            .min(self.max_distance);
+
+        let rotation = look_rotation(self.yaw, self.pitch);
+        transform.rotation = rotation;
+        transform.translation = self.target + rotation * Vector3::new(0., 0., self.distance);
+    }
+}
+
+/// Free flight, WASD to move, Space and Shift to go up and down, dragging
+/// with the mouse to look around.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FlyController {
+    /// Units per second
+    pub speed: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub sensitivity: f32,
+}
+
+impl Default for FlyController {
+    fn default() -> FlyController {
+        FlyController {
+            speed: 5.,
+            yaw: 0.,
+            pitch: 0.,
+            sensitivity: 0.005,
+        }
+    }
+}
+
+impl FlyController {
+    fn update(&mut self, input: &Input, dt: f32, transform: &mut Transform) {
+        if input.mouse_down {
+            self.yaw -= input.mouse_delta.0 * self.sensitivity;
+            self.pitch += input.mouse_delta.1 * self.sensitivity;
+            self.pitch = self.pitch.max(-MAX_PITCH).min(MAX_PITCH);
+        }
+        let rotation = look_rotation(self.yaw, self.pitch);
+
+        let mut direction = Vector3::zeros();
+        let axis = |positive: &str, negative: &str| {
+            (input.is_key_down(positive) as i32 - input.is_key_down(negative) as i32) as f32
+        };
+        direction.x = axis("KeyD", "KeyA");
+        direction.y = axis("Space", "ShiftLeft");
+        direction.z = axis("KeyS", "KeyW");
+
+        if direction.norm_squared() > 0. {
+            let movement = rotation * direction.normalize() * self.speed * dt;
+            transform.translation += movement;
+        }
+        transform.rotation = rotation;
+    }
+}
+
+/// Trails behind another entity, easing towards a fixed offset from it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FollowController {
+    pub target: Entity,
+    /// World space offset from the target
+    pub offset: Vector3<f32>,
+    /// How fast the camera catches up, higher is snappier
+    pub stiffness: f32,
+}
+
+impl FollowController {
+    pub fn new(target: Entity) -> FollowController {
+        FollowController {
+            target,
+            offset: Vector3::new(0., 3., 8.),
+            stiffness: 5.,
+        }
+    }
+
+    fn update(&self, target: &Vector3<f32>, dt: f32, transform: &mut Transform) {
+        let desired = target + self.offset;
+        let t = 1. - (-self.stiffness * dt).exp();
+        transform.translation += (desired - transform.translation) * t;
+
+        let direction = target - transform.translation;
+        if direction.norm_squared() > 0. {
+            let direction = direction.normalize();
+            let yaw = (-direction.x).atan2(-direction.z);
+            let pitch = (-direction.y).asin();
+            transform.rotation = look_rotation(yaw, pitch);
+        }
+    }
+}
+
+/// How a camera entity is steered, one at a time.
+#[derive(Component, Copy, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub enum CameraController {
+    Orbit(OrbitController),
+    Fly(FlyController),
+    Follow(FollowController),
+}
+
+impl CameraController {
+    /// A controller picking up from where the camera currently is. `kind` is
+    /// one of "orbit", "fly" or "follow", the latter needs a `target`.
+    pub fn from_kind(
+        kind: &str,
+        transform: &Transform,
+        target: Option<Entity>,
+    ) -> Result<CameraController, String> {
+        let (yaw, pitch) = yaw_pitch(&transform.rotation);
+
+        match kind {
+            "orbit" => {
+                let orbit = OrbitController::default();
+                let forward = transform.rotation * Vector3::new(0., 0., -1.);
+                Ok(CameraController::Orbit(OrbitController {
+                    target: transform.translation + forward * orbit.distance,
+                    yaw,
+                    pitch,
+                    ..orbit
+                }))
+            }
+            "fly" => Ok(CameraController::Fly(FlyController {
+                yaw,
+                pitch,
+                ..FlyController::default()
+            })),
+            "follow" => target
+                .map(|target| CameraController::Follow(FollowController::new(target)))
+                .ok_or_else(|| "A follow camera needs a target entity".to_string()),
+            _ => Err(format!(r#"Unknown camera controller '{}'"#, kind)),
+        }
+    }
+}
+
+/// Moves camera entities according to their controller, on real time so
+/// they keep working while the game is paused.
+pub struct CameraControllerSystem;
+
+impl<'a> System<'a> for CameraControllerSystem {
+    type SystemData = (
+        Read<'a, Input>,
+        Read<'a, RealDeltaTime>,
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, CameraController>,
+        WriteStorage<'a, Transform>,
+    );
+
+    fn run(&mut self, (input, dt, global, mut controller, mut transform): Self::SystemData) {
+        let dt = dt.0;
+
+        for (controller, transform) in (&mut controller, &mut transform).join() {
+            match controller {
+                CameraController::Orbit(orbit) => orbit.update(&input, transform),
+                CameraController::Fly(fly) => fly.update(&input, dt, transform),
+                CameraController::Follow(follow) => {
+                    if let Some(target) = global.get(follow.target) {
+                        let target = Vector3::new(
+                            target.0[(0, 3)],
+                            target.0[(1, 3)],
+                            target.0[(2, 3)],
+                        );
+                        follow.update(&target, dt, transform);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::{Builder, RunNow, World};
+
+    fn close(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        (a - b).norm() < 1e-4
+    }
+
+    fn dragging(dx: f32, dy: f32) -> Input {
+        let mut input = Input::new();
+        input.mouse_down(0, 0);
+        input.mouse_delta = (dx, dy);
+        input
+    }
+
+    #[test]
+    fn orbit_circles_its_target() {
+        let mut orbit = OrbitController {
+            pitch: 0.,
+            ..OrbitController::default()
+        };
+        let mut transform = Transform::default();
+
+        orbit.update(&Input::new(), &mut transform);
+        assert!(close(transform.translation, Vector3::new(0., 0., 10.)));
+
+        orbit.update(&dragging(-PI * 50., 0.), &mut transform);
+        assert!((orbit.yaw - PI * 0.5).abs() < 1e-5);
+        assert!(close(transform.translation, Vector3::new(10., 0., 0.)));
+        let forward = transform.rotation * Vector3::new(0., 0., -1.);
+        assert!(close(forward, Vector3::new(-1., 0., 0.)));
+    }
+
+    #[test]
+    fn orbit_clamps_pitch_and_distance() {
+        let mut orbit = OrbitController::default();
+        let mut transform = Transform::default();
+
+        let mut input = dragging(0., 1e4);
+        input.zoom(1e4);
+        orbit.update(&input, &mut transform);
+        assert_eq!(orbit.pitch, MAX_PITCH);
+        assert_eq!(orbit.distance, orbit.max_distance);
+
+        let mut input = dragging(0., -1e4);
+        input.zoom(-1e4);
+        orbit.update(&input, &mut transform);
+        assert_eq!(orbit.pitch, -MAX_PITCH);
+        assert_eq!(orbit.distance, orbit.min_distance);
+    }
+
+    #[test]
+    fn fly_moves_along_where_it_looks() {
+        let mut fly = FlyController::default();
+        let mut transform = Transform::default();
+        let mut input = Input::new();
+        input.key_down("KeyW".to_string());
+
+        fly.update(&input, 0.5, &mut transform);
+        assert!(close(transform.translation, Vector3::new(0., 0., -2.5)));
+
+        // Diagonals are no faster
+        input.key_down("KeyD".to_string());
+        fly.update(&input, 1., &mut transform);
+        let step = 5. / 2f32.sqrt();
+        assert!(close(
+            transform.translation,
+            Vector3::new(step, 0., -2.5 - step)
+        ));
+
+        fly.yaw = PI * 0.5;
+        input.key_up("KeyD");
+        transform.translation = Vector3::zeros();
+        fly.update(&input, 1., &mut transform);
+        assert!(close(transform.translation, Vector3::new(-5., 0., 0.)));
+    }
+
+    #[test]
+    fn from_kind_picks_up_the_current_view() {
+        let transform =
+            Transform::new(Vector3::new(0., 0., 10.)).with_rotation(look_rotation(0.3, 0.2));
+
+        match CameraController::from_kind("fly", &transform, None) {
+            Ok(CameraController::Fly(fly)) => {
+                assert!((fly.yaw - 0.3).abs() < 1e-5);
+                assert!((fly.pitch - 0.2).abs() < 1e-5);
+            }
+            other => panic!("Expected a fly controller, got {:?}", other),
+        }
+
+        let level = Transform::new(Vector3::new(0., 0., 10.));
+        match CameraController::from_kind("orbit", &level, None) {
+            Ok(CameraController::Orbit(orbit)) => {
+                assert!(close(orbit.target, Vector3::zeros()));
+                assert_eq!((orbit.yaw, orbit.pitch), (0., 0.));
+            }
+            other => panic!("Expected an orbit controller, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_kind_rejects_follow_without_target_and_unknown_kinds() {
+        let transform = Transform::default();
+        let mut world = World::new();
+        let target = world.create_entity().build();
+
+        assert!(CameraController::from_kind("follow", &transform, None).is_err());
+        assert_eq!(
+            CameraController::from_kind("follow", &transform, Some(target)),
+            Ok(CameraController::Follow(FollowController::new(target)))
+        );
+        assert!(CameraController::from_kind("dolly", &transform, None).is_err());
+    }
+
+    #[test]
+    fn controllers_run_on_real_time() {
+        let mut world = World::new();
+        world.register::<CameraController>();
+        world.register::<Transform>();
+        world.register::<GlobalTransform>();
+        let mut input = Input::new();
+        input.key_down("KeyW".to_string());
+        world.add_resource(input);
+        // As while the game is paused
+        world.add_resource(crate::engine::DeltaTime(0.));
+        world.add_resource(RealDeltaTime(1.));
+        let camera = world
+            .create_entity()
+            .with(Transform::default())
+            .with(CameraController::Fly(FlyController::default()))
+            .build();
+
+        CameraControllerSystem.run_now(&world.res);
+
+        let transform = world.read_storage::<Transform>();
+        assert!(close(
+            transform.get(camera).unwrap().translation,
+            Vector3::new(0., 0., -5.)
+        ));
+    }
+}
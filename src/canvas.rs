@@ -1,26 +1,34 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use js_sys::{Function, Reflect};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use web_sys::WebGlRenderingContext;
-use chal_engine::state::{State, StateEvent};
 
-use crate::render::Renderer;
+use crate::input::Input;
 
-// pub fn create_webgl_context(state: Rc<RefCell<State>>) -> Result<WebGlRenderingContext, JsValue> {
-pub fn create_webgl_context() -> Result<WebGlRenderingContext, JsValue> {
+pub fn create_webgl_context(input: Rc<RefCell<Input>>) -> Result<WebGlRenderingContext, JsValue> {
     let window = web_sys::window().unwrap();
     let document = window.document().unwrap();
     let canvas = document.get_element_by_id("canvas").unwrap();
     let canvas: web_sys::HtmlCanvasElement = canvas.dyn_into::<web_sys::HtmlCanvasElement>()?;
 
-    // attach_mouse_move_handler(&canvas, Rc::clone(&state));
-    // attach_mouse_down_handler(&canvas, Rc::clone(&state));
-    // attach_mouse_up_handler(&canvas, Rc::clone(&state));
-    // attach_zoom_handler(&canvas, Rc::clone(&state));
-
-    let gl: WebGlRenderingContext = canvas.get_context("webgl")?.unwrap().dyn_into()?;
+    attach_mouse_move_handler(&canvas, Rc::clone(&input));
+    attach_mouse_down_handler(&canvas, Rc::clone(&input));
+    attach_mouse_up_handler(&canvas, Rc::clone(&input));
+    attach_zoom_handler(&canvas, Rc::clone(&input));
+    attach_key_handlers(&window, input);
+
+    // WebGL2 contexts aren't instances of the WebGL1 one, but have all of
+    // its functions under the same names
+    let gl: WebGlRenderingContext = match canvas.get_context("webgl2")? {
+        Some(context) => context.unchecked_into(),
+        None => canvas
+            .get_context("webgl")?
+            .ok_or_else(|| JsValue::from_str("WebGL is not supported"))?
+            .dyn_into()?,
+    };
 
     gl.clear_color(0.0, 0.0, 0.0, 1.0);
     gl.enable(WebGlRenderingContext::DEPTH_TEST);
@@ -28,12 +36,64 @@ pub fn create_webgl_context() -> Result<WebGlRenderingContext, JsValue> {
     Ok(gl)
 }
 
-fn attach_mouse_move_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefCell<State>>) {
+/// WebGL2 contexts report their version as "WebGL 2.0 ...".
+pub fn is_webgl2(gl: &WebGlRenderingContext) -> bool {
+    gl.get_parameter(WebGlRenderingContext::VERSION)
+        .ok()
+        .and_then(|version| version.as_string())
+        .map(|version| version.starts_with("WebGL 2"))
+        .unwrap_or(false)
+}
+
+/// The functions of `OES_vertex_array_object`. WebGL2 has them built in
+/// without the suffix, and doesn't offer the extension, so they are bound
+/// to the context under the extension's names.
+pub fn vertex_array_objects(gl: &WebGlRenderingContext) -> Result<js_sys::Object, JsValue> {
+    if !is_webgl2(gl) {
+        return gl
+            .get_extension("OES_vertex_array_object")?
+            .ok_or_else(|| JsValue::from_str("OES_vertex_array_object is not supported"));
+    }
+
+    let functions = js_sys::Object::new();
+    for name in ["createVertexArray", "bindVertexArray", "deleteVertexArray"].iter() {
+        let function: Function = Reflect::get(gl, &(*name).into())?.dyn_into()?;
+        let extension_name = format!("{}OES", name);
+        Reflect::set(&functions, &extension_name.into(), &function.bind(gl))?;
+    }
+    Ok(functions)
+}
+
+/// A position in CSS pixels, as mouse events give it, in pixels of the
+/// drawing buffer. The two differ on high DPI screens and stretched canvases.
+pub fn to_drawing_buffer(gl: &WebGlRenderingContext, x: f32, y: f32) -> (f32, f32) {
+    let canvas = gl
+        .canvas()
+        .and_then(|canvas| canvas.dyn_into::<web_sys::HtmlCanvasElement>().ok());
+    let (client_width, client_height) = match canvas {
+        Some(canvas) => (canvas.client_width(), canvas.client_height()),
+        None => return (x, y),
+    };
+
+    let scale = |buffer: i32, client: i32| {
+        if client > 0 {
+            buffer as f32 / client as f32
+        } else {
+            1.
+        }
+    };
+    (
+        x * scale(gl.drawing_buffer_width(), client_width),
+        y * scale(gl.drawing_buffer_height(), client_height),
+    )
+}
+
+fn attach_mouse_move_handler(canvas: &web_sys::HtmlCanvasElement, input: Rc<RefCell<Input>>) {
     let handler = move |event: web_sys::MouseEvent| {
         event.prevent_default();
-        let x = event.client_x();
-        let y = event.client_y();
-        state.borrow_mut().msg(&StateEvent::MouseMove(x, y));
+        let x = event.offset_x();
+        let y = event.offset_y();
+        input.borrow_mut().mouse_move(x, y);
     };
 
     let handler = Closure::wrap(Box::new(handler) as Box<FnMut(_)>);
@@ -41,11 +101,11 @@ fn attach_mouse_move_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefC
     handler.forget();
 }
 
-fn attach_mouse_down_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefCell<State>>) {
+fn attach_mouse_down_handler(canvas: &web_sys::HtmlCanvasElement, input: Rc<RefCell<Input>>) {
     let handler = move |event: web_sys::MouseEvent| {
-        let x = event.client_x();
-        let y = event.client_y();
-        state.borrow_mut().msg(&StateEvent::MouseDown(x, y));
+        let x = event.offset_x();
+        let y = event.offset_y();
+        input.borrow_mut().mouse_down(x, y);
     };
 
     let handler = Closure::wrap(Box::new(handler) as Box<FnMut(_)>);
@@ -53,9 +113,11 @@ fn attach_mouse_down_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefC
     handler.forget();
 }
 
-fn attach_mouse_up_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefCell<State>>) {
-    let handler = move |_: web_sys::MouseEvent| {
-        state.borrow_mut().msg(&StateEvent::MouseUp);
+fn attach_mouse_up_handler(canvas: &web_sys::HtmlCanvasElement, input: Rc<RefCell<Input>>) {
+    let handler = move |event: web_sys::MouseEvent| {
+        let x = event.offset_x();
+        let y = event.offset_y();
+        input.borrow_mut().mouse_up(x, y);
     };
 
     let handler = Closure::wrap(Box::new(handler) as Box<FnMut(_)>);
@@ -63,16 +125,36 @@ fn attach_mouse_up_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefCel
     handler.forget();
 }
 
-fn attach_zoom_handler(canvas: &web_sys::HtmlCanvasElement, state: Rc<RefCell<State>>) {
+fn attach_zoom_handler(canvas: &web_sys::HtmlCanvasElement, input: Rc<RefCell<Input>>) {
     let handler = move |event: web_sys::WheelEvent| {
         event.prevent_default();
 
         let zoom_amount = event.delta_y() / 50.0;
 
-        state.borrow_mut().msg(&StateEvent::Zoom(zoom_amount as f32));
+        input.borrow_mut().zoom(zoom_amount as f32);
     };
 
     let handler = Closure::wrap(Box::new(handler) as Box<FnMut(_)>);
     canvas.add_event_listener_with_callback("wheel", handler.as_ref().unchecked_ref()).unwrap();
     handler.forget();
 }
+
+/// Keys are listened for on the window, the canvas only gets them when focused.
+fn attach_key_handlers(window: &web_sys::Window, input: Rc<RefCell<Input>>) {
+    let down_input = Rc::clone(&input);
+    let down_handler = move |event: web_sys::KeyboardEvent| {
+        down_input.borrow_mut().key_down(event.code());
+    };
+
+    let down_handler = Closure::wrap(Box::new(down_handler) as Box<FnMut(_)>);
+    window.add_event_listener_with_callback("keydown", down_handler.as_ref().unchecked_ref()).unwrap();
+    down_handler.forget();
+
+    let up_handler = move |event: web_sys::KeyboardEvent| {
+        input.borrow_mut().key_up(&event.code());
+    };
+
+    let up_handler = Closure::wrap(Box::new(up_handler) as Box<FnMut(_)>);
+    window.add_event_listener_with_callback("keyup", up_handler.as_ref().unchecked_ref()).unwrap();
+    up_handler.forget();
+}
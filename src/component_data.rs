@@ -0,0 +1,551 @@
+use chal_engine::shader::ShaderKind;
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use serde_json::Value;
+use specs::{Entity, World};
+
+use crate::camera::Camera;
+use crate::light::Light;
+use crate::particles::ParticleEmitter;
+use crate::physics::collider::{Collider, Shape, TriMesh, DEFAULT_FRICTION};
+use crate::physics::heightfield::Heightfield;
+use crate::physics::{BodyKind, CollisionEvent, CollisionEventKind, RigidBody};
+use crate::render::component::Mesh;
+use crate::render::draw::{Indices, Topology};
+use crate::render::layout::{VertexAttribute, VertexLayout};
+use crate::render::library::MeshLibrary;
+use crate::render::material::Material;
+use crate::terrain::GroundSnap;
+use crate::transform::Transform;
+
+/// The components that can be read and written from outside of Rust.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ComponentKind {
+    Transform,
+    Mesh,
+    Material,
+    Light,
+    Camera,
+    RigidBody,
+    Collider,
+    GroundSnap,
+    ParticleEmitter,
+}
+
+impl ComponentKind {
+    pub fn from_name(name: &str) -> Result<ComponentKind, String> {
+        match name {
+            "transform" => Ok(ComponentKind::Transform),
+            "mesh" => Ok(ComponentKind::Mesh),
+            "material" => Ok(ComponentKind::Material),
+            "light" => Ok(ComponentKind::Light),
+            "camera" => Ok(ComponentKind::Camera),
+            "rigid_body" => Ok(ComponentKind::RigidBody),
+            "collider" => Ok(ComponentKind::Collider),
+            "ground_snap" => Ok(ComponentKind::GroundSnap),
+            "particle_emitter" => Ok(ComponentKind::ParticleEmitter),
+            _ => Err(format!(r#"Unknown component '{}'"#, name)),
+        }
+    }
+
+    pub fn has(self, world: &World, entity: Entity) -> bool {
+        match self {
+            ComponentKind::Transform => world.read_storage::<Transform>().contains(entity),
+            ComponentKind::Mesh => world.read_storage::<Mesh>().contains(entity),
+            ComponentKind::Material => world.read_storage::<Material>().contains(entity),
+            ComponentKind::Light => world.read_storage::<Light>().contains(entity),
+            ComponentKind::Camera => world.read_storage::<Camera>().contains(entity),
+            ComponentKind::RigidBody => world.read_storage::<RigidBody>().contains(entity),
+            ComponentKind::Collider => world.read_storage::<Collider>().contains(entity),
+            ComponentKind::GroundSnap => world.read_storage::<GroundSnap>().contains(entity),
+            ComponentKind::ParticleEmitter => {
+                world.read_storage::<ParticleEmitter>().contains(entity)
+            }
+        }
+    }
+
+    pub fn get(self, world: &World, entity: Entity) -> Option<ComponentData> {
+        match self {
+            ComponentKind::Transform => world
+                .read_storage::<Transform>()
+                .get(entity)
+                .map(|t| ComponentData::Transform(TransformData::from(t))),
+            ComponentKind::Mesh => world
+                .read_storage::<Mesh>()
+                .get(entity)
+                .map(|m| ComponentData::Mesh(MeshData::from(m))),
+            ComponentKind::Material => world
+                .read_storage::<Material>()
+                .get(entity)
+                .map(|m| ComponentData::Material(m.clone())),
+            ComponentKind::Light => world
+                .read_storage::<Light>()
+                .get(entity)
+                .map(|l| ComponentData::Light(*l)),
+            ComponentKind::Camera => world
+                .read_storage::<Camera>()
+                .get(entity)
+                .map(|c| ComponentData::Camera(*c)),
+            ComponentKind::RigidBody => world
+                .read_storage::<RigidBody>()
+                .get(entity)
+                .map(|b| ComponentData::RigidBody(RigidBodyData::from(b))),
+            ComponentKind::Collider => world
+                .read_storage::<Collider>()
+                .get(entity)
+                .map(|c| ComponentData::Collider(ColliderData::from(c))),
+            ComponentKind::GroundSnap => world
+                .read_storage::<GroundSnap>()
+                .get(entity)
+                .map(|g| ComponentData::GroundSnap(*g)),
+            ComponentKind::ParticleEmitter => world
+                .read_storage::<ParticleEmitter>()
+                .get(entity)
+                .map(|e| ComponentData::ParticleEmitter(e.clone())),
+        }
+    }
+
+    pub fn remove(self, world: &World, entity: Entity) {
+        match self {
+            ComponentKind::Transform => {
+                world.write_storage::<Transform>().remove(entity);
+            }
+            ComponentKind::Mesh => {
+                world.write_storage::<Mesh>().remove(entity);
+            }
+            ComponentKind::Material => {
+                world.write_storage::<Material>().remove(entity);
+            }
+            ComponentKind::Light => {
+                world.write_storage::<Light>().remove(entity);
+            }
+            ComponentKind::Camera => {
+                world.write_storage::<Camera>().remove(entity);
+            }
+            ComponentKind::RigidBody => {
+                world.write_storage::<RigidBody>().remove(entity);
+            }
+            ComponentKind::Collider => {
+                world.write_storage::<Collider>().remove(entity);
+            }
+            ComponentKind::GroundSnap => {
+                world.write_storage::<GroundSnap>().remove(entity);
+            }
+            ComponentKind::ParticleEmitter => {
+                world.write_storage::<ParticleEmitter>().remove(entity);
+            }
+        }
+    }
+}
+
+/// A component in a form that serializes to plain JSON.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentData {
+    Transform(TransformData),
+    Mesh(MeshData),
+    Material(Material),
+    Light(Light),
+    Camera(Camera),
+    RigidBody(RigidBodyData),
+    Collider(ColliderData),
+    GroundSnap(GroundSnap),
+    ParticleEmitter(ParticleEmitter),
+}
+
+impl ComponentData {
+    /// Read the data of a `kind` component from its plain JSON form.
+    pub fn from_value(kind: ComponentKind, value: Value) -> Result<ComponentData, String> {
+        let data = match kind {
+            ComponentKind::Transform => serde_json::from_value(value).map(ComponentData::Transform),
+            ComponentKind::Mesh => serde_json::from_value(value).map(ComponentData::Mesh),
+            ComponentKind::Material => serde_json::from_value(value).map(ComponentData::Material),
+            ComponentKind::Light => serde_json::from_value(value).map(ComponentData::Light),
+            ComponentKind::Camera => serde_json::from_value(value).map(ComponentData::Camera),
+            ComponentKind::RigidBody => serde_json::from_value(value).map(ComponentData::RigidBody),
+            ComponentKind::Collider => serde_json::from_value(value).map(ComponentData::Collider),
+            ComponentKind::GroundSnap => serde_json::from_value(value).map(ComponentData::GroundSnap),
+            ComponentKind::ParticleEmitter => {
+                serde_json::from_value(value).map(ComponentData::ParticleEmitter)
+            }
+        };
+
+        data.map_err(|err| format!("Invalid {:?} data: {}", kind, err))
+    }
+
+    /// The plain JSON form, without saying which component it is.
+    pub fn to_value(&self) -> Value {
+        let value = match self {
+            ComponentData::Transform(data) => serde_json::to_value(data),
+            ComponentData::Mesh(data) => serde_json::to_value(data),
+            ComponentData::Material(material) => serde_json::to_value(material),
+            ComponentData::Light(light) => serde_json::to_value(light),
+            ComponentData::Camera(camera) => serde_json::to_value(camera),
+            ComponentData::RigidBody(data) => serde_json::to_value(data),
+            ComponentData::Collider(data) => serde_json::to_value(data),
+            ComponentData::GroundSnap(snap) => serde_json::to_value(snap),
+            ComponentData::ParticleEmitter(emitter) => serde_json::to_value(emitter),
+        };
+
+        value.expect("Components serialize to JSON")
+    }
+
+    /// Add the component to `entity`, replacing the one it already had.
+    pub fn insert(self, world: &World, entity: Entity) -> Result<(), String> {
+        let result = match self {
+            ComponentData::Transform(data) => world
+                .write_storage::<Transform>()
+                .insert(entity, data.into_transform()?)
+                .map(|_| ()),
+            ComponentData::Mesh(data) => world
+                .write_storage::<Mesh>()
+                .insert(entity, data.into_mesh()?)
+                .map(|_| ()),
+            ComponentData::Material(material) => world
+                .write_storage::<Material>()
+                .insert(entity, material)
+                .map(|_| ()),
+            ComponentData::Light(light) => {
+                world.write_storage::<Light>().insert(entity, light).map(|_| ())
+            }
+            ComponentData::Camera(camera) => {
+                world.write_storage::<Camera>().insert(entity, camera).map(|_| ())
+            }
+            ComponentData::RigidBody(data) => world
+                .write_storage::<RigidBody>()
+                .insert(entity, data.into())
+                .map(|_| ()),
+            ComponentData::Collider(data) => {
+                let collider = data.into_collider(&world.read_resource::<MeshLibrary>())?;
+                world
+                    .write_storage::<Collider>()
+                    .insert(entity, collider)
+                    .map(|_| ())
+            }
+            ComponentData::GroundSnap(snap) => world
+                .write_storage::<GroundSnap>()
+                .insert(entity, snap)
+                .map(|_| ()),
+            ComponentData::ParticleEmitter(emitter) => world
+                .write_storage::<ParticleEmitter>()
+                .insert(entity, emitter)
+                .map(|_| ()),
+        };
+
+        result.map_err(|err| err.to_string())
+    }
+}
+
+/// A `Transform` with the rotation as an `[x, y, z, w]` quaternion.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TransformData {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+}
+
+impl Default for TransformData {
+    fn default() -> TransformData {
+        TransformData::from(&Transform::default())
+    }
+}
+
+impl<'a> From<&'a Transform> for TransformData {
+    fn from(transform: &Transform) -> TransformData {
+        let t = transform.translation;
+        let q = transform.rotation.as_ref().coords;
+        let s = transform.scale;
+
+        TransformData {
+            translation: [t.x, t.y, t.z],
+            rotation: [q.x, q.y, q.z, q.w],
+            scale: [s.x, s.y, s.z],
+        }
+    }
+}
+
+impl TransformData {
+    /// The rotation is normalized, and has to be long enough for that.
+    pub fn into_transform(self) -> Result<Transform, String> {
+        let [x, y, z, w] = self.rotation;
+        let t = self.translation;
+        let s = self.scale;
+
+        let rotation = Some(Quaternion::new(w, x, y, z))
+            .filter(|q| q.coords.iter().all(|c| c.is_finite()))
+            .and_then(|q| UnitQuaternion::try_new(q, 1e-6))
+            .ok_or_else(|| format!("The rotation {:?} is not a valid quaternion", self.rotation))?;
+
+        Ok(Transform {
+            translation: Vector3::new(t[0], t[1], t[2]),
+            rotation,
+            scale: Vector3::new(s[0], s[1], s[2]),
+        })
+    }
+}
+
+/// The geometry of a `Mesh`. Without `attributes` the vertices are read as
+/// 2D positions.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MeshData {
+    pub name: String,
+    #[serde(default)]
+    pub vertices: Vec<f32>,
+    #[serde(default)]
+    pub attributes: Option<Vec<VertexAttribute>>,
+    #[serde(default)]
+    pub indices: Option<Vec<u32>>,
+    #[serde(default)]
+    pub topology: Topology,
+    /// Whether the vertices will be edited over time
+    #[serde(default)]
+    pub dynamic: bool,
+}
+
+impl<'a> From<&'a Mesh> for MeshData {
+    fn from(mesh: &Mesh) -> MeshData {
+        let indices = mesh.indices().map(|indices| {
+            (0..indices.len())
+                .filter_map(|i| indices.get(i))
+                .map(|i| i as u32)
+                .collect()
+        });
+
+        MeshData {
+            name: mesh.name().to_string(),
+            vertices: mesh.vertices().to_vec(),
+            attributes: Some(mesh.layout().attributes().to_vec()),
+            indices,
+            topology: mesh.topology(),
+            dynamic: mesh.is_dynamic(),
+        }
+    }
+}
+
+impl MeshData {
+    pub fn into_mesh(self) -> Result<Mesh, String> {
+        let mut mesh = if self.dynamic {
+            Mesh::dynamic(self.name, self.vertices, ShaderKind::NonSkinnedMesh)
+        } else {
+            Mesh::new(self.name, self.vertices, ShaderKind::NonSkinnedMesh)
+        };
+
+        if let Some(attributes) = self.attributes {
+            mesh = mesh.with_layout(VertexLayout::new(attributes));
+        }
+
+        if let Some(indices) = self.indices {
+            let vertex_count = mesh.vertex_count() as u32;
+            if let Some(out_of_range) = indices.iter().find(|&&i| i >= vertex_count) {
+                return Err(format!(
+                    "Index {} is out of range for {} vertices",
+                    out_of_range, vertex_count
+                ));
+            }
+
+            // 32 bit indices need an extension on WebGL1, only use them when
+            // there are too many vertices for 16 bits
+            let indices = if vertex_count <= u32::from(std::u16::MAX) + 1 {
+                Indices::U16(indices.into_iter().map(|i| i as u16).collect())
+            } else {
+                Indices::U32(indices)
+            };
+            mesh = mesh.with_indices(indices);
+        }
+
+        Ok(mesh.with_topology(self.topology))
+    }
+}
+
+/// A `RigidBody` with its vectors as arrays. Forces waiting for the next
+/// step are left out.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RigidBodyData {
+    pub kind: BodyKind,
+    pub mass: f32,
+    pub velocity: [f32; 3],
+    pub angular_velocity: [f32; 3],
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub gravity_scale: f32,
+}
+
+impl Default for RigidBodyData {
+    fn default() -> RigidBodyData {
+        RigidBodyData::from(&RigidBody::default())
+    }
+}
+
+impl<'a> From<&'a RigidBody> for RigidBodyData {
+    fn from(body: &RigidBody) -> RigidBodyData {
+        let v = body.velocity;
+        let w = body.angular_velocity;
+
+        RigidBodyData {
+            kind: body.kind,
+            mass: body.mass,
+            velocity: [v.x, v.y, v.z],
+            angular_velocity: [w.x, w.y, w.z],
+            linear_damping: body.linear_damping,
+            angular_damping: body.angular_damping,
+            gravity_scale: body.gravity_scale,
+        }
+    }
+}
+
+impl From<RigidBodyData> for RigidBody {
+    fn from(data: RigidBodyData) -> RigidBody {
+        let v = data.velocity;
+        let w = data.angular_velocity;
+
+        let mut body = RigidBody::dynamic(data.mass);
+        body.kind = data.kind;
+        body.velocity = Vector3::new(v[0], v[1], v[2]);
+        body.angular_velocity = Vector3::new(w[0], w[1], w[2]);
+        body.linear_damping = data.linear_damping;
+        body.angular_damping = data.angular_damping;
+        body.gravity_scale = data.gravity_scale;
+        body
+    }
+}
+
+/// The shape of a `Collider`. Triangle meshes and heightfields are built
+/// from a mesh in the `MeshLibrary`, by name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ShapeData {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: [f32; 3],
+    },
+    Capsule {
+        half_height: f32,
+        radius: f32,
+    },
+    TriMesh {
+        mesh: String,
+    },
+    /// The mesh sampled from above on a `columns` by `rows` grid
+    Heightfield {
+        mesh: String,
+        #[serde(default = "default_resolution")]
+        columns: usize,
+        #[serde(default = "default_resolution")]
+        rows: usize,
+    },
+}
+
+fn default_resolution() -> usize {
+    64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ColliderData {
+    #[serde(flatten)]
+    pub shape: ShapeData,
+    #[serde(default)]
+    pub sensor: bool,
+    #[serde(default = "default_friction")]
+    pub friction: f32,
+    #[serde(default)]
+    pub restitution: f32,
+}
+
+fn default_friction() -> f32 {
+    DEFAULT_FRICTION
+}
+
+impl<'a> From<&'a Collider> for ColliderData {
+    fn from(collider: &Collider) -> ColliderData {
+        let shape = match &collider.shape {
+            Shape::Sphere { radius } => ShapeData::Sphere { radius: *radius },
+            Shape::Box { half_extents } => ShapeData::Box {
+                half_extents: [half_extents.x, half_extents.y, half_extents.z],
+            },
+            Shape::Capsule {
+                half_height,
+                radius,
+            } => ShapeData::Capsule {
+                half_height: *half_height,
+                radius: *radius,
+            },
+            Shape::TriMesh(mesh) => ShapeData::TriMesh {
+                mesh: mesh.name().to_string(),
+            },
+            Shape::Heightfield(heightfield) => ShapeData::Heightfield {
+                mesh: heightfield.name().to_string(),
+                columns: heightfield.columns(),
+                rows: heightfield.rows(),
+            },
+        };
+
+        ColliderData {
+            shape,
+            sensor: collider.sensor,
+            friction: collider.friction,
+            restitution: collider.restitution,
+        }
+    }
+}
+
+impl ColliderData {
+    pub fn into_collider(self, library: &MeshLibrary) -> Result<Collider, String> {
+        let mesh = |name: &str| {
+            library
+                .get(name)
+                .ok_or_else(|| format!(r#"There is no mesh called '{}' in the library"#, name))
+        };
+        let no_triangles = |name: &str| format!(r#"Mesh '{}' has no triangles"#, name);
+
+        let collider = match self.shape {
+            ShapeData::Sphere { radius } => Collider::sphere(radius),
+            ShapeData::Box { half_extents: h } => Collider::cuboid(Vector3::new(h[0], h[1], h[2])),
+            ShapeData::Capsule {
+                half_height,
+                radius,
+            } => Collider::capsule(half_height, radius),
+            ShapeData::TriMesh { mesh: name } => {
+                let tri_mesh =
+                    TriMesh::from_mesh(mesh(&name)?).ok_or_else(|| no_triangles(&name))?;
+                Collider::tri_mesh(tri_mesh)
+            }
+            ShapeData::Heightfield {
+                mesh: name,
+                columns,
+                rows,
+            } => {
+                let heightfield = Heightfield::from_mesh(mesh(&name)?, columns, rows)
+                    .ok_or_else(|| no_triangles(&name))?;
+                Collider::heightfield(heightfield)
+            }
+        };
+
+        Ok(collider
+            .with_sensor(self.sensor)
+            .with_friction(self.friction)
+            .with_restitution(self.restitution))
+    }
+}
+
+/// A `CollisionEvent` with entity ids.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CollisionEventData {
+    pub kind: CollisionEventKind,
+    pub a: u32,
+    pub b: u32,
+    pub sensor: bool,
+}
+
+impl<'a> From<&'a CollisionEvent> for CollisionEventData {
+    fn from(event: &CollisionEvent) -> CollisionEventData {
+        CollisionEventData {
+            kind: event.kind,
+            a: event.a.id(),
+            b: event.b.id(),
+            sensor: event.sensor,
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::f32::consts::PI;
+use std::ops::Range;
+
+use nalgebra::{Matrix4, Point3, Vector3, Vector4};
+
+use crate::bounds::Aabb;
+
+/// Floats per line vertex: the position and an RGBA color.
+pub const VERTEX_FLOATS: usize = 7;
+
+/// Segments of each circle of a sphere.
+const SPHERE_SEGMENTS: usize = 24;
+
+/// The twelve edges of a box, between corners numbered by their x, y and z
+/// bits.
+const BOX_EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (2, 3),
+    (4, 5),
+    (6, 7),
+    (0, 2),
+    (1, 3),
+    (4, 6),
+    (5, 7),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+/// Lines to draw over the scene for debugging, in world space. Systems push
+/// shapes into it every frame and they are drawn on top of everything in
+/// the next render, then cleared when the next frame starts. Lines pushed
+/// during a fixed step are kept until the next step instead, so they are
+/// drawn once whether a frame runs no steps or several. Disabled by
+/// default, pushing does nothing until it is enabled.
+#[derive(Default)]
+pub struct DebugDraw {
+    enabled: bool,
+    vertices: Vec<f32>,
+    /// The vertices pushed during the latest fixed step
+    step: Range<usize>,
+}
+
+impl DebugDraw {
+    pub fn new() -> DebugDraw {
+        DebugDraw::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Disabling drops the lines pushed so far.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.clear();
+        }
+    }
+
+    /// The line vertices pushed so far, two per line, `VERTEX_FLOATS` each.
+    pub fn vertices(&self) -> &[f32] {
+        &self.vertices[..]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    /// Drop every line, those of the latest fixed step too.
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+        self.step = 0..0;
+    }
+
+    /// Drop the lines of the previous frame, keeping those of the latest
+    /// fixed step.
+    pub fn begin_frame(&mut self) {
+        self.vertices.truncate(self.step.end);
+        self.vertices.drain(..self.step.start);
+        self.step = 0..self.vertices.len();
+    }
+
+    /// Replace the lines of the previous fixed step with those pushed until
+    /// `end_step`.
+    pub fn begin_step(&mut self) {
+        self.vertices.drain(self.step.clone());
+        self.step = self.vertices.len()..self.vertices.len();
+    }
+
+    pub fn end_step(&mut self) {
+        self.step.end = self.vertices.len();
+    }
+
+    /// A line from `from` to `to`, in RGBA `color`.
+    pub fn line(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: [f32; 4]) {
+        if !self.enabled {
+            return;
+        }
+
+        for point in [from, to].iter() {
+            self.vertices
+                .extend_from_slice(&[point.x, point.y, point.z]);
+            self.vertices.extend_from_slice(&color);
+        }
+    }
+
+    pub fn aabb(&mut self, aabb: &Aabb, color: [f32; 4]) {
+        let (min, max) = (aabb.min, aabb.max);
+        self.box_edges(
+            |x, y, z| {
+                Vector3::new(
+                    if x { max.x } else { min.x },
+                    if y { max.y } else { min.y },
+                    if z { max.z } else { min.z },
+                )
+            },
+            color,
+        );
+    }
+
+    /// A box reaching `half_extents` out from the origin of `transform`
+    /// along its axes.
+    pub fn oriented_box(
+        &mut self,
+        transform: &Matrix4<f32>,
+        half_extents: Vector3<f32>,
+        color: [f32; 4],
+    ) {
+        let sign = |bit: bool| if bit { 1. } else { -1. };
+        self.box_edges(
+            |x, y, z| {
+                let corner = Point3::new(
+                    sign(x) * half_extents.x,
+                    sign(y) * half_extents.y,
+                    sign(z) * half_extents.z,
+                );
+                transform.transform_point(&corner).coords
+            },
+            color,
+        );
+    }
+
+    /// Three circles around `center`, one around every axis.
+    pub fn sphere(&mut self, center: Vector3<f32>, radius: f32, color: [f32; 4]) {
+        let axes = [Vector3::x(), Vector3::y(), Vector3::z()];
+
+        for i in 0..3 {
+            let (u, v) = (axes[(i + 1) % 3] * radius, axes[(i + 2) % 3] * radius);
+            let point = |segment: usize| {
+                let angle = segment as f32 / SPHERE_SEGMENTS as f32 * 2. * PI;
+                center + u * angle.cos() + v * angle.sin()
+            };
+
+            for segment in 0..SPHERE_SEGMENTS {
+                self.line(point(segment), point(segment + 1), color);
+            }
+        }
+    }
+
+    /// The axes of `transform` from its origin, `size` long: X red, Y green
+    /// and Z blue.
+    pub fn axes(&mut self, transform: &Matrix4<f32>, size: f32) {
+        let origin = transform.transform_point(&Point3::origin()).coords;
+        let axes = [
+            (Vector3::x(), [1., 0., 0., 1.]),
+            (Vector3::y(), [0., 1., 0., 1.]),
+            (Vector3::z(), [0., 0., 1., 1.]),
+        ];
+
+        for &(axis, color) in axes.iter() {
+            let end = transform.transform_point(&Point3::from_coordinates(axis * size));
+            self.line(origin, end.coords, color);
+        }
+    }
+
+    /// The volume a camera with `view_projection` sees, from the near to
+    /// the far plane. Does nothing when the matrix can't be inverted.
+    pub fn frustum(&mut self, view_projection: &Matrix4<f32>, color: [f32; 4]) {
+        let inverse = match view_projection.try_inverse() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+
+        let sign = |bit: bool| if bit { 1. } else { -1. };
+        self.box_edges(
+            |x, y, z| {
+                let corner = inverse * Vector4::new(sign(x), sign(y), sign(z), 1.);
+                Vector3::new(corner.x, corner.y, corner.z) / corner.w
+            },
+            color,
+        );
+    }
+
+    /// The edges between the eight corners `corner` gives for every
+    /// combination of high (`true`) and low coordinates.
+    fn box_edges<F>(&mut self, corner: F, color: [f32; 4])
+    where
+        F: Fn(bool, bool, bool) -> Vector3<f32>,
+    {
+        if !self.enabled {
+            return;
+        }
+
+        let corners: Vec<Vector3<f32>> = (0..8)
+            .map(|bits| corner(bits & 1 != 0, bits & 2 != 0, bits & 4 != 0))
+            .collect();
+        for &(a, b) in BOX_EDGES.iter() {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Perspective3;
+
+    fn enabled() -> DebugDraw {
+        let mut debug_draw = DebugDraw::new();
+        debug_draw.set_enabled(true);
+        debug_draw
+    }
+
+    fn points(debug_draw: &DebugDraw) -> Vec<Vector3<f32>> {
+        debug_draw
+            .vertices()
+            .chunks(VERTEX_FLOATS)
+            .map(|vertex| Vector3::new(vertex[0], vertex[1], vertex[2]))
+            .collect()
+    }
+
+    #[test]
+    fn nothing_is_pushed_while_disabled() {
+        let mut debug_draw = DebugDraw::new();
+        debug_draw.line(Vector3::zeros(), Vector3::x(), [1.; 4]);
+        debug_draw.sphere(Vector3::zeros(), 1., [1.; 4]);
+        assert!(debug_draw.is_empty());
+
+        let mut debug_draw = enabled();
+        debug_draw.line(Vector3::zeros(), Vector3::x(), [1.; 4]);
+        assert_eq!(debug_draw.vertices().len(), 2 * VERTEX_FLOATS);
+
+        debug_draw.set_enabled(false);
+        assert!(debug_draw.is_empty());
+    }
+
+    #[test]
+    fn frustum_corners_are_divided_by_w() {
+        let projection = Perspective3::new(1., PI / 2., 1., 10.);
+        let mut debug_draw = enabled();
+        debug_draw.frustum(projection.as_matrix(), [1.; 4]);
+
+        let corners = points(&debug_draw);
+        assert_eq!(corners.len(), 24);
+        for corner in corners {
+            // The sides are at 45 degrees, the corners as far out as deep
+            assert!(
+                (corner.z + 1.).abs() < 1.0e-3 || (corner.z + 10.).abs() < 1.0e-3,
+                "{:?} is on neither plane",
+                corner
+            );
+            assert!((corner.x.abs() + corner.z).abs() < 1.0e-3);
+            assert!((corner.y.abs() + corner.z).abs() < 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn step_lines_last_until_the_next_step() {
+        let mut debug_draw = enabled();
+        let line = |debug_draw: &mut DebugDraw, x: f32| {
+            debug_draw.line(Vector3::new(x, 0., 0.), Vector3::zeros(), [1.; 4]);
+        };
+
+        debug_draw.begin_frame();
+        line(&mut debug_draw, 1.);
+        debug_draw.begin_step();
+        line(&mut debug_draw, 2.);
+        debug_draw.end_step();
+        debug_draw.begin_step();
+        line(&mut debug_draw, 3.);
+        debug_draw.end_step();
+        line(&mut debug_draw, 4.);
+        let xs = |debug_draw: &DebugDraw| -> Vec<f32> {
+            points(debug_draw).iter().step_by(2).map(|p| p.x).collect()
+        };
+        assert_eq!(xs(&debug_draw), vec![1., 3., 4.]);
+
+        // A frame without steps keeps drawing the lines of the last one
+        debug_draw.begin_frame();
+        line(&mut debug_draw, 5.);
+        assert_eq!(xs(&debug_draw), vec![3., 5.]);
+
+        debug_draw.begin_frame();
+        debug_draw.begin_step();
+        debug_draw.end_step();
+        assert!(debug_draw.is_empty());
+    }
+}
@@ -1,18 +1,50 @@
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use chal_engine::assets::Assets;
 use chal_engine::components::Position;
 use chal_engine::shader::ShaderKind;
 use chal_engine::state::State;
-use specs::RunNow;
-use specs::{Builder, World};
+use nalgebra::Vector3;
+use specs::{Builder, Entity, Join, System, World};
 use wasm_bindgen::prelude::*;
 use web_sys::WebGlRenderingContext;
 
-use crate::canvas::create_webgl_context;
-use crate::render::component::{Mesh, RenderSystem};
+use crate::bounds::{Bounds, BoundsSystem};
+use crate::camera::{
+    ActiveCamera, Camera, CameraController, CameraControllerSystem, CameraView, OrbitController,
+};
+use crate::canvas::{create_webgl_context, to_drawing_buffer};
+use crate::component_data::{CollisionEventData, ComponentData, ComponentKind};
+use crate::debug_draw::DebugDraw;
+use crate::hierarchy::{self, Children, Parent, SkeletonPose, TransformPropagationSystem};
+use crate::input::Input;
+use crate::light::Light;
+use crate::particles::{ParticleEmitter, ParticleSystem};
+use crate::physics::collider::Collider;
+use crate::physics::{CollisionEvents, Gravity, PhysicsSystem, RigidBody};
+use crate::picking::{self, PickHit, Ray};
+use crate::prefab::{Prefab, PrefabLibrary};
+use crate::render::component::{Mesh, RenderStats, RenderSystem};
+use crate::render::fog::Fog;
+use crate::render::library::MeshLibrary;
+use crate::render::lod::LodGroup;
+use crate::render::material::Material;
+use crate::render::skybox::Skybox;
+use crate::render::texture::{CubeMapData, TextureData, TextureLibrary};
 use crate::render::Renderer;
+use crate::scene::{EntityData, SceneData};
+use crate::schedule::{Schedule, Shared, Stage};
+use crate::terrain::generator::{TerrainGenerator, TerrainSettings};
+use crate::terrain::streaming::{TerrainChunk, TerrainChunks, TerrainStreamingSystem};
+use crate::terrain::{
+    GroundSnap, GroundSnapSystem, Terrain, TerrainHit, TERRAIN_MESH, TERRAIN_RESOLUTION,
+};
+use crate::time::{AnimationLoop, Clock, Interpolation};
+use crate::transform::{
+    GlobalTransform, InterpolatedTransform, Transform, TransformSnapshotSystem,
+};
 use crate::utils;
 
 pub struct WebGlContext {
@@ -40,10 +72,21 @@ lazy_static! {
 
 #[wasm_bindgen]
 pub struct Engine {
+    core: Rc<RefCell<EngineCore>>,
+    /// Set while `run` drives the engine
+    animation_loop: Option<AnimationLoop>,
+}
+
+/// Everything the engine runs on, shared with the animation frame loop.
+struct EngineCore {
     renderer: Renderer,
     assets: Assets,
     world: World,
-    render_system: RenderSystem,
+    input: Rc<RefCell<Input>>,
+    clock: Clock,
+    schedule: Schedule,
+    /// Also in the render stage, kept here for GPU picking
+    render_system: Rc<RefCell<RenderSystem>>,
 }
 
 #[wasm_bindgen]
@@ -55,29 +98,44 @@ impl Engine {
 
         // let state = Rc::new(RefCell::new(State::new()));
         let state = State::new();
-        // let gl_context = create_webgl_context(Rc::clone(&state)).unwrap();
-        let gl_context = create_webgl_context().unwrap();
+        let input = Rc::new(RefCell::new(Input::new()));
+        let gl_context = create_webgl_context(Rc::clone(&input)).unwrap();
         GLC.contexts.borrow_mut().push(gl_context);
 
         let renderer = Renderer::new();
         let mut assets = Assets::new();
-        let world = setup_world(state);
+        let mut world = setup_world(state);
 
-        let render_system = RenderSystem::new();
+        let render_system = Rc::new(RefCell::new(RenderSystem::new()));
+        let schedule = default_schedule(&render_system).expect("Default systems");
 
         assets.download_meshes(include_bytes!("./meshes/meshes.bytes"));
 
-        Engine {
+        world
+            .write_resource::<MeshLibrary>()
+            .import(&assets, TERRAIN_MESH, ShaderKind::NonSkinnedMesh);
+        let terrain = sampled_terrain(&world);
+        *world.write_resource::<Terrain>() = terrain;
+
+        let core = EngineCore {
             renderer,
             assets,
             world,
+            input,
+            clock: Clock::default(),
+            schedule,
             render_system,
+        };
+
+        Engine {
+            core: Rc::new(RefCell::new(core)),
+            animation_loop: None,
         }
     }
 
     pub fn start(&mut self) -> Result<(), JsValue> {
         log!("start");
-        self.world.create_entity()
+        self.core.borrow_mut().world.create_entity()
             .with(Position { x: 0., y: 0. })
             .with(Mesh::new(
             "Test",
@@ -91,22 +149,674 @@ impl Engine {
         Ok(())
     }
 
-    pub fn update(&self, dt: f32) {
-        // self.state.borrow_mut().advance_clock(dt);
-        let mut world_dt = self.world.write_resource::<DeltaTime>();
-        *world_dt = DeltaTime(dt);
+    /// Update and render on every animation frame, until `stop` is called or
+    /// the engine is freed.
+    pub fn run(&mut self) {
+        if self.animation_loop.is_some() {
+            return;
+        }
+
+        let core = Rc::downgrade(&self.core);
+        let mut last_timestamp: Option<f64> = None;
+
+        self.animation_loop = Some(AnimationLoop::start(move |timestamp| {
+            let core = match core.upgrade() {
+                Some(core) => core,
+                None => return,
+            };
+
+            let dt = last_timestamp
+                .map(|last| ((timestamp - last) / 1000.) as f32)
+                .unwrap_or(0.);
+            last_timestamp = Some(timestamp);
+
+            let mut core = core.borrow_mut();
+            core.update(dt);
+            core.render();
+        }));
+    }
+
+    pub fn stop(&mut self) {
+        self.animation_loop = None;
+    }
+
+    /// Freeze the simulation. Frames keep being rendered.
+    pub fn pause(&mut self) {
+        self.core.borrow_mut().clock.pause();
+    }
+
+    pub fn resume(&mut self) {
+        self.core.borrow_mut().clock.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.core.borrow().clock.is_paused()
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.core.borrow().clock.time_scale()
+    }
+
+    /// Slow motion below 1, fast forward above.
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.core.borrow_mut().clock.set_time_scale(time_scale);
+    }
+
+    /// Advance by `dt` seconds of real time, for driving the engine without
+    /// `run`.
+    pub fn update(&mut self, dt: f32) {
+        self.core.borrow_mut().update(dt);
+    }
+
+    /// Steer the active camera with "orbit", "fly" or "follow". Following
+    /// needs the id of the entity to follow.
+    pub fn set_camera_controller(
+        &mut self,
+        kind: &str,
+        target: Option<u32>,
+    ) -> Result<(), JsValue> {
+        let core = self.core.borrow();
+        let world = &core.world;
+
+        let camera = world
+            .read_resource::<ActiveCamera>()
+            .0
+            .ok_or_else(|| JsValue::from_str("There is no active camera"))?;
+        let target = target.map(|id| world.entities().entity(id));
+
+        let controller = {
+            let transforms = world.read_storage::<Transform>();
+            let transform = transforms.get(camera).cloned().unwrap_or_default();
+            CameraController::from_kind(kind, &transform, target)?
+        };
+
+        world
+            .write_storage::<CameraController>()
+            .insert(camera, controller)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(())
+    }
+
+    /// The entity under (`x`, `y`) in CSS pixels from the top left of the
+    /// canvas, like mouse event offsets, found by casting a ray against the
+    /// triangles of every mesh.
+    pub fn pick(&self, x: f32, y: f32) -> Option<PickHit> {
+        let core = self.core.borrow();
+        let ray = core.pick_ray(x, y)?;
+        picking::ray_cast(&core.world, &ray)
+    }
+
+    /// Like `pick`, but asks the GPU which entity was drawn on the pixel in
+    /// the last frame. Exact for any geometry, at the cost of a readback.
+    pub fn pick_gpu(&mut self, x: f32, y: f32) -> Option<PickHit> {
+        let mut core = self.core.borrow_mut();
+        let (buffer_x, buffer_y) = to_drawing_buffer(&GLC.contexts.borrow()[0], x, y);
+        let entity = core
+            .render_system
+            .borrow_mut()
+            .pick(buffer_x as i32, buffer_y as i32)?;
+
+        // Deleted since the last frame, its id may already be reused
+        if !core.world.is_alive(entity) {
+            return None;
+        }
+
+        let ray = core.pick_ray(x, y)?;
+        picking::ray_cast_entity(&core.world, &ray, entity)
+    }
+
+    /// Height of the terrain at (`x`, `z`), `undefined` beyond its edges.
+    pub fn terrain_height(&self, x: f32, z: f32) -> Option<f32> {
+        let core = self.core.borrow();
+        let terrain = core.world.read_resource::<Terrain>();
+        terrain.height_at(x, z)
+    }
+
+    /// Normal of the terrain at (`x`, `z`) as `[x, y, z]`, `null` beyond its
+    /// edges.
+    pub fn terrain_normal(&self, x: f32, z: f32) -> JsValue {
+        let core = self.core.borrow();
+        let normal = core.world.read_resource::<Terrain>().normal_at(x, z);
+        JsValue::from_serde(&normal.map(|n| [n.x, n.y, n.z])).unwrap()
+    }
+
+    /// Where a ray from `origin` going along `direction`, both `[x, y, z]`,
+    /// hits the terrain at most `max_distance` away.
+    pub fn raycast_terrain(
+        &self,
+        origin: &[f32],
+        direction: &[f32],
+        max_distance: f32,
+    ) -> Result<Option<TerrainHit>, JsValue> {
+        if origin.len() != 3 || direction.len() != 3 {
+            return Err(JsValue::from_str("Origin and direction are [x, y, z]"));
+        }
+        let direction = Vector3::from_column_slice(direction);
+        if direction.norm() == 0. {
+            return Err(JsValue::from_str("The direction can't be zero"));
+        }
+
+        let ray = Ray::new(Vector3::from_column_slice(origin), direction);
+        let core = self.core.borrow();
+        let terrain = core.world.read_resource::<Terrain>();
+        Ok(terrain.raycast(&ray, max_distance))
+    }
+
+    /// The point of the terrain under (`x`, `y`) in CSS pixels, like `pick`.
+    pub fn pick_terrain(&self, x: f32, y: f32) -> Option<TerrainHit> {
+        let core = self.core.borrow();
+        let ray = core.pick_ray(x, y)?;
+        let terrain = core.world.read_resource::<Terrain>();
+        terrain.raycast(&ray, std::f32::INFINITY)
+    }
+
+    /// Whether an entity with a ground snap component stood on the terrain
+    /// after the last fixed step.
+    pub fn is_grounded(&self, entity: u32) -> Result<bool, JsValue> {
+        let core = self.core.borrow();
+        let entity = core.entity(entity)?;
+        let snaps = core.world.read_storage::<GroundSnap>();
+        Ok(snaps.get(entity).map_or(false, GroundSnap::grounded))
+    }
+
+    /// Replace the bundled terrain with one generated from noise, in chunks
+    /// around the active camera. `settings` are `TerrainSettings` as a plain
+    /// object, `undefined` for the defaults. Terrain queries and ground
+    /// snapping follow the generated terrain from then on.
+    pub fn generate_terrain(&mut self, settings: JsValue) -> Result<(), JsValue> {
+        let settings: TerrainSettings = if settings.is_undefined() || settings.is_null() {
+            TerrainSettings::default()
+        } else {
+            settings
+                .into_serde()
+                .map_err(|err| JsValue::from_str(&err.to_string()))?
+        };
+        let generator = Arc::new(TerrainGenerator::new(settings)?);
+
+        let core = self.core.borrow();
+        let world = &core.world;
+        *world.write_resource::<Terrain>() = Terrain::generated(Arc::clone(&generator));
+        world
+            .write_resource::<TerrainChunks>()
+            .set_generator(Some(generator));
+        Ok(())
+    }
+
+    /// Remove the generated terrain and go back to the bundled one.
+    pub fn clear_generated_terrain(&mut self) {
+        let core = self.core.borrow();
+        let world = &core.world;
+        world.write_resource::<TerrainChunks>().set_generator(None);
+        *world.write_resource::<Terrain>() = sampled_terrain(world);
     }
 
     pub fn render(&mut self) {
+        self.core.borrow_mut().render();
+    }
+
+    /// Drawn, culled and LOD hidden entity counts of the last rendered frame.
+    pub fn render_stats(&self) -> RenderStats {
+        *self.core.borrow().world.read_resource::<RenderStats>()
+    }
+
+    /// Add an image for particle emitters and the maps of lit materials to
+    /// refer to by `name`, replacing the one with the same name. `pixels`
+    /// are `width` by `height` RGBA bytes, row by row from the top.
+    pub fn add_texture(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<(), JsValue> {
+        let texture = TextureData::new(width, height, pixels.to_vec())?;
+        let core = self.core.borrow();
+        core.world
+            .write_resource::<TextureLibrary>()
+            .insert(name, texture);
+        Ok(())
+    }
+
+    /// Add a cube map for skyboxes to refer to by `name`, replacing the one
+    /// with the same name. `pixels` are the six `size` by `size` RGBA faces
+    /// one after the other, in the order +X, -X, +Y, -Y, +Z, -Z.
+    pub fn add_cube_map(&mut self, name: &str, size: u32, pixels: &[u8]) -> Result<(), JsValue> {
+        let cube_map = CubeMapData::from_faces(size, pixels)?;
+        let core = self.core.borrow();
+        core.world
+            .write_resource::<TextureLibrary>()
+            .insert_cube_map(name, cube_map);
+        Ok(())
+    }
+
+    /// Like `add_cube_map`, from a `width` by `height` panorama in the
+    /// equirectangular projection, resampled into faces of `size` pixels.
+    pub fn add_equirectangular_cube_map(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        size: u32,
+    ) -> Result<(), JsValue> {
+        if size == 0 {
+            return Err(JsValue::from_str("Cube map faces need at least one pixel"));
+        }
+        let panorama = TextureData::new(width, height, pixels.to_vec())?;
+        let cube_map = CubeMapData::from_equirectangular(&panorama, size);
+        let core = self.core.borrow();
+        core.world
+            .write_resource::<TextureLibrary>()
+            .insert_cube_map(name, cube_map);
+        Ok(())
+    }
+
+    /// Change what is seen behind everything and reflected by materials.
+    /// `skybox` is a `Skybox` as a plain object, `undefined` for the
+    /// default sky blue.
+    pub fn set_skybox(&mut self, skybox: JsValue) -> Result<(), JsValue> {
+        let skybox: Skybox = if skybox.is_undefined() || skybox.is_null() {
+            Skybox::default()
+        } else {
+            skybox
+                .into_serde()
+                .map_err(|err| JsValue::from_str(&err.to_string()))?
+        };
+
+        let core = self.core.borrow();
+        *core.world.write_resource::<Skybox>() = skybox;
+        Ok(())
+    }
+
+    pub fn skybox(&self) -> JsValue {
+        let core = self.core.borrow();
+        let skybox = core.world.read_resource::<Skybox>();
+        JsValue::from_serde(&*skybox).unwrap()
+    }
+
+    /// Change the fog over the scene. `fog` is a `Fog` as a plain object,
+    /// `undefined` for none.
+    pub fn set_fog(&mut self, fog: JsValue) -> Result<(), JsValue> {
+        let fog: Fog = if fog.is_undefined() || fog.is_null() {
+            Fog::default()
+        } else {
+            fog.into_serde()
+                .map_err(|err| JsValue::from_str(&err.to_string()))?
+        };
+
+        let core = self.core.borrow();
+        *core.world.write_resource::<Fog>() = fog;
+        Ok(())
+    }
+
+    pub fn fog(&self) -> JsValue {
+        let core = self.core.borrow();
+        let fog = core.world.read_resource::<Fog>();
+        JsValue::from_serde(&*fog).unwrap()
+    }
+
+    /// Show the lines systems push into the `DebugDraw` resource, drawn on
+    /// top of the scene. Off by default.
+    pub fn set_debug_draw(&mut self, enabled: bool) {
+        let core = self.core.borrow();
+        let mut debug_draw = core.world.write_resource::<DebugDraw>();
+        debug_draw.set_enabled(enabled);
+    }
+
+    pub fn debug_draw(&self) -> bool {
+        let core = self.core.borrow();
+        let debug_draw = core.world.read_resource::<DebugDraw>();
+        debug_draw.is_enabled()
+    }
+}
+
+#[wasm_bindgen]
+impl Engine {
+    /// Create an entity without any components, returning its id.
+    pub fn spawn(&mut self) -> u32 {
+        self.core.borrow_mut().world.create_entity().build().id()
+    }
+
+    /// Delete an entity along with everything attached to it.
+    pub fn despawn(&mut self, entity: u32) -> Result<(), JsValue> {
+        let mut core = self.core.borrow_mut();
+        let entity = core.entity(entity)?;
+        hierarchy::despawn_recursive(&core.world, entity);
+        // Gone right away, rather than alive until the next frame
+        core.world.maintain();
+        Ok(())
+    }
+
+    pub fn is_alive(&self, entity: u32) -> bool {
+        self.core.borrow().entity(entity).is_ok()
+    }
+
+    /// Add a component to an entity or replace the one it has. `kind` is one
+    /// of "transform", "mesh", "material", "light", "camera", "rigid_body",
+    /// "collider", "ground_snap" or "particle_emitter", `data` a plain object in the shape
+    /// `get_component` returns.
+    pub fn set_component(
+        &mut self,
+        entity: u32,
+        kind: &str,
+        data: JsValue,
+    ) -> Result<(), JsValue> {
+        let core = self.core.borrow();
+        let entity = core.entity(entity)?;
+        let kind = ComponentKind::from_name(kind)?;
+
+        let value = data
+            .into_serde()
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        ComponentData::from_value(kind, value)?.insert(&core.world, entity)?;
+        Ok(())
+    }
+
+    /// The component as a plain object, `undefined` when the entity doesn't
+    /// have it.
+    pub fn get_component(&self, entity: u32, kind: &str) -> Result<JsValue, JsValue> {
+        let core = self.core.borrow();
+        let entity = core.entity(entity)?;
+        let kind = ComponentKind::from_name(kind)?;
+
+        match kind.get(&core.world, entity) {
+            Some(data) => JsValue::from_serde(&data.to_value())
+                .map_err(|err| JsValue::from_str(&err.to_string())),
+            None => Ok(JsValue::UNDEFINED),
+        }
+    }
+
+    pub fn remove_component(&mut self, entity: u32, kind: &str) -> Result<(), JsValue> {
+        let core = self.core.borrow();
+        let entity = core.entity(entity)?;
+        ComponentKind::from_name(kind)?.remove(&core.world, entity);
+        Ok(())
+    }
+
+    /// Ids of the entities that have every component named in `kinds`.
+    pub fn query(&self, kinds: Box<[JsValue]>) -> Result<Vec<u32>, JsValue> {
+        let core = self.core.borrow();
+        let kinds = kinds
+            .iter()
+            .map(|kind| {
+                let name = kind
+                    .as_string()
+                    .ok_or_else(|| "Component kinds are strings".to_string())?;
+                ComponentKind::from_name(&name)
+            })
+            .collect::<Result<Vec<ComponentKind>, String>>()?;
+
+        let entities = core.world.entities();
+        let ids = (&*entities)
+            .join()
+            .filter(|&entity| kinds.iter().all(|kind| kind.has(&core.world, entity)))
+            .map(|entity| entity.id())
+            .collect();
+
+        Ok(ids)
+    }
+
+    /// Replace everything in the world with the entities of a JSON scene,
+    /// see the `scene` module for the format.
+    pub fn load_scene(&mut self, json: &str) -> Result<(), JsValue> {
+        let scene = SceneData::from_json(json)?;
+
+        let mut core = self.core.borrow_mut();
+        let world = &mut core.world;
+        // Everything that can fail happens before the world is cleared
+        let built = scene.build(&world.read_resource::<MeshLibrary>())?;
+
+        world.delete_all();
+        world.maintain();
+        *world.write_resource::<ActiveCamera>() = ActiveCamera(None);
+        *world.write_resource::<Skybox>() = Skybox::default();
+        *world.write_resource::<Fog>() = Fog::default();
+
+        scene.instantiate_built(world, built, None);
+        world.maintain();
+        Ok(())
+    }
+
+    /// The world as a JSON scene, to be loaded again with `load_scene`.
+    pub fn save_scene(&self) -> String {
+        SceneData::from_world(&self.core.borrow().world).to_json()
+    }
+
+    /// Add a prefab, or replace the one with the same name. See the `prefab`
+    /// module for the format.
+    pub fn add_prefab(&mut self, name: &str, json: &str) -> Result<(), JsValue> {
+        let prefab = Prefab::from_json(json)?;
+        let core = self.core.borrow();
+        core.world
+            .write_resource::<PrefabLibrary>()
+            .insert(name, prefab);
+        Ok(())
+    }
+
+    /// Spawn a copy of a prefab and return its root. `overrides` is an entity
+    /// in the scene format whose components replace those of the root, or
+    /// `undefined`.
+    pub fn spawn_prefab(&mut self, name: &str, overrides: JsValue) -> Result<u32, JsValue> {
+        let overrides: Option<EntityData> = if overrides.is_undefined() || overrides.is_null() {
+            None
+        } else {
+            let overrides = overrides
+                .into_serde()
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            Some(overrides)
+        };
+
+        let mut core = self.core.borrow_mut();
+        let world = &mut core.world;
+        let template = {
+            let meshes = world.read_resource::<MeshLibrary>();
+            world
+                .write_resource::<PrefabLibrary>()
+                .template(name, &meshes)?
+        };
+
+        let root = template.spawn(world, overrides.as_ref())?;
+        world.maintain();
+        Ok(root.id())
+    }
+
+    /// Spawn one copy of a prefab at each `[x, y, z]` of a flat list of
+    /// translations, returning the roots. The prefab is resolved once for
+    /// all of them, the copies share the vertices of its meshes and the GPU
+    /// buffers of its static ones.
+    pub fn spawn_prefabs(&mut self, name: &str, translations: &[f32]) -> Result<Vec<u32>, JsValue> {
+        if translations.len() % 3 != 0 {
+            return Err(JsValue::from_str("Translations come in groups of three"));
+        }
+
+        let mut core = self.core.borrow_mut();
+        let world = &mut core.world;
+        let template = {
+            let meshes = world.read_resource::<MeshLibrary>();
+            world
+                .write_resource::<PrefabLibrary>()
+                .template(name, &meshes)?
+        };
+
+        let mut overrides = EntityData {
+            transform: Some(template.root_transform()),
+            ..EntityData::default()
+        };
+        let mut roots = Vec::with_capacity(translations.len() / 3);
+        for translation in translations.chunks(3) {
+            if let Some(transform) = overrides.transform.as_mut() {
+                transform.translation.copy_from_slice(translation);
+            }
+            roots.push(template.spawn(world, Some(&overrides))?.id());
+        }
+
+        world.maintain();
+        Ok(roots)
+    }
+
+    /// Collisions that started or stopped during the last update, as
+    /// `{ kind, a, b, sensor }` objects with `kind` either "started" or
+    /// "stopped".
+    pub fn collision_events(&self) -> JsValue {
+        let core = self.core.borrow();
+        let events: Vec<CollisionEventData> = core
+            .world
+            .read_resource::<CollisionEvents>()
+            .all()
+            .iter()
+            .map(CollisionEventData::from)
+            .collect();
+        JsValue::from_serde(&events).unwrap()
+    }
+
+    pub fn set_gravity(&mut self, x: f32, y: f32, z: f32) {
+        let core = self.core.borrow();
+        *core.world.write_resource::<Gravity>() = Gravity(Vector3::new(x, y, z));
+    }
+
+    /// Change the velocity of a rigid body at once, as if hit at its center.
+    pub fn apply_impulse(&mut self, entity: u32, x: f32, y: f32, z: f32) -> Result<(), JsValue> {
+        let core = self.core.borrow();
+        let entity = core.entity(entity)?;
+
+        let mut rigid_bodies = core.world.write_storage::<RigidBody>();
+        let rigid_body = rigid_bodies
+            .get_mut(entity)
+            .ok_or_else(|| JsValue::from_str("The entity has no rigid body"))?;
+        rigid_body.apply_impulse(Vector3::new(x, y, z));
+        Ok(())
+    }
+
+    /// Render from this entity, which needs a camera component.
+    pub fn set_active_camera(&mut self, entity: u32) -> Result<(), JsValue> {
+        let core = self.core.borrow();
+        let entity = core.entity(entity)?;
+
+        if !ComponentKind::Camera.has(&core.world, entity) {
+            return Err(JsValue::from_str("The entity has no camera component"));
+        }
+        *core.world.write_resource::<ActiveCamera>() = ActiveCamera(Some(entity));
+        Ok(())
+    }
+}
+
+impl Engine {
+    /// Run `system` every frame in `stage`, after the systems named in
+    /// `dependencies`. Systems have to be added before the first update.
+    pub fn add_system<S>(
+        &mut self,
+        stage: Stage,
+        system: S,
+        name: &str,
+        dependencies: &[&str],
+    ) -> Result<(), String>
+    where
+        S: for<'c> System<'c> + Send + 'static,
+    {
+        let mut core = self.core.borrow_mut();
+        core.schedule.add(stage, system, name, dependencies)
+    }
+
+    /// Like `add_system`, for systems that have to stay on the main thread.
+    pub fn add_thread_local_system<S>(&mut self, stage: Stage, system: S) -> Result<(), String>
+    where
+        S: for<'c> System<'c> + 'static,
+    {
+        let mut core = self.core.borrow_mut();
+        core.schedule.add_thread_local(stage, system)
+    }
+}
+
+impl EngineCore {
+    /// Run as many fixed steps as fit in `real_dt`, then everything that
+    /// happens once per frame.
+    fn update(&mut self, real_dt: f32) {
+        // self.state.borrow_mut().advance_clock(dt);
+        let tick = self.clock.advance(real_dt);
+        self.world.write_resource::<CollisionEvents>().clear();
+        // Debug lines last until the frame they were pushed in is rendered,
+        // or until the next step for those pushed during a step
+        self.world.write_resource::<DebugDraw>().begin_frame();
+
+        {
+            let mut input = self.input.borrow_mut();
+            *self.world.write_resource::<Input>() = input.clone();
+            input.end_frame();
+        }
+
+        *self.world.write_resource::<DeltaTime>() = DeltaTime(tick.dt);
+        *self.world.write_resource::<RealDeltaTime>() = RealDeltaTime(tick.real_dt);
+        self.schedule.dispatch(Stage::Input, &mut self.world.res);
+
+        for _ in 0..tick.steps {
+            *self.world.write_resource::<DeltaTime>() = DeltaTime(self.clock.fixed_step);
+            self.world.write_resource::<DebugDraw>().begin_step();
+            self.schedule.dispatch(Stage::Simulation, &mut self.world.res);
+            self.world.write_resource::<DebugDraw>().end_step();
+            self.world.maintain();
+        }
+
+        *self.world.write_resource::<Interpolation>() = Interpolation(tick.alpha);
+        *self.world.write_resource::<DeltaTime>() = DeltaTime(tick.dt);
+    }
+
+    fn render(&mut self) {
         // self.renderer.render(&self.state.borrow(), &self.assets)
-        self.render_system.run_now(&self.world.res);
+        self.schedule.dispatch(Stage::Transform, &mut self.world.res);
+        self.schedule.dispatch(Stage::Animation, &mut self.world.res);
+        self.schedule.dispatch(Stage::Render, &mut self.world.res);
         self.world.maintain();
     }
+
+    /// The living entity with id `id`.
+    fn entity(&self, id: u32) -> Result<Entity, JsValue> {
+        let entities = self.world.entities();
+        let entity = entities.entity(id);
+
+        if entities.is_alive(entity) {
+            Ok(entity)
+        } else {
+            Err(JsValue::from_str(&format!("Entity {} does not exist", id)))
+        }
+    }
+
+    /// The ray through the point under (`x`, `y`) in CSS pixels.
+    fn pick_ray(&self, x: f32, y: f32) -> Option<Ray> {
+        let gl = &GLC.contexts.borrow()[0];
+        let canvas_size = (gl.drawing_buffer_width(), gl.drawing_buffer_height());
+        let (x, y) = to_drawing_buffer(gl, x, y);
+
+        let view = CameraView::active(
+            &self.world.read_resource::<ActiveCamera>(),
+            &self.world.read_storage::<Camera>(),
+            &self.world.read_storage::<GlobalTransform>(),
+            canvas_size,
+        )?;
+
+        Ray::from_screen(x, y, canvas_size.1, &view)
+    }
 }
 
 use std::default::Default;
 
-struct DeltaTime(f32);
+pub struct DeltaTime(pub f32);
+
+impl Default for DeltaTime {
+    fn default() -> DeltaTime {
+        DeltaTime(0.0)
+    }
+}
+
+/// Time since the previous frame, still running while the clock is paused
+/// or slowed down. For things like cameras that aren't part of the game.
+pub struct RealDeltaTime(pub f32);
+
+impl Default for RealDeltaTime {
+    fn default() -> RealDeltaTime {
+        RealDeltaTime(0.0)
+    }
+}
+
 pub struct GameState(pub State);
 
 impl Default for GameState {
@@ -115,13 +825,93 @@ impl Default for GameState {
     }
 }
 
+/// The engine's own systems. The transform snapshot goes first in every
+/// fixed step, so gameplay systems added later see it done. Physics runs
+/// after the snapshot, systems that need its results depend on "physics",
+/// and ground snapping after physics. Generated terrain chunks are created
+/// before transforms are propagated, so they show up the frame they are.
+/// Particles move once per frame, from where their emitters are drawn.
+fn default_schedule(render_system: &Rc<RefCell<RenderSystem>>) -> Result<Schedule, String> {
+    let mut schedule = Schedule::new();
+
+    schedule.add(Stage::Input, CameraControllerSystem, "camera_controller", &[])?;
+
+    schedule.add(Stage::Simulation, TransformSnapshotSystem, "transform_snapshot", &[])?;
+    schedule.add_barrier(Stage::Simulation)?;
+    schedule.add(Stage::Simulation, PhysicsSystem::default(), "physics", &[])?;
+    schedule.add(Stage::Simulation, GroundSnapSystem, "ground_snap", &["physics"])?;
+
+    schedule.add(Stage::Transform, TerrainStreamingSystem, "terrain_streaming", &[])?;
+    schedule.add(
+        Stage::Transform,
+        TransformPropagationSystem,
+        "transform_propagation",
+        &["terrain_streaming"],
+    )?;
+    schedule.add(Stage::Transform, BoundsSystem, "bounds", &["transform_propagation"])?;
+
+    schedule.add(Stage::Animation, ParticleSystem, "particles", &[])?;
+
+    schedule.add_thread_local(Stage::Render, Shared::new(render_system))?;
+
+    Ok(schedule)
+}
+
+/// The bundled terrain mesh as a heightfield, or no terrain without it.
+fn sampled_terrain(world: &World) -> Terrain {
+    world
+        .read_resource::<MeshLibrary>()
+        .get(TERRAIN_MESH)
+        .map(|mesh| Terrain::from_mesh(mesh, TERRAIN_RESOLUTION))
+        .unwrap_or_default()
+}
+
 fn setup_world(state: State) -> World {
     let mut world = World::new();
     world.register::<Position>();
     world.register::<Mesh>();
+    world.register::<Transform>();
+    world.register::<GlobalTransform>();
+    world.register::<InterpolatedTransform>();
+    world.register::<Parent>();
+    world.register::<Children>();
+    world.register::<SkeletonPose>();
+    world.register::<Material>();
+    world.register::<Bounds>();
+    world.register::<LodGroup>();
+    world.register::<Camera>();
+    world.register::<CameraController>();
+    world.register::<Light>();
+    world.register::<RigidBody>();
+    world.register::<Collider>();
+    world.register::<GroundSnap>();
+    world.register::<TerrainChunk>();
+    world.register::<ParticleEmitter>();
 
     world.add_resource(DeltaTime(0.0));
+    world.add_resource(RealDeltaTime(0.0));
+    world.add_resource(Interpolation::default());
     world.add_resource(GameState(state));
+    world.add_resource(RenderStats::default());
+    world.add_resource(MeshLibrary::new());
+    world.add_resource(PrefabLibrary::new());
+    world.add_resource(Gravity::default());
+    world.add_resource(CollisionEvents::default());
+    world.add_resource(Terrain::new());
+    world.add_resource(TerrainChunks::new());
+    world.add_resource(TextureLibrary::new());
+    world.add_resource(Skybox::default());
+    world.add_resource(Fog::default());
+    world.add_resource(Input::new());
+    world.add_resource(DebugDraw::new());
+
+    let camera = world
+        .create_entity()
+        .with(Transform::default())
+        .with(Camera::perspective(std::f32::consts::PI / 4., 0.1, 1000.))
+        .with(CameraController::Orbit(OrbitController::default()))
+        .build();
+    world.add_resource(ActiveCamera(Some(camera)));
 
     // world
     //     .create_entity()
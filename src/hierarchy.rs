@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+
+use nalgebra::Matrix4;
+use specs::{
+    Component, DenseVecStorage, Entities, Entity, Join, Read, ReadStorage, System, World,
+    WriteStorage,
+};
+
+use crate::time::Interpolation;
+use crate::transform::{GlobalTransform, InterpolatedTransform, Transform};
+
+/// Attaches an entity to another one, so it moves along with it. The child's
+/// `Transform` is then relative to its parent, or to one of the parent's
+/// bones.
+///
+/// Reparenting is a matter of inserting a new `Parent`, the local transform
+/// is kept as is.
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(DenseVecStorage)]
+pub struct Parent {
+    pub entity: Entity,
+    /// Name of a bone in the parent's `SkeletonPose` to follow
+    pub bone: Option<String>,
+}
+
+impl Parent {
+    pub fn new(entity: Entity) -> Parent {
+        Parent { entity, bone: None }
+    }
+
+    pub fn bone<S: Into<String>>(entity: Entity, bone: S) -> Parent {
+        Parent {
+            entity,
+            bone: Some(bone.into()),
+        }
+    }
+}
+
+/// The direct children of an entity. Derived from the `Parent` components
+/// every frame, so don't edit it by hand.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+#[storage(DenseVecStorage)]
+pub struct Children(Vec<Entity>);
+
+impl Children {
+    pub fn iter(&self) -> impl Iterator<Item = &Entity> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Model space matrices of the bones of an armature, written by whatever
+/// poses it. Children attached to a bone follow these.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+#[storage(DenseVecStorage)]
+pub struct SkeletonPose {
+    bones: HashMap<String, Matrix4<f32>>,
+}
+
+impl SkeletonPose {
+    pub fn new() -> SkeletonPose {
+        SkeletonPose::default()
+    }
+
+    pub fn set_bone<S: Into<String>>(&mut self, bone: S, matrix: Matrix4<f32>) {
+        self.bones.insert(bone.into(), matrix);
+    }
+
+    pub fn bone(&self, bone: &str) -> Option<&Matrix4<f32>> {
+        self.bones.get(bone)
+    }
+}
+
+/// Computes the `GlobalTransform` of every entity, parents before children.
+/// Entities without a `Transform` or children lose theirs. A parent cycle
+/// is broken by making one of its entities a root.
+pub struct TransformPropagationSystem;
+
+impl<'a> System<'a> for TransformPropagationSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Interpolation>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, InterpolatedTransform>,
+        ReadStorage<'a, SkeletonPose>,
+        WriteStorage<'a, Parent>,
+        WriteStorage<'a, Children>,
+        WriteStorage<'a, GlobalTransform>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            interpolation,
+            transform,
+            interpolated,
+            pose,
+            mut parent,
+            mut children,
+            mut global,
+        ) = data;
+
+        // Children of a deleted parent become roots
+        let orphans: Vec<Entity> = (&entities, &parent)
+            .join()
+            .filter(|(_, parent)| !entities.is_alive(parent.entity))
+            .map(|(entity, _)| entity)
+            .collect();
+        for orphan in orphans {
+            parent.remove(orphan);
+        }
+
+        // Nothing in a cycle is reached from a root
+        let links: Vec<(Entity, Entity)> = (&entities, &parent)
+            .join()
+            .map(|(e, p)| (e, p.entity))
+            .collect();
+        for entity in cycle_breakers(&links) {
+            log!(
+                "Entity {} is its own ancestor, detaching it from its parent",
+                entity.id()
+            );
+            parent.remove(entity);
+        }
+
+        let hierarchy =
+            children_by_parent((&entities, &parent).join().map(|(e, p)| (e, p.entity)));
+
+        let stale: Vec<Entity> = (&entities, &children)
+            .join()
+            .filter(|(entity, _)| !hierarchy.contains_key(entity))
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in stale {
+            children.remove(entity);
+        }
+        // Only touched when they changed, so change tracking stays useful
+        for (&entity, kids) in hierarchy.iter() {
+            if children
+                .get(entity)
+                .map_or(false, |existing| existing.0 == *kids)
+            {
+                continue;
+            }
+            match children.get_mut(entity) {
+                Some(existing) => existing.0 = kids.clone(),
+                None => {
+                    children
+                        .insert(entity, Children(kids.clone()))
+                        .expect("Insert children");
+                }
+            }
+        }
+
+        let mut stack: Vec<(Entity, Matrix4<f32>)> = (&entities, !&parent)
+            .join()
+            .map(|(entity, _)| (entity, Matrix4::identity()))
+            .collect();
+        let mut visited = HashSet::new();
+        let mut placed = HashSet::new();
+
+        while let Some((entity, parent_world)) = stack.pop() {
+            // Guards against cycles, which are never reached from a root anyway
+            if !visited.insert(entity) {
+                continue;
+            }
+
+            let local = transform.get(entity).map(|t| match interpolated.get(entity) {
+                Some(interpolated) => interpolated.previous.lerp(t, interpolation.0).matrix(),
+                None => t.matrix(),
+            });
+            let world = parent_world * local.unwrap_or_else(Matrix4::identity);
+
+            let kids = hierarchy.get(&entity);
+            if local.is_some() || kids.is_some() {
+                match global.get_mut(entity) {
+                    Some(existing) => existing.0 = world,
+                    None => {
+                        global
+                            .insert(entity, GlobalTransform(world))
+                            .expect("Insert global transform");
+                    }
+                }
+                placed.insert(entity);
+            }
+
+            for &child in kids.into_iter().flatten() {
+                let attachment = parent
+                    .get(child)
+                    .and_then(|p| p.bone.as_ref())
+                    .and_then(|bone| pose.get(entity).and_then(|pose| pose.bone(bone)))
+                    .cloned()
+                    .unwrap_or_else(Matrix4::identity);
+
+                stack.push((child, world * attachment));
+            }
+        }
+
+        // Left behind by a removed `Transform`
+        let unplaced: Vec<Entity> = (&entities, &global)
+            .join()
+            .map(|(entity, _)| entity)
+            .filter(|entity| !placed.contains(entity))
+            .collect();
+        for entity in unplaced {
+            global.remove(entity);
+        }
+    }
+}
+
+/// One entity of every cycle in the `(child, parent)` links, which becomes
+/// a root once its link is removed.
+fn cycle_breakers(links: &[(Entity, Entity)]) -> Vec<Entity> {
+    let parents: HashMap<Entity, Entity> = links.iter().cloned().collect();
+    // Entities whose chain of ancestors was followed before
+    let mut settled = HashSet::new();
+    let mut breakers = Vec::new();
+
+    for &(start, _) in links {
+        let mut chain = HashSet::new();
+        let mut current = start;
+        while !settled.contains(&current) {
+            if !chain.insert(current) {
+                breakers.push(current);
+                break;
+            }
+            match parents.get(&current) {
+                Some(&next) => current = next,
+                None => break,
+            }
+        }
+        settled.extend(chain);
+    }
+
+    breakers
+}
+
+/// Group `(child, parent)` links by parent.
+fn children_by_parent<I>(links: I) -> HashMap<Entity, Vec<Entity>>
+where
+    I: IntoIterator<Item = (Entity, Entity)>,
+{
+    let mut hierarchy: HashMap<Entity, Vec<Entity>> = HashMap::new();
+    for (child, parent) in links {
+        hierarchy.entry(parent).or_default().push(child);
+    }
+    hierarchy
+}
+
+/// Attach `child` to `parent`, replacing any previous parent.
+pub fn set_parent(world: &World, child: Entity, parent: Parent) {
+    world
+        .write_storage::<Parent>()
+        .insert(child, parent)
+        .expect("Insert parent");
+}
+
+/// Detach `child`, making it a root. Its local transform becomes its world
+/// transform.
+pub fn remove_parent(world: &World, child: Entity) {
+    world.write_storage::<Parent>().remove(child);
+}
+
+/// Delete `root` and everything attached to it. The entities are gone after
+/// the next `World::maintain`.
+pub fn despawn_recursive(world: &World, root: Entity) {
+    let entities = world.entities();
+    let parent = world.read_storage::<Parent>();
+    let hierarchy =
+        children_by_parent((&*entities, &parent).join().map(|(e, p)| (e, p.entity)));
+
+    let mut doomed = vec![root];
+    let mut deleted = HashSet::new();
+    while let Some(entity) = doomed.pop() {
+        // A parent cycle would come around again
+        if !deleted.insert(entity) {
+            continue;
+        }
+
+        if let Some(kids) = hierarchy.get(&entity) {
+            doomed.extend(kids.iter().cloned());
+        }
+        entities.delete(entity).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+    use specs::{Builder, RunNow};
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.register::<Parent>();
+        world.register::<Children>();
+        world.register::<SkeletonPose>();
+        world.register::<Transform>();
+        world.register::<InterpolatedTransform>();
+        world.register::<GlobalTransform>();
+        world.add_resource(Interpolation::default());
+        world
+    }
+
+    fn propagate(world: &mut World) {
+        TransformPropagationSystem.run_now(&world.res);
+        world.maintain();
+    }
+
+    fn global(world: &World, entity: Entity) -> Option<Matrix4<f32>> {
+        world
+            .read_storage::<GlobalTransform>()
+            .get(entity)
+            .map(|global| global.0)
+    }
+
+    #[test]
+    fn propagation_composes_parent_chains() {
+        let mut world = world();
+        let root = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(1.0, 0.0, 0.0)))
+            .build();
+        let child = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0.0, 2.0, 0.0)).with_scale(Vector3::repeat(2.0)))
+            .with(Parent::new(root))
+            .build();
+        let grandchild = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0.0, 0.0, 3.0)))
+            .with(Parent::new(child))
+            .build();
+
+        propagate(&mut world);
+
+        let expected =
+            Matrix4::new_translation(&Vector3::new(1.0, 2.0, 6.0)) * Matrix4::new_scaling(2.0);
+        assert_eq!(global(&world, grandchild), Some(expected));
+        assert_eq!(
+            world.read_storage::<Children>().get(root),
+            Some(&Children(vec![child]))
+        );
+    }
+
+    #[test]
+    fn bone_attachments_follow_the_pose() {
+        let mut world = world();
+        let bone = Matrix4::new_translation(&Vector3::new(0.0, 5.0, 0.0));
+        let mut pose = SkeletonPose::new();
+        pose.set_bone("hand", bone);
+        let body = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(1.0, 0.0, 0.0)))
+            .with(pose)
+            .build();
+        let sword = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0.0, 0.0, 1.0)))
+            .with(Parent::bone(body, "hand"))
+            .build();
+        let unknown = world
+            .create_entity()
+            .with(Transform::default())
+            .with(Parent::bone(body, "tail"))
+            .build();
+
+        propagate(&mut world);
+
+        let expected = Matrix4::new_translation(&Vector3::new(1.0, 5.0, 1.0));
+        assert_eq!(global(&world, sword), Some(expected));
+        // A missing bone attaches to the parent itself
+        assert_eq!(global(&world, unknown), global(&world, body));
+    }
+
+    #[test]
+    fn removing_the_transform_removes_the_global_transform() {
+        let mut world = world();
+        let entity = world.create_entity().with(Transform::default()).build();
+        propagate(&mut world);
+        assert!(global(&world, entity).is_some());
+
+        world.write_storage::<Transform>().remove(entity);
+        propagate(&mut world);
+
+        assert_eq!(global(&world, entity), None);
+    }
+
+    #[test]
+    fn one_entity_per_cycle_breaks_it() {
+        let mut world = world();
+        let a = world.create_entity().build();
+        let b = world.create_entity().build();
+        let c = world.create_entity().build();
+        let lonely = world.create_entity().build();
+        let tail = world.create_entity().build();
+
+        let breakers = cycle_breakers(&[(a, b), (b, c), (c, a), (lonely, lonely), (tail, a)]);
+
+        assert_eq!(breakers.len(), 2);
+        assert!(breakers.contains(&lonely));
+        assert!([a, b, c].iter().any(|e| breakers.contains(e)));
+        assert!(cycle_breakers(&[(b, a), (c, b), (tail, c)]).is_empty());
+    }
+
+    #[test]
+    fn despawn_recursive_deletes_the_whole_subtree() {
+        let mut world = world();
+        let root = world.create_entity().build();
+        let child = world.create_entity().with(Parent::new(root)).build();
+        let grandchild = world.create_entity().with(Parent::new(child)).build();
+        let other = world.create_entity().build();
+
+        despawn_recursive(&world, root);
+        world.maintain();
+
+        assert!(!world.is_alive(root));
+        assert!(!world.is_alive(child));
+        assert!(!world.is_alive(grandchild));
+        assert!(world.is_alive(other));
+    }
+
+    #[test]
+    fn despawn_recursive_survives_parent_cycles() {
+        let mut world = world();
+        let a = world.create_entity().build();
+        let b = world.create_entity().with(Parent::new(a)).build();
+        set_parent(&world, a, Parent::new(b));
+        let lonely = world.create_entity().build();
+        set_parent(&world, lonely, Parent::new(lonely));
+
+        despawn_recursive(&world, a);
+        despawn_recursive(&world, lonely);
+        world.maintain();
+
+        assert!(!world.is_alive(a));
+        assert!(!world.is_alive(b));
+        assert!(!world.is_alive(lonely));
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashSet;
+
+/// Mouse and keyboard state of the current frame.
+///
+/// The DOM event handlers write into a shared copy, which the engine hands
+/// to the world once per update before clearing the per-frame deltas.
+#[derive(Clone, Debug, Default)]
+pub struct Input {
+    pub mouse_position: (i32, i32),
+    /// Movement since the previous update, in pixels
+    pub mouse_delta: (f32, f32),
+    pub mouse_down: bool,
+    /// Wheel movement since the previous update
+    pub wheel: f32,
+    /// Positions of mouse clicks since the previous update
+    pub clicks: Vec<(i32, i32)>,
+    /// `KeyboardEvent.code` of every key being held, e.g. "KeyW"
+    keys: HashSet<String>,
+}
+
+impl Input {
+    pub fn new() -> Input {
+        Input::default()
+    }
+
+    pub fn is_key_down(&self, code: &str) -> bool {
+        self.keys.contains(code)
+    }
+
+    pub fn mouse_move(&mut self, x: i32, y: i32) {
+        self.mouse_delta.0 += (x - self.mouse_position.0) as f32;
+        self.mouse_delta.1 += (y - self.mouse_position.1) as f32;
+        self.mouse_position = (x, y);
+    }
+
+    pub fn mouse_down(&mut self, x: i32, y: i32) {
+        self.mouse_down = true;
+        self.mouse_position = (x, y);
+    }
+
+    pub fn mouse_up(&mut self, x: i32, y: i32) {
+        self.mouse_down = false;
+        self.clicks.push((x, y));
+    }
+
+    pub fn zoom(&mut self, amount: f32) {
+        self.wheel += amount;
+    }
+
+    pub fn key_down(&mut self, code: String) {
+        self.keys.insert(code);
+    }
+
+    pub fn key_up(&mut self, code: &str) {
+        self.keys.remove(code);
+    }
+
+    /// Forget what only applies to a single frame.
+    pub fn end_frame(&mut self) {
+        self.mouse_delta = (0., 0.);
+        self.wheel = 0.;
+        self.clicks.clear();
+    }
+}
@@ -8,6 +8,10 @@ extern crate chal_engine;
 extern crate specs;
 #[macro_use]
 extern crate specs_derive;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 #[macro_use]
 mod utils;
@@ -15,6 +19,26 @@ mod engine;
 mod shader;
 mod render;
 mod canvas;
+mod bounds;
+mod transform;
+mod hierarchy;
+mod input;
+mod camera;
+mod picking;
+mod time;
+mod schedule;
+mod light;
+mod component_data;
+mod scene;
+mod prefab;
+mod physics;
+mod terrain;
+mod particles;
+mod debug_draw;
+
+pub use crate::debug_draw::DebugDraw;
+pub use crate::engine::Engine;
+pub use crate::schedule::Stage;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
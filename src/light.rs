@@ -0,0 +1,57 @@
+use specs::{Component, VecStorage};
+
+/// A light source. Directional and spot lights shine along the local -Z
+/// axis of the entity's transform, point and spot lights sit at its origin.
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Light {
+    Directional {
+        /// Linear RGB
+        color: [f32; 3],
+        intensity: f32,
+    },
+    Point {
+        color: [f32; 3],
+        intensity: f32,
+        /// Distance at which the light has faded out completely
+        range: f32,
+    },
+    Spot {
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+        /// Half angle of the cone in radians
+        angle: f32,
+    },
+}
+
+impl Light {
+    pub fn directional(color: [f32; 3], intensity: f32) -> Light {
+        Light::Directional { color, intensity }
+    }
+
+    pub fn point(color: [f32; 3], intensity: f32, range: f32) -> Light {
+        Light::Point {
+            color,
+            intensity,
+            range,
+        }
+    }
+
+    pub fn color(&self) -> [f32; 3] {
+        match *self {
+            Light::Directional { color, .. }
+            | Light::Point { color, .. }
+            | Light::Spot { color, .. } => color,
+        }
+    }
+
+    pub fn intensity(&self) -> f32 {
+        match *self {
+            Light::Directional { intensity, .. }
+            | Light::Point { intensity, .. }
+            | Light::Spot { intensity, .. } => intensity,
+        }
+    }
+}
@@ -0,0 +1,10 @@
+precision mediump float;
+
+// The entity id, packed into the instance color
+varying vec4 v_color;
+
+void main() {
+  // No cross-fade dither, an entity should be pickable on every pixel it
+  // covers
+  gl_FragColor = v_color;
+}
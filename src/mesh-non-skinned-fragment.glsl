@@ -1,40 +1,254 @@
+// Defined once the extension is enabled on the context, before compiling
+#ifdef GL_EXT_shader_texture_lod
+#extension GL_EXT_shader_texture_lod : enable
+#endif
+
 precision mediump float;
 
-varying vec3 vNormal;
-varying vec3 vWorldPos;
+// Lights passed at most, the rest is left out
+#define MAX_LIGHTS 4
+#define PI 3.14159265
+
+varying vec4 v_color;
+varying float v_fade;
+varying float v_reflectivity;
+varying vec3 v_normal;
+varying vec4 v_tangent;
+varying vec3 v_world_position;
+varying vec2 v_uv;
+
+uniform vec3 u_eye;
+// The skybox as a cube map, on texture unit 1. Its mipmaps are prefiltered
+// for rougher and rougher surfaces
+uniform samplerCube u_environment;
+uniform bool u_has_environment;
+// The smallest mipmap level, as blurry as the environment gets
+uniform float u_environment_lod;
+
+// 0 for no distance fog, 1 linear, 2 exponential, 3 exponential squared
+uniform int u_fog_mode;
+// Start and end of linear fog, density of exponential fog
+uniform vec3 u_fog_distance;
+// Density at the base height, base height and falloff, no density for none
+uniform vec3 u_fog_height;
+uniform vec3 u_fog_color;
+// Fade to the sky behind the fragment instead of `u_fog_color`
+uniform bool u_fog_from_sky;
+
+// Physically based shading with the metallic-roughness model, flat colors
+// otherwise
+uniform bool u_lit;
+// Metallic, roughness, occlusion strength and normal scale
+uniform vec4 u_pbr_factors;
+uniform vec3 u_emissive;
+uniform sampler2D u_albedo_map;
+uniform sampler2D u_normal_map;
+// Roughness in green and metallic in blue, like glTF
+uniform sampler2D u_metallic_roughness_map;
+uniform sampler2D u_occlusion_map;
+uniform sampler2D u_emissive_map;
+// Scale and bias of the specular reflectance by the view angle and roughness
+uniform sampler2D u_brdf_lut;
 
-varying vec2 vUvs;
+uniform int u_light_count;
+// Direction the light shines in with w 0, or position with w 1
+uniform vec4 u_light_position[MAX_LIGHTS];
+// Color times intensity, and the range of point and spot lights
+uniform vec4 u_light_color[MAX_LIGHTS];
+// Direction of spot lights and the cosine of their half angle, -2 for
+// other lights
+uniform vec4 u_light_spot[MAX_LIGHTS];
 
-varying vec3 fromFragmentToCamera;
+// Interleaved gradient noise, a cheap screen space dither pattern in [0, 1)
+float dither() {
+  return fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+}
 
-varying vec4 worldPosition;
-uniform vec4 clipPlane;
+vec3 to_linear(vec3 srgb) {
+  return pow(srgb, vec3(2.2));
+}
 
-float shininess = 0.4;
+vec3 to_srgb(vec3 linear) {
+  return pow(linear, vec3(1.0 / 2.2));
+}
 
-vec3 sunlightColor = vec3(1.0, 1.0, 1.0);
-vec3 sunlightDir = normalize(vec3(-1.0, -1.0, 0.5));
+// How much of the fragment the fog hides, from 0 to 1
+float fog_amount(vec3 to_fragment) {
+  float fragment_distance = length(to_fragment);
 
-// uniform sampler2D meshTexture;
+  float fog = 0.0;
+  if (u_fog_mode == 1) {
+    float start = u_fog_distance.x;
+    fog = clamp((fragment_distance - start) / (u_fog_distance.y - start), 0.0, 1.0);
+  } else if (u_fog_mode == 2) {
+    fog = 1.0 - exp(-u_fog_distance.z * fragment_distance);
+  } else if (u_fog_mode == 3) {
+    float depth = u_fog_distance.z * fragment_distance;
+    fog = 1.0 - exp(-depth * depth);
+  }
 
-void main(void) {
-    if (dot(worldPosition, clipPlane) < 0.0) {
-        discard;
+  // The density integrated along the view ray, it falls off exponentially
+  // with height
+  if (u_fog_height.x > 0.0) {
+    float falloff = u_fog_height.z;
+    float rise = falloff * to_fragment.y;
+    float eye_density = u_fog_height.x * exp(-falloff * (u_eye.y - u_fog_height.y));
+    float along = abs(rise) > 0.001 ? (1.0 - exp(-rise)) / rise : 1.0;
+    float height_fog = 1.0 - exp(-eye_density * along * fragment_distance);
+    fog = 1.0 - (1.0 - fog) * (1.0 - height_fog);
+  }
+
+  return fog;
+}
+
+// The normal with the normal map applied, when the mesh has tangents
+vec3 shading_normal(vec3 normal) {
+  if (dot(v_tangent.xyz, v_tangent.xyz) == 0.0) {
+    return normal;
+  }
+
+  vec3 tangent = normalize(v_tangent.xyz - normal * dot(normal, v_tangent.xyz));
+  vec3 bitangent = cross(normal, tangent) * v_tangent.w;
+  vec3 mapped = texture2D(u_normal_map, v_uv).xyz * 2.0 - 1.0;
+  mapped.xy *= u_pbr_factors.w;
+  return normalize(mat3(tangent, bitangent, normal) * mapped);
+}
+
+// GGX normal distribution
+float distribution(float n_dot_h, float roughness) {
+  float a = roughness * roughness;
+  float a2 = a * a;
+  float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+  return a2 / (PI * d * d);
+}
+
+// Smith-Schlick geometry term for both directions
+float geometry(float n_dot_v, float n_dot_l, float roughness) {
+  float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+  float view = n_dot_v / (n_dot_v * (1.0 - k) + k);
+  float light = n_dot_l / (n_dot_l * (1.0 - k) + k);
+  return view * light;
+}
+
+vec3 fresnel(float cos_theta, vec3 f0) {
+  return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Fresnel averaged over the rough microfacets, for the environment
+vec3 fresnel_roughness(float cos_theta, vec3 f0, float roughness) {
+  return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Linear color of a lit material
+vec3 shade(vec3 normal, vec3 view) {
+  vec3 albedo = to_linear(v_color.rgb) * to_linear(texture2D(u_albedo_map, v_uv).rgb);
+  vec4 metallic_roughness = texture2D(u_metallic_roughness_map, v_uv);
+  float metallic = clamp(u_pbr_factors.x * metallic_roughness.b, 0.0, 1.0);
+  // Perfectly smooth surfaces reflect lights off a single point
+  float roughness = clamp(u_pbr_factors.y * metallic_roughness.g, 0.04, 1.0);
+  float occlusion = mix(1.0, texture2D(u_occlusion_map, v_uv).r, u_pbr_factors.z);
+  vec3 emissive = to_linear(u_emissive * texture2D(u_emissive_map, v_uv).rgb);
+
+  vec3 f0 = mix(vec3(0.04), albedo, metallic);
+  float n_dot_v = max(dot(normal, view), 0.001);
+
+  vec3 color = vec3(0.0);
+  for (int i = 0; i < MAX_LIGHTS; i++) {
+    if (i >= u_light_count) {
+      break;
+    }
+
+    vec4 position = u_light_position[i];
+    vec3 to_light = -position.xyz;
+    float attenuation = 1.0;
+    if (position.w > 0.0) {
+      to_light = position.xyz - v_world_position;
+      float light_distance = length(to_light);
+      float range = u_light_color[i].w;
+      float window = clamp(1.0 - pow(light_distance / range, 4.0), 0.0, 1.0);
+      attenuation = window * window / (light_distance * light_distance + 1.0);
+    }
+    vec3 l = normalize(to_light);
+
+    vec4 spot = u_light_spot[i];
+    if (spot.w > -1.5) {
+      float inner = mix(spot.w, 1.0, 0.2);
+      attenuation *= smoothstep(spot.w, inner, dot(-l, spot.xyz));
     }
 
-    vec3 ambient = vec3(0.24725, 0.1995, 0.0745);
+    float n_dot_l = max(dot(normal, l), 0.0);
+    if (n_dot_l <= 0.0 || attenuation <= 0.0) {
+      continue;
+    }
+
+    vec3 h = normalize(view + l);
+    vec3 f = fresnel(max(dot(h, view), 0.0), f0);
+    float d = distribution(max(dot(normal, h), 0.0), roughness);
+    float g = geometry(n_dot_v, n_dot_l, roughness);
+    vec3 specular = d * g * f / (4.0 * n_dot_v * n_dot_l + 0.001);
+    vec3 diffuse = (1.0 - f) * (1.0 - metallic) * albedo / PI;
+
+    color += (diffuse + specular) * u_light_color[i].rgb * attenuation * n_dot_l;
+  }
+
+  // Image based lighting, the split sum approximation. The blurriest
+  // mipmap stands in for the irradiance. Without EXT_shader_texture_lod
+  // the mipmap level can only be biased, which is the level itself as long
+  // as the environment is magnified
+  if (u_has_environment) {
+    vec3 f = fresnel_roughness(n_dot_v, f0, roughness);
+    vec3 reflected = reflect(-view, normal);
+#ifdef GL_EXT_shader_texture_lod
+    vec3 prefiltered = to_linear(
+      textureCubeLodEXT(u_environment, reflected, roughness * u_environment_lod).rgb);
+    vec3 irradiance =
+      to_linear(textureCubeLodEXT(u_environment, normal, u_environment_lod).rgb);
+#else
+    vec3 prefiltered =
+      to_linear(textureCube(u_environment, reflected, roughness * u_environment_lod).rgb);
+    vec3 irradiance = to_linear(textureCube(u_environment, normal, u_environment_lod).rgb);
+#endif
+    vec2 brdf = texture2D(u_brdf_lut, vec2(n_dot_v, roughness)).rg;
+
+    vec3 diffuse = (1.0 - f) * (1.0 - metallic) * irradiance * albedo;
+    vec3 specular = prefiltered * (f * brdf.x + brdf.y);
+    color += (diffuse + specular) * occlusion;
+  }
+
+  return color + emissive;
+}
+
+void main() {
+  // LOD cross-fade: [0, 1] keeps that fraction of the pixels, (1, 2] keeps
+  // the complement of the fade - 1
+  float d = dither();
+  if (v_fade <= 1.0) {
+    if (d >= v_fade) {
+      discard;
+    }
+  } else if (d < v_fade - 1.0) {
+    discard;
+  }
 
-    vec3 normal = normalize(vNormal);
-    float diff = max(dot(normal, -sunlightDir), 0.0);
-    vec3 diffuse = diff * sunlightColor;
+  vec4 color = v_color;
+  vec3 to_fragment = v_world_position - u_eye;
+  vec3 incident = normalize(to_fragment);
+  // A mesh without normals reads a zero normal
+  bool has_normal = dot(v_normal, v_normal) > 0.0;
 
-    vec3 reflectDir = reflect(-sunlightDir, normal);
-    float spec = pow(max(dot(normalize(fromFragmentToCamera), reflectDir), 0.0), 32.0);
-    vec3 specular = shininess * spec * vec3(0.628281, 0.555802, 0.366065);
+  if (u_lit && has_normal) {
+    vec3 normal = shading_normal(normalize(v_normal));
+    color.rgb = to_srgb(shade(normal, -incident));
+  } else if (u_has_environment && v_reflectivity > 0.0 && has_normal) {
+    vec3 reflected = reflect(incident, normalize(v_normal));
+    color.rgb = mix(color.rgb, textureCube(u_environment, reflected).rgb, v_reflectivity);
+  }
 
-    vec4 lighting = vec4(ambient + diffuse + specular, 1.0);
-    // vec4 textureColor = texture2D(meshTexture, vUvs);
-    vec4 textureColor = vec4(1.0, 1.0, 1.0, 1.0);
+  float fog = fog_amount(to_fragment);
+  if (fog > 0.0) {
+    vec3 fog_color = u_fog_from_sky ? textureCube(u_environment, incident).rgb : u_fog_color;
+    color.rgb = mix(color.rgb, fog_color, fog);
+  }
 
-    gl_FragColor = textureColor * lighting;
+  gl_FragColor = color;
 }
@@ -1,28 +1,39 @@
-attribute vec3 position;
-attribute vec3 normal;
-
-attribute vec2 uvs;
-varying vec2 vUvs;
-
-uniform mat4 model;
-uniform mat4 view;
-uniform mat4 perspective;
-
-varying vec3 vNormal;
-varying vec3 vWorldPos;
-varying vec4 worldPosition;
-
-uniform vec3 cameraPos;
-varying vec3 fromFragmentToCamera;
-
-void main (void) {
-  worldPosition = model * vec4(position, 1.0);
-
-  gl_Position = perspective * view * worldPosition;
-
-  vNormal = normal;
-  vWorldPos = worldPosition.xyz;
-  fromFragmentToCamera = cameraPos - worldPosition.xyz;
-
-  vUvs = uvs;
-}
\ No newline at end of file
+attribute vec4 a_position;
+// Optional, meshes without normals are never lit and don't reflect the
+// environment
+attribute vec3 a_normal;
+// Optional, for the texture maps of lit materials
+attribute vec2 a_uv;
+// Optional, the tangent along the u direction of the texture coordinates
+// and the handedness of the bitangent in w, for normal maps
+attribute vec4 a_tangent;
+
+// Per instance, either streamed with a divisor or set as constant attributes
+attribute mat4 a_model;
+attribute vec4 a_color;
+attribute float a_fade;
+attribute float a_reflectivity;
+
+uniform mat4 u_view;
+uniform mat4 u_projection;
+
+varying vec4 v_color;
+varying float v_fade;
+varying float v_reflectivity;
+varying vec3 v_normal;
+varying vec4 v_tangent;
+varying vec3 v_world_position;
+varying vec2 v_uv;
+
+void main() {
+  vec4 world_position = a_model * a_position;
+  gl_Position = u_projection * u_view * world_position;
+  v_color = a_color;
+  v_fade = a_fade;
+  v_reflectivity = a_reflectivity;
+  // Good enough without non-uniform scale
+  v_normal = (a_model * vec4(a_normal, 0.0)).xyz;
+  v_tangent = vec4((a_model * vec4(a_tangent.xyz, 0.0)).xyz, a_tangent.w);
+  v_world_position = world_position.xyz;
+  v_uv = a_uv;
+}
@@ -0,0 +1,21 @@
+precision mediump float;
+
+varying vec2 v_corner;
+varying vec2 v_uv;
+varying vec4 v_color;
+
+uniform sampler2D u_texture;
+uniform bool u_textured;
+
+void main() {
+  vec4 color;
+  if (u_textured) {
+    color = texture2D(u_texture, v_uv);
+  } else {
+    // A soft round dot
+    float falloff = 1.0 - smoothstep(0.25, 0.5, length(v_corner));
+    color = vec4(1.0, 1.0, 1.0, falloff);
+  }
+
+  gl_FragColor = color * v_color;
+}
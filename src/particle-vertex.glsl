@@ -0,0 +1,24 @@
+attribute vec3 a_center;
+// From -0.5 to 0.5 along the camera's right and up
+attribute vec2 a_corner;
+attribute vec2 a_uv;
+attribute vec4 a_color;
+attribute float a_size;
+
+uniform mat4 u_view;
+uniform mat4 u_projection;
+
+varying vec2 v_corner;
+varying vec2 v_uv;
+varying vec4 v_color;
+
+void main() {
+  // Offsetting in view space keeps the quad facing the camera
+  vec4 center = u_view * vec4(a_center, 1.0);
+  center.xy += a_corner * a_size;
+  gl_Position = u_projection * center;
+
+  v_corner = a_corner;
+  v_uv = a_uv;
+  v_color = a_color;
+}
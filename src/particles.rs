@@ -0,0 +1,462 @@
+//! Particle effects such as fire, smoke and dust. Every `ParticleEmitter`
+//! simulates its own particles in world space, the render system draws
+//! them as camera facing quads.
+
+use nalgebra::{Point3, Vector3};
+use specs::{Component, Entities, Join, Read, ReadStorage, System, VecStorage, WriteStorage};
+
+use crate::engine::DeltaTime;
+use crate::physics::Gravity;
+use crate::render::blend::BlendMode;
+use crate::transform::GlobalTransform;
+
+/// Something a `Curve` can blend between.
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: f32, t: f32) -> f32 {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for [f32; 4] {
+    fn lerp(self, other: [f32; 4], t: f32) -> [f32; 4] {
+        let mut out = self;
+        for (out, other) in out.iter_mut().zip(other.iter()) {
+            *out = out.lerp(*other, t);
+        }
+        out
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe<T> {
+    pub time: f32,
+    pub value: T,
+}
+
+/// A value over the life of a particle, from 0 when it is born to 1 when it
+/// dies. Keyframes go in order of time, the value is interpolated linearly
+/// between them and held before the first and after the last. Serialized
+/// as the list of keyframes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(transparent)]
+pub struct Curve<T> {
+    keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Lerp> Curve<T> {
+    pub fn new(keys: Vec<Keyframe<T>>) -> Curve<T> {
+        Curve { keys }
+    }
+
+    /// The same value over the whole life.
+    pub fn constant(value: T) -> Curve<T> {
+        Curve::new(vec![Keyframe { time: 0., value }])
+    }
+
+    /// `start` at birth, `end` at death.
+    pub fn linear(start: T, end: T) -> Curve<T> {
+        Curve::new(vec![
+            Keyframe {
+                time: 0.,
+                value: start,
+            },
+            Keyframe {
+                time: 1.,
+                value: end,
+            },
+        ])
+    }
+
+    /// The value at `t`, `None` without keyframes.
+    pub fn sample(&self, t: f32) -> Option<T> {
+        let next = self.keys.iter().position(|key| key.time > t);
+        match next {
+            Some(0) => self.keys.first().map(|key| key.value),
+            Some(next) => {
+                let (a, b) = (&self.keys[next - 1], &self.keys[next]);
+                Some(a.value.lerp(b.value, (t - a.time) / (b.time - a.time)))
+            }
+            None => self.keys.last().map(|key| key.value),
+        }
+    }
+}
+
+/// A texture divided into `columns` by `rows` equally sized frames, read
+/// left to right and top to bottom.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AtlasFrames {
+    pub columns: u32,
+    pub rows: u32,
+    /// Frames that are used, 0 for all of them
+    pub count: u32,
+    /// Frames per second, 0 to play the frames once over each particle's
+    /// life
+    pub fps: f32,
+}
+
+impl AtlasFrames {
+    pub fn count(&self) -> u32 {
+        let all = self.columns.max(1) * self.rows.max(1);
+        if self.count == 0 {
+            all
+        } else {
+            self.count.min(all)
+        }
+    }
+
+    /// The frame shown by a particle `age` seconds into a life of
+    /// `lifetime` seconds.
+    pub fn frame(&self, age: f32, lifetime: f32) -> u32 {
+        let count = self.count();
+        let frame = if self.fps > 0. {
+            (age * self.fps) as u32 % count
+        } else {
+            ((age / lifetime) * count as f32) as u32
+        };
+        frame.min(count - 1)
+    }
+
+    /// Texture coordinates of the top left and bottom right corner of
+    /// `frame`.
+    pub fn uv_rect(&self, frame: u32) -> [f32; 4] {
+        let (columns, rows) = (self.columns.max(1), self.rows.max(1));
+        let (width, height) = (1. / columns as f32, 1. / rows as f32);
+        let (column, row) = ((frame % columns) as f32, (frame / columns) as f32);
+        [
+            column * width,
+            row * height,
+            (column + 1.) * width,
+            (row + 1.) * height,
+        ]
+    }
+}
+
+impl Default for AtlasFrames {
+    fn default() -> AtlasFrames {
+        AtlasFrames {
+            columns: 1,
+            rows: 1,
+            count: 0,
+            fps: 0.,
+        }
+    }
+}
+
+/// A single live particle, in world space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Particle {
+    pub position: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    /// Seconds since it was spawned
+    pub age: f32,
+    pub lifetime: f32,
+}
+
+impl Particle {
+    /// How far along its life it is, from 0 to 1.
+    pub fn life(&self) -> f32 {
+        (self.age / self.lifetime).min(1.)
+    }
+}
+
+/// Spawns particles at the entity's origin and keeps them moving. Spawned
+/// particles don't follow the entity anymore, velocities are picked in the
+/// entity's local space.
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+#[serde(default)]
+pub struct ParticleEmitter {
+    /// Particles per second
+    pub rate: f32,
+    /// Particles alive at once at most, spawning waits while there are
+    /// this many
+    pub max_particles: usize,
+    /// Shortest and longest life of a particle in seconds
+    pub lifetime: [f32; 2],
+    /// Particles spawn in a sphere of this radius around the origin
+    pub radius: f32,
+    /// Initial velocity, each component picked between the minimum and the
+    /// maximum
+    pub velocity_min: [f32; 3],
+    pub velocity_max: [f32; 3],
+    /// Multiplier of the world's `Gravity`, negative to rise like smoke
+    pub gravity_scale: f32,
+    pub color_over_life: Curve<[f32; 4]>,
+    /// Width and height of the quad over life, in world units
+    pub size_over_life: Curve<f32>,
+    /// Name of a texture in the `TextureLibrary`, soft round dots without
+    pub texture: Option<String>,
+    pub atlas: AtlasFrames,
+    pub blend: BlendMode,
+    /// Whether new particles are spawned, those alive keep going either way
+    pub emitting: bool,
+    #[serde(skip)]
+    particles: Vec<Particle>,
+    /// Fraction of a particle owed from the last frame
+    #[serde(skip)]
+    pending: f32,
+    #[serde(skip)]
+    rng: Rng,
+}
+
+impl ParticleEmitter {
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// Age and move the particles by `dt` and spawn new ones at `origin`.
+    fn update(&mut self, dt: f32, gravity: Vector3<f32>, origin: &GlobalTransform) {
+        self.particles
+            .retain(|particle| particle.age + dt < particle.lifetime);
+
+        let acceleration = gravity * self.gravity_scale;
+        for particle in self.particles.iter_mut() {
+            particle.velocity += acceleration * dt;
+            particle.position += particle.velocity * dt;
+            particle.age += dt;
+        }
+
+        if !self.emitting {
+            self.pending = 0.;
+            return;
+        }
+
+        self.pending += self.rate.max(0.) * dt;
+        let room = self.max_particles.saturating_sub(self.particles.len());
+        let count = (self.pending as usize).min(room);
+        self.pending -= self.pending.floor();
+
+        for _ in 0..count {
+            let particle = self.spawn(origin);
+            self.particles.push(particle);
+        }
+    }
+
+    fn spawn(&mut self, origin: &GlobalTransform) -> Particle {
+        let rng = &mut self.rng;
+        let offset = rng.in_sphere() * self.radius;
+        let (min, max) = (self.velocity_min, self.velocity_max);
+        let velocity = Vector3::new(
+            rng.range(min[0], max[0]),
+            rng.range(min[1], max[1]),
+            rng.range(min[2], max[2]),
+        );
+
+        let position = origin
+            .0
+            .transform_point(&Point3::from_coordinates(offset))
+            .coords;
+        let velocity = origin.0.transform_vector(&velocity);
+        let lifetime = rng.range(self.lifetime[0], self.lifetime[1]).max(0.001);
+
+        Particle {
+            position,
+            velocity,
+            age: 0.,
+            lifetime,
+        }
+    }
+}
+
+impl Default for ParticleEmitter {
+    fn default() -> ParticleEmitter {
+        ParticleEmitter {
+            rate: 10.,
+            max_particles: 256,
+            lifetime: [1., 2.],
+            radius: 0.,
+            velocity_min: [-0.5, 1., -0.5],
+            velocity_max: [0.5, 2., 0.5],
+            gravity_scale: 0.,
+            color_over_life: Curve::linear([1., 1., 1., 1.], [1., 1., 1., 0.]),
+            size_over_life: Curve::constant(0.5),
+            texture: None,
+            atlas: AtlasFrames::default(),
+            blend: BlendMode::default(),
+            emitting: true,
+            particles: Vec::new(),
+            pending: 0.,
+            rng: Rng::default(),
+        }
+    }
+}
+
+/// Xorshift, good enough to scatter particles and the same on every run.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Rng(u32);
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng(0x2545_f491)
+    }
+}
+
+impl Rng {
+    /// Between 0 and 1.
+    fn unit(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.unit()
+    }
+
+    /// A point in the unit sphere.
+    fn in_sphere(&mut self) -> Vector3<f32> {
+        loop {
+            let point = Vector3::new(
+                self.range(-1., 1.),
+                self.range(-1., 1.),
+                self.range(-1., 1.),
+            );
+            if point.norm_squared() <= 1. {
+                return point;
+            }
+        }
+    }
+}
+
+/// Moves, ages and spawns the particles of every emitter, once per frame
+/// in the animation stage. Particles stand still while the engine is
+/// paused.
+pub struct ParticleSystem;
+
+impl<'a> System<'a> for ParticleSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, DeltaTime>,
+        Read<'a, Gravity>,
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, ParticleEmitter>,
+    );
+
+    fn run(&mut self, (entities, dt, gravity, transforms, mut emitters): Self::SystemData) {
+        if dt.0 <= 0. {
+            return;
+        }
+        let identity = GlobalTransform::default();
+
+        for (entity, emitter, transform) in (&entities, &mut emitters, transforms.maybe()).join() {
+            // Emitters sharing the default seed would scatter alike
+            if emitter.rng == Rng::default() {
+                let seed = (entity.id() + 1).wrapping_mul(0x9e37_79b9);
+                emitter.rng = Rng((Rng::default().0 ^ seed) | 1);
+            }
+
+            emitter.update(dt.0, gravity.0, transform.unwrap_or(&identity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key<T>(time: f32, value: T) -> Keyframe<T> {
+        Keyframe { time, value }
+    }
+
+    #[test]
+    fn curves_interpolate_between_keyframes() {
+        let curve = Curve::new(vec![key(0.25, 1.), key(0.75, 3.), key(1., 0.)]);
+
+        assert_eq!(curve.sample(0.5), Some(2.));
+        assert_eq!(curve.sample(0.875), Some(1.5));
+        assert_eq!(curve.sample(0.75), Some(3.));
+    }
+
+    #[test]
+    fn curves_hold_their_ends() {
+        let curve = Curve::linear(2., 4.);
+
+        assert_eq!(curve.sample(-1.), Some(2.));
+        assert_eq!(curve.sample(0.5), Some(3.));
+        assert_eq!(curve.sample(2.), Some(4.));
+        assert_eq!(Curve::constant(7.).sample(0.3), Some(7.));
+        assert_eq!(Curve::<f32>::new(Vec::new()).sample(0.3), None);
+    }
+
+    #[test]
+    fn colors_blend_per_channel() {
+        let curve = Curve::linear([1., 0., 0., 1.], [0., 1., 0., 0.]);
+        assert_eq!(curve.sample(0.5), Some([0.5, 0.5, 0., 0.5]));
+    }
+
+    #[test]
+    fn curves_serialize_as_their_keyframes() {
+        let curve = Curve::linear(0., 1.);
+        let json = serde_json::to_value(&curve).unwrap();
+        let keys = serde_json::json!([{"time": 0., "value": 0.}, {"time": 1., "value": 1.}]);
+
+        assert_eq!(json, keys);
+        assert_eq!(serde_json::from_value::<Curve<f32>>(json).unwrap(), curve);
+    }
+
+    #[test]
+    fn atlas_frames_play_over_the_life() {
+        let atlas = AtlasFrames {
+            columns: 4,
+            rows: 2,
+            ..AtlasFrames::default()
+        };
+
+        assert_eq!(atlas.count(), 8);
+        assert_eq!(atlas.frame(0., 2.), 0);
+        assert_eq!(atlas.frame(1., 2.), 4);
+        assert_eq!(atlas.frame(2., 2.), 7);
+        assert_eq!(atlas.frame(5., 2.), 7);
+    }
+
+    #[test]
+    fn atlas_frames_loop_at_their_rate() {
+        let atlas = AtlasFrames {
+            columns: 4,
+            rows: 2,
+            count: 6,
+            fps: 10.,
+        };
+
+        assert_eq!(atlas.count(), 6);
+        assert_eq!(atlas.frame(0.25, 10.), 2);
+        assert_eq!(atlas.frame(0.75, 10.), 1);
+    }
+
+    #[test]
+    fn atlas_frames_count_at_most_every_frame() {
+        let atlas = AtlasFrames {
+            columns: 2,
+            rows: 2,
+            count: 9,
+            fps: 0.,
+        };
+        assert_eq!(atlas.count(), 4);
+
+        let empty = AtlasFrames {
+            columns: 0,
+            rows: 0,
+            ..AtlasFrames::default()
+        };
+        assert_eq!(empty.count(), 1);
+        assert_eq!(empty.frame(1., 0.), 0);
+    }
+
+    #[test]
+    fn atlas_frames_read_left_to_right_then_down() {
+        let atlas = AtlasFrames {
+            columns: 4,
+            rows: 2,
+            ..AtlasFrames::default()
+        };
+
+        assert_eq!(atlas.uv_rect(0), [0., 0., 0.25, 0.5]);
+        assert_eq!(atlas.uv_rect(5), [0.25, 0.5, 0.5, 1.]);
+    }
+}
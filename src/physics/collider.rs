@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use blender_mesh::BlenderMesh;
+use nalgebra::{Matrix4, UnitQuaternion, Vector3};
+use specs::{Component, VecStorage};
+
+use crate::bounds::Aabb;
+use crate::physics::heightfield::Heightfield;
+use crate::render::component::Mesh;
+
+/// Friction of a collider unless set otherwise.
+pub const DEFAULT_FRICTION: f32 = 0.5;
+
+/// Where a collider is in the world. Colliders don't scale along with the
+/// transform of their entity, their sizes are in world units.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pose {
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+}
+
+impl Pose {
+    pub fn new(translation: Vector3<f32>, rotation: UnitQuaternion<f32>) -> Pose {
+        Pose {
+            translation,
+            rotation,
+        }
+    }
+
+    pub fn transform_point(&self, point: &Vector3<f32>) -> Vector3<f32> {
+        self.rotation * *point + self.translation
+    }
+
+    pub fn transform_vector(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        self.rotation * *vector
+    }
+
+    pub fn inverse_transform_point(&self, point: &Vector3<f32>) -> Vector3<f32> {
+        self.rotation.inverse() * (*point - self.translation)
+    }
+
+    /// This pose as seen from `other`.
+    pub fn relative_to(&self, other: &Pose) -> Pose {
+        let inverse = other.rotation.inverse();
+        Pose {
+            translation: inverse * (self.translation - other.translation),
+            rotation: inverse * self.rotation,
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.translation) * self.rotation.to_homogeneous()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: Vector3<f32>,
+    },
+    /// Upright along the local Y axis, `half_height` going up to the
+    /// centers of the rounded caps
+    Capsule {
+        half_height: f32,
+        radius: f32,
+    },
+    /// Only collides with the other shapes, not with meshes or heightfields
+    TriMesh(Arc<TriMesh>),
+    Heightfield(Arc<Heightfield>),
+}
+
+impl Shape {
+    /// Spheres, boxes and capsules. The others are only tested against
+    /// these.
+    pub fn is_convex(&self) -> bool {
+        match self {
+            Shape::TriMesh(_) | Shape::Heightfield(_) => false,
+            _ => true,
+        }
+    }
+
+    pub fn aabb(&self, pose: &Pose) -> Aabb {
+        match self {
+            Shape::Sphere { radius } => {
+                let radius = Vector3::repeat(*radius);
+                Aabb::new(pose.translation - radius, pose.translation + radius)
+            }
+            Shape::Box { half_extents } => {
+                Aabb::new(-half_extents, *half_extents).transformed(&pose.matrix())
+            }
+            Shape::Capsule {
+                half_height,
+                radius,
+            } => {
+                let axis = pose.transform_vector(&Vector3::new(0., *half_height, 0.));
+                let a = pose.translation + axis;
+                let b = pose.translation - axis;
+                let radius = Vector3::repeat(*radius);
+                Aabb::new(
+                    a.zip_map(&b, f32::min) - radius,
+                    a.zip_map(&b, f32::max) + radius,
+                )
+            }
+            Shape::TriMesh(mesh) => mesh.aabb().transformed(&pose.matrix()),
+            Shape::Heightfield(heightfield) => heightfield.aabb().transformed(&pose.matrix()),
+        }
+    }
+
+    /// Moments of inertia around the local axes, for a uniformly dense body
+    /// of `mass`.
+    pub fn inertia(&self, mass: f32) -> Vector3<f32> {
+        match self {
+            Shape::Sphere { radius } => Vector3::repeat(0.4 * mass * radius * radius),
+            Shape::Box { half_extents } => box_inertia(mass, half_extents),
+            // Taken as a cylinder running through the caps
+            Shape::Capsule {
+                half_height,
+                radius,
+            } => {
+                let height = 2. * (half_height + radius);
+                let across = mass * (3. * radius * radius + height * height) / 12.;
+                Vector3::new(across, 0.5 * mass * radius * radius, across)
+            }
+            Shape::TriMesh(mesh) => box_inertia(mass, &mesh.aabb().half_extents()),
+            Shape::Heightfield(heightfield) => {
+                box_inertia(mass, &heightfield.aabb().half_extents())
+            }
+        }
+    }
+}
+
+fn box_inertia(mass: f32, half_extents: &Vector3<f32>) -> Vector3<f32> {
+    let size = half_extents * 2.;
+    let sq = size.component_mul(&size);
+    Vector3::new(sq.y + sq.z, sq.x + sq.z, sq.x + sq.y) * (mass / 12.)
+}
+
+/// Triangles to collide with, one sided: shapes are pushed out towards the
+/// side the triangles are wound counter-clockwise from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TriMesh {
+    name: String,
+    triangles: Vec<[Vector3<f32>; 3]>,
+    aabb: Aabb,
+}
+
+impl TriMesh {
+    pub fn new<S: Into<String>>(name: S, triangles: Vec<[Vector3<f32>; 3]>) -> Option<TriMesh> {
+        let aabb = Aabb::from_points(triangles.iter().flat_map(|t| t.iter().cloned()))?;
+        Some(TriMesh {
+            name: name.into(),
+            triangles,
+            aabb,
+        })
+    }
+
+    pub fn from_mesh(mesh: &Mesh) -> Option<TriMesh> {
+        TriMesh::new(mesh.name(), mesh.triangles())
+    }
+
+    pub fn from_blender<S: Into<String>>(name: S, blender_mesh: &BlenderMesh) -> Option<TriMesh> {
+        let positions = &blender_mesh.vertex_positions;
+        let vertex = |idx: u16| {
+            let idx = idx as usize * 3;
+            let p = positions.get(idx..idx + 3)?;
+            Some(Vector3::new(p[0], p[1], p[2]))
+        };
+
+        let triangles = blender_mesh
+            .vertex_position_indices
+            .chunks(3)
+            .filter(|corners| corners.len() == 3)
+            .filter_map(|c| Some([vertex(c[0])?, vertex(c[1])?, vertex(c[2])?]))
+            .collect();
+
+        TriMesh::new(name, triangles)
+    }
+
+    /// Name of the mesh the triangles came from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+
+    pub fn triangles_in(&self, aabb: &Aabb) -> Vec<[Vector3<f32>; 3]> {
+        self.triangles
+            .iter()
+            .filter(|triangle| {
+                Aabb::from_points(triangle.iter().cloned())
+                    .map_or(false, |bounds| aabb.intersects(&bounds))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// The shape of an entity for collisions. Without a `RigidBody` the entity
+/// is static scenery.
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct Collider {
+    pub shape: Shape,
+    /// Reports overlaps as collision events without pushing anything away
+    pub sensor: bool,
+    pub friction: f32,
+    /// Bounciness, 0 stops dead and 1 bounces back at full speed
+    pub restitution: f32,
+}
+
+impl Collider {
+    pub fn new(shape: Shape) -> Collider {
+        Collider {
+            shape,
+            sensor: false,
+            friction: DEFAULT_FRICTION,
+            restitution: 0.,
+        }
+    }
+
+    pub fn sphere(radius: f32) -> Collider {
+        Collider::new(Shape::Sphere { radius })
+    }
+
+    pub fn cuboid(half_extents: Vector3<f32>) -> Collider {
+        Collider::new(Shape::Box { half_extents })
+    }
+
+    pub fn capsule(half_height: f32, radius: f32) -> Collider {
+        Collider::new(Shape::Capsule {
+            half_height,
+            radius,
+        })
+    }
+
+    pub fn tri_mesh(mesh: TriMesh) -> Collider {
+        Collider::new(Shape::TriMesh(Arc::new(mesh)))
+    }
+
+    pub fn heightfield(heightfield: Heightfield) -> Collider {
+        Collider::new(Shape::Heightfield(Arc::new(heightfield)))
+    }
+
+    pub fn with_sensor(mut self, sensor: bool) -> Collider {
+        self.sensor = sensor;
+        self
+    }
+
+    pub fn with_friction(mut self, friction: f32) -> Collider {
+        self.friction = friction;
+        self
+    }
+
+    pub fn with_restitution(mut self, restitution: f32) -> Collider {
+        self.restitution = restitution;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Unit;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    fn close(aabb: &Aabb, min: [f32; 3], max: [f32; 3]) -> bool {
+        let near =
+            |a: &Vector3<f32>, b: [f32; 3]| (a - Vector3::from_column_slice(&b)).norm() < 1e-5;
+        near(&aabb.min, min) && near(&aabb.max, max)
+    }
+
+    fn at(x: f32, y: f32, z: f32) -> Pose {
+        Pose::new(Vector3::new(x, y, z), UnitQuaternion::identity())
+    }
+
+    fn turned(axis: &Unit<Vector3<f32>>, angle: f32) -> Pose {
+        Pose::new(
+            Vector3::zeros(),
+            UnitQuaternion::from_axis_angle(axis, angle),
+        )
+    }
+
+    #[test]
+    fn spheres_ignore_rotation() {
+        let sphere = Shape::Sphere { radius: 0.5 };
+
+        let aabb = sphere.aabb(&at(1., 2., 3.));
+        assert!(close(&aabb, [0.5, 1.5, 2.5], [1.5, 2.5, 3.5]));
+
+        let aabb = sphere.aabb(&turned(&Vector3::y_axis(), 1.));
+        assert!(close(&aabb, [-0.5; 3], [0.5; 3]));
+    }
+
+    #[test]
+    fn turned_boxes_grow_their_aabb() {
+        let cube = Shape::Box {
+            half_extents: Vector3::new(1., 2., 1.),
+        };
+        let half_diagonal = 2f32.sqrt();
+
+        let aabb = cube.aabb(&turned(&Vector3::y_axis(), FRAC_PI_4));
+        assert!(close(
+            &aabb,
+            [-half_diagonal, -2., -half_diagonal],
+            [half_diagonal, 2., half_diagonal]
+        ));
+    }
+
+    #[test]
+    fn capsules_cover_their_caps() {
+        let capsule = Shape::Capsule {
+            half_height: 1.,
+            radius: 0.5,
+        };
+
+        let aabb = capsule.aabb(&at(0., 1., 0.));
+        assert!(close(&aabb, [-0.5, -0.5, -0.5], [0.5, 2.5, 0.5]));
+
+        let lying = capsule.aabb(&turned(&Vector3::z_axis(), FRAC_PI_2));
+        assert!(close(&lying, [-1.5, -0.5, -0.5], [1.5, 0.5, 0.5]));
+    }
+
+    #[test]
+    fn meshes_and_heightfields_move_their_aabb() {
+        let triangle = TriMesh::new(
+            "triangle",
+            vec![[
+                Vector3::new(0., 0., 0.),
+                Vector3::new(1., 0., 0.),
+                Vector3::new(0., 1., 0.),
+            ]],
+        )
+        .unwrap();
+        let aabb = Shape::TriMesh(Arc::new(triangle)).aabb(&at(1., 0., 0.));
+        assert!(close(&aabb, [1., 0., 0.], [2., 1., 0.]));
+
+        let heightfield = Heightfield::new("hill", -1., -1., (1., 1.), 3, 3, vec![0.; 9]);
+        let aabb = Shape::Heightfield(Arc::new(heightfield)).aabb(&at(0., 2., 0.));
+        assert!(close(&aabb, [-1., 2., -1.], [1., 2., 1.]));
+
+        assert_eq!(TriMesh::new("empty", vec![]), None);
+    }
+}
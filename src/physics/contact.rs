@@ -0,0 +1,848 @@
+//! Finding where two shapes touch.
+//!
+//! Spheres and capsules are handled as a segment with a radius around it, a
+//! sphere being a segment of length 0. Everything is in world space unless
+//! noted otherwise.
+
+use nalgebra::Vector3;
+
+use crate::bounds::Aabb;
+use crate::physics::collider::{Pose, Shape};
+
+const EPSILON: f32 = 1e-6;
+
+/// Box vertices this far outside of the other box still count as touching
+/// it, which keeps resting boxes from flickering between 4 contacts and 1.
+const VERTEX_MARGIN: f32 = 0.01;
+
+/// A point where two shapes overlap.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Contact {
+    pub point: Vector3<f32>,
+    /// Points from the first shape towards the second
+    pub normal: Vector3<f32>,
+    /// How far the shapes have to move apart along the normal to separate
+    pub depth: f32,
+}
+
+impl Contact {
+    fn flipped(self) -> Contact {
+        Contact {
+            normal: -self.normal,
+            ..self
+        }
+    }
+
+    fn transformed(self, pose: &Pose) -> Contact {
+        Contact {
+            point: pose.transform_point(&self.point),
+            normal: pose.transform_vector(&self.normal),
+            depth: self.depth,
+        }
+    }
+}
+
+/// The contacts between shape `a` at `pose_a` and shape `b` at `pose_b`,
+/// empty when they don't touch.
+pub fn contacts(a: &Shape, pose_a: &Pose, b: &Shape, pose_b: &Pose) -> Vec<Contact> {
+    if !a.is_convex() && !b.is_convex() {
+        return Vec::new();
+    }
+    if !b.is_convex() {
+        return flip(contacts(b, pose_b, a, pose_a));
+    }
+
+    match (a, b) {
+        (Shape::TriMesh(mesh), _) => {
+            mesh_contacts(pose_a, b, pose_b, |aabb| mesh.triangles_in(aabb))
+        }
+        (Shape::Heightfield(heightfield), _) => {
+            mesh_contacts(pose_a, b, pose_b, |aabb| heightfield.triangles_in(aabb))
+        }
+        (Shape::Box { half_extents: ha }, Shape::Box { half_extents: hb }) => {
+            box_box(pose_a, ha, pose_b, hb)
+        }
+        (Shape::Box { half_extents }, _) => box_core(pose_a, half_extents, &core(b, pose_b)),
+        (_, Shape::Box { half_extents }) => flip(box_core(pose_b, half_extents, &core(a, pose_a))),
+        _ => core_core(&core(a, pose_a), &core(b, pose_b))
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn flip(contacts: Vec<Contact>) -> Vec<Contact> {
+    contacts.into_iter().map(Contact::flipped).collect()
+}
+
+/// A sphere swept along the segment from `a` to `b`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Core {
+    a: Vector3<f32>,
+    b: Vector3<f32>,
+    radius: f32,
+}
+
+impl Core {
+    fn is_sphere(&self) -> bool {
+        (self.b - self.a).norm_squared() < EPSILON
+    }
+
+    fn center(&self) -> Vector3<f32> {
+        (self.a + self.b) * 0.5
+    }
+}
+
+/// Only meant for spheres and capsules.
+fn core(shape: &Shape, pose: &Pose) -> Core {
+    match shape {
+        Shape::Capsule {
+            half_height,
+            radius,
+        } => {
+            let axis = pose.transform_vector(&Vector3::new(0., *half_height, 0.));
+            Core {
+                a: pose.translation - axis,
+                b: pose.translation + axis,
+                radius: *radius,
+            }
+        }
+        Shape::Sphere { radius } => Core {
+            a: pose.translation,
+            b: pose.translation,
+            radius: *radius,
+        },
+        _ => unreachable!("Only spheres and capsules have a core"),
+    }
+}
+
+/// Test a convex shape against the triangles returned for its bounding box
+/// in the mesh's space. `pose_mesh` is where the mesh is.
+fn mesh_contacts<F>(pose_mesh: &Pose, shape: &Shape, pose: &Pose, triangles_in: F) -> Vec<Contact>
+where
+    F: Fn(&Aabb) -> Vec<[Vector3<f32>; 3]>,
+{
+    let local = pose.relative_to(pose_mesh);
+    let triangles = triangles_in(&shape.aabb(&local));
+
+    let mut contacts = Vec::new();
+    for triangle in triangles.iter() {
+        match shape {
+            Shape::Box { half_extents } => {
+                contacts.extend(triangle_box(triangle, &local, half_extents));
+            }
+            _ => contacts.extend(triangle_core(triangle, &core(shape, &local))),
+        }
+    }
+
+    contacts
+        .into_iter()
+        .map(|contact| contact.transformed(pose_mesh))
+        .collect()
+}
+
+fn core_core(a: &Core, b: &Core) -> Option<Contact> {
+    let (on_a, on_b) = closest_points_on_segments(&a.a, &a.b, &b.a, &b.b);
+    sphere_sphere(&on_a, a.radius, &on_b, b.radius)
+}
+
+fn sphere_sphere(
+    center_a: &Vector3<f32>,
+    radius_a: f32,
+    center_b: &Vector3<f32>,
+    radius_b: f32,
+) -> Option<Contact> {
+    let delta = center_b - center_a;
+    let distance = delta.norm();
+    let radius = radius_a + radius_b;
+    if distance >= radius {
+        return None;
+    }
+
+    // Right on top of each other there is no telling which way is out
+    let normal = if distance > EPSILON {
+        delta / distance
+    } else {
+        Vector3::y()
+    };
+    let depth = radius - distance;
+
+    Some(Contact {
+        point: center_a + normal * (radius_a - depth * 0.5),
+        normal,
+        depth,
+    })
+}
+
+/// The normal points from the box to the sphere.
+fn box_sphere(
+    pose: &Pose,
+    half_extents: &Vector3<f32>,
+    center: &Vector3<f32>,
+    radius: f32,
+) -> Option<Contact> {
+    let local = pose.inverse_transform_point(center);
+    let clamped = clamp_to_box(&local, half_extents);
+    let delta = local - clamped;
+    let distance = delta.norm();
+
+    let (point, normal, depth) = if distance > EPSILON {
+        if distance >= radius {
+            return None;
+        }
+        let normal = delta / distance;
+        let depth = radius - distance;
+        (clamped - normal * (depth * 0.5), normal, depth)
+    } else {
+        // The center is inside, push out through the nearest face
+        let (axis, inside) = (0..3)
+            .map(|axis| (axis, half_extents[axis] - local[axis].abs()))
+            .fold((0, std::f32::INFINITY), |nearest, (axis, inside)| {
+                if inside < nearest.1 {
+                    (axis, inside)
+                } else {
+                    nearest
+                }
+            });
+        let mut normal = Vector3::zeros();
+        normal[axis] = if local[axis] < 0. { -1. } else { 1. };
+        (local, normal, inside + radius)
+    };
+
+    Some(Contact {
+        point: pose.transform_point(&point),
+        normal: pose.transform_vector(&normal),
+        depth,
+    })
+}
+
+/// The normal points from the box to the core. Capsules get a contact at
+/// each end as well as the closest point, so they can lie flat on a box.
+fn box_core(pose: &Pose, half_extents: &Vector3<f32>, core: &Core) -> Vec<Contact> {
+    if core.is_sphere() {
+        return box_sphere(pose, half_extents, &core.a, core.radius)
+            .into_iter()
+            .collect();
+    }
+
+    let closest = closest_point_on_segment_to_box(pose, half_extents, core);
+    let mut points = vec![core.a, core.b];
+    if (closest - core.a).norm() > core.radius * 0.5
+        && (closest - core.b).norm() > core.radius * 0.5
+    {
+        points.push(closest);
+    }
+
+    points
+        .iter()
+        .filter_map(|point| box_sphere(pose, half_extents, point, core.radius))
+        .collect()
+}
+
+/// The point of a box centered on the origin nearest to `point`.
+fn clamp_to_box(point: &Vector3<f32>, half_extents: &Vector3<f32>) -> Vector3<f32> {
+    point.zip_map(half_extents, |v, half| v.max(-half).min(half))
+}
+
+/// The point of the core's segment nearest to the box. The distance to a
+/// box along a segment is convex, so a ternary search finds it.
+fn closest_point_on_segment_to_box(
+    pose: &Pose,
+    half_extents: &Vector3<f32>,
+    core: &Core,
+) -> Vector3<f32> {
+    let a = pose.inverse_transform_point(&core.a);
+    let b = pose.inverse_transform_point(&core.b);
+    let distance = |t: f32| {
+        let point = a + (b - a) * t;
+        (point - clamp_to_box(&point, half_extents)).norm_squared()
+    };
+
+    let (mut low, mut high) = (0f32, 1f32);
+    for _ in 0..24 {
+        let third = (high - low) / 3.;
+        if distance(low + third) < distance(high - third) {
+            high -= third;
+        } else {
+            low += third;
+        }
+    }
+
+    core.a + (core.b - core.a) * ((low + high) * 0.5)
+}
+
+/// The normal points from box `a` to box `b`. Uses the separating axis test
+/// for the normal, with the vertices of each box inside the other as
+/// contact points.
+fn box_box(
+    pose_a: &Pose,
+    half_a: &Vector3<f32>,
+    pose_b: &Pose,
+    half_b: &Vector3<f32>,
+) -> Vec<Contact> {
+    let axes_a = box_axes(pose_a);
+    let axes_b = box_axes(pose_b);
+    let offset = pose_b.translation - pose_a.translation;
+
+    let projected = |axes: &[Vector3<f32>; 3], half: &Vector3<f32>, axis: &Vector3<f32>| {
+        (0..3)
+            .map(|i| half[i] * axes[i].dot(axis).abs())
+            .sum::<f32>()
+    };
+
+    let mut candidates: Vec<(Vector3<f32>, bool)> = Vec::with_capacity(15);
+    candidates.extend(axes_a.iter().map(|axis| (*axis, true)));
+    candidates.extend(axes_b.iter().map(|axis| (*axis, true)));
+    for axis_a in axes_a.iter() {
+        for axis_b in axes_b.iter() {
+            let cross = axis_a.cross(axis_b);
+            let length = cross.norm();
+            // Parallel edges, their faces are tested already
+            if length > 1e-4 {
+                candidates.push((cross / length, false));
+            }
+        }
+    }
+
+    let mut best: Option<(Vector3<f32>, f32)> = None;
+    for (axis, is_face) in candidates {
+        let reach = projected(&axes_a, half_a, &axis) + projected(&axes_b, half_b, &axis);
+        let distance = offset.dot(&axis);
+        let overlap = reach - distance.abs();
+        if overlap < 0. {
+            return Vec::new();
+        }
+
+        // Edge axes only win by a margin, face contacts are more stable
+        let score = if is_face {
+            overlap
+        } else {
+            overlap * 1.05 + 1e-3
+        };
+        if best.map_or(true, |(_, best)| score < best) {
+            let normal = if distance < 0. { -axis } else { axis };
+            best = Some((normal, score));
+        }
+    }
+
+    let normal = match best {
+        Some((normal, _)) => normal,
+        None => return Vec::new(),
+    };
+    let overlap = projected(&axes_a, half_a, &normal) + projected(&axes_b, half_b, &normal)
+        - offset.dot(&normal).abs();
+
+    let front_a = pose_a.translation.dot(&normal) + projected(&axes_a, half_a, &normal);
+    let back_b = pose_b.translation.dot(&normal) - projected(&axes_b, half_b, &normal);
+
+    let mut contacts = Vec::new();
+    for vertex in box_vertices(pose_b, half_b).iter() {
+        if inside_box(pose_a, half_a, vertex) {
+            let depth = (front_a - vertex.dot(&normal)).min(overlap);
+            if depth > 0. {
+                contacts.push(Contact {
+                    point: *vertex,
+                    normal,
+                    depth,
+                });
+            }
+        }
+    }
+    for vertex in box_vertices(pose_a, half_a).iter() {
+        if inside_box(pose_b, half_b, vertex) {
+            let depth = (vertex.dot(&normal) - back_b).min(overlap);
+            if depth > 0. {
+                contacts.push(Contact {
+                    point: *vertex,
+                    normal,
+                    depth,
+                });
+            }
+        }
+    }
+
+    // Edge against edge, no vertex is inside. Meet halfway between the
+    // deepest points of both boxes.
+    if contacts.is_empty() {
+        let support =
+            |pose: &Pose, axes: &[Vector3<f32>; 3], half: &Vector3<f32>, dir: Vector3<f32>| {
+                (0..3).fold(pose.translation, |point, i| {
+                    let sign = if axes[i].dot(&dir) < 0. { -1. } else { 1. };
+                    point + axes[i] * (half[i] * sign)
+                })
+            };
+        let deepest_a = support(pose_a, &axes_a, half_a, normal);
+        let deepest_b = support(pose_b, &axes_b, half_b, -normal);
+        contacts.push(Contact {
+            point: (deepest_a + deepest_b) * 0.5,
+            normal,
+            depth: overlap,
+        });
+    }
+
+    contacts
+}
+
+fn box_axes(pose: &Pose) -> [Vector3<f32>; 3] {
+    [
+        pose.transform_vector(&Vector3::x()),
+        pose.transform_vector(&Vector3::y()),
+        pose.transform_vector(&Vector3::z()),
+    ]
+}
+
+fn box_vertices(pose: &Pose, half_extents: &Vector3<f32>) -> [Vector3<f32>; 8] {
+    let mut vertices = [Vector3::zeros(); 8];
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let sign = |bit: usize| if i & bit == 0 { -1. } else { 1. };
+        let local = Vector3::new(
+            half_extents.x * sign(1),
+            half_extents.y * sign(2),
+            half_extents.z * sign(4),
+        );
+        *vertex = pose.transform_point(&local);
+    }
+    vertices
+}
+
+fn inside_box(pose: &Pose, half_extents: &Vector3<f32>, point: &Vector3<f32>) -> bool {
+    let local = pose.inverse_transform_point(point);
+    (0..3).all(|i| local[i].abs() <= half_extents[i] + VERTEX_MARGIN)
+}
+
+/// The normal points from the triangle to the core. Cores behind the
+/// triangle pass through it.
+fn triangle_core(triangle: &[Vector3<f32>; 3], core: &Core) -> Vec<Contact> {
+    let face = match face_normal(triangle) {
+        Some(face) => face,
+        None => return Vec::new(),
+    };
+    if (core.center() - triangle[0]).dot(&face) < 0. {
+        return Vec::new();
+    }
+
+    let touching = |on_core: Vector3<f32>, on_triangle: Vector3<f32>| {
+        let delta = on_core - on_triangle;
+        let distance = delta.norm();
+        if distance >= core.radius {
+            return None;
+        }
+
+        let (normal, depth) = if distance > EPSILON {
+            (delta / distance, core.radius - distance)
+        } else {
+            // Through the triangle, push out far enough to clear both ends
+            let below = (core.a - triangle[0])
+                .dot(&face)
+                .min((core.b - triangle[0]).dot(&face))
+                .min(0.);
+            (face, core.radius - below)
+        };
+
+        Some(Contact {
+            point: on_triangle,
+            normal,
+            depth,
+        })
+    };
+
+    let mut contacts = Vec::new();
+    let (on_core, on_triangle) = closest_points_segment_triangle(&core.a, &core.b, triangle);
+    contacts.extend(touching(on_core, on_triangle));
+
+    // The ends of a capsule lying on the triangle
+    if !core.is_sphere() {
+        for end in [core.a, core.b].iter() {
+            if (end - on_core).norm() > core.radius * 0.5 {
+                contacts.extend(touching(*end, closest_point_on_triangle(end, triangle)));
+            }
+        }
+    }
+
+    contacts
+}
+
+/// The normal points from the triangle to the box. Only box vertices
+/// through the face and triangle vertices inside the box are found, which
+/// is enough for boxes resting on or sliding over a surface.
+fn triangle_box(
+    triangle: &[Vector3<f32>; 3],
+    pose: &Pose,
+    half_extents: &Vector3<f32>,
+) -> Vec<Contact> {
+    let face = match face_normal(triangle) {
+        Some(face) => face,
+        None => return Vec::new(),
+    };
+    let axes = box_axes(pose);
+    let reach: f32 = (0..3)
+        .map(|i| half_extents[i] * axes[i].dot(&face).abs())
+        .sum();
+    let height = (pose.translation - triangle[0]).dot(&face);
+    if height < 0. || height - reach > 0. {
+        return Vec::new();
+    }
+
+    let mut contacts = Vec::new();
+    for vertex in box_vertices(pose, half_extents).iter() {
+        let above = (vertex - triangle[0]).dot(&face);
+        if above >= 0. {
+            continue;
+        }
+        let projected = vertex - face * above;
+        if (closest_point_on_triangle(&projected, triangle) - projected).norm() < 1e-4 {
+            contacts.push(Contact {
+                point: *vertex,
+                normal: face,
+                depth: -above,
+            });
+        }
+    }
+
+    for corner in triangle.iter() {
+        if let Some(contact) = box_sphere(pose, half_extents, corner, 0.) {
+            contacts.push(contact.flipped());
+        }
+    }
+
+    contacts
+}
+
+/// Unit normal of the counter-clockwise side, `None` for slivers.
+fn face_normal(triangle: &[Vector3<f32>; 3]) -> Option<Vector3<f32>> {
+    let normal = (triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0]));
+    let length = normal.norm();
+    if length > EPSILON {
+        Some(normal / length)
+    } else {
+        None
+    }
+}
+
+/// The closest pair of points, one on segment `p1`-`q1` and one on `p2`-`q2`.
+pub fn closest_points_on_segments(
+    p1: &Vector3<f32>,
+    q1: &Vector3<f32>,
+    p2: &Vector3<f32>,
+    q2: &Vector3<f32>,
+) -> (Vector3<f32>, Vector3<f32>) {
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = d1.norm_squared();
+    let e = d2.norm_squared();
+    let f = d2.dot(&r);
+    let clamp = |x: f32| x.max(0.).min(1.);
+
+    if a < EPSILON && e < EPSILON {
+        return (*p1, *p2);
+    }
+
+    let (s, t) = if a < EPSILON {
+        (0., clamp(f / e))
+    } else {
+        let c = d1.dot(&r);
+        if e < EPSILON {
+            (clamp(-c / a), 0.)
+        } else {
+            let b = d1.dot(&d2);
+            let denominator = a * e - b * b;
+            let s = if denominator > EPSILON {
+                clamp((b * f - c * e) / denominator)
+            } else {
+                0.
+            };
+
+            let t = (b * s + f) / e;
+            if t < 0. {
+                (clamp(-c / a), 0.)
+            } else if t > 1. {
+                (clamp((b - c) / a), 1.)
+            } else {
+                (s, t)
+            }
+        }
+    };
+
+    (p1 + d1 * s, p2 + d2 * t)
+}
+
+pub fn closest_point_on_triangle(
+    point: &Vector3<f32>,
+    triangle: &[Vector3<f32>; 3],
+) -> Vector3<f32> {
+    let [a, b, c] = triangle;
+    let ab = b - a;
+    let ac = c - a;
+
+    let ap = point - a;
+    let d1 = ab.dot(&ap);
+    let d2 = ac.dot(&ap);
+    if d1 <= 0. && d2 <= 0. {
+        return *a;
+    }
+
+    let bp = point - b;
+    let d3 = ab.dot(&bp);
+    let d4 = ac.dot(&bp);
+    if d3 >= 0. && d4 <= d3 {
+        return *b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = point - c;
+    let d5 = ab.dot(&cp);
+    let d6 = ac.dot(&cp);
+    if d6 >= 0. && d5 <= d6 {
+        return *c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && d4 - d3 >= 0. && d5 - d6 >= 0. {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denominator = 1. / (va + vb + vc);
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+/// The closest pair of points, one on segment `p`-`q` and one on the
+/// triangle. The same point twice when the segment goes through it.
+fn closest_points_segment_triangle(
+    p: &Vector3<f32>,
+    q: &Vector3<f32>,
+    triangle: &[Vector3<f32>; 3],
+) -> (Vector3<f32>, Vector3<f32>) {
+    if let Some(normal) = face_normal(triangle) {
+        let dp = (p - triangle[0]).dot(&normal);
+        let dq = (q - triangle[0]).dot(&normal);
+        if dp * dq < 0. {
+            let crossing = p + (q - p) * (dp / (dp - dq));
+            if (closest_point_on_triangle(&crossing, triangle) - crossing).norm() < 1e-4 {
+                return (crossing, crossing);
+            }
+        }
+    }
+
+    let mut candidates = vec![
+        (*p, closest_point_on_triangle(p, triangle)),
+        (*q, closest_point_on_triangle(q, triangle)),
+    ];
+    for i in 0..3 {
+        let (a, b) = (&triangle[i], &triangle[(i + 1) % 3]);
+        candidates.push(closest_points_on_segments(p, q, a, b));
+    }
+
+    candidates
+        .into_iter()
+        .min_by(|x, y| {
+            let x = (x.0 - x.1).norm_squared();
+            let y = (y.0 - y.1).norm_squared();
+            x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .expect("There are always candidates")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::collider::TriMesh;
+    use crate::physics::heightfield::Heightfield;
+    use nalgebra::UnitQuaternion;
+
+    fn at(x: f32, y: f32, z: f32) -> Pose {
+        Pose::new(Vector3::new(x, y, z), UnitQuaternion::identity())
+    }
+
+    fn close(a: &Vector3<f32>, b: &Vector3<f32>) -> bool {
+        (a - b).norm() < 1.0e-3
+    }
+
+    #[test]
+    fn overlapping_spheres_touch_once() {
+        let sphere = Shape::Sphere { radius: 1. };
+        let found = contacts(&sphere, &at(0., 0., 0.), &sphere, &at(1.5, 0., 0.));
+
+        assert_eq!(found.len(), 1);
+        assert!(close(&found[0].normal, &Vector3::x()));
+        assert!((found[0].depth - 0.5).abs() < 1.0e-5);
+        assert!(close(&found[0].point, &Vector3::new(0.75, 0., 0.)));
+    }
+
+    #[test]
+    fn separate_spheres_do_not_touch() {
+        let sphere = Shape::Sphere { radius: 1. };
+        assert!(contacts(&sphere, &at(0., 0., 0.), &sphere, &at(2.5, 0., 0.)).is_empty());
+    }
+
+    #[test]
+    fn stacked_boxes_touch_across_their_faces() {
+        let cube = Shape::Box {
+            half_extents: Vector3::repeat(0.5),
+        };
+        let found = contacts(&cube, &at(0., 0., 0.), &cube, &at(0., 0.9, 0.));
+
+        assert!(found.len() >= 4);
+        for contact in found.iter() {
+            assert!(close(&contact.normal, &Vector3::y()));
+            assert!(contact.depth > 0. && contact.depth <= 0.1 + 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn rotated_boxes_apart_do_not_touch() {
+        let cube = Shape::Box {
+            half_extents: Vector3::repeat(0.5),
+        };
+        let turned = Pose::new(
+            Vector3::new(1.3, 0., 0.),
+            UnitQuaternion::from_axis_angle(&Vector3::y_axis(), 0.3),
+        );
+
+        assert!(contacts(&cube, &at(0., 0., 0.), &cube, &turned).is_empty());
+    }
+
+    #[test]
+    fn flipped_arguments_flip_the_normal() {
+        let cube = Shape::Box {
+            half_extents: Vector3::repeat(0.5),
+        };
+        let sphere = Shape::Sphere { radius: 0.5 };
+        let found = contacts(&sphere, &at(0., 0.9, 0.), &cube, &at(0., 0., 0.));
+
+        assert_eq!(found.len(), 1);
+        assert!(close(&found[0].normal, &-Vector3::y()));
+        assert!((found[0].depth - 0.1).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn capsule_lies_flat_on_a_triangle() {
+        let ground = TriMesh::new(
+            "ground",
+            vec![[
+                Vector3::new(-10., 0., 10.),
+                Vector3::new(10., 0., 10.),
+                Vector3::new(0., 0., -10.),
+            ]],
+        )
+        .unwrap();
+        let ground = Shape::TriMesh(std::sync::Arc::new(ground));
+        let capsule = Shape::Capsule {
+            half_height: 1.,
+            radius: 0.5,
+        };
+        // Lying along X, sunk 0.1 into the ground
+        let lying = Pose::new(
+            Vector3::new(0., 0.4, 0.),
+            UnitQuaternion::from_axis_angle(&Vector3::z_axis(), std::f32::consts::FRAC_PI_2),
+        );
+
+        let found = contacts(&ground, &at(0., 0., 0.), &capsule, &lying);
+        assert!(found.len() >= 2);
+        for contact in found.iter() {
+            assert!(close(&contact.normal, &Vector3::y()));
+            assert!((contact.depth - 0.1).abs() < 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn capsules_pass_through_the_back_of_a_triangle() {
+        let triangle = [
+            Vector3::new(-10., 0., 10.),
+            Vector3::new(10., 0., 10.),
+            Vector3::new(0., 0., -10.),
+        ];
+        let below = Core {
+            a: Vector3::new(-1., -0.4, 0.),
+            b: Vector3::new(1., -0.4, 0.),
+            radius: 0.5,
+        };
+
+        assert!(triangle_core(&triangle, &below).is_empty());
+    }
+
+    #[test]
+    fn sphere_rests_on_a_heightfield() {
+        let flat = Heightfield::new("flat", -2., -2., (1., 1.), 5, 5, vec![1.; 25]);
+        let ground = Shape::Heightfield(std::sync::Arc::new(flat));
+        let sphere = Shape::Sphere { radius: 0.5 };
+
+        // Neighbouring triangles touch the sphere at their edges too
+        let found = contacts(&ground, &at(0., 0., 0.), &sphere, &at(0.3, 1.4, 0.2));
+        let deepest = found
+            .iter()
+            .max_by(|a, b| a.depth.partial_cmp(&b.depth).unwrap())
+            .unwrap();
+        assert!(close(&deepest.normal, &Vector3::y()));
+        assert!((deepest.depth - 0.1).abs() < 1.0e-3);
+
+        assert!(contacts(&ground, &at(0., 0., 0.), &sphere, &at(0.3, 1.6, 0.2)).is_empty());
+    }
+
+    #[test]
+    fn box_sinks_into_a_heightfield() {
+        let flat = Heightfield::new("flat", -2., -2., (1., 1.), 5, 5, vec![0.; 25]);
+        let ground = Shape::Heightfield(std::sync::Arc::new(flat));
+        let cube = Shape::Box {
+            half_extents: Vector3::repeat(0.5),
+        };
+
+        let found = contacts(&ground, &at(0., 0., 0.), &cube, &at(0.1, 0.45, 0.1));
+        assert!(found.len() >= 4);
+        for contact in found.iter() {
+            assert!(close(&contact.normal, &Vector3::y()));
+            assert!(contact.depth > 0. && contact.depth <= 0.05 + 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn heightfield_edges_hold_up_to_the_last_cell() {
+        let flat = Heightfield::new("flat", -2., -2., (1., 1.), 5, 5, vec![0.; 25]);
+        let ground = Shape::Heightfield(std::sync::Arc::new(flat));
+        let sphere = Shape::Sphere { radius: 0.5 };
+
+        // Over the corner cell
+        let found = contacts(&ground, &at(0., 0., 0.), &sphere, &at(1.9, 0.4, 1.9));
+        assert!(found
+            .iter()
+            .any(|c| close(&c.normal, &Vector3::y()) && (c.depth - 0.1).abs() < 1.0e-3));
+
+        // Out of reach past the edges or far below
+        for &(x, z) in [(3., 0.), (0., -3.), (3., 3.)].iter() {
+            assert!(contacts(&ground, &at(0., 0., 0.), &sphere, &at(x, 0.4, z)).is_empty());
+        }
+        assert!(contacts(&ground, &at(0., 0., 0.), &sphere, &at(0., -5., 0.)).is_empty());
+    }
+
+    #[test]
+    fn heightfields_move_with_their_pose() {
+        let flat = Heightfield::new("flat", 0., 0., (1., 1.), 2, 2, vec![0.; 4]);
+        let ground = Shape::Heightfield(std::sync::Arc::new(flat));
+        let sphere = Shape::Sphere { radius: 0.5 };
+
+        assert!(contacts(&ground, &at(0., 0., 0.), &sphere, &at(10.5, 0.4, 0.5)).is_empty());
+        let found = contacts(&ground, &at(10., 0., 0.), &sphere, &at(10.5, 0.4, 0.5));
+        assert!(found.iter().any(|c| close(&c.normal, &Vector3::y())));
+    }
+
+    #[test]
+    fn closest_points_of_crossing_segments() {
+        let (on_a, on_b) = closest_points_on_segments(
+            &Vector3::new(-1., 0., 0.),
+            &Vector3::new(1., 0., 0.),
+            &Vector3::new(0., 1., -1.),
+            &Vector3::new(0., 1., 1.),
+        );
+
+        assert!(close(&on_a, &Vector3::new(0., 0., 0.)));
+        assert!(close(&on_b, &Vector3::new(0., 1., 0.)));
+    }
+}
@@ -0,0 +1,459 @@
+use nalgebra::Vector3;
+
+use crate::bounds::Aabb;
+use crate::picking::Ray;
+use crate::render::component::Mesh;
+
+/// Heights sampled on a regular grid over the XZ plane. Each cell is split
+/// into two triangles facing up, which is what collisions are tested
+/// against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heightfield {
+    name: String,
+    min_x: f32,
+    min_z: f32,
+    cell_x: f32,
+    cell_z: f32,
+    /// Samples along X
+    columns: usize,
+    /// Samples along Z
+    rows: usize,
+    /// Row after row
+    heights: Vec<f32>,
+    aabb: Aabb,
+}
+
+impl Heightfield {
+    /// A grid starting at (`min_x`, `min_z`), `heights` going along X first.
+    pub fn new<S: Into<String>>(
+        name: S,
+        min_x: f32,
+        min_z: f32,
+        cell_size: (f32, f32),
+        columns: usize,
+        rows: usize,
+        heights: Vec<f32>,
+    ) -> Heightfield {
+        assert!(
+            columns >= 2 && rows >= 2,
+            "A heightfield needs at least 2x2 samples"
+        );
+        assert_eq!(heights.len(), columns * rows, "One height per sample");
+
+        let (cell_x, cell_z) = cell_size;
+        let min_y = heights.iter().cloned().fold(std::f32::INFINITY, f32::min);
+        let max_y = heights
+            .iter()
+            .cloned()
+            .fold(std::f32::NEG_INFINITY, f32::max);
+        let aabb = Aabb::new(
+            Vector3::new(min_x, min_y, min_z),
+            Vector3::new(
+                min_x + cell_x * (columns - 1) as f32,
+                max_y,
+                min_z + cell_z * (rows - 1) as f32,
+            ),
+        );
+
+        Heightfield {
+            name: name.into(),
+            min_x,
+            min_z,
+            cell_x,
+            cell_z,
+            columns,
+            rows,
+            heights,
+            aabb,
+        }
+    }
+
+    /// Sample the highest surface of `mesh` from above on a `columns` by
+    /// `rows` grid spanning it. `None` when the mesh has no triangles.
+    pub fn from_mesh(mesh: &Mesh, columns: usize, rows: usize) -> Option<Heightfield> {
+        let columns = columns.max(2);
+        let rows = rows.max(2);
+
+        let triangles = mesh.triangles();
+        let aabb = Aabb::from_points(triangles.iter().flat_map(|t| t.iter().cloned()))?;
+        let cell_x = (aabb.max.x - aabb.min.x) / (columns - 1) as f32;
+        let cell_z = (aabb.max.z - aabb.min.z) / (rows - 1) as f32;
+
+        // Rasterize every triangle onto the samples below it, rather than
+        // testing every sample against every triangle
+        let mut heights = vec![std::f32::NEG_INFINITY; columns * rows];
+        for [a, b, c] in triangles.iter() {
+            let first = |min: f32, origin: f32, cell: f32| {
+                if cell > 0. {
+                    ((min - origin) / cell).ceil().max(0.) as usize
+                } else {
+                    0
+                }
+            };
+            let last = |max: f32, origin: f32, cell: f32, count: usize| {
+                if cell > 0. {
+                    (((max - origin) / cell).floor().max(0.) as usize).min(count - 1)
+                } else {
+                    count - 1
+                }
+            };
+
+            let min = a.zip_map(b, f32::min).zip_map(c, f32::min);
+            let max = a.zip_map(b, f32::max).zip_map(c, f32::max);
+            for row in first(min.z, aabb.min.z, cell_z)..=last(max.z, aabb.min.z, cell_z, rows) {
+                for column in
+                    first(min.x, aabb.min.x, cell_x)..=last(max.x, aabb.min.x, cell_x, columns)
+                {
+                    let x = aabb.min.x + column as f32 * cell_x;
+                    let z = aabb.min.z + row as f32 * cell_z;
+                    if let Some(y) = height_in_triangle(x, z, [a, b, c]) {
+                        let height = &mut heights[row * columns + column];
+                        *height = height.max(y);
+                    }
+                }
+            }
+        }
+
+        // Samples no triangle covers, outside the outline of the mesh, sit at
+        // its lowest point
+        let lowest = heights
+            .iter()
+            .cloned()
+            .filter(|h| h.is_finite())
+            .fold(aabb.min.y, f32::min);
+        for height in heights.iter_mut().filter(|h| !h.is_finite()) {
+            *height = lowest;
+        }
+
+        Some(Heightfield::new(
+            mesh.name(),
+            aabb.min.x,
+            aabb.min.z,
+            (cell_x, cell_z),
+            columns,
+            rows,
+            heights,
+        ))
+    }
+
+    /// Name of the mesh this was sampled from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+
+    pub fn height(&self, column: usize, row: usize) -> f32 {
+        self.heights[row * self.columns + column]
+    }
+
+    /// Height of the surface at (`x`, `z`), `None` outside of the grid.
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (column, row, fx, fz) = self.cell_at(x, z)?;
+
+        let h00 = self.height(column, row);
+        let h10 = self.height(column + 1, row);
+        let h01 = self.height(column, row + 1);
+        let h11 = self.height(column + 1, row + 1);
+
+        // The same split as `cell_triangles`
+        let height = if fx + fz <= 1. {
+            h00 + fx * (h10 - h00) + fz * (h01 - h00)
+        } else {
+            h11 + (1. - fx) * (h01 - h11) + (1. - fz) * (h10 - h11)
+        };
+
+        Some(height)
+    }
+
+    /// Normal of the surface at (`x`, `z`), that of the triangle under the
+    /// point. `None` outside of the grid.
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vector3<f32>> {
+        let (column, row, fx, fz) = self.cell_at(x, z)?;
+
+        let triangles = self.cell_triangles(column, row);
+        let [a, b, c] = if fx + fz <= 1. {
+            triangles[0]
+        } else {
+            triangles[1]
+        };
+        Some((b - a).cross(&(c - a)).normalize())
+    }
+
+    /// Distance along `ray` to the first point where it hits the surface,
+    /// from either side, up to `max_distance`. Only the cells the ray passes
+    /// over are tested, in the order it passes over them.
+    pub fn raycast(&self, ray: &Ray, max_distance: f32) -> Option<f32> {
+        let start = ray.intersect_aabb(&self.aabb)?;
+        if start > max_distance {
+            return None;
+        }
+
+        let entry = ray.at(start);
+        let cell = |offset: f32, cell: f32, count: usize| {
+            ((offset / cell).floor().max(0.) as usize).min(count - 2)
+        };
+        let mut column = cell(entry.x - self.min_x, self.cell_x, self.columns);
+        let mut row = cell(entry.z - self.min_z, self.cell_z, self.rows);
+
+        // Distance to the next cell boundary along an axis, and between two
+        // boundaries
+        let boundary = |direction: f32, position: f32, origin: f32, cell: f32, index: usize| {
+            if direction == 0. {
+                return (std::f32::INFINITY, std::f32::INFINITY);
+            }
+            let next = if direction > 0. { index + 1 } else { index };
+            let edge = origin + next as f32 * cell;
+            (
+                start + (edge - position) / direction,
+                cell / direction.abs(),
+            )
+        };
+        let (mut next_x, step_x) =
+            boundary(ray.direction.x, entry.x, self.min_x, self.cell_x, column);
+        let (mut next_z, step_z) = boundary(ray.direction.z, entry.z, self.min_z, self.cell_z, row);
+
+        loop {
+            let hit = self
+                .cell_triangles(column, row)
+                .iter()
+                .filter_map(|triangle| ray.intersect_triangle(triangle))
+                .fold(None, |closest: Option<f32>, t| {
+                    Some(closest.map_or(t, |closest| closest.min(t)))
+                });
+            if let Some(distance) = hit {
+                return if distance <= max_distance {
+                    Some(distance)
+                } else {
+                    None
+                };
+            }
+
+            if next_x.min(next_z) > max_distance {
+                return None;
+            }
+            if next_x < next_z {
+                if ray.direction.x > 0. && column + 2 < self.columns {
+                    column += 1;
+                } else if ray.direction.x < 0. && column > 0 {
+                    column -= 1;
+                } else {
+                    return None;
+                }
+                next_x += step_x;
+            } else {
+                if ray.direction.z > 0. && row + 2 < self.rows {
+                    row += 1;
+                } else if ray.direction.z < 0. && row > 0 {
+                    row -= 1;
+                } else {
+                    return None;
+                }
+                next_z += step_z;
+            }
+        }
+    }
+
+    /// The triangles of every cell overlapping `aabb`.
+    pub fn triangles_in(&self, aabb: &Aabb) -> Vec<[Vector3<f32>; 3]> {
+        if aabb.max.y < self.aabb.min.y || aabb.min.y > self.aabb.max.y {
+            return Vec::new();
+        }
+
+        let cells = |min: f32, max: f32, origin: f32, cell: f32, count: usize| {
+            let first = ((min - origin) / cell).floor().max(0.);
+            let last = ((max - origin) / cell).floor().min((count - 2) as f32);
+            if last < first {
+                None
+            } else {
+                Some(first as usize..=last as usize)
+            }
+        };
+        let columns = cells(
+            aabb.min.x,
+            aabb.max.x,
+            self.min_x,
+            self.cell_x,
+            self.columns,
+        );
+        let rows = cells(aabb.min.z, aabb.max.z, self.min_z, self.cell_z, self.rows);
+
+        let mut triangles = Vec::new();
+        if let (Some(columns), Some(rows)) = (columns, rows) {
+            for row in rows {
+                for column in columns.clone() {
+                    triangles.extend_from_slice(&self.cell_triangles(column, row));
+                }
+            }
+        }
+
+        triangles
+    }
+
+    /// The cell containing (`x`, `z`) and how far along it the point is.
+    fn cell_at(&self, x: f32, z: f32) -> Option<(usize, usize, f32, f32)> {
+        let fx = (x - self.min_x) / self.cell_x;
+        let fz = (z - self.min_z) / self.cell_z;
+        if !(fx >= 0. && fz >= 0.) || fx > (self.columns - 1) as f32 || fz > (self.rows - 1) as f32
+        {
+            return None;
+        }
+
+        let column = (fx.floor() as usize).min(self.columns - 2);
+        let row = (fz.floor() as usize).min(self.rows - 2);
+        Some((column, row, fx - column as f32, fz - row as f32))
+    }
+
+    /// Two triangles, wound counter-clockwise seen from above.
+    fn cell_triangles(&self, column: usize, row: usize) -> [[Vector3<f32>; 3]; 2] {
+        let p00 = self.point(column, row);
+        let p10 = self.point(column + 1, row);
+        let p01 = self.point(column, row + 1);
+        let p11 = self.point(column + 1, row + 1);
+
+        [[p00, p01, p10], [p10, p01, p11]]
+    }
+
+    fn point(&self, column: usize, row: usize) -> Vector3<f32> {
+        Vector3::new(
+            self.min_x + column as f32 * self.cell_x,
+            self.height(column, row),
+            self.min_z + row as f32 * self.cell_z,
+        )
+    }
+}
+
+/// Height of the triangle at (`x`, `z`) when it lies above or below it.
+fn height_in_triangle(x: f32, z: f32, [a, b, c]: [&Vector3<f32>; 3]) -> Option<f32> {
+    let (e0x, e0z) = (b.x - a.x, b.z - a.z);
+    let (e1x, e1z) = (c.x - a.x, c.z - a.z);
+    let (px, pz) = (x - a.x, z - a.z);
+
+    let det = e0x * e1z - e1x * e0z;
+    if det.abs() < std::f32::EPSILON {
+        return None;
+    }
+
+    let u = (px * e1z - e1x * pz) / det;
+    let v = (e0x * pz - px * e0z) / det;
+    let tolerance = 1e-4;
+    if u < -tolerance || v < -tolerance || u + v > 1. + tolerance {
+        return None;
+    }
+
+    Some(a.y + u * (b.y - a.y) + v * (c.y - a.y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three by three samples over x in 0..2 and z in 0..4, a plane rising
+    /// as `x + 1.5 z`.
+    fn slope() -> Heightfield {
+        let heights = (0..9).map(|i| i as f32).collect();
+        Heightfield::new("slope", 0., 0., (1., 2.), 3, 3, heights)
+    }
+
+    fn close(a: Option<f32>, b: f32) -> bool {
+        a.map_or(false, |a| (a - b).abs() < 1e-5)
+    }
+
+    #[test]
+    fn heights_are_interpolated_inside_the_grid() {
+        let slope = slope();
+        for &(x, z) in [(0., 0.), (0.5, 1.), (1.25, 3.5), (1.9, 0.1)].iter() {
+            assert!(close(slope.height_at(x, z), x + 1.5 * z), "{} {}", x, z);
+        }
+        assert_eq!(slope.height(2, 1), 5.);
+    }
+
+    #[test]
+    fn each_cell_is_split_along_its_diagonal() {
+        let bump = Heightfield::new("bump", 0., 0., (1., 1.), 2, 2, vec![0., 0., 0., 1.]);
+
+        assert!(close(bump.height_at(0.25, 0.25), 0.));
+        assert!(close(bump.height_at(0.75, 0.75), 0.5));
+        assert!(close(bump.height_at(0.5, 0.5), 0.));
+    }
+
+    #[test]
+    fn far_edges_belong_to_the_last_cell() {
+        let slope = slope();
+
+        assert!(close(slope.height_at(2., 4.), 8.));
+        assert!(close(slope.height_at(2., 0.), 2.));
+        assert!(close(slope.height_at(0., 4.), 6.));
+        assert!(slope.normal_at(2., 4.).is_some());
+    }
+
+    #[test]
+    fn points_off_the_grid_have_no_height() {
+        let slope = slope();
+
+        for &(x, z) in [
+            (-0.01, 1.),
+            (1., -0.01),
+            (2.01, 1.),
+            (1., 4.01),
+            (std::f32::NAN, 1.),
+        ]
+        .iter()
+        {
+            assert_eq!(slope.height_at(x, z), None);
+            assert_eq!(slope.normal_at(x, z), None);
+        }
+    }
+
+    #[test]
+    fn normals_face_up_the_slope() {
+        let normal = slope().normal_at(1.5, 2.5).unwrap();
+        let expected = Vector3::new(-1., 1., -1.5).normalize();
+        assert!((normal - expected).norm() < 1e-5);
+    }
+
+    #[test]
+    fn triangles_in_clamps_to_the_grid() {
+        let slope = slope();
+        let tall = |min_x: f32, max_x: f32| {
+            Aabb::new(Vector3::new(min_x, -10., 0.5), Vector3::new(max_x, 10., 1.))
+        };
+
+        assert_eq!(slope.triangles_in(&tall(1.5, 1.9)).len(), 2);
+        // Past the far edge is still the last column
+        assert_eq!(slope.triangles_in(&tall(1.5, 5.)).len(), 2);
+        assert_eq!(slope.triangles_in(&tall(-1., 5.)).len(), 4);
+        assert!(slope.triangles_in(&tall(2.5, 5.)).is_empty());
+        assert!(slope.triangles_in(&tall(-5., -0.5)).is_empty());
+
+        let above = Aabb::new(Vector3::new(0., 20., 0.), Vector3::new(2., 30., 4.));
+        assert!(slope.triangles_in(&above).is_empty());
+    }
+
+    #[test]
+    fn rays_hit_the_surface_where_it_is() {
+        let slope = slope();
+
+        let down = Ray::new(Vector3::new(1.5, 20., 2.5), Vector3::new(0., -1., 0.));
+        let distance = slope.raycast(&down, 100.).unwrap();
+        assert!((down.at(distance).y - 5.25).abs() < 1e-4);
+        assert_eq!(slope.raycast(&down, 10.), None);
+
+        // Coming in from the side, over a cell before reaching the surface
+        let across = Ray::new(Vector3::new(-1., 6., 3.), Vector3::new(1., 0., 0.));
+        assert!(close(slope.raycast(&across, 100.), 2.5));
+
+        let beside = Ray::new(Vector3::new(3., 20., 2.), Vector3::new(0., -1., 0.));
+        assert_eq!(slope.raycast(&beside, 100.), None);
+    }
+}
@@ -0,0 +1,688 @@
+//! Rigid body physics, stepped once per fixed step in the simulation stage.
+//!
+//! Bodies are positioned by their entity's `Transform`, which the physics
+//! system writes back after every step. Bodies are meant to be at the root
+//! of the hierarchy, a parent's transform is not taken into account. Give
+//! moving bodies an `InterpolatedTransform` to render them smoothly in
+//! between steps.
+
+pub mod collider;
+pub mod contact;
+pub mod heightfield;
+
+use std::collections::HashMap;
+
+use chal_engine::components::Position;
+use nalgebra::{Matrix3, UnitQuaternion, Vector3};
+use specs::{
+    Component, Entities, Entity, Join, Read, ReadStorage, System, VecStorage, Write, WriteStorage,
+};
+
+use crate::bounds::Aabb;
+use crate::engine::DeltaTime;
+use crate::physics::collider::{Collider, Pose, Shape};
+use crate::physics::contact::Contact;
+use crate::transform::Transform;
+
+/// Rounds of impulses per step. More makes stacks steadier.
+const SOLVER_ITERATIONS: usize = 8;
+
+/// Share of the penetration resolved per step.
+const BAUMGARTE: f32 = 0.2;
+
+/// Penetration that is left alone, so resting contacts stay in contact.
+const SLOP: f32 = 0.005;
+
+/// Slower collisions than this don't bounce, which keeps resting bodies
+/// from jittering.
+const RESTITUTION_THRESHOLD: f32 = 1.;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyKind {
+    /// Moved by forces and collisions
+    Dynamic,
+    /// Moved by its velocity only, pushing dynamic bodies out of the way
+    Kinematic,
+    /// Never moves
+    Static,
+}
+
+impl Default for BodyKind {
+    fn default() -> BodyKind {
+        BodyKind::Dynamic
+    }
+}
+
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct RigidBody {
+    pub kind: BodyKind,
+    /// In kilograms
+    pub mass: f32,
+    pub velocity: Vector3<f32>,
+    /// Axis times radians per second
+    pub angular_velocity: Vector3<f32>,
+    /// Share of the velocity lost per second
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub gravity_scale: f32,
+    force: Vector3<f32>,
+    torque: Vector3<f32>,
+}
+
+impl Default for RigidBody {
+    fn default() -> RigidBody {
+        RigidBody::dynamic(1.)
+    }
+}
+
+impl RigidBody {
+    pub fn dynamic(mass: f32) -> RigidBody {
+        RigidBody {
+            kind: BodyKind::Dynamic,
+            mass,
+            velocity: Vector3::zeros(),
+            angular_velocity: Vector3::zeros(),
+            linear_damping: 0.01,
+            angular_damping: 0.05,
+            gravity_scale: 1.,
+            force: Vector3::zeros(),
+            torque: Vector3::zeros(),
+        }
+    }
+
+    pub fn kinematic() -> RigidBody {
+        RigidBody {
+            kind: BodyKind::Kinematic,
+            ..RigidBody::dynamic(0.)
+        }
+    }
+
+    pub fn fixed() -> RigidBody {
+        RigidBody {
+            kind: BodyKind::Static,
+            ..RigidBody::dynamic(0.)
+        }
+    }
+
+    pub fn with_velocity(mut self, velocity: Vector3<f32>) -> RigidBody {
+        self.velocity = velocity;
+        self
+    }
+
+    /// Push through the center of mass during the next step.
+    pub fn apply_force(&mut self, force: Vector3<f32>) {
+        self.force += force;
+    }
+
+    pub fn apply_torque(&mut self, torque: Vector3<f32>) {
+        self.torque += torque;
+    }
+
+    /// Change the velocity at once, as with a hit.
+    pub fn apply_impulse(&mut self, impulse: Vector3<f32>) {
+        if self.kind == BodyKind::Dynamic && self.mass > 0. {
+            self.velocity += impulse / self.mass;
+        }
+    }
+}
+
+/// Acceleration of every dynamic body, scaled by its `gravity_scale`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gravity(pub Vector3<f32>);
+
+impl Default for Gravity {
+    fn default() -> Gravity {
+        Gravity(Vector3::new(0., -9.81, 0.))
+    }
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionEventKind {
+    Started,
+    Stopped,
+}
+
+/// Two entities started or stopped touching. `a` has the lower id.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CollisionEvent {
+    pub kind: CollisionEventKind,
+    pub a: Entity,
+    pub b: Entity,
+    /// One of the two is a sensor
+    pub sensor: bool,
+}
+
+/// The collision events of the current frame. The engine clears them at the
+/// start of every update, a frame can hold several fixed steps.
+#[derive(Clone, Debug, Default)]
+pub struct CollisionEvents {
+    events: Vec<CollisionEvent>,
+    step_start: usize,
+}
+
+impl CollisionEvents {
+    /// Every event since the frame began.
+    pub fn all(&self) -> &[CollisionEvent] {
+        &self.events[..]
+    }
+
+    /// The events of the latest fixed step, for systems that run every step.
+    pub fn latest(&self) -> &[CollisionEvent] {
+        &self.events[self.step_start..]
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.step_start = 0;
+    }
+
+    fn begin_step(&mut self) {
+        self.step_start = self.events.len();
+    }
+}
+
+/// A body as the solver sees it during a single step.
+struct Body<'c> {
+    entity: Entity,
+    kind: BodyKind,
+    position: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+    velocity: Vector3<f32>,
+    angular_velocity: Vector3<f32>,
+    inverse_mass: f32,
+    /// In world space
+    inverse_inertia: Matrix3<f32>,
+    collider: Option<&'c Collider>,
+}
+
+impl<'c> Body<'c> {
+    fn new(
+        entity: Entity,
+        transform: &Transform,
+        collider: Option<&'c Collider>,
+        body: Option<&RigidBody>,
+    ) -> Body<'c> {
+        let kind = body.map_or(BodyKind::Static, |body| body.kind);
+        let (inverse_mass, inverse_inertia) = match body {
+            Some(body) if kind == BodyKind::Dynamic && body.mass > 0. => {
+                let inertia = match collider {
+                    Some(collider) => collider.shape.inertia(body.mass),
+                    None => Shape::Sphere { radius: 0.5 }.inertia(body.mass),
+                };
+                let inverse = inertia.map(|i| if i > 0. { 1. / i } else { 0. });
+
+                let rotation = transform.rotation.to_rotation_matrix();
+                let rotation = rotation.matrix();
+                let world = rotation * Matrix3::from_diagonal(&inverse) * rotation.transpose();
+                (1. / body.mass, world)
+            }
+            _ => (0., Matrix3::zeros()),
+        };
+
+        Body {
+            entity,
+            kind,
+            position: transform.translation,
+            rotation: transform.rotation,
+            velocity: body.map_or(Vector3::zeros(), |body| body.velocity),
+            angular_velocity: body.map_or(Vector3::zeros(), |body| body.angular_velocity),
+            inverse_mass,
+            inverse_inertia,
+            collider,
+        }
+    }
+
+    fn moves(&self) -> bool {
+        self.kind != BodyKind::Static
+    }
+
+    fn pose(&self) -> Pose {
+        Pose::new(self.position, self.rotation)
+    }
+
+    fn velocity_at(&self, offset: &Vector3<f32>) -> Vector3<f32> {
+        self.velocity + self.angular_velocity.cross(offset)
+    }
+
+    fn apply_impulse(&mut self, offset: &Vector3<f32>, impulse: &Vector3<f32>) {
+        self.velocity += impulse * self.inverse_mass;
+        self.angular_velocity += self.inverse_inertia * offset.cross(impulse);
+    }
+
+    /// How hard the body is to push along `direction` at `offset`.
+    fn inverse_mass_along(&self, offset: &Vector3<f32>, direction: &Vector3<f32>) -> f32 {
+        let turn = self.inverse_inertia * offset.cross(direction);
+        self.inverse_mass + direction.dot(&turn.cross(offset))
+    }
+}
+
+/// A contact between bodies `a` and `b`, with the impulses that keep them
+/// apart so far.
+struct ContactConstraint {
+    a: usize,
+    b: usize,
+    offset_a: Vector3<f32>,
+    offset_b: Vector3<f32>,
+    normal: Vector3<f32>,
+    tangents: [Vector3<f32>; 2],
+    normal_mass: f32,
+    tangent_mass: [f32; 2],
+    /// Separating speed to aim for, from bouncing or resolving penetration
+    bias: f32,
+    friction: f32,
+    normal_impulse: f32,
+    tangent_impulse: [f32; 2],
+}
+
+impl ContactConstraint {
+    fn new(bodies: &[Body], a: usize, b: usize, contact: &Contact, dt: f32) -> ContactConstraint {
+        let (body_a, body_b) = (&bodies[a], &bodies[b]);
+        let (collider_a, collider_b) = match (body_a.collider, body_b.collider) {
+            (Some(collider_a), Some(collider_b)) => (collider_a, collider_b),
+            _ => unreachable!("Only bodies with colliders touch"),
+        };
+
+        let offset_a = contact.point - body_a.position;
+        let offset_b = contact.point - body_b.position;
+        let normal = contact.normal;
+        let tangents = tangents(&normal);
+
+        let mass = |direction: &Vector3<f32>| {
+            let k = body_a.inverse_mass_along(&offset_a, direction)
+                + body_b.inverse_mass_along(&offset_b, direction);
+            if k > 0. {
+                1. / k
+            } else {
+                0.
+            }
+        };
+
+        let approach = (body_b.velocity_at(&offset_b) - body_a.velocity_at(&offset_a)).dot(&normal);
+        let restitution = collider_a.restitution.max(collider_b.restitution);
+        let bounce = if approach < -RESTITUTION_THRESHOLD {
+            -restitution * approach
+        } else {
+            0.
+        };
+        let push_out = BAUMGARTE / dt * (contact.depth - SLOP).max(0.);
+
+        ContactConstraint {
+            a,
+            b,
+            offset_a,
+            offset_b,
+            normal,
+            tangents,
+            normal_mass: mass(&normal),
+            tangent_mass: [mass(&tangents[0]), mass(&tangents[1])],
+            bias: bounce.max(push_out),
+            friction: (collider_a.friction * collider_b.friction).sqrt(),
+            normal_impulse: 0.,
+            tangent_impulse: [0., 0.],
+        }
+    }
+
+    fn solve(&mut self, bodies: &mut [Body]) {
+        // Friction, limited by how hard the bodies are pressed together
+        for i in 0..2 {
+            let tangent = self.tangents[i];
+            let speed = self.relative_velocity(bodies).dot(&tangent);
+            let limit = self.friction * self.normal_impulse;
+            let total = (self.tangent_impulse[i] - speed * self.tangent_mass[i])
+                .max(-limit)
+                .min(limit);
+            let change = total - self.tangent_impulse[i];
+            self.tangent_impulse[i] = total;
+            self.apply(bodies, &(tangent * change));
+        }
+
+        // Only ever push apart, never pull together
+        let speed = self.relative_velocity(bodies).dot(&self.normal);
+        let total = (self.normal_impulse + (self.bias - speed) * self.normal_mass).max(0.);
+        let change = total - self.normal_impulse;
+        self.normal_impulse = total;
+        self.apply(bodies, &(self.normal * change));
+    }
+
+    fn relative_velocity(&self, bodies: &[Body]) -> Vector3<f32> {
+        bodies[self.b].velocity_at(&self.offset_b) - bodies[self.a].velocity_at(&self.offset_a)
+    }
+
+    fn apply(&self, bodies: &mut [Body], impulse: &Vector3<f32>) {
+        bodies[self.a].apply_impulse(&self.offset_a, &-impulse);
+        bodies[self.b].apply_impulse(&self.offset_b, impulse);
+    }
+}
+
+/// Two directions perpendicular to `normal` and to each other.
+fn tangents(normal: &Vector3<f32>) -> [Vector3<f32>; 2] {
+    let first = if normal.x.abs() >= 0.57735 {
+        Vector3::new(normal.y, -normal.x, 0.)
+    } else {
+        Vector3::new(0., normal.z, -normal.y)
+    }
+    .normalize();
+
+    [first, normal.cross(&first)]
+}
+
+/// Steps every rigid body and collider, meant for the simulation stage.
+#[derive(Default)]
+pub struct PhysicsSystem {
+    /// Pairs touching after the previous step and whether a sensor is
+    /// involved
+    touching: HashMap<(Entity, Entity), bool>,
+}
+
+impl<'a> System<'a> for PhysicsSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, DeltaTime>,
+        Read<'a, Gravity>,
+        Write<'a, CollisionEvents>,
+        ReadStorage<'a, Collider>,
+        WriteStorage<'a, RigidBody>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Position>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            dt,
+            gravity,
+            mut events,
+            colliders,
+            mut rigid_bodies,
+            mut transforms,
+            mut positions,
+        ) = data;
+        let dt = dt.0;
+
+        events.begin_step();
+        if dt <= 0. {
+            return;
+        }
+
+        let mut bodies: Vec<Body> = (
+            &entities,
+            &transforms,
+            (&colliders).maybe(),
+            (&rigid_bodies).maybe(),
+        )
+            .join()
+            .filter(|(_, _, collider, body)| collider.is_some() || body.is_some())
+            .map(|(entity, transform, collider, body)| Body::new(entity, transform, collider, body))
+            .collect();
+
+        // Forces and gravity
+        for body in bodies.iter_mut().filter(|body| body.inverse_mass > 0.) {
+            let rigid_body = rigid_bodies
+                .get(body.entity)
+                .expect("Dynamic bodies are rigid bodies");
+            let acceleration =
+                gravity.0 * rigid_body.gravity_scale + rigid_body.force * body.inverse_mass;
+            body.velocity += acceleration * dt;
+            body.angular_velocity += body.inverse_inertia * rigid_body.torque * dt;
+
+            body.velocity *= 1. / (1. + dt * rigid_body.linear_damping);
+            body.angular_velocity *= 1. / (1. + dt * rigid_body.angular_damping);
+        }
+
+        let pairs = self.find_pairs(&bodies);
+
+        let mut touching = HashMap::with_capacity(pairs.len());
+        let mut constraints = Vec::new();
+        for (a, b) in pairs {
+            let (collider_a, collider_b) = match (bodies[a].collider, bodies[b].collider) {
+                (Some(collider_a), Some(collider_b)) => (collider_a, collider_b),
+                _ => continue,
+            };
+            let contacts = contact::contacts(
+                &collider_a.shape,
+                &bodies[a].pose(),
+                &collider_b.shape,
+                &bodies[b].pose(),
+            );
+            if contacts.is_empty() {
+                continue;
+            }
+
+            let sensor = collider_a.sensor || collider_b.sensor;
+            touching.insert(pair_key(bodies[a].entity, bodies[b].entity), sensor);
+
+            let pushes = bodies[a].inverse_mass > 0. || bodies[b].inverse_mass > 0.;
+            if !sensor && pushes {
+                constraints.extend(
+                    contacts
+                        .iter()
+                        .map(|contact| ContactConstraint::new(&bodies, a, b, contact, dt)),
+                );
+            }
+        }
+
+        for _ in 0..SOLVER_ITERATIONS {
+            for constraint in constraints.iter_mut() {
+                constraint.solve(&mut bodies);
+            }
+        }
+
+        for body in bodies.iter_mut().filter(|body| body.moves()) {
+            body.position += body.velocity * dt;
+            let turn = UnitQuaternion::from_scaled_axis(body.angular_velocity * dt);
+            body.rotation = UnitQuaternion::new_normalize(*(turn * body.rotation).as_ref());
+        }
+
+        for body in bodies.iter().filter(|body| body.moves()) {
+            if let Some(transform) = transforms.get_mut(body.entity) {
+                transform.translation = body.position;
+                transform.rotation = body.rotation;
+            }
+            if let Some(position) = positions.get_mut(body.entity) {
+                position.x = body.position.x;
+                position.y = body.position.y;
+            }
+            if let Some(rigid_body) = rigid_bodies.get_mut(body.entity) {
+                rigid_body.velocity = body.velocity;
+                rigid_body.angular_velocity = body.angular_velocity;
+                rigid_body.force = Vector3::zeros();
+                rigid_body.torque = Vector3::zeros();
+            }
+        }
+
+        self.report(&touching, &mut events);
+        self.touching = touching;
+    }
+}
+
+impl PhysicsSystem {
+    /// Pairs of bodies whose bounding boxes overlap, with at least one of
+    /// them moving. Sorted along X so only neighbours are compared.
+    fn find_pairs(&self, bodies: &[Body]) -> Vec<(usize, usize)> {
+        let mut boxes: Vec<(usize, Aabb)> = bodies
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, body)| Some((idx, body.collider?.shape.aabb(&body.pose()))))
+            .collect();
+        boxes.sort_by(|(_, a), (_, b)| {
+            a.min
+                .x
+                .partial_cmp(&b.min.x)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let mut pairs = Vec::new();
+        for (i, (a, aabb_a)) in boxes.iter().enumerate() {
+            for (b, aabb_b) in boxes[i + 1..].iter() {
+                if aabb_b.min.x > aabb_a.max.x {
+                    break;
+                }
+                if (bodies[*a].moves() || bodies[*b].moves()) && aabb_a.intersects(aabb_b) {
+                    pairs.push((*a, *b));
+                }
+            }
+        }
+
+        pairs
+    }
+
+    /// Compare the pairs touching now with those of the previous step.
+    fn report(&self, touching: &HashMap<(Entity, Entity), bool>, events: &mut CollisionEvents) {
+        let event = |kind, (a, b): (Entity, Entity), sensor| CollisionEvent { kind, a, b, sensor };
+
+        for (&pair, &sensor) in touching.iter() {
+            if !self.touching.contains_key(&pair) {
+                events
+                    .events
+                    .push(event(CollisionEventKind::Started, pair, sensor));
+            }
+        }
+        for (&pair, &sensor) in self.touching.iter() {
+            if !touching.contains_key(&pair) {
+                events
+                    .events
+                    .push(event(CollisionEventKind::Stopped, pair, sensor));
+            }
+        }
+    }
+}
+
+fn pair_key(a: Entity, b: Entity) -> (Entity, Entity) {
+    if a.id() <= b.id() {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::heightfield::Heightfield;
+    use specs::{Builder, RunNow, World};
+    use std::sync::Arc;
+
+    /// A world stepped at 60 steps per second.
+    fn empty_world() -> (World, PhysicsSystem) {
+        let mut world = World::new();
+        let mut system = PhysicsSystem::default();
+        System::setup(&mut system, &mut world.res);
+        world.add_resource(DeltaTime(1. / 60.));
+        (world, system)
+    }
+
+    /// With a static ground box whose top is at zero.
+    fn world() -> (World, PhysicsSystem) {
+        let (mut world, system) = empty_world();
+        world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0., -0.5, 0.)))
+            .with(Collider::cuboid(Vector3::new(10., 0.5, 10.)))
+            .build();
+
+        (world, system)
+    }
+
+    fn step(world: &World, system: &mut PhysicsSystem, steps: usize) {
+        for _ in 0..steps {
+            system.run_now(&world.res);
+        }
+    }
+
+    fn transform(world: &World, entity: Entity) -> Transform {
+        let transforms = world.read_storage::<Transform>();
+        transforms.get(entity).unwrap().clone()
+    }
+
+    fn height(world: &World, entity: Entity) -> f32 {
+        transform(world, entity).translation.y
+    }
+
+    fn speed(world: &World, entity: Entity) -> f32 {
+        let bodies = world.read_storage::<RigidBody>();
+        let body = bodies.get(entity).unwrap();
+        body.velocity.norm() + body.angular_velocity.norm()
+    }
+
+    #[test]
+    fn box_resting_on_the_ground_stays_put() {
+        let (mut world, mut system) = world();
+        let cube = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0., 0.5, 0.)))
+            .with(Collider::cuboid(Vector3::repeat(0.5)))
+            .with(RigidBody::dynamic(1.))
+            .build();
+
+        step(&world, &mut system, 300);
+
+        assert!((height(&world, cube) - 0.5).abs() < 0.02);
+        assert!(speed(&world, cube) < 0.05);
+        assert!(transform(&world, cube).rotation.angle() < 0.01);
+    }
+
+    #[test]
+    fn dropped_sphere_comes_to_rest() {
+        let (mut world, mut system) = world();
+        let ball = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0., 2., 0.)))
+            .with(Collider::sphere(0.5))
+            .with(RigidBody::dynamic(1.))
+            .build();
+
+        step(&world, &mut system, 300);
+        let rested = height(&world, ball);
+        step(&world, &mut system, 60);
+
+        assert!((rested - 0.5).abs() < 0.02);
+        assert!((height(&world, ball) - rested).abs() < 1.0e-3);
+        assert!(speed(&world, ball) < 0.05);
+    }
+
+    #[test]
+    fn sphere_rests_on_a_heightfield() {
+        let (mut world, mut system) = empty_world();
+        let flat = Heightfield::new("flat", -4., -4., (1., 1.), 9, 9, vec![1.; 81]);
+        world
+            .create_entity()
+            .with(Transform::new(Vector3::zeros()))
+            .with(Collider::new(Shape::Heightfield(Arc::new(flat))))
+            .build();
+        let ball = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0.3, 2., 0.2)))
+            .with(Collider::sphere(0.5))
+            .with(RigidBody::dynamic(1.))
+            .build();
+
+        step(&world, &mut system, 300);
+
+        assert!((height(&world, ball) - 1.5).abs() < 0.02);
+        assert!(speed(&world, ball) < 0.05);
+    }
+
+    #[test]
+    fn touching_starts_once() {
+        let (mut world, mut system) = world();
+        world
+            .create_entity()
+            .with(Transform::new(Vector3::new(0., 0.5, 0.)))
+            .with(Collider::cuboid(Vector3::repeat(0.5)))
+            .with(RigidBody::dynamic(1.))
+            .build();
+
+        step(&world, &mut system, 60);
+
+        let events = world.read_resource::<CollisionEvents>();
+        let started = events
+            .all()
+            .iter()
+            .filter(|event| event.kind == CollisionEventKind::Started)
+            .count();
+        assert_eq!(started, 1);
+    }
+}
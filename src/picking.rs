@@ -0,0 +1,337 @@
+use nalgebra::{Matrix4, Point3, Vector3, Vector4};
+use specs::{Entity, Join, World};
+use wasm_bindgen::prelude::*;
+
+use crate::bounds::{Aabb, Bounds};
+use crate::camera::CameraView;
+use crate::render::component::Mesh;
+use crate::render::library::MeshLibrary;
+use crate::render::lod::LodGroup;
+use crate::transform::GlobalTransform;
+
+const EPSILON: f32 = 1e-6;
+
+/// A half line through the scene. The direction is normalized.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    /// The ray through canvas pixel (`x`, `y`), measured from the top left
+    /// like mouse events are.
+    pub fn from_screen(x: f32, y: f32, canvas_height: i32, camera: &CameraView) -> Option<Ray> {
+        let [vx, vy, width, height] = camera.viewport;
+        if width <= 0 || height <= 0 {
+            return None;
+        }
+
+        // The viewport is measured from the bottom left
+        let ndc_x = (x - vx as f32) / width as f32 * 2. - 1.;
+        let ndc_y = (canvas_height as f32 - y - vy as f32) / height as f32 * 2. - 1.;
+
+        let inverse = camera.view_projection().try_inverse()?;
+        let unproject = |z: f32| {
+            let point = inverse * Vector4::new(ndc_x, ndc_y, z, 1.);
+            Vector3::new(point.x, point.y, point.z) / point.w
+        };
+
+        let near = unproject(-1.);
+        let far = unproject(1.);
+        Some(Ray::new(near, far - near))
+    }
+
+    pub fn at(&self, distance: f32) -> Vector3<f32> {
+        self.origin + self.direction * distance
+    }
+
+    /// Distance at which the ray enters the box, zero when it starts inside.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut near = 0f32;
+        let mut far = std::f32::INFINITY;
+
+        for axis in 0..3 {
+            let inverse = 1. / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inverse;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inverse;
+            if inverse < 0. {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            near = near.max(t0);
+            far = far.min(t1);
+            if far < near {
+                return None;
+            }
+        }
+
+        Some(near)
+    }
+
+    /// Möller-Trumbore, hitting triangles from either side.
+    pub fn intersect_triangle(&self, triangle: &[Vector3<f32>; 3]) -> Option<f32> {
+        let [a, b, c] = triangle;
+        let edge1 = b - a;
+        let edge2 = c - a;
+
+        let p = self.direction.cross(&edge2);
+        let det = edge1.dot(&p);
+        if det.abs() < EPSILON {
+            return None;
+        }
+        let inverse_det = 1. / det;
+
+        let s = self.origin - a;
+        let u = s.dot(&p) * inverse_det;
+        if u < 0. || u > 1. {
+            return None;
+        }
+
+        let q = s.cross(&edge1);
+        let v = self.direction.dot(&q) * inverse_det;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+
+        let t = edge2.dot(&q) * inverse_det;
+        if t > EPSILON {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    /// Closest hit on the triangles of `mesh` placed by `model`.
+    pub fn intersect_mesh(&self, mesh: &Mesh, model: &Matrix4<f32>) -> Option<f32> {
+        let to_world =
+            |v: &Vector3<f32>| model.transform_point(&Point3::from_coordinates(*v)).coords;
+
+        mesh.triangles()
+            .iter()
+            .filter_map(|triangle| {
+                let triangle = [
+                    to_world(&triangle[0]),
+                    to_world(&triangle[1]),
+                    to_world(&triangle[2]),
+                ];
+                self.intersect_triangle(&triangle)
+            })
+            .fold(None, |closest: Option<f32>, t| {
+                Some(closest.map_or(t, |closest| closest.min(t)))
+            })
+    }
+}
+
+/// An entity under the cursor and where it was hit.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PickHit {
+    /// The specs entity id
+    pub entity: u32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    /// Distance from the camera along the ray
+    pub distance: f32,
+}
+
+impl PickHit {
+    pub fn new(entity: Entity, ray: &Ray, distance: f32) -> PickHit {
+        let point = ray.at(distance);
+
+        PickHit {
+            entity: entity.id(),
+            x: point.x,
+            y: point.y,
+            z: point.z,
+            distance,
+        }
+    }
+}
+
+/// The closest entity whose triangles `ray` hits. Entities with a `LodGroup`
+/// are tested against their most detailed level.
+pub fn ray_cast(world: &World, ray: &Ray) -> Option<PickHit> {
+    let entities = world.entities();
+    let mesh = world.read_storage::<Mesh>();
+    let transform = world.read_storage::<GlobalTransform>();
+    let bounds = world.read_storage::<Bounds>();
+    let lod_group = world.read_storage::<LodGroup>();
+    let library = world.read_resource::<MeshLibrary>();
+
+    let mut closest: Option<(Entity, f32)> = None;
+    let mut consider =
+        |entity: Entity, mesh: &Mesh, model: &Matrix4<f32>, aabb: Option<Aabb>| {
+            let best = closest.map(|(_, t)| t).unwrap_or(std::f32::INFINITY);
+
+            // Skip the triangles when the box alone is missed or further away
+            if let Some(aabb) = aabb {
+                match ray.intersect_aabb(&aabb) {
+                    Some(t) if t < best => {}
+                    _ => return,
+                }
+            }
+
+            if let Some(t) = ray.intersect_mesh(mesh, model) {
+                if t < best {
+                    closest = Some((entity, t));
+                }
+            }
+        };
+
+    // A `LodGroup` draws instead of the entity's own mesh
+    for (entity, mesh, transform, bounds, _) in (
+        &entities,
+        &mesh,
+        transform.maybe(),
+        bounds.maybe(),
+        !&lod_group,
+    )
+        .join()
+    {
+        let model = transform.map(|t| t.0).unwrap_or_else(Matrix4::identity);
+        consider(entity, mesh, &model, bounds.map(|b| b.aabb));
+    }
+
+    for (entity, lod_group, transform) in (&entities, &lod_group, transform.maybe()).join() {
+        let base = match lod_group.levels().first().and_then(|level| library.get(&level.mesh)) {
+            Some(base) => base,
+            None => continue,
+        };
+
+        let model = transform.map(|t| t.0).unwrap_or_else(Matrix4::identity);
+        let aabb = base.local_bounds().map(|local| local.aabb.transformed(&model));
+        consider(entity, base, &model, aabb);
+    }
+
+    closest.map(|(entity, t)| PickHit::new(entity, ray, t))
+}
+
+/// Where `ray` hits `entity`, for when it is already known which entity is
+/// under the cursor. Falls back to the center of its bounds for entities
+/// without triangles.
+pub fn ray_cast_entity(world: &World, ray: &Ray, entity: Entity) -> Option<PickHit> {
+    let mesh = world.read_storage::<Mesh>();
+    let transform = world.read_storage::<GlobalTransform>();
+    let bounds = world.read_storage::<Bounds>();
+    let lod_group = world.read_storage::<LodGroup>();
+    let library = world.read_resource::<MeshLibrary>();
+
+    let model = transform.get(entity).map(|t| t.0).unwrap_or_else(Matrix4::identity);
+    // Like `ray_cast`, a `LodGroup` goes before the entity's own mesh
+    let mesh = match lod_group.get(entity) {
+        Some(lod_group) => lod_group
+            .levels()
+            .first()
+            .and_then(|level| library.get(&level.mesh)),
+        None => mesh.get(entity),
+    };
+
+    let distance = mesh
+        .and_then(|mesh| ray.intersect_mesh(mesh, &model))
+        .or_else(|| {
+            let center = bounds.get(entity)?.sphere.center;
+            Some((center - ray.origin).dot(&ray.direction))
+        })?;
+
+    Some(PickHit::new(entity, ray, distance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        (a - b).norm() < 1e-4
+    }
+
+    fn triangle() -> [Vector3<f32>; 3] {
+        [
+            Vector3::new(-1., -1., -5.),
+            Vector3::new(1., -1., -5.),
+            Vector3::new(0., 1., -5.),
+        ]
+    }
+
+    /// Standing at the origin, looking down -Z.
+    fn view(viewport: [i32; 4]) -> CameraView {
+        CameraView {
+            view: Matrix4::identity(),
+            projection: Matrix4::new_perspective(1., std::f32::consts::FRAC_PI_2, 0.1, 100.),
+            eye: Vector3::zeros(),
+            viewport,
+        }
+    }
+
+    #[test]
+    fn triangles_are_hit_from_either_side() {
+        let front = Ray::new(Vector3::zeros(), Vector3::new(0., 0., -1.));
+        assert_eq!(front.intersect_triangle(&triangle()), Some(5.));
+
+        let back = Ray::new(Vector3::new(0., 0., -10.), Vector3::new(0., 0., 1.));
+        assert_eq!(back.intersect_triangle(&triangle()), Some(5.));
+    }
+
+    #[test]
+    fn triangles_behind_beside_or_along_the_ray_are_missed() {
+        let away = Ray::new(Vector3::zeros(), Vector3::new(0., 0., 1.));
+        assert_eq!(away.intersect_triangle(&triangle()), None);
+
+        let beside = Ray::new(Vector3::new(2., 0., 0.), Vector3::new(0., 0., -1.));
+        assert_eq!(beside.intersect_triangle(&triangle()), None);
+
+        let parallel = Ray::new(Vector3::new(-5., 0., -5.), Vector3::new(1., 0., 0.));
+        assert_eq!(parallel.intersect_triangle(&triangle()), None);
+    }
+
+    #[test]
+    fn aabbs_are_entered_where_the_ray_reaches_them() {
+        let aabb = Aabb::new(Vector3::new(-1., -1., -6.), Vector3::new(1., 1., -4.));
+
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(0., 0., -1.));
+        assert_eq!(ray.intersect_aabb(&aabb), Some(4.));
+
+        let diagonal = Ray::new(Vector3::new(-4., 0., -1.), Vector3::new(1., 0., -1.));
+        let t = diagonal.intersect_aabb(&aabb).unwrap();
+        assert!(close(diagonal.at(t), Vector3::new(-1., 0., -4.)));
+
+        let inside = Ray::new(Vector3::new(0., 0., -5.), Vector3::new(0., 1., 0.));
+        assert_eq!(inside.intersect_aabb(&aabb), Some(0.));
+
+        let away = Ray::new(Vector3::zeros(), Vector3::new(0., 0., 1.));
+        assert_eq!(away.intersect_aabb(&aabb), None);
+
+        let beside = Ray::new(Vector3::new(0., 2., 0.), Vector3::new(0., 0., -1.));
+        assert_eq!(beside.intersect_aabb(&aabb), None);
+    }
+
+    #[test]
+    fn from_screen_goes_through_the_pixel() {
+        let ray = Ray::from_screen(50., 50., 100, &view([0, 0, 100, 100])).unwrap();
+        assert!(close(ray.direction, Vector3::new(0., 0., -1.)));
+        assert!(close(ray.origin, Vector3::new(0., 0., -0.1)));
+
+        // A 90 degree field of view spans as much sideways as forward
+        let ray = Ray::from_screen(0., 0., 100, &view([0, 0, 100, 100])).unwrap();
+        let corner = Vector3::new(-1., 1., -1.).normalize();
+        assert!(close(ray.direction, corner));
+    }
+
+    #[test]
+    fn from_screen_measures_from_the_viewport() {
+        // The bottom right quarter of a 100 by 100 canvas
+        let viewport = [50, 0, 50, 50];
+        let ray = Ray::from_screen(75., 75., 100, &view(viewport)).unwrap();
+        assert!(close(ray.direction, Vector3::new(0., 0., -1.)));
+
+        assert_eq!(Ray::from_screen(0., 0., 100, &view([0, 0, 0, 100])), None);
+    }
+}
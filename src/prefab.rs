@@ -0,0 +1,413 @@
+//! Prefabs, reusable entity templates stored as data.
+//!
+//! A prefab is an entity in the same shape as in a scene, plus the
+//! `children` attached to it. A node can start from another prefab by name
+//! and put its own components on top, which replace the ones it brings.
+//!
+//! ```json
+//! {
+//!   "transform": { "translation": [0, 0.5, 0] },
+//!   "mesh": { "name": "Body", "vertices": [0, 0, 1, 0, 1, 1] },
+//!   "material": { "color": [0.8, 0.1, 0.1, 1] },
+//!   "children": [
+//!     { "prefab": "Wheel", "transform": { "translation": [-1, 0, 0] } },
+//!     { "prefab": "Wheel", "transform": { "translation": [1, 0, 0] } },
+//!     { "bone": "Roof", "light": { "kind": "point", "color": [1, 1, 0], "intensity": 1, "range": 5 } }
+//!   ]
+//! }
+//! ```
+//!
+//! Entities inside a prefab don't refer to each other by index, the
+//! hierarchy comes from `children` alone.
+//!
+//! Meshes and colliders are built once per prefab. Copies share the
+//! vertices of meshes until they are edited, and static meshes share their
+//! GPU buffers as well. Triangle mesh and heightfield colliders share their
+//! triangles.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use specs::{Entity, World};
+
+use crate::component_data::TransformData;
+use crate::render::library::MeshLibrary;
+use crate::scene::{
+    BuiltComponents, CameraControllerData, EntityData, ParentData, SceneData, SCENE_VERSION,
+};
+
+/// Deeper than this and a prefab most likely includes itself.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Prefab {
+    #[serde(flatten)]
+    pub entity: EntityData,
+    /// Name of a prefab to start from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefab: Option<String>,
+    /// Bone of the parent node to attach to, ignored on the root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bone: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Prefab>,
+}
+
+impl Prefab {
+    pub fn from_json(json: &str) -> Result<Prefab, String> {
+        serde_json::from_str(json).map_err(|err| format!("Invalid prefab: {}", err))
+    }
+}
+
+/// A prefab with its nested prefabs resolved and its meshes and colliders
+/// built, ready to be spawned any number of times.
+#[derive(Clone)]
+pub struct PrefabTemplate {
+    /// The root comes first, without mesh or collider data
+    scene: SceneData,
+    /// Copies of meshes share their vertices with these, copies of triangle
+    /// mesh and heightfield colliders share their triangles
+    built: Vec<BuiltComponents>,
+}
+
+impl PrefabTemplate {
+    /// The transform of the root entity, the identity if it has none.
+    pub fn root_transform(&self) -> TransformData {
+        self.scene.entities[0].transform.clone().unwrap_or_default()
+    }
+
+    /// Create the entities of the prefab, with the components in `overrides`
+    /// replacing those of the root. Returns the root.
+    pub fn spawn(
+        &self,
+        world: &mut World,
+        overrides: Option<&EntityData>,
+    ) -> Result<Entity, String> {
+        let mut built = self.built.clone();
+
+        let entities = match overrides {
+            Some(overrides) => {
+                check_node(overrides)?;
+                let root = overrides.build(&world.read_resource::<MeshLibrary>())?;
+                if let Some(transform) = root.transform {
+                    built[0].transform = Some(transform);
+                }
+                if let Some(mesh) = root.mesh {
+                    built[0].mesh = Some(mesh);
+                }
+                if let Some(collider) = root.collider {
+                    built[0].collider = Some(collider);
+                }
+
+                // Only the root is copied, the rest comes from the template
+                let mut root = self.scene.entities[0].clone();
+                apply_overrides(&mut root, overrides);
+                root.mesh = None;
+                root.collider = None;
+                self.scene.instantiate_built(world, built, Some(&root))
+            }
+            None => self.scene.instantiate_built(world, built, None),
+        };
+
+        Ok(entities[0])
+    }
+}
+
+/// Prefabs by name. Each one is resolved into a template the first time it
+/// is spawned, adding or replacing a prefab starts over with all of them.
+#[derive(Default)]
+pub struct PrefabLibrary {
+    prefabs: HashMap<String, Prefab>,
+    templates: HashMap<String, Arc<PrefabTemplate>>,
+}
+
+impl PrefabLibrary {
+    pub fn new() -> PrefabLibrary {
+        PrefabLibrary::default()
+    }
+
+    pub fn insert<S: Into<String>>(&mut self, name: S, prefab: Prefab) {
+        self.prefabs.insert(name.into(), prefab);
+        self.templates.clear();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.prefabs.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Prefab> {
+        self.templates.clear();
+        self.prefabs.remove(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.prefabs.contains_key(name)
+    }
+
+    /// The template of the prefab `name`, shared so it can be spawned from
+    /// while the library is borrowed elsewhere. Meshes named by colliders
+    /// are looked up in `meshes`.
+    pub fn template(
+        &mut self,
+        name: &str,
+        meshes: &MeshLibrary,
+    ) -> Result<Arc<PrefabTemplate>, String> {
+        if let Some(template) = self.templates.get(name) {
+            return Ok(Arc::clone(template));
+        }
+
+        let prefab = self.lookup(name)?;
+        let mut entities = vec![];
+        self.flatten(prefab, None, 0, &mut entities)?;
+
+        let built = entities
+            .iter()
+            .map(|data| data.build(meshes))
+            .collect::<Result<Vec<BuiltComponents>, String>>()
+            .map_err(|err| format!(r#"Prefab '{}': {}"#, name, err))?;
+        for data in entities.iter_mut() {
+            data.mesh = None;
+            data.collider = None;
+        }
+
+        let scene = SceneData {
+            version: SCENE_VERSION,
+            active_camera: None,
+            entities,
+            skybox: None,
+            fog: None,
+        };
+        scene.check_references()?;
+
+        let template = Arc::new(PrefabTemplate { scene, built });
+        self.templates
+            .insert(name.to_string(), Arc::clone(&template));
+        Ok(template)
+    }
+
+    fn lookup(&self, name: &str) -> Result<&Prefab, String> {
+        self.prefabs
+            .get(name)
+            .ok_or_else(|| format!(r#"There is no prefab called '{}'"#, name))
+    }
+
+    /// Append `node` and everything below it to `out`, depth first.
+    fn flatten(
+        &self,
+        node: &Prefab,
+        parent: Option<u32>,
+        depth: usize,
+        out: &mut Vec<EntityData>,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(format!(
+                "Prefabs are nested more than {} deep, one of them probably includes itself",
+                MAX_DEPTH
+            ));
+        }
+        check_node(&node.entity)?;
+
+        let index = out.len();
+        match node.prefab.as_ref() {
+            Some(name) => {
+                self.flatten(self.lookup(name)?, None, depth + 1, out)?;
+                apply_overrides(&mut out[index], &node.entity);
+            }
+            None => out.push(node.entity.clone()),
+        }
+
+        out[index].parent = parent.map(|entity| ParentData {
+            entity,
+            bone: node.bone.clone(),
+        });
+
+        for child in node.children.iter() {
+            self.flatten(child, Some(index as u32), depth + 1, out)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Prefabs can't point at entities by index, there is no scene to index.
+fn check_node(data: &EntityData) -> Result<(), String> {
+    if data.parent.is_some() {
+        return Err("Prefab entities are attached through `children`, not `parent`".to_string());
+    }
+    if let Some(CameraControllerData::Follow { .. }) = data.camera_controller {
+        return Err("A follow camera can't be part of a prefab".to_string());
+    }
+
+    Ok(())
+}
+
+/// Replace the components of `data` with those set in `overrides`.
+fn apply_overrides(data: &mut EntityData, overrides: &EntityData) {
+    fn replace<T: Clone>(component: &mut Option<T>, with: &Option<T>) {
+        if with.is_some() {
+            *component = with.clone();
+        }
+    }
+
+    replace(&mut data.transform, &overrides.transform);
+    data.interpolated |= overrides.interpolated;
+    replace(&mut data.mesh, &overrides.mesh);
+    replace(&mut data.material, &overrides.material);
+    replace(&mut data.lod_group, &overrides.lod_group);
+    replace(&mut data.light, &overrides.light);
+    replace(&mut data.camera, &overrides.camera);
+    replace(&mut data.camera_controller, &overrides.camera_controller);
+    replace(&mut data.rigid_body, &overrides.rigid_body);
+    replace(&mut data.collider, &overrides.collider);
+    replace(&mut data.ground_snap, &overrides.ground_snap);
+    replace(&mut data.particle_emitter, &overrides.particle_emitter);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(prefabs: &[(&str, &str)]) -> PrefabLibrary {
+        let mut library = PrefabLibrary::new();
+        for (name, json) in prefabs {
+            library.insert(*name, Prefab::from_json(json).unwrap());
+        }
+        library
+    }
+
+    fn flatten(library: &PrefabLibrary, name: &str) -> Result<Vec<EntityData>, String> {
+        let mut out = vec![];
+        library.flatten(library.get(name).unwrap(), None, 0, &mut out)?;
+        Ok(out)
+    }
+
+    fn translation(data: &EntityData) -> [f32; 3] {
+        data.transform.as_ref().unwrap().translation
+    }
+
+    #[test]
+    fn nested_prefabs_are_resolved_depth_first() {
+        let library = library(&[
+            (
+                "Wheel",
+                r#"{
+                    "transform": { "translation": [0, 0.5, 0] },
+                    "material": { "color": [0.1, 0.1, 0.1, 1] },
+                    "children": [{ "transform": { "translation": [0, 0, 0.1] } }]
+                }"#,
+            ),
+            (
+                "Car",
+                r#"{
+                    "children": [
+                        { "prefab": "Wheel", "transform": { "translation": [-1, 0, 0] } },
+                        { "prefab": "Wheel", "bone": "Axle" }
+                    ]
+                }"#,
+            ),
+        ]);
+
+        let entities = flatten(&library, "Car").unwrap();
+        let parents: Vec<Option<(u32, Option<&str>)>> = entities
+            .iter()
+            .map(|data| {
+                let parent = data.parent.as_ref()?;
+                Some((parent.entity, parent.bone.as_ref().map(String::as_str)))
+            })
+            .collect();
+        assert_eq!(
+            parents,
+            vec![
+                None,
+                Some((0, None)),
+                Some((1, None)),
+                Some((0, Some("Axle"))),
+                Some((3, None)),
+            ]
+        );
+
+        let wheel = library.get("Wheel").unwrap();
+        assert_eq!(translation(&entities[1]), [-1., 0., 0.]);
+        assert_eq!(entities[1].material, wheel.entity.material);
+        assert_eq!(translation(&entities[3]), [0., 0.5, 0.]);
+        assert_eq!(translation(&entities[4]), [0., 0., 0.1]);
+    }
+
+    #[test]
+    fn overrides_replace_only_what_they_set() {
+        let mut data: EntityData = serde_json::from_str(
+            r#"{
+                "transform": { "translation": [1, 2, 3] },
+                "material": { "color": [1, 0, 0, 1] },
+                "light": { "kind": "point", "color": [1, 1, 0], "intensity": 1, "range": 5 }
+            }"#,
+        )
+        .unwrap();
+        let light = data.light;
+        let overrides: EntityData = serde_json::from_str(
+            r#"{
+                "interpolated": true,
+                "material": { "color": [0, 0, 1, 1] }
+            }"#,
+        )
+        .unwrap();
+
+        apply_overrides(&mut data, &overrides);
+
+        assert_eq!(translation(&data), [1., 2., 3.]);
+        assert_eq!(data.material, overrides.material);
+        assert_eq!(data.light, light);
+        assert!(data.interpolated);
+
+        // Leaving a flag out doesn't clear it
+        apply_overrides(&mut data, &EntityData::default());
+        assert!(data.interpolated);
+    }
+
+    #[test]
+    fn prefabs_including_themselves_are_rejected() {
+        let library = library(&[
+            ("Loop", r#"{ "prefab": "Loop" }"#),
+            ("Ping", r#"{ "children": [{ "prefab": "Pong" }] }"#),
+            ("Pong", r#"{ "children": [{ "prefab": "Ping" }] }"#),
+        ]);
+
+        for name in ["Loop", "Ping"].iter() {
+            let err = flatten(&library, name).unwrap_err();
+            assert!(err.contains(&MAX_DEPTH.to_string()), "{}", err);
+        }
+    }
+
+    #[test]
+    fn unknown_prefabs_are_rejected() {
+        let library = library(&[("Car", r#"{ "children": [{ "prefab": "Wheel" }] }"#)]);
+
+        let err = flatten(&library, "Car").unwrap_err();
+        assert!(err.contains("'Wheel'"), "{}", err);
+    }
+
+    #[test]
+    fn nodes_cant_refer_to_entities_by_index() {
+        let mut library = library(&[
+            (
+                "Attached",
+                r#"{ "children": [{ "parent": { "entity": 0 } }] }"#,
+            ),
+            (
+                "Follower",
+                r#"{ "camera_controller": { "kind": "follow", "target": 0 } }"#,
+            ),
+            ("Fine", r#"{ "camera_controller": { "kind": "fly" } }"#),
+        ]);
+        let meshes = MeshLibrary::new();
+
+        assert!(library.template("Attached", &meshes).is_err());
+        assert!(library.template("Follower", &meshes).is_err());
+        assert!(library.template("Fine", &meshes).is_ok());
+
+        let follow: EntityData =
+            serde_json::from_str(r#"{ "camera_controller": { "kind": "follow", "target": 0 } }"#)
+                .unwrap();
+        assert!(check_node(&follow).is_err());
+    }
+}
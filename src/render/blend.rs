@@ -0,0 +1,46 @@
+use web_sys::WebGlRenderingContext as GL;
+
+/// How a draw is combined with what is already on screen.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    /// Covers what is behind it by its alpha
+    Alpha,
+    /// Adds its color weighted by its alpha
+    Additive,
+    /// Darkens what is behind it by its color
+    Multiply,
+    /// Like `Alpha`, for colors already multiplied by their alpha
+    Premultiplied,
+}
+
+impl BlendMode {
+    /// Enable blending with the factors of this mode. The alpha of the
+    /// canvas is left alone, so the page doesn't show through it.
+    pub fn apply(self, gl: &GL) {
+        gl.enable(GL::BLEND);
+
+        let (source, destination) = match self {
+            BlendMode::Alpha => (GL::SRC_ALPHA, GL::ONE_MINUS_SRC_ALPHA),
+            BlendMode::Additive => (GL::SRC_ALPHA, GL::ONE),
+            BlendMode::Multiply => (GL::DST_COLOR, GL::ZERO),
+            BlendMode::Premultiplied => (GL::ONE, GL::ONE_MINUS_SRC_ALPHA),
+        };
+        gl.blend_func_separate(source, destination, GL::ZERO, GL::ONE);
+    }
+
+    /// Whether the result depends on the order things are drawn in, so
+    /// they have to go back to front.
+    pub fn is_order_dependent(self) -> bool {
+        match self {
+            BlendMode::Alpha | BlendMode::Premultiplied => true,
+            BlendMode::Additive | BlendMode::Multiply => false,
+        }
+    }
+}
+
+impl Default for BlendMode {
+    fn default() -> BlendMode {
+        BlendMode::Alpha
+    }
+}
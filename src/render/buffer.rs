@@ -0,0 +1,314 @@
+use std::ops::Range;
+
+use js_sys;
+use js_sys::WebAssembly;
+use wasm_bindgen;
+use wasm_bindgen::JsCast;
+use web_sys::{WebGlBuffer, WebGlRenderingContext as GL};
+
+use crate::render::draw::Indices;
+
+/// How often the contents of a buffer are expected to change.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BufferUsage {
+    /// Uploaded once, drawn many times
+    Static,
+    /// Updated in place every now and then
+    Dynamic,
+    /// Rewritten every frame
+    Stream,
+}
+
+impl BufferUsage {
+    pub fn gl_usage(self) -> u32 {
+        match self {
+            BufferUsage::Static => GL::STATIC_DRAW,
+            BufferUsage::Dynamic => GL::DYNAMIC_DRAW,
+            BufferUsage::Stream => GL::STREAM_DRAW,
+        }
+    }
+}
+
+/// Float ranges of a vertex array that changed since the last upload.
+/// Overlapping and adjacent ranges are merged as they are marked.
+#[derive(Clone, Debug, Default)]
+pub struct DirtyRanges {
+    ranges: Vec<Range<usize>>,
+}
+
+impl DirtyRanges {
+    pub fn mark(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        let mut merged = range;
+        self.ranges.retain(|r| {
+            if r.start <= merged.end && merged.start <= r.end {
+                merged.start = merged.start.min(r.start);
+                merged.end = merged.end.max(r.end);
+                false
+            } else {
+                true
+            }
+        });
+
+        let idx = self
+            .ranges
+            .iter()
+            .position(|r| r.start > merged.start)
+            .unwrap_or(self.ranges.len());
+        self.ranges.insert(idx, merged);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    pub fn take(&mut self) -> Vec<Range<usize>> {
+        std::mem::replace(&mut self.ranges, Vec::new())
+    }
+}
+
+/// A view on `data` straight into the wasm memory, without copying.
+/// The view is invalidated as soon as the memory grows, so use it right away.
+pub fn f32_view(data: &[f32]) -> js_sys::Float32Array {
+    let memory_buffer = wasm_bindgen::memory()
+        .dyn_into::<WebAssembly::Memory>()
+        .unwrap()
+        .buffer();
+
+    let data_location = data.as_ptr() as u32 / 4;
+
+    js_sys::Float32Array::new(&memory_buffer)
+        .subarray(data_location, data_location + data.len() as u32)
+}
+
+pub fn u16_view(data: &[u16]) -> js_sys::Uint16Array {
+    let memory_buffer = wasm_bindgen::memory()
+        .dyn_into::<WebAssembly::Memory>()
+        .unwrap()
+        .buffer();
+
+    let data_location = data.as_ptr() as u32 / 2;
+
+    js_sys::Uint16Array::new(&memory_buffer)
+        .subarray(data_location, data_location + data.len() as u32)
+}
+
+pub fn u8_view(data: &[u8]) -> js_sys::Uint8Array {
+    let memory_buffer = wasm_bindgen::memory()
+        .dyn_into::<WebAssembly::Memory>()
+        .unwrap()
+        .buffer();
+
+    let data_location = data.as_ptr() as u32;
+
+    js_sys::Uint8Array::new(&memory_buffer)
+        .subarray(data_location, data_location + data.len() as u32)
+}
+
+/// Create a new array buffer filled with `data`. The buffer is left bound.
+pub fn create_f32_buffer(gl: &GL, data: &[f32], usage: BufferUsage) -> WebGlBuffer {
+    let buffer = gl.create_buffer().unwrap();
+
+    gl.bind_buffer(GL::ARRAY_BUFFER, Some(&buffer));
+    gl.buffer_data_with_array_buffer_view(GL::ARRAY_BUFFER, &f32_view(data), usage.gl_usage());
+
+    buffer
+}
+
+/// Create a new array buffer filled with already packed vertex bytes.
+/// The buffer is left bound.
+pub fn create_u8_buffer(gl: &GL, data: &[u8], usage: BufferUsage) -> WebGlBuffer {
+    let buffer = gl.create_buffer().unwrap();
+
+    gl.bind_buffer(GL::ARRAY_BUFFER, Some(&buffer));
+    gl.buffer_data_with_array_buffer_view(GL::ARRAY_BUFFER, &u8_view(data), usage.gl_usage());
+
+    buffer
+}
+
+/// Replace the whole contents of `buffer`, possibly changing its size.
+pub fn respecify_u8_buffer(gl: &GL, buffer: &WebGlBuffer, data: &[u8], usage: BufferUsage) {
+    gl.bind_buffer(GL::ARRAY_BUFFER, Some(buffer));
+    gl.buffer_data_with_array_buffer_view(GL::ARRAY_BUFFER, &u8_view(data), usage.gl_usage());
+}
+
+/// Overwrite part of `buffer` in place, `byte_offset` is counted in bytes.
+pub fn update_u8_buffer(gl: &GL, buffer: &WebGlBuffer, byte_offset: usize, data: &[u8]) {
+    gl.bind_buffer(GL::ARRAY_BUFFER, Some(buffer));
+    gl.buffer_sub_data_with_i32_and_array_buffer_view(
+        GL::ARRAY_BUFFER,
+        byte_offset as i32,
+        &u8_view(data),
+    );
+}
+
+/// Create a new element array buffer. The buffer is left bound, which also
+/// attaches it to the currently bound VAO.
+pub fn create_u16_index_buffer(gl: &GL, indices: &[u16], usage: BufferUsage) -> WebGlBuffer {
+    let index_buffer = gl.create_buffer().unwrap();
+
+    gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(&index_buffer));
+    gl.buffer_data_with_array_buffer_view(
+        GL::ELEMENT_ARRAY_BUFFER,
+        &u16_view(indices),
+        usage.gl_usage(),
+    );
+
+    index_buffer
+}
+
+/// Create an element array buffer for indices of any width. The buffer is
+/// left bound, which also attaches it to the currently bound VAO.
+pub fn create_index_buffer(gl: &GL, indices: &Indices, usage: BufferUsage) -> WebGlBuffer {
+    let index_buffer = gl.create_buffer().unwrap();
+    respecify_index_buffer(gl, &index_buffer, indices, usage);
+
+    index_buffer
+}
+
+/// Replace the indices of `buffer`. Make sure the VAO owning it is bound.
+pub fn respecify_index_buffer(gl: &GL, buffer: &WebGlBuffer, indices: &Indices, usage: BufferUsage) {
+    let data = indices.to_bytes();
+
+    gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(buffer));
+    gl.buffer_data_with_array_buffer_view(
+        GL::ELEMENT_ARRAY_BUFFER,
+        &u8_view(&data[..]),
+        usage.gl_usage(),
+    );
+}
+
+/// Where a chunk of streamed data ended up inside a `StreamBuffer`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamSlice {
+    /// Offset in bytes, ready for `vertex_attrib_pointer`
+    pub byte_offset: i32,
+    /// Number of floats written
+    pub len: usize,
+}
+
+/// A ring buffer for geometry that is regenerated every frame, such as debug
+/// lines or particles.
+///
+/// Data is appended behind what was written before. Once the end of the
+/// buffer is reached it is orphaned and writing starts over at the front, so
+/// we never overwrite data the GPU might still be reading.
+pub struct StreamBuffer {
+    buffer: WebGlBuffer,
+    capacity: usize,
+    head: usize,
+}
+
+impl StreamBuffer {
+    /// Allocate a ring buffer holding `capacity` floats.
+    pub fn new(gl: &GL, capacity: usize) -> StreamBuffer {
+        let buffer = gl.create_buffer().unwrap();
+
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&buffer));
+        gl.buffer_data_with_i32(GL::ARRAY_BUFFER, (capacity * 4) as i32, GL::STREAM_DRAW);
+
+        StreamBuffer {
+            buffer,
+            capacity,
+            head: 0,
+        }
+    }
+
+    pub fn buffer(&self) -> &WebGlBuffer {
+        &self.buffer
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Append `data` to the buffer, leaving it bound to `ARRAY_BUFFER`.
+    /// Returns `None` when `data` is larger than the whole buffer.
+    pub fn push(&mut self, gl: &GL, data: &[f32]) -> Option<StreamSlice> {
+        if data.len() > self.capacity {
+            return None;
+        }
+
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&self.buffer));
+
+        if self.head + data.len() > self.capacity {
+            gl.buffer_data_with_i32(
+                GL::ARRAY_BUFFER,
+                (self.capacity * 4) as i32,
+                GL::STREAM_DRAW,
+            );
+            self.head = 0;
+        }
+
+        gl.buffer_sub_data_with_i32_and_array_buffer_view(
+            GL::ARRAY_BUFFER,
+            (self.head * 4) as i32,
+            &f32_view(data),
+        );
+
+        let slice = StreamSlice {
+            byte_offset: (self.head * 4) as i32,
+            len: data.len(),
+        };
+        self.head += data.len();
+
+        Some(slice)
+    }
+
+    pub fn delete(self, gl: &GL) {
+        gl.delete_buffer(Some(&self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_ranges_merge_overlapping_and_adjacent() {
+        let mut dirty = DirtyRanges::default();
+        dirty.mark(10..20);
+        dirty.mark(0..4);
+        dirty.mark(15..30);
+        dirty.mark(4..6);
+
+        assert_eq!(dirty.take(), vec![0..6, 10..30]);
+        assert!(dirty.is_empty());
+    }
+
+    #[test]
+    fn dirty_ranges_swallow_ranges_inside_a_marked_one() {
+        let mut dirty = DirtyRanges::default();
+        dirty.mark(2..3);
+        dirty.mark(8..9);
+        dirty.mark(0..10);
+
+        assert_eq!(dirty.take(), vec![0..10]);
+    }
+
+    #[test]
+    fn dirty_ranges_ignore_empty_ranges() {
+        let mut dirty = DirtyRanges::default();
+        dirty.mark(5..5);
+        dirty.mark(7..3);
+
+        assert!(dirty.is_empty());
+    }
+
+    #[test]
+    fn dirty_ranges_stay_sorted() {
+        let mut dirty = DirtyRanges::default();
+        dirty.mark(40..50);
+        dirty.mark(20..30);
+        dirty.mark(0..10);
+
+        assert_eq!(dirty.take(), vec![0..10, 20..30, 40..50]);
+    }
+}
@@ -1,70 +1,716 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::Arc;
 
 use js_sys;
-use js_sys::WebAssembly;
-use nalgebra::{Isometry3, Vector3};
-use wasm_bindgen;
-use wasm_bindgen::JsCast;
+use nalgebra::{Isometry3, Matrix4, Point3, Vector3};
 
 use chal_engine::render::{Render, Vao, VaoExtension};
 use chal_engine::shader::{Shader, ShaderKind, ShaderSystem};
 use chal_engine::state::State;
 use js_sys::Reflect;
-use specs::Entities;
+use specs::{Entities, Entity};
 use specs::{Component, VecStorage};
-use specs::{Read, ReadStorage, System};
-use web_sys::WebGlRenderingContext as GL;
+use specs::{Read, ReadStorage, System, Write, WriteStorage};
+use wasm_bindgen::prelude::*;
+use web_sys::{WebGlBuffer, WebGlRenderingContext as GL, WebGlTexture};
 
+use crate::bounds::{Bounds, Frustum, LocalBounds};
+use crate::camera::{ActiveCamera, Camera, CameraView};
+use crate::canvas::{is_webgl2, vertex_array_objects};
+use crate::debug_draw::DebugDraw;
 use crate::engine::{GLC, GameState};
+use crate::light::Light;
+use crate::particles::ParticleEmitter;
+use crate::render::buffer::{self, BufferUsage, DirtyRanges};
+use crate::render::buffer::StreamBuffer;
+use crate::render::debug_lines::DebugLineRenderer;
+use crate::render::draw::{DrawParams, Indices, Topology};
+use crate::render::fog::Fog;
+use crate::render::id_buffer::{id_color, IdBuffer};
+use crate::render::instancing::{
+    InstanceAttributes, InstanceBatch, InstanceMaterial, InstancedArrays, INSTANCE_ATTRIBUTES,
+    INSTANCE_FLOATS,
+};
+use crate::render::layout::VertexLayout;
+use crate::render::library::MeshLibrary;
+use crate::render::lod::LodGroup;
+use crate::render::material::{Material, PbrMaterial};
+use crate::render::particles::ParticleRenderer;
+use crate::render::pbr::{self, PbrTextures};
+use crate::render::queue::{BatchKey, DrawState, Instance, RenderQueue};
+use crate::render::registry::{Geometry, MeshHandle, MeshKey, MeshRegistry};
+use crate::render::skybox::{SkyRenderer, Skybox, ENVIRONMENT_LEVELS};
+use crate::render::texture::{TextureCache, TextureLibrary};
 use crate::shader::{WebShader, WebShaderSystem};
+use crate::transform::GlobalTransform;
+
+/// How many instances fit in the instance stream buffer at once. Larger
+/// batches are split into several draws.
+const MAX_INSTANCES: usize = 1024;
+
+/// Attributes the mesh shader reads when a mesh has them, and does without
+/// otherwise.
+const OPTIONAL_ATTRIBUTES: &[&str] = &["a_normal", "a_uv", "a_tangent"];
 
 #[derive(Component)]
 #[storage(VecStorage)]
 pub struct Mesh {
-    name: &'static str,
-    pub vertices: Vec<f32>,
+    name: String,
+    /// Shared between copies until one of them is edited
+    vertices: Arc<Vec<f32>>,
+    layout: VertexLayout,
+    indices: Option<Indices>,
+    topology: Topology,
+    key: MeshKey,
     shader_kind: ShaderKind,
+    usage: BufferUsage,
+    dirty: DirtyRanges,
+    resized: bool,
+    indices_dirty: bool,
+    bounds: Option<LocalBounds>,
+    /// The vertices changed since the bounds were fitted
+    bounds_stale: bool,
     // shader: WebShader
 }
 
+/// Copies share their vertices with the original until either is edited,
+/// static copies share their GPU buffers as well. A copy of a dynamic mesh
+/// gets buffers of its own, so editing one doesn't change the other.
+impl Clone for Mesh {
+    fn clone(&self) -> Mesh {
+        let key = if self.is_dynamic() {
+            MeshKey::unique()
+        } else {
+            self.key
+        };
+
+        Mesh {
+            name: self.name.clone(),
+            vertices: Arc::clone(&self.vertices),
+            layout: self.layout.clone(),
+            indices: self.indices.clone(),
+            topology: self.topology,
+            key,
+            shader_kind: self.shader_kind,
+            usage: self.usage,
+            dirty: DirtyRanges::default(),
+            resized: false,
+            indices_dirty: false,
+            bounds: self.bounds,
+            bounds_stale: self.bounds_stale,
+        }
+    }
+}
+
+/// Pending changes of a dynamic mesh that still have to reach the GPU.
+enum MeshUpdate {
+    None,
+    Full,
+    Ranges(Vec<Range<usize>>),
+}
+
 impl Mesh {
-    pub fn new(name: &'static str, vertices: Vec<f32>, shader_kind: ShaderKind) -> Mesh {
+    pub fn new<S: Into<String>>(name: S, vertices: Vec<f32>, shader_kind: ShaderKind) -> Mesh {
+        let layout = VertexLayout::position_2d();
+        let key = MeshKey::geometry(&vertices[..], &layout, None);
+
+        let bounds = LocalBounds::from_points(&layout.positions(&vertices[..], "a_position"));
+
         Mesh {
-            name, vertices, shader_kind
+            name: name.into(),
+            vertices: Arc::new(vertices),
+            layout,
+            indices: None,
+            topology: Topology::default(),
+            key,
+            shader_kind,
+            usage: BufferUsage::Static,
+            dirty: DirtyRanges::default(),
+            resized: false,
+            indices_dirty: false,
+            bounds,
+            bounds_stale: false,
+        }
+    }
+
+    /// A mesh whose vertices are edited over time. Its buffers are never
+    /// shared with other entities and changes are written in place.
+    pub fn dynamic<S: Into<String>>(
+        name: S,
+        vertices: Vec<f32>,
+        shader_kind: ShaderKind,
+    ) -> Mesh {
+        Mesh {
+            key: MeshKey::unique(),
+            usage: BufferUsage::Dynamic,
+            ..Mesh::new(name, vertices, shader_kind)
+        }
+    }
+
+    /// Describe how `vertices` are interleaved. Defaults to a bare 2D
+    /// position.
+    pub fn with_layout(mut self, layout: VertexLayout) -> Mesh {
+        self.layout = layout;
+        self.resized = self.is_dynamic();
+        self.rekey();
+        self.bounds_stale = true;
+        self.refresh_bounds();
+        self
+    }
+
+    /// Draw the vertices through an index buffer instead of in order.
+    pub fn with_indices(mut self, indices: Indices) -> Mesh {
+        self.set_indices(Some(indices));
+        self
+    }
+
+    /// How vertices are assembled, triangles unless specified.
+    pub fn with_topology(mut self, topology: Topology) -> Mesh {
+        self.topology = topology;
+        self
+    }
+
+    pub fn indices(&self) -> Option<&Indices> {
+        self.indices.as_ref()
+    }
+
+    pub fn set_indices(&mut self, indices: Option<Indices>) {
+        self.indices = indices;
+        self.indices_dirty = self.is_dynamic();
+        self.rekey();
+    }
+
+    pub fn topology(&self) -> Topology {
+        self.topology
+    }
+
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.layout.vertex_count(&self.vertices[..])
+    }
+
+    pub fn index_count(&self) -> usize {
+        self.indices.as_ref().map(|indices| indices.len()).unwrap_or(0)
+    }
+
+    pub fn draw_params(&self) -> DrawParams {
+        DrawParams {
+            topology: self.topology,
+            vertex_count: self.vertex_count(),
+            index_count: self.index_count(),
+            index_type: self.indices.as_ref().map(|indices| indices.index_type()),
+        }
+    }
+
+    pub fn layout(&self) -> &VertexLayout {
+        &self.layout
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.usage != BufferUsage::Static
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn vertices(&self) -> &[f32] {
+        &self.vertices[..]
+    }
+
+    pub fn key(&self) -> MeshKey {
+        self.key
+    }
+
+    /// Replace the geometry. A static mesh is uploaded again (or shares an
+    /// existing upload with identical data) the next time it is rendered, a
+    /// dynamic mesh rewrites its own buffer.
+    pub fn set_vertices(&mut self, vertices: Vec<f32>) {
+        if self.is_dynamic() {
+            if vertices.len() == self.vertices.len() {
+                self.dirty.mark(0..vertices.len());
+            } else {
+                self.resized = true;
+            }
         }
+
+        self.vertices = Arc::new(vertices);
+        self.rekey();
+        self.bounds_stale = true;
     }
+
+    /// Overwrite the vertices starting at float `offset`, growing the mesh
+    /// when writing past its end.
+    pub fn update_vertices(&mut self, offset: usize, data: &[f32]) {
+        let end = offset + data.len();
+        let dynamic = self.is_dynamic();
+        let vertices = Arc::make_mut(&mut self.vertices);
+        if end > vertices.len() {
+            vertices.resize(end, 0.);
+            self.resized = dynamic;
+        }
+        vertices[offset..end].copy_from_slice(data);
+
+        if self.is_dynamic() {
+            self.dirty.mark(offset..end);
+        }
+        self.rekey();
+        self.bounds_stale = true;
+    }
+
+    /// Direct access to the vertices. Call `mark_dirty` with the ranges that
+    /// were touched so they get uploaded.
+    pub fn vertices_mut(&mut self) -> &mut [f32] {
+        &mut Arc::make_mut(&mut self.vertices)[..]
+    }
+
+    /// A dynamic mesh uploads `range` again, a static mesh is keyed anew and
+    /// uploaded as a whole.
+    pub fn mark_dirty(&mut self, range: Range<usize>) {
+        if self.is_dynamic() {
+            let end = range.end.min(self.vertices.len());
+            self.dirty.mark(range.start..end);
+        }
+        self.rekey();
+        self.bounds_stale = true;
+    }
+
+    /// Bounding volumes around the `a_position` attribute, `None` when the
+    /// mesh has no positions. Edits show up after the next `refresh_bounds`,
+    /// which the `BoundsSystem` does every frame.
+    pub fn local_bounds(&self) -> Option<&LocalBounds> {
+        self.bounds.as_ref()
+    }
+
+    /// Fit the bounds to the vertices again if they were edited since the
+    /// last time.
+    pub fn refresh_bounds(&mut self) {
+        if self.bounds_stale {
+            self.bounds_stale = false;
+            let positions = self.layout.positions(&self.vertices[..], "a_position");
+            self.bounds = LocalBounds::from_points(&positions[..]);
+        }
+    }
+
+    /// The triangles of the mesh in its own space, for ray casts. Point and
+    /// line meshes have none.
+    pub fn triangles(&self) -> Vec<[Vector3<f32>; 3]> {
+        let positions = self.layout.positions(&self.vertices[..], "a_position");
+        let vertex = |idx: usize| positions.get(idx).cloned();
+
+        self.triangle_indices()
+            .into_iter()
+            .filter_map(|[a, b, c]| Some([vertex(a)?, vertex(b)?, vertex(c)?]))
+            .collect()
+    }
+
+    /// The vertices of every triangle, by index, whatever the topology.
+    pub fn triangle_indices(&self) -> Vec<[usize; 3]> {
+        let indices = self.indices.as_ref();
+        let count = indices
+            .map(|indices| indices.len())
+            .unwrap_or_else(|| self.vertex_count());
+
+        let vertex = |idx: usize| match indices {
+            Some(indices) => indices.get(idx),
+            None => Some(idx),
+        };
+
+        let corners: Vec<[usize; 3]> = match self.topology {
+            Topology::Triangles => (0..count / 3).map(|t| [t * 3, t * 3 + 1, t * 3 + 2]).collect(),
+            // Every other triangle of a strip is wound the other way around
+            Topology::TriangleStrip => (0..count.saturating_sub(2))
+                .map(|t| if t % 2 == 0 { [t, t + 1, t + 2] } else { [t + 1, t, t + 2] })
+                .collect(),
+            Topology::TriangleFan => (1..count.saturating_sub(1)).map(|t| [0, t, t + 1]).collect(),
+            _ => Vec::new(),
+        };
+
+        corners
+            .into_iter()
+            .filter_map(|[a, b, c]| Some([vertex(a)?, vertex(b)?, vertex(c)?]))
+            .collect()
+    }
+
+    /// What a shared upload of the mesh is made from, `None` for dynamic
+    /// meshes which are never shared.
+    fn geometry(&self) -> Option<Geometry> {
+        if self.is_dynamic() {
+            return None;
+        }
+
+        Some(Geometry::new(
+            Arc::clone(&self.vertices),
+            self.layout.clone(),
+            self.indices.clone(),
+        ))
+    }
+
+    fn is_geometry(&self, geometry: &Geometry) -> bool {
+        geometry.matches(&self.vertices, &self.layout, self.indices.as_ref())
+    }
+
+    /// Static meshes are keyed by their contents, dynamic ones keep the
+    /// unique key they were created with.
+    fn rekey(&mut self) {
+        if !self.is_dynamic() {
+            self.key = MeshKey::geometry(&self.vertices[..], &self.layout, self.indices.as_ref());
+        }
+    }
+
+    fn take_update(&mut self) -> MeshUpdate {
+        if self.resized {
+            self.resized = false;
+            self.dirty.clear();
+            return MeshUpdate::Full;
+        }
+
+        if self.dirty.is_empty() {
+            MeshUpdate::None
+        } else {
+            MeshUpdate::Ranges(self.dirty.take())
+        }
+    }
+
+    /// Upload the vertex data into the currently bound VAO, returning the
+    /// buffers that were created so they can be freed later.
+    fn upload(&self, gl: &GL, shader: &WebShader) -> Vec<WebGlBuffer> {
+        let external: Vec<&str> = INSTANCE_ATTRIBUTES
+            .iter()
+            .chain(OPTIONAL_ATTRIBUTES)
+            .cloned()
+            .collect();
+        let layout = self.layout.for_context(gl);
+        if let Err(err) = layout.validate(gl, &shader.program, &external) {
+            log!("Mesh '{}': {}", self.name, err);
+        }
+
+        let data = layout.pack(&self.vertices[..]);
+        let mut buffers = vec![buffer::create_u8_buffer(gl, &data[..], self.usage)];
+        layout.bind(gl, &shader.program);
+
+        if let Some(indices) = self.indices.as_ref() {
+            buffers.push(buffer::create_index_buffer(gl, indices, self.usage));
+        }
+
+        buffers
+    }
+}
+
+/// What the render system did during the last frame.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// Entities that passed frustum culling
+    pub drawn: u32,
+    /// Entities skipped because they were outside the camera frustum
+    pub culled: u32,
+    /// LOD groups in view but too small on screen for any of their levels
+    pub lod_hidden: u32,
+    pub draw_calls: u32,
 }
 
 pub struct RenderSystem {
     vao_ext: VaoExtension<js_sys::Object>,
     shader_sys: WebShaderSystem,
+    registry: MeshRegistry,
+    entity_meshes: HashMap<Entity, MeshHandle>,
+    library_meshes: HashMap<String, MeshHandle>,
+    instancing: Option<InstancedArrays>,
+    instance_buffer: StreamBuffer,
+    /// Created on the first GPU pick
+    id_buffer: Option<IdBuffer>,
+    /// Created when there are particles to draw for the first time
+    particles: Option<ParticleRenderer>,
+    /// Created when the sky is more than a color or something reflects it
+    /// for the first time
+    sky: Option<SkyRenderer>,
+    /// Created when there are debug lines to draw for the first time
+    debug_lines: Option<DebugLineRenderer>,
+    /// Shared by every lit material, which are drawn unlit without them
+    pbr: Option<PbrTextures>,
+    textures: TextureCache,
+    last_frame: Option<Frame>,
+}
+
+/// What was drawn in the last frame, kept around to draw it again into the
+/// ID buffer when picking.
+struct Frame {
+    batches: Vec<(BatchKey, InstanceBatch)>,
+    view: [f32; 16],
+    projection: [f32; 16],
+    viewport: [i32; 4],
 }
 
 impl<'a> System<'a> for RenderSystem {
     type SystemData = (
         Read<'a, GameState>,
+        Read<'a, ActiveCamera>,
+        ReadStorage<'a, Camera>,
         Entities<'a>,
-        ReadStorage<'a, Mesh>
+        WriteStorage<'a, Mesh>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, Material>,
+        ReadStorage<'a, Bounds>,
+        ReadStorage<'a, LodGroup>,
+        Read<'a, MeshLibrary>,
+        ReadStorage<'a, ParticleEmitter>,
+        Read<'a, TextureLibrary>,
+        Read<'a, Skybox>,
+        Read<'a, Fog>,
+        ReadStorage<'a, Light>,
+        Read<'a, DebugDraw>,
+        Write<'a, RenderStats>,
     );
 
-    fn run(&mut self, (state, entity, mesh): Self::SystemData) {
+    fn run(&mut self, data: Self::SystemData) {
         use specs::Join;
+        let (
+            state,
+            active_camera,
+            camera,
+            entities,
+            mut mesh,
+            transform,
+            material,
+            bounds,
+            lod_group,
+            library,
+            emitters,
+            texture_library,
+            skybox,
+            fog,
+            light,
+            debug_draw,
+            mut stats,
+        ) = data;
         let gl = &GLC.contexts.borrow()[0];
         let state = &state.0;
-        state.camera().view();
 
-        gl.clear_color(0.53, 0.8, 0.98, 1.0);
-        gl.clear(GL::COLOR_BUFFER_BIT | GL::DEPTH_BUFFER_BIT);
+        let canvas_size = (gl.drawing_buffer_width(), gl.drawing_buffer_height());
+        let camera_view = CameraView::active(&active_camera, &camera, &transform, canvas_size)
+            .unwrap_or_else(|| CameraView {
+                view: Matrix4::from_column_slice(&state.camera().view()[..]),
+                projection: Matrix4::from_column_slice(&state.camera().projection()[..]),
+                eye: state.camera().get_eye_pos(),
+                viewport: [0, 0, canvas_size.0, canvas_size.1],
+            });
+
+        let mut view = [0.; 16];
+        view.copy_from_slice(camera_view.view.as_slice());
+        let mut projection = [0.; 16];
+        projection.copy_from_slice(camera_view.projection.as_slice());
 
-        gl.viewport(0, 0, 500, 500);
+        let frustum = Frustum::from_matrix(&camera_view.view_projection());
+        *stats = RenderStats::default();
 
-        for (pos, mesh) in (&entity, &mesh).join() {
-            let shader = self.shader_sys.get_shader(&mesh.shader_kind()).unwrap();
+        let [x, y, width, height] = camera_view.viewport;
+        gl.viewport(x, y, width, height);
+
+        let [r, g, b] = skybox.clear_color();
+        gl.clear_color(r, g, b, 1.0);
+        gl.clear(GL::COLOR_BUFFER_BIT | GL::DEPTH_BUFFER_BIT);
+
+        let mut alive = HashSet::new();
+        let mut reflective = false;
+        let mut lit = false;
+        let mut queue = RenderQueue::new(camera_view.eye);
+        // The meshes of a `LodGroup` take the place of the entity's own
+        for (entity, mesh, transform, material, bounds, _) in (
+            &entities,
+            &mut mesh,
+            transform.maybe(),
+            material.maybe(),
+            bounds.maybe(),
+            !&lod_group,
+        )
+            .join()
+        {
             self.shader_sys.use_program(gl, mesh.shader_kind());
-            self.prepare_for_render(mesh, gl, shader);
-            mesh.render(gl);
+            let handle = self.prepare_for_render(entity, mesh, gl);
+            alive.insert(entity);
+
+            // Entities without bounds yet are always drawn
+            if let Some(bounds) = bounds {
+                if !frustum.intersects(bounds) {
+                    stats.culled += 1;
+                    continue;
+                }
+            }
+            stats.drawn += 1;
+
+            if mesh.is_dynamic() {
+                let gpu_mesh = self.registry.get(handle).expect("Registered mesh");
+                self.bind_vao(&gpu_mesh.vao);
+                self.sync_dynamic(gl, handle, mesh);
+            }
+
+            let model = transform.map(|t| t.0).unwrap_or_else(Matrix4::identity);
+            let instance_material = InstanceMaterial::of(material);
+            reflective |= instance_material.reflectivity > 0.;
+            let material_key = material
+                .and_then(|material| material.pbr.as_ref())
+                .map(|pbr| queue.material_key(pbr));
+            lit |= material_key.is_some();
+
+            let key = BatchKey {
+                mesh: handle,
+                shader_kind: mesh.shader_kind(),
+                topology: mesh.topology(),
+                state: DrawState::of(material),
+                material: material_key,
+            };
+            let instance = Instance {
+                entity,
+                model,
+                material: instance_material,
+                fade: 1.,
+                center: bounds.map(|bounds| bounds.sphere.center),
+            };
+            queue.push(key, mesh.draw_params(), instance);
+        }
+
+        let view_matrix = camera_view.view;
+        let focal = projection[5];
+        for (entity, lod_group, transform, material) in (
+            &entities,
+            &lod_group,
+            transform.maybe(),
+            material.maybe(),
+        )
+            .join()
+        {
+            let levels = lod_group.levels();
+            let base = match levels.first().and_then(|level| library.get(&level.mesh)) {
+                Some(base) => base,
+                None => continue,
+            };
+
+            let model = transform.map(|t| t.0).unwrap_or_else(Matrix4::identity);
+            let instance_material = InstanceMaterial::of(material);
+            reflective |= instance_material.reflectivity > 0.;
+            let material_key = material
+                .and_then(|material| material.pbr.as_ref())
+                .map(|pbr| queue.material_key(pbr));
+            lit |= material_key.is_some();
+
+            // The most detailed level decides visibility and screen size,
+            // without bounds it is always drawn in full detail
+            let (screen_size, center) = match base.local_bounds() {
+                Some(local) => {
+                    let sphere = local.sphere.transformed(&model);
+                    if !frustum.intersects_sphere(&sphere) {
+                        stats.culled += 1;
+                        continue;
+                    }
+
+                    let in_view =
+                        view_matrix.transform_point(&Point3::from_coordinates(sphere.center));
+                    let screen_size =
+                        LodGroup::screen_size(sphere.radius, in_view.coords.norm(), focal);
+                    (screen_size, Some(sphere.center))
+                }
+                None => (std::f32::INFINITY, None),
+            };
+
+            let selected = lod_group.select(screen_size);
+            if selected.is_empty() {
+                stats.lod_hidden += 1;
+                continue;
+            }
+
+            for (level, fade) in selected {
+                let mesh = match library.get(&levels[level].mesh) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                let handle = self.prepare_library_mesh(mesh, gl);
+
+                let key = BatchKey {
+                    mesh: handle,
+                    shader_kind: mesh.shader_kind(),
+                    topology: mesh.topology(),
+                    state: DrawState::of(material),
+                    material: material_key,
+                };
+                let instance = Instance {
+                    entity,
+                    model,
+                    material: instance_material,
+                    fade,
+                    center,
+                };
+                queue.push(key, mesh.draw_params(), instance);
+            }
+            stats.drawn += 1;
         }
+
+        let environment = self.prepare_environment(
+            gl,
+            &skybox,
+            &texture_library,
+            reflective || lit,
+            camera_view.eye,
+        );
+        // A sky of one color is cheaper to fade to than the environment
+        let sky_color = if environment.is_some() && !skybox.is_color() {
+            None
+        } else {
+            Some(skybox.clear_color())
+        };
+        self.set_fog(gl, &fog, sky_color);
+
+        let lights: Vec<(Light, Matrix4<f32>)> = (&light, transform.maybe())
+            .join()
+            .map(|(light, transform)| {
+                let model = transform.map(|t| t.0).unwrap_or_else(Matrix4::identity);
+                (*light, model)
+            })
+            .take(pbr::MAX_LIGHTS)
+            .collect();
+        self.set_lighting(gl, &lights[..]);
+
+        // The sky goes behind the opaque geometry, blended geometry is
+        // drawn over it
+        let materials = queue.materials().to_vec();
+        let (opaque, transparent) = queue.into_batches();
+        for (key, batch) in opaque.iter() {
+            self.bind_material(gl, key, &materials[..], &texture_library);
+            stats.draw_calls +=
+                self.draw_batch(gl, key, batch, &mut view[..], &mut projection[..], false);
+        }
+        DrawState::default().apply(gl);
+        stats.draw_calls +=
+            self.draw_sky(gl, &skybox, &texture_library, &view[..], &projection[..]);
+        for (key, batch) in transparent.iter() {
+            self.bind_material(gl, key, &materials[..], &texture_library);
+            stats.draw_calls +=
+                self.draw_batch(gl, key, batch, &mut view[..], &mut projection[..], false);
+        }
+        DrawState::default().apply(gl);
+        stats.draw_calls += self.draw_particles(
+            gl,
+            &emitters,
+            &texture_library,
+            &mut view[..],
+            &mut projection[..],
+        );
+        stats.draw_calls +=
+            self.draw_debug_lines(gl, &debug_draw, &mut view[..], &mut projection[..]);
+
+        self.release_unused(gl, &alive, &library);
+
+        self.last_frame = Some(Frame {
+            batches: opaque.into_iter().chain(transparent).collect(),
+            view,
+            projection,
+            viewport: camera_view.viewport,
+        });
     }
 }
 
@@ -73,41 +719,521 @@ impl RenderSystem {
         let gl = &GLC.contexts.borrow()[0];
         let shader_sys = WebShaderSystem::new(&gl);
 
-        let oes_vao_ext = gl
-            .get_extension("OES_vertex_array_object")
-            .expect("EOS ext")
-            .expect("EOS ext");
+        let oes_vao_ext = vertex_array_objects(gl).expect("Vertex array objects");
+
+        // Enables `Indices::U32`, WebGL1 only supports 8 and 16 bit indices
+        // without it. WebGL2 always has them.
+        let index_uint = gl.get_extension("OES_element_index_uint").ok().and_then(|ext| ext);
+        if !is_webgl2(gl) && index_uint.is_none() {
+            log!("OES_element_index_uint is not supported, 32 bit indices won't render");
+        }
 
         let vao_ext = VaoExtension {
             oes_vao_ext,
             vaos: RefCell::new(HashMap::new()),
         };
 
+        let instancing = InstancedArrays::new(gl);
+        if instancing.is_none() {
+            log!("Instanced arrays are not supported, drawing every entity separately");
+        }
+        let instance_buffer = StreamBuffer::new(gl, MAX_INSTANCES * INSTANCE_FLOATS);
+        let pbr = match PbrTextures::new(gl) {
+            Ok(pbr) => Some(pbr),
+            Err(err) => {
+                log!("Could not create the PBR textures, drawn unlit: {:?}", err);
+                None
+            }
+        };
+
         RenderSystem {
             shader_sys,
             vao_ext,
+            registry: MeshRegistry::new(),
+            entity_meshes: HashMap::new(),
+            library_meshes: HashMap::new(),
+            instancing,
+            instance_buffer,
+            id_buffer: None,
+            particles: None,
+            sky: None,
+            debug_lines: None,
+            pbr,
+            textures: TextureCache::new(),
+            last_frame: None,
+        }
+    }
+
+    /// Draw the last frame again into the ID buffer and read back which
+    /// entity covers drawing buffer pixel (`x`, `y`), measured from the top
+    /// left. The entity is the one drawn then, which may have been deleted
+    /// since.
+    pub fn pick(&mut self, x: i32, y: i32) -> Option<Entity> {
+        let gl = &GLC.contexts.borrow()[0];
+        let canvas_size = (gl.drawing_buffer_width(), gl.drawing_buffer_height());
+        let y = canvas_size.1 - 1 - y;
+
+        if self.id_buffer.is_none() {
+            let mesh_shader = self
+                .shader_sys
+                .get_shader(&ShaderKind::NonSkinnedMesh)
+                .unwrap();
+            match IdBuffer::new(gl, &mesh_shader.program) {
+                Ok(id_buffer) => self.id_buffer = Some(id_buffer),
+                Err(err) => {
+                    log!("Could not create the ID buffer: {:?}", err);
+                    return None;
+                }
+            }
         }
+
+        let mut frame = self.last_frame.take()?;
+        let id_buffer = self.id_buffer.as_mut().unwrap();
+        id_buffer.bind(gl, canvas_size);
+
+        // Only the pixel under the cursor matters
+        let [vx, vy, width, height] = frame.viewport;
+        gl.viewport(vx, vy, width, height);
+        gl.enable(GL::SCISSOR_TEST);
+        gl.scissor(x, y, 1, 1);
+        gl.clear_color(0., 0., 0., 0.);
+        gl.clear(GL::COLOR_BUFFER_BIT | GL::DEPTH_BUFFER_BIT);
+
+        // Ids are colors, they can't be blended
+        DrawState::default().apply(gl);
+        gl.use_program(Some(&id_buffer.shader.program));
+        for (key, batch) in frame.batches.iter() {
+            let batch = batch.recolored(id_color);
+            self.draw_batch(
+                gl,
+                key,
+                &batch,
+                &mut frame.view[..],
+                &mut frame.projection[..],
+                true,
+            );
+        }
+
+        let id_buffer = self.id_buffer.as_ref().unwrap();
+        let id = id_buffer.read(gl, x, y);
+
+        gl.disable(GL::SCISSOR_TEST);
+        id_buffer.unbind(gl);
+        self.shader_sys.restore_program(gl);
+
+        // The ID buffer only holds the id, the generation comes from the
+        // frame
+        let entity = id.and_then(|id| {
+            frame
+                .batches
+                .iter()
+                .flat_map(|(_, batch)| batch.entities.iter())
+                .find(|entity| entity.id() == id)
+                .cloned()
+        });
+        self.last_frame = Some(frame);
+
+        entity
+    }
+
+    /// Draw every instance of a batch, in a single instanced draw when the
+    /// extension is around and there is more than one instance. Returns the
+    /// number of draw calls issued.
+    ///
+    /// The ID pass draws with the shader of the ID buffer, which has to be in
+    /// use already, and leaves blending and depth writes alone.
+    fn draw_batch(
+        &mut self,
+        gl: &GL,
+        key: &BatchKey,
+        batch: &InstanceBatch,
+        view: &mut [f32],
+        projection: &mut [f32],
+        id_pass: bool,
+    ) -> u32 {
+        let shader = if id_pass {
+            &self.id_buffer.as_ref().expect("ID buffer").shader
+        } else {
+            key.state.apply(gl);
+            self.shader_sys.use_program(gl, key.shader_kind);
+            self.shader_sys.get_shader(&key.shader_kind).unwrap()
+        };
+
+        let view_uni = shader.get_uniform_location(gl, "u_view");
+        let projection_uni = shader.get_uniform_location(gl, "u_projection");
+        gl.uniform_matrix4fv_with_f32_array(view_uni.as_ref(), false, view);
+        gl.uniform_matrix4fv_with_f32_array(projection_uni.as_ref(), false, projection);
+
+        let attributes = InstanceAttributes::new(gl, &shader.program);
+
+        let gpu_mesh = self.registry.get(key.mesh).expect("Registered mesh");
+        self.bind_vao(&gpu_mesh.vao);
+
+        let mut draw_calls = 0;
+        match self.instancing.as_ref() {
+            Some(ext) if batch.len() > 1 => {
+                for chunk in batch.data.chunks(MAX_INSTANCES * INSTANCE_FLOATS) {
+                    let slice = self
+                        .instance_buffer
+                        .push(gl, chunk)
+                        .expect("Instance chunk fits the stream buffer");
+                    attributes.bind_arrays(gl, ext, slice.byte_offset);
+                    ext.draw(&batch.params, chunk.len() / INSTANCE_FLOATS);
+                    draw_calls += 1;
+                }
+                attributes.unbind_arrays(gl, ext);
+            }
+            _ => {
+                for instance in batch.data.chunks(INSTANCE_FLOATS) {
+                    attributes.set_constant(gl, instance);
+                    batch.params.draw(gl);
+                    draw_calls += 1;
+                }
+            }
+        }
+
+        draw_calls
+    }
+
+    /// Draw the particles of every emitter on top of the opaque geometry.
+    /// Returns the number of draw calls issued.
+    fn draw_particles(
+        &mut self,
+        gl: &GL,
+        emitters: &ReadStorage<ParticleEmitter>,
+        library: &TextureLibrary,
+        view: &mut [f32],
+        projection: &mut [f32],
+    ) -> u32 {
+        use specs::Join;
+        if emitters
+            .join()
+            .all(|emitter| emitter.particles().is_empty())
+        {
+            return 0;
+        }
+
+        if self.particles.is_none() {
+            let vao = self.create_vao();
+            self.bind_vao(&vao);
+            match ParticleRenderer::new(gl, vao) {
+                Ok(particles) => self.particles = Some(particles),
+                Err(err) => {
+                    log!("Could not create the particle renderer: {:?}", err);
+                    return 0;
+                }
+            }
+        }
+
+        self.bind_vao(&self.particles.as_ref().unwrap().vao);
+        let particles = self.particles.as_mut().unwrap();
+        let draw_calls = particles.draw(
+            gl,
+            emitters.join(),
+            &mut self.textures,
+            library,
+            view,
+            projection,
+        );
+        self.shader_sys.restore_program(gl);
+
+        draw_calls
+    }
+
+    /// Draw the lines of `debug_draw` on top of everything. Returns the
+    /// number of draw calls issued.
+    fn draw_debug_lines(
+        &mut self,
+        gl: &GL,
+        debug_draw: &DebugDraw,
+        view: &mut [f32],
+        projection: &mut [f32],
+    ) -> u32 {
+        if debug_draw.is_empty() {
+            return 0;
+        }
+
+        if self.debug_lines.is_none() {
+            let vao = self.create_vao();
+            self.bind_vao(&vao);
+            match DebugLineRenderer::new(gl, vao) {
+                Ok(debug_lines) => self.debug_lines = Some(debug_lines),
+                Err(err) => {
+                    log!("Could not create the debug line renderer: {:?}", err);
+                    return 0;
+                }
+            }
+        }
+
+        self.bind_vao(&self.debug_lines.as_ref().unwrap().vao);
+        let debug_lines = self.debug_lines.as_mut().unwrap();
+        let draw_calls = debug_lines.draw(gl, debug_draw.vertices(), view, projection);
+        self.shader_sys.restore_program(gl);
+
+        draw_calls
+    }
+
+    /// Bind the cube map reflective and lit materials sample to texture
+    /// unit 1 and point the mesh shader at it, baking it from the sky first
+    /// when needed. `sampled` tells whether any material samples it.
+    fn prepare_environment(
+        &mut self,
+        gl: &GL,
+        skybox: &Skybox,
+        library: &TextureLibrary,
+        sampled: bool,
+        eye: Vector3<f32>,
+    ) -> Option<WebGlTexture> {
+        if self.sky.is_none() && (sampled || !skybox.is_color()) {
+            let vao = self.create_vao();
+            self.bind_vao(&vao);
+            match SkyRenderer::new(gl, vao) {
+                Ok(sky) => self.sky = Some(sky),
+                Err(err) => log!("Could not create the sky renderer: {:?}", err),
+            }
+        }
+
+        let environment = match self.sky.as_mut() {
+            Some(sky) => sky.environment(gl, skybox, library),
+            None => None,
+        };
+
+        self.shader_sys.use_program(gl, ShaderKind::NonSkinnedMesh);
+        let shader = self
+            .shader_sys
+            .get_shader(&ShaderKind::NonSkinnedMesh)
+            .unwrap();
+        let eye_uni = shader.get_uniform_location(gl, "u_eye");
+        let environment_uni = shader.get_uniform_location(gl, "u_environment");
+        let has_environment_uni = shader.get_uniform_location(gl, "u_has_environment");
+        gl.uniform3f(eye_uni.as_ref(), eye.x, eye.y, eye.z);
+        gl.uniform1i(environment_uni.as_ref(), 1);
+        gl.uniform1i(has_environment_uni.as_ref(), environment.is_some() as i32);
+
+        gl.active_texture(GL::TEXTURE1);
+        gl.bind_texture(GL::TEXTURE_CUBE_MAP, environment.as_ref());
+        gl.active_texture(GL::TEXTURE0);
+
+        environment
+    }
+
+    /// Point the mesh shader at the fog, fading to `sky_color` or the
+    /// environment cube map without one.
+    fn set_fog(&self, gl: &GL, fog: &Fog, sky_color: Option<[f32; 3]>) {
+        self.shader_sys.use_program(gl, ShaderKind::NonSkinnedMesh);
+        let shader = self
+            .shader_sys
+            .get_shader(&ShaderKind::NonSkinnedMesh)
+            .unwrap();
+        fog.set_uniforms(gl, shader, sky_color);
+    }
+
+    /// Point the mesh shader at the lights and the textures every lit
+    /// material shares.
+    fn set_lighting(&self, gl: &GL, lights: &[(Light, Matrix4<f32>)]) {
+        self.shader_sys.use_program(gl, ShaderKind::NonSkinnedMesh);
+        let shader = self
+            .shader_sys
+            .get_shader(&ShaderKind::NonSkinnedMesh)
+            .unwrap();
+        pbr::set_lights(gl, shader, lights);
+        if let Some(pbr) = self.pbr.as_ref() {
+            pbr.bind(gl, shader, (ENVIRONMENT_LEVELS - 1) as f32);
+        }
+    }
+
+    /// Set up the shader of `key` for its lit material, one of `materials`,
+    /// or for drawing unlit without one or without the PBR textures.
+    fn bind_material(
+        &mut self,
+        gl: &GL,
+        key: &BatchKey,
+        materials: &[PbrMaterial],
+        library: &TextureLibrary,
+    ) {
+        self.shader_sys.use_program(gl, key.shader_kind);
+        let shader = self.shader_sys.get_shader(&key.shader_kind).unwrap();
+        let material = key.material.and_then(|index| materials.get(index as usize));
+        match self.pbr.as_ref() {
+            Some(pbr) => pbr.bind_material(gl, shader, material, &mut self.textures, library),
+            None => pbr::set_unlit(gl, shader),
+        }
+    }
+
+    /// Draw the sky behind the opaque geometry, a cube map sky at the full
+    /// size of its cube map. Returns the number of draw calls issued.
+    fn draw_sky(
+        &mut self,
+        gl: &GL,
+        skybox: &Skybox,
+        library: &TextureLibrary,
+        view: &[f32],
+        projection: &[f32],
+    ) -> u32 {
+        let cube_map = match skybox {
+            Skybox::CubeMap { texture } => self.textures.cube_map(gl, library, texture).cloned(),
+            _ => None,
+        };
+        let sky = match self.sky.as_ref() {
+            Some(sky) => sky,
+            None => return 0,
+        };
+
+        self.bind_vao(&sky.vao);
+        let draw_calls = sky.draw(gl, skybox, cube_map.as_ref(), view, projection);
+        self.shader_sys.restore_program(gl);
+
+        draw_calls
     }
 
-    fn prepare_for_render(&self, mesh: &Mesh, gl: &GL, shader: &WebShader) {
-        // TODO: instead of key use entityID?
-        let mut vaos = self.vao_ext.vaos.borrow_mut();
-        let vao = vaos.get(mesh.name);
+    /// Make sure the geometry of `mesh` lives on the GPU and is referenced by
+    /// `entity`, uploading it if no other entity shares the same data.
+    fn prepare_for_render(&mut self, entity: Entity, mesh: &Mesh, gl: &GL) -> MeshHandle {
+        if let Some(&handle) = self.entity_meshes.get(&entity) {
+            if self.holds_geometry(handle, mesh) {
+                return handle;
+            }
 
-        match vao {
-            Some(vao) => {
-                self.bind_vao(vao);
+            // The entity's geometry changed, let go of the old upload
+            self.entity_meshes.remove(&entity);
+            self.release_mesh(gl, handle);
+        }
+
+        let handle = self.acquire_geometry(mesh, gl);
+        self.entity_meshes.insert(entity, handle);
+        handle
+    }
+
+    /// Like `prepare_for_render`, but the reference is held by the library
+    /// entry instead of an entity.
+    fn prepare_library_mesh(&mut self, mesh: &Mesh, gl: &GL) -> MeshHandle {
+        if let Some(&handle) = self.library_meshes.get(mesh.name()) {
+            if self.holds_geometry(handle, mesh) {
+                return handle;
             }
+
+            self.library_meshes.remove(mesh.name());
+            self.release_mesh(gl, handle);
+        }
+
+        let handle = self.acquire_geometry(mesh, gl);
+        self.library_meshes.insert(mesh.name().to_string(), handle);
+        handle
+    }
+
+    fn holds_geometry(&self, handle: MeshHandle, mesh: &Mesh) -> bool {
+        self.registry
+            .holds(handle, &mesh.key(), |geometry| mesh.is_geometry(geometry))
+    }
+
+    /// Find or upload the geometry of `mesh` and take a reference to it.
+    fn acquire_geometry(&mut self, mesh: &Mesh, gl: &GL) -> MeshHandle {
+        let found = self
+            .registry
+            .lookup(&mesh.key(), |geometry| mesh.is_geometry(geometry));
+        let handle = match found {
+            Some(handle) => handle,
             None => {
+                let shader = self.shader_sys.get_shader(&mesh.shader_kind()).unwrap();
                 let vao = self.create_vao();
                 self.bind_vao(&vao);
-                // TODO: buffer attributes
-                mesh.buffer_attributes(shader);
-                vaos.insert(mesh.name.to_string(), vao);
+                let buffers = mesh.upload(gl, shader);
+                self.registry
+                    .insert(mesh.key(), mesh.geometry(), vao, buffers)
+            }
+        };
+
+        self.registry.acquire(handle);
+        handle
+    }
+
+    /// Push the pending edits of a dynamic mesh into its buffers. Expects the
+    /// mesh's VAO to be bound.
+    fn sync_dynamic(&mut self, gl: &GL, handle: MeshHandle, mesh: &mut Mesh) {
+        if mesh.indices_dirty {
+            mesh.indices_dirty = false;
+            self.sync_indices(gl, handle, mesh);
+        }
+
+        let gpu_mesh = self.registry.get(handle).expect("Registered mesh");
+        let vertex_buffer = &gpu_mesh.buffers[0];
+
+        let update = mesh.take_update();
+        let layout = mesh.layout.for_context(gl);
+        match update {
+            MeshUpdate::None => {}
+            MeshUpdate::Full => {
+                let data = layout.pack(mesh.vertices());
+                buffer::respecify_u8_buffer(gl, vertex_buffer, &data[..], mesh.usage);
+            }
+            MeshUpdate::Ranges(ranges) => {
+                for range in ranges {
+                    let vertices = layout.vertex_range(range);
+                    let byte_offset = vertices.start * layout.stride();
+                    let data = layout.pack_range(mesh.vertices(), vertices);
+                    buffer::update_u8_buffer(gl, vertex_buffer, byte_offset, &data[..]);
+                }
             }
         }
     }
 
+    fn sync_indices(&mut self, gl: &GL, handle: MeshHandle, mesh: &Mesh) {
+        let gpu_mesh = self.registry.get_mut(handle).expect("Registered mesh");
+
+        match (mesh.indices.as_ref(), gpu_mesh.buffers.get(1)) {
+            (Some(indices), Some(index_buffer)) => {
+                buffer::respecify_index_buffer(gl, index_buffer, indices, mesh.usage);
+            }
+            (Some(indices), None) => {
+                let index_buffer = buffer::create_index_buffer(gl, indices, mesh.usage);
+                gpu_mesh.buffers.push(index_buffer);
+            }
+            (None, _) => {
+                // Nothing to unbind, drawing simply stops using the indices
+            }
+        }
+    }
+
+    /// Release the meshes of entities that were not rendered this frame,
+    /// either because they were deleted or lost their `Mesh` component, and
+    /// of library meshes that were removed.
+    fn release_unused(&mut self, gl: &GL, alive: &HashSet<Entity>, library: &MeshLibrary) {
+        let removed: Vec<String> = self
+            .library_meshes
+            .keys()
+            .filter(|name| !library.contains(name))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            if let Some(handle) = self.library_meshes.remove(&name) {
+                self.release_mesh(gl, handle);
+            }
+        }
+
+        let stale: Vec<Entity> = self
+            .entity_meshes
+            .keys()
+            .filter(|entity| !alive.contains(entity))
+            .cloned()
+            .collect();
+
+        for entity in stale {
+            if let Some(handle) = self.entity_meshes.remove(&entity) {
+                self.release_mesh(gl, handle);
+            }
+        }
+    }
+
+    fn release_mesh(&mut self, gl: &GL, handle: MeshHandle) {
+        if let Some(gpu_mesh) = self.registry.release(handle) {
+            for buffer in gpu_mesh.buffers.iter() {
+                gl.delete_buffer(Some(buffer));
+            }
+            self.delete_vao(&gpu_mesh.vao);
+        }
+    }
+
     fn create_vao(&self) -> Vao<js_sys::Object> {
         let oes_vao_ext = &self.vao_ext.oes_vao_ext;
 
@@ -134,6 +1260,39 @@ impl RenderSystem {
 
         Reflect::apply(&bind_vao_ext, oes_vao_ext, &args).expect("Bound VAO");
     }
+
+    fn delete_vao(&self, vao: &Vao<js_sys::Object>) {
+        let oes_vao_ext = &self.vao_ext.oes_vao_ext;
+
+        let delete_vao_ext = Reflect::get(&oes_vao_ext, &"deleteVertexArrayOES".into())
+            .expect("Delete vao func")
+            .into();
+
+        let args = js_sys::Array::new();
+        args.push(&vao.0);
+
+        Reflect::apply(&delete_vao_ext, oes_vao_ext, &args).expect("Deleted VAO");
+    }
+}
+
+thread_local! {
+    /// Buffers uploaded through the `Render` trait, whose methods can't hand
+    /// them back. Kept until `delete_render_buffers`.
+    static RENDER_BUFFERS: RefCell<Vec<WebGlBuffer>> = RefCell::new(Vec::new());
+}
+
+fn keep_render_buffer(buffer: WebGlBuffer) {
+    RENDER_BUFFERS.with(|buffers| buffers.borrow_mut().push(buffer));
+}
+
+/// Delete every buffer uploaded through the `Render` trait so far, once
+/// nothing draws them anymore.
+pub fn delete_render_buffers(gl: &GL) {
+    RENDER_BUFFERS.with(|buffers| {
+        for buffer in buffers.borrow_mut().drain(..) {
+            gl.delete_buffer(Some(&buffer));
+        }
+    });
 }
 
 impl<'a> Render<'a, GL, WebShader> for Mesh {
@@ -141,28 +1300,12 @@ impl<'a> Render<'a, GL, WebShader> for Mesh {
         self.shader_kind
     }
 
+    /// Uploads through the vertex layout, like the render system does.
     fn buffer_attributes(&self, shader: &WebShader) {
         let gl = &GLC.contexts.borrow()[0];
-        // let mesh = self.mesh;
-
-        let vertex_data_attrib = gl.get_attrib_location(&shader.program, "a_position");
-        // let normal_attrib = gl.get_attrib_location(&shader.program, "normal");
-        // let uv_attrib = gl.get_attrib_location(&shader.program, "uvs");
-
-        gl.enable_vertex_attrib_array(vertex_data_attrib as u32);
-        // gl.enable_vertex_attrib_array(normal_attrib as u32);
-        // gl.enable_vertex_attrib_array(uv_attrib as u32);
-
-        log!("verts {:?}", self.vertices);
-        Mesh::buffer_f32_data(&gl, &self.vertices[..], vertex_data_attrib as u32, 2);
-        // Mesh::buffer_f32_data(&gl, &mesh.vertex_normals[..], normal_attrib as u32, 3);
-        // Mesh::buffer_f32_data(
-        //     &gl,
-        //     &mesh.vertex_uvs.as_ref().expect("uvs")[..],
-        //     uv_attrib as u32,
-        //     2,
-        // );
-        // Mesh::buffer_u16_indices(&gl, &mesh.vertex_position_indices[..]);
+        for buffer in self.upload(gl, shader) {
+            keep_render_buffer(buffer);
+        }
     }
 
     fn render(&self, gl: &GL) { //, shader: &WebShader, state: &State) {
@@ -172,61 +1315,24 @@ impl<'a> Render<'a, GL, WebShader> for Mesh {
         //     self.texture_unit as i32,
         // );
 
-        gl.draw_arrays(GL::TRIANGLES, 0, 3);
+        self.draw_params().draw(gl);
     }
 
     fn buffer_f32_data(gl: &GL, data: &[f32], attrib: u32, size: i32) {
-        let memory_buffer = wasm_bindgen::memory()
-            .dyn_into::<WebAssembly::Memory>()
-            .unwrap()
-            .buffer();
-
-        let data_location = data.as_ptr() as u32 / 4;
-
-        let data_array = js_sys::Float32Array::new(&memory_buffer)
-            .subarray(data_location, data_location + data.len() as u32);
-
-        let buffer = gl.create_buffer().unwrap();
-
-        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&buffer));
-        gl.buffer_data_with_array_buffer_view(GL::ARRAY_BUFFER, &data_array, GL::STATIC_DRAW);
+        keep_render_buffer(buffer::create_f32_buffer(gl, data, BufferUsage::Static));
         gl.vertex_attrib_pointer_with_i32(attrib, size, GL::FLOAT, false, 0, 0);
     }
 
     fn buffer_u8_data(gl: &GL, data: &[u8], attrib: u32, size: i32) {
-        let memory_buffer = wasm_bindgen::memory()
-            .dyn_into::<WebAssembly::Memory>()
-            .unwrap()
-            .buffer();
-
-        let data_location = data.as_ptr() as u32;
-
-        let data_array = js_sys::Uint8Array::new(&memory_buffer)
-            .subarray(data_location, data_location + data.len() as u32);
-
-        let buffer = gl.create_buffer().unwrap();
-
-        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&buffer));
-        gl.buffer_data_with_array_buffer_view(GL::ARRAY_BUFFER, &data_array, GL::STATIC_DRAW);
+        keep_render_buffer(buffer::create_u8_buffer(gl, data, BufferUsage::Static));
         gl.vertex_attrib_pointer_with_i32(attrib, size, GL::UNSIGNED_BYTE, false, 0, 0);
     }
 
     fn buffer_u16_indices(gl: &GL, indices: &[u16]) {
-        let memory_buffer = wasm_bindgen::memory()
-            .dyn_into::<WebAssembly::Memory>()
-            .unwrap()
-            .buffer();
-
-        let indices_location = indices.as_ptr() as u32 / 2;
-        let indices_array = js_sys::Uint16Array::new(&memory_buffer)
-            .subarray(indices_location, indices_location + indices.len() as u32);
-
-        let index_buffer = gl.create_buffer().unwrap();
-        gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(&index_buffer));
-        gl.buffer_data_with_array_buffer_view(
-            GL::ELEMENT_ARRAY_BUFFER,
-            &indices_array,
-            GL::STATIC_DRAW,
-        );
+        keep_render_buffer(buffer::create_u16_index_buffer(
+            gl,
+            indices,
+            BufferUsage::Static,
+        ));
     }
 }
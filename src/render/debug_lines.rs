@@ -0,0 +1,106 @@
+use chal_engine::render::Vao;
+use chal_engine::shader::Shader;
+use wasm_bindgen::JsValue;
+use web_sys::WebGlRenderingContext as GL;
+
+use crate::debug_draw::VERTEX_FLOATS;
+use crate::render::blend::BlendMode;
+use crate::render::buffer::StreamBuffer;
+use crate::shader::WebShader;
+
+static DEBUG_LINE_VS: &'static str = include_str!("../debug-line-vertex.glsl");
+static DEBUG_LINE_FS: &'static str = include_str!("../debug-line-fragment.glsl");
+
+/// Lines per draw call at most. More are split over several draws.
+const MAX_LINES: usize = 8192;
+
+/// Attribute name, number of floats and offset in floats within a vertex.
+const ATTRIBUTES: [(&str, i32, i32); 2] = [("a_position", 3, 0), ("a_color", 4, 3)];
+
+/// Draws the lines of the `DebugDraw` resource over the scene, streaming
+/// their vertices anew every frame.
+pub struct DebugLineRenderer {
+    shader: WebShader,
+    /// Holds the attribute arrays
+    pub vao: Vao<js_sys::Object>,
+    vertices: StreamBuffer,
+    locations: [i32; 2],
+}
+
+impl DebugLineRenderer {
+    /// Expects `vao` to be bound, it is set up for drawing lines.
+    pub fn new(gl: &GL, vao: Vao<js_sys::Object>) -> Result<DebugLineRenderer, JsValue> {
+        let shader = WebShader::new(gl, DEBUG_LINE_VS, DEBUG_LINE_FS)?;
+
+        let mut locations = [-1; 2];
+        for (location, &(name, _, _)) in locations.iter_mut().zip(ATTRIBUTES.iter()) {
+            *location = gl.get_attrib_location(&shader.program, name);
+            if *location >= 0 {
+                gl.enable_vertex_attrib_array(*location as u32);
+            }
+        }
+
+        Ok(DebugLineRenderer {
+            shader,
+            vao,
+            vertices: StreamBuffer::new(gl, MAX_LINES * 2 * VERTEX_FLOATS),
+            locations,
+        })
+    }
+
+    /// Draw `vertices`, two per line, without depth testing so nothing
+    /// hides them. Expects `vao` to be bound and leaves the program of the
+    /// lines in use. Returns the number of draw calls issued.
+    pub fn draw(
+        &mut self,
+        gl: &GL,
+        vertices: &[f32],
+        view: &mut [f32],
+        projection: &mut [f32],
+    ) -> u32 {
+        gl.use_program(Some(&self.shader.program));
+        let view_uni = self.shader.get_uniform_location(gl, "u_view");
+        let projection_uni = self.shader.get_uniform_location(gl, "u_projection");
+        gl.uniform_matrix4fv_with_f32_array(view_uni.as_ref(), false, view);
+        gl.uniform_matrix4fv_with_f32_array(projection_uni.as_ref(), false, projection);
+
+        gl.disable(GL::DEPTH_TEST);
+        BlendMode::Alpha.apply(gl);
+
+        let mut draw_calls = 0;
+        for chunk in vertices.chunks(MAX_LINES * 2 * VERTEX_FLOATS) {
+            let slice = self
+                .vertices
+                .push(gl, chunk)
+                .expect("Line chunk fits the stream buffer");
+            self.bind_attributes(gl, slice.byte_offset);
+
+            gl.draw_arrays(GL::LINES, 0, (chunk.len() / VERTEX_FLOATS) as i32);
+            draw_calls += 1;
+        }
+
+        gl.disable(GL::BLEND);
+        gl.enable(GL::DEPTH_TEST);
+
+        draw_calls
+    }
+
+    /// Point the attributes at vertices in the bound array buffer, starting
+    /// at `byte_offset`.
+    fn bind_attributes(&self, gl: &GL, byte_offset: i32) {
+        let stride = (VERTEX_FLOATS * 4) as i32;
+
+        for (&location, &(_, size, offset)) in self.locations.iter().zip(ATTRIBUTES.iter()) {
+            if location >= 0 {
+                gl.vertex_attrib_pointer_with_i32(
+                    location as u32,
+                    size,
+                    GL::FLOAT,
+                    false,
+                    stride,
+                    byte_offset + offset * 4,
+                );
+            }
+        }
+    }
+}
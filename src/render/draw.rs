@@ -0,0 +1,129 @@
+use web_sys::WebGlRenderingContext as GL;
+
+/// How the vertices of a mesh are assembled into primitives.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topology {
+    Points,
+    Lines,
+    LineStrip,
+    LineLoop,
+    Triangles,
+    TriangleStrip,
+    TriangleFan,
+}
+
+impl Topology {
+    pub fn gl_mode(self) -> u32 {
+        match self {
+            Topology::Points => GL::POINTS,
+            Topology::Lines => GL::LINES,
+            Topology::LineStrip => GL::LINE_STRIP,
+            Topology::LineLoop => GL::LINE_LOOP,
+            Topology::Triangles => GL::TRIANGLES,
+            Topology::TriangleStrip => GL::TRIANGLE_STRIP,
+            Topology::TriangleFan => GL::TRIANGLE_FAN,
+        }
+    }
+}
+
+impl Default for Topology {
+    fn default() -> Topology {
+        Topology::Triangles
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum IndexType {
+    U8,
+    U16,
+    /// Requires the `OES_element_index_uint` extension on WebGL1
+    U32,
+}
+
+impl IndexType {
+    pub fn gl_type(self) -> u32 {
+        match self {
+            IndexType::U8 => GL::UNSIGNED_BYTE,
+            IndexType::U16 => GL::UNSIGNED_SHORT,
+            IndexType::U32 => GL::UNSIGNED_INT,
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            IndexType::U8 => 1,
+            IndexType::U16 => 2,
+            IndexType::U32 => 4,
+        }
+    }
+}
+
+/// Index data of a mesh, in whichever width fits the vertex count.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Indices {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl Indices {
+    pub fn index_type(&self) -> IndexType {
+        match self {
+            Indices::U8(_) => IndexType::U8,
+            Indices::U16(_) => IndexType::U16,
+            Indices::U32(_) => IndexType::U32,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Indices::U8(indices) => indices.len(),
+            Indices::U16(indices) => indices.len(),
+            Indices::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Byte representation, ready to be uploaded into an element buffer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Indices::U8(indices) => indices.clone(),
+            Indices::U16(indices) => indices.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect(),
+            Indices::U32(indices) => indices.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect(),
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<usize> {
+        match self {
+            Indices::U8(indices) => indices.get(idx).map(|&i| i as usize),
+            Indices::U16(indices) => indices.get(idx).map(|&i| i as usize),
+            Indices::U32(indices) => indices.get(idx).map(|&i| i as usize),
+        }
+    }
+}
+
+/// Everything needed to issue the draw call of a mesh.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DrawParams {
+    pub topology: Topology,
+    pub vertex_count: usize,
+    pub index_count: usize,
+    pub index_type: Option<IndexType>,
+}
+
+impl DrawParams {
+    pub fn draw(&self, gl: &GL) {
+        let mode = self.topology.gl_mode();
+
+        match self.index_type {
+            Some(index_type) => {
+                gl.draw_elements_with_i32(mode, self.index_count as i32, index_type.gl_type(), 0)
+            }
+            None => gl.draw_arrays(mode, 0, self.vertex_count as i32),
+        }
+    }
+}
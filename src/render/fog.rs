@@ -0,0 +1,119 @@
+use chal_engine::shader::Shader;
+use web_sys::WebGlRenderingContext as GL;
+
+use crate::shader::WebShader;
+
+/// How fog thickens with the distance from the camera.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DistanceFog {
+    None,
+    /// Clear up to `start`, fully fogged from `end` on
+    Linear {
+        start: f32,
+        end: f32,
+    },
+    /// Thickens quickly near the camera and slowly further out
+    Exponential {
+        density: f32,
+    },
+    /// Stays clear for longer, then closes in quickly
+    ExponentialSquared {
+        density: f32,
+    },
+}
+
+impl DistanceFog {
+    /// The mode as the mesh shader numbers it.
+    fn mode(&self) -> i32 {
+        match self {
+            DistanceFog::None => 0,
+            DistanceFog::Linear { .. } => 1,
+            DistanceFog::Exponential { .. } => 2,
+            DistanceFog::ExponentialSquared { .. } => 3,
+        }
+    }
+}
+
+impl Default for DistanceFog {
+    fn default() -> DistanceFog {
+        DistanceFog::None
+    }
+}
+
+/// Fog lying in valleys, thinning out exponentially with height.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HeightFog {
+    /// Density at `base`, per world unit
+    pub density: f32,
+    /// Height the density is given at
+    pub base: f32,
+    /// How quickly the density falls off above `base`, 1 halves it about
+    /// every 0.7 units
+    pub falloff: f32,
+}
+
+impl Default for HeightFog {
+    fn default() -> HeightFog {
+        HeightFog {
+            density: 0.05,
+            base: 0.,
+            falloff: 0.2,
+        }
+    }
+}
+
+/// Fog over the meshes of the scene, hiding where geometry ends at the far
+/// plane. Distance and height fog add up. No fog by default.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Fog {
+    pub distance: DistanceFog,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<HeightFog>,
+    /// RGB color fogged geometry fades to. By default the sky behind it,
+    /// so far away meshes blend into the skybox and light up towards the
+    /// sun of a gradient sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<[f32; 3]>,
+}
+
+impl Fog {
+    pub fn is_enabled(&self) -> bool {
+        self.distance != DistanceFog::None || self.height.is_some()
+    }
+
+    /// Set the fog uniforms of the mesh `shader`, which has to be in use.
+    /// `sky_color` is the color of a sky that is a single color, `None`
+    /// to take the color from the environment cube map instead.
+    pub fn set_uniforms(&self, gl: &GL, shader: &WebShader, sky_color: Option<[f32; 3]>) {
+        let uniform = |name: &str| shader.get_uniform_location(gl, name);
+
+        let (start, end, density) = match self.distance {
+            DistanceFog::None => (0., 0., 0.),
+            DistanceFog::Linear { start, end } => (start, end.max(start + 1.0e-3), 0.),
+            DistanceFog::Exponential { density } => (0., 0., density),
+            DistanceFog::ExponentialSquared { density } => (0., 0., density),
+        };
+        gl.uniform1i(uniform("u_fog_mode").as_ref(), self.distance.mode());
+        gl.uniform3f(uniform("u_fog_distance").as_ref(), start, end, density);
+
+        // No height fog is height fog without density
+        let height = self.height.unwrap_or(HeightFog {
+            density: 0.,
+            ..HeightFog::default()
+        });
+        gl.uniform3f(
+            uniform("u_fog_height").as_ref(),
+            height.density,
+            height.base,
+            height.falloff,
+        );
+
+        let color = self.color.or(sky_color);
+        let [r, g, b] = color.unwrap_or([0., 0., 0.]);
+        gl.uniform3f(uniform("u_fog_color").as_ref(), r, g, b);
+        gl.uniform1i(uniform("u_fog_from_sky").as_ref(), color.is_none() as i32);
+    }
+}
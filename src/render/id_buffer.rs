@@ -0,0 +1,178 @@
+use chal_engine::shader::Shader;
+use specs::Entity;
+use wasm_bindgen::JsValue;
+use web_sys::{
+    WebGlFramebuffer, WebGlProgram, WebGlRenderbuffer, WebGlRenderingContext as GL, WebGlTexture,
+};
+
+use crate::shader::WebShader;
+
+static MESH_ID_VS: &'static str = include_str!("../mesh-non-skinned-vertex.glsl");
+static MESH_ID_FS: &'static str = include_str!("../mesh-id-fragment.glsl");
+
+/// The color an entity is drawn with in the ID buffer. Its id plus one is
+/// spread over the four channels, so a cleared pixel reads as no entity.
+pub fn id_color(entity: Entity) -> [f32; 4] {
+    let bytes = (entity.id() + 1).to_le_bytes();
+    [
+        bytes[0] as f32 / 255.,
+        bytes[1] as f32 / 255.,
+        bytes[2] as f32 / 255.,
+        bytes[3] as f32 / 255.,
+    ]
+}
+
+fn decode_id(pixel: [u8; 4]) -> Option<u32> {
+    match u32::from_le_bytes(pixel) {
+        0 => None,
+        id => Some(id - 1),
+    }
+}
+
+/// An offscreen target the scene is drawn into with entity ids as colors, to
+/// find out which entity covers a pixel.
+pub struct IdBuffer {
+    framebuffer: WebGlFramebuffer,
+    color: WebGlTexture,
+    depth: WebGlRenderbuffer,
+    size: (i32, i32),
+    pub shader: WebShader,
+}
+
+impl IdBuffer {
+    /// The ID shader gets the attribute locations of `mesh_program`, so the
+    /// VAOs set up for it can be drawn as they are.
+    pub fn new(gl: &GL, mesh_program: &WebGlProgram) -> Result<IdBuffer, JsValue> {
+        let shader = WebShader::new(gl, MESH_ID_VS, MESH_ID_FS)?;
+        match_attribute_locations(gl, mesh_program, &shader.program)?;
+
+        let framebuffer = gl
+            .create_framebuffer()
+            .ok_or_else(|| JsValue::from_str("Could not create framebuffer"))?;
+        let color = gl
+            .create_texture()
+            .ok_or_else(|| JsValue::from_str("Could not create texture"))?;
+        let depth = gl
+            .create_renderbuffer()
+            .ok_or_else(|| JsValue::from_str("Could not create renderbuffer"))?;
+
+        gl.bind_texture(GL::TEXTURE_2D, Some(&color));
+        gl.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_MIN_FILTER, GL::NEAREST as i32);
+        gl.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_MAG_FILTER, GL::NEAREST as i32);
+        gl.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_WRAP_S, GL::CLAMP_TO_EDGE as i32);
+        gl.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_WRAP_T, GL::CLAMP_TO_EDGE as i32);
+
+        gl.bind_framebuffer(GL::FRAMEBUFFER, Some(&framebuffer));
+        gl.framebuffer_texture_2d(
+            GL::FRAMEBUFFER,
+            GL::COLOR_ATTACHMENT0,
+            GL::TEXTURE_2D,
+            Some(&color),
+            0,
+        );
+        gl.bind_renderbuffer(GL::RENDERBUFFER, Some(&depth));
+        gl.framebuffer_renderbuffer(
+            GL::FRAMEBUFFER,
+            GL::DEPTH_ATTACHMENT,
+            GL::RENDERBUFFER,
+            Some(&depth),
+        );
+        gl.bind_framebuffer(GL::FRAMEBUFFER, None);
+
+        Ok(IdBuffer {
+            framebuffer,
+            color,
+            depth,
+            size: (0, 0),
+            shader,
+        })
+    }
+
+    /// Draw into the ID buffer from now on, resizing it to `size` first.
+    pub fn bind(&mut self, gl: &GL, size: (i32, i32)) {
+        if self.size != size {
+            self.resize(gl, size);
+        }
+        gl.bind_framebuffer(GL::FRAMEBUFFER, Some(&self.framebuffer));
+    }
+
+    /// Go back to drawing on the canvas.
+    pub fn unbind(&self, gl: &GL) {
+        gl.bind_framebuffer(GL::FRAMEBUFFER, None);
+    }
+
+    /// The id at pixel (`x`, `y`), counted from the bottom left. Expects the
+    /// buffer to be bound.
+    pub fn read(&self, gl: &GL, x: i32, y: i32) -> Option<u32> {
+        let mut pixel = [0u8; 4];
+        gl.read_pixels_with_opt_u8_array(
+            x,
+            y,
+            1,
+            1,
+            GL::RGBA,
+            GL::UNSIGNED_BYTE,
+            Some(&mut pixel[..]),
+        )
+        .ok()?;
+
+        decode_id(pixel)
+    }
+
+    fn resize(&mut self, gl: &GL, (width, height): (i32, i32)) {
+        gl.bind_texture(GL::TEXTURE_2D, Some(&self.color));
+        gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+            GL::TEXTURE_2D,
+            0,
+            GL::RGBA as i32,
+            width,
+            height,
+            0,
+            GL::RGBA,
+            GL::UNSIGNED_BYTE,
+            None,
+        )
+        .expect("ID buffer texture");
+
+        gl.bind_renderbuffer(GL::RENDERBUFFER, Some(&self.depth));
+        gl.renderbuffer_storage(GL::RENDERBUFFER, GL::DEPTH_COMPONENT16, width, height);
+
+        self.size = (width, height);
+    }
+}
+
+/// Give the attributes of `program` the locations they have in `source` and
+/// link it again.
+fn match_attribute_locations(
+    gl: &GL,
+    source: &WebGlProgram,
+    program: &WebGlProgram,
+) -> Result<(), String> {
+    let active = gl
+        .get_program_parameter(source, GL::ACTIVE_ATTRIBUTES)
+        .as_f64()
+        .unwrap_or(0.) as u32;
+
+    for idx in 0..active {
+        if let Some(info) = gl.get_active_attrib(source, idx) {
+            let location = gl.get_attrib_location(source, &info.name());
+            if location >= 0 {
+                gl.bind_attrib_location(program, location as u32, &info.name());
+            }
+        }
+    }
+
+    gl.link_program(program);
+
+    if gl
+        .get_program_parameter(program, GL::LINK_STATUS)
+        .as_bool()
+        .unwrap_or(false)
+    {
+        Ok(())
+    } else {
+        Err(gl
+            .get_program_info_log(program)
+            .unwrap_or_else(|| "Unknown error linking the ID shader".to_string()))
+    }
+}
@@ -0,0 +1,341 @@
+use js_sys::{Function, Reflect};
+use nalgebra::Matrix4;
+use specs::Entity;
+use wasm_bindgen::JsValue;
+use web_sys::{WebGlProgram, WebGlRenderingContext as GL};
+
+use crate::canvas::is_webgl2;
+use crate::render::draw::DrawParams;
+use crate::render::material::Material;
+
+/// Floats per instance: a 4x4 model matrix, an RGBA color, the LOD
+/// cross-fade and the reflectivity.
+pub const INSTANCE_FLOATS: usize = 22;
+
+/// Attributes that are fed per instance rather than from the mesh buffers.
+pub const INSTANCE_ATTRIBUTES: &[&str] = &["a_model", "a_color", "a_fade", "a_reflectivity"];
+
+/// Instanced drawing, through `ANGLE_instanced_arrays` on WebGL1 or the
+/// native functions of a WebGL2 context.
+pub struct InstancedArrays {
+    target: JsValue,
+    vertex_attrib_divisor: Function,
+    draw_arrays_instanced: Function,
+    draw_elements_instanced: Function,
+}
+
+impl InstancedArrays {
+    pub fn new(gl: &GL) -> Option<InstancedArrays> {
+        let (target, suffix): (JsValue, &str) = if is_webgl2(gl) {
+            (gl.clone().into(), "")
+        } else {
+            let ext = gl.get_extension("ANGLE_instanced_arrays").ok()??;
+            (ext.into(), "ANGLE")
+        };
+
+        let vertex_attrib_divisor = method(&target, &format!("vertexAttribDivisor{}", suffix));
+        let draw_arrays_instanced = method(&target, &format!("drawArraysInstanced{}", suffix));
+        let draw_elements_instanced =
+            method(&target, &format!("drawElementsInstanced{}", suffix));
+
+        Some(InstancedArrays {
+            target,
+            vertex_attrib_divisor,
+            draw_arrays_instanced,
+            draw_elements_instanced,
+        })
+    }
+
+    pub fn vertex_attrib_divisor(&self, index: u32, divisor: u32) {
+        self.call(&self.vertex_attrib_divisor, &[index as f64, divisor as f64]);
+    }
+
+    pub fn draw(&self, params: &DrawParams, instances: usize) {
+        let mode = params.topology.gl_mode() as f64;
+
+        match params.index_type {
+            Some(index_type) => self.call(
+                &self.draw_elements_instanced,
+                &[
+                    mode,
+                    params.index_count as f64,
+                    index_type.gl_type() as f64,
+                    0.,
+                    instances as f64,
+                ],
+            ),
+            None => self.call(
+                &self.draw_arrays_instanced,
+                &[mode, 0., params.vertex_count as f64, instances as f64],
+            ),
+        }
+    }
+
+    fn call(&self, func: &Function, args: &[f64]) {
+        let array = js_sys::Array::new();
+        for &arg in args {
+            array.push(&JsValue::from_f64(arg));
+        }
+
+        Reflect::apply(func, &self.target, &array).expect("Instanced arrays call");
+    }
+}
+
+fn method(target: &JsValue, name: &str) -> Function {
+    Reflect::get(target, &name.into())
+        .expect(&format!("{} func", name))
+        .into()
+}
+
+/// The parts of a `Material` that vary per instance.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InstanceMaterial {
+    pub color: [f32; 4],
+    pub reflectivity: f32,
+}
+
+impl InstanceMaterial {
+    /// White and matte without a material.
+    pub fn of(material: Option<&Material>) -> InstanceMaterial {
+        match material {
+            Some(material) => InstanceMaterial {
+                color: material.color,
+                reflectivity: material.reflectivity,
+            },
+            None => InstanceMaterial {
+                color: [1., 1., 1., 1.],
+                reflectivity: 0.,
+            },
+        }
+    }
+}
+
+/// The per-instance data of every entity sharing a mesh.
+#[derive(Clone, Debug)]
+pub struct InstanceBatch {
+    pub params: DrawParams,
+    pub data: Vec<f32>,
+    /// The entity of every instance, in the same order as `data`
+    pub entities: Vec<Entity>,
+}
+
+impl InstanceBatch {
+    pub fn new(params: DrawParams) -> InstanceBatch {
+        InstanceBatch {
+            params,
+            data: Vec::new(),
+            entities: Vec::new(),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        entity: Entity,
+        model: &Matrix4<f32>,
+        material: InstanceMaterial,
+        fade: f32,
+    ) {
+        self.data.extend_from_slice(model.as_slice());
+        self.data.extend_from_slice(&material.color);
+        self.data.push(fade);
+        self.data.push(material.reflectivity);
+        self.entities.push(entity);
+    }
+
+    /// A copy of the batch with every instance colored by `color_of` its
+    /// entity instead.
+    pub fn recolored<F: Fn(Entity) -> [f32; 4]>(&self, color_of: F) -> InstanceBatch {
+        let mut batch = self.clone();
+        for (instance, &entity) in batch
+            .data
+            .chunks_mut(INSTANCE_FLOATS)
+            .zip(self.entities.iter())
+        {
+            instance[16..20].copy_from_slice(&color_of(entity));
+        }
+        batch
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / INSTANCE_FLOATS
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Locations of the per-instance attributes in a program.
+pub struct InstanceAttributes {
+    model: i32,
+    color: i32,
+    fade: i32,
+    reflectivity: i32,
+}
+
+impl InstanceAttributes {
+    pub fn new(gl: &GL, program: &WebGlProgram) -> InstanceAttributes {
+        InstanceAttributes {
+            model: gl.get_attrib_location(program, "a_model"),
+            color: gl.get_attrib_location(program, "a_color"),
+            fade: gl.get_attrib_location(program, "a_fade"),
+            reflectivity: gl.get_attrib_location(program, "a_reflectivity"),
+        }
+    }
+
+    /// Each column of a matrix attribute takes up its own location.
+    fn locations(&self) -> Vec<(u32, i32, i32)> {
+        let mut locations = Vec::with_capacity(7);
+
+        if self.model >= 0 {
+            for column in 0..4 {
+                locations.push((self.model as u32 + column, 4, column as i32 * 16));
+            }
+        }
+        if self.color >= 0 {
+            locations.push((self.color as u32, 4, 64));
+        }
+        if self.fade >= 0 {
+            locations.push((self.fade as u32, 1, 80));
+        }
+        if self.reflectivity >= 0 {
+            locations.push((self.reflectivity as u32, 1, 84));
+        }
+
+        locations
+    }
+
+    /// Point the attributes at instance data in the bound array buffer,
+    /// starting at `byte_offset`, and advance them once per instance.
+    pub fn bind_arrays(&self, gl: &GL, ext: &InstancedArrays, byte_offset: i32) {
+        let stride = (INSTANCE_FLOATS * 4) as i32;
+
+        for (location, size, offset) in self.locations() {
+            gl.enable_vertex_attrib_array(location);
+            gl.vertex_attrib_pointer_with_i32(
+                location,
+                size,
+                GL::FLOAT,
+                false,
+                stride,
+                byte_offset + offset,
+            );
+            ext.vertex_attrib_divisor(location, 1);
+        }
+    }
+
+    /// Restore the attributes so the bound VAO can be drawn without
+    /// instancing again.
+    pub fn unbind_arrays(&self, gl: &GL, ext: &InstancedArrays) {
+        for (location, _, _) in self.locations() {
+            ext.vertex_attrib_divisor(location, 0);
+            gl.disable_vertex_attrib_array(location);
+        }
+    }
+
+    /// Feed a single instance as constant attribute values, for drawing
+    /// without instancing.
+    pub fn set_constant(&self, gl: &GL, instance: &[f32]) {
+        for (location, size, offset) in self.locations() {
+            let start = offset as usize / 4;
+            gl.disable_vertex_attrib_array(location);
+
+            if size == 1 {
+                gl.vertex_attrib1f(location, instance[start]);
+            } else {
+                let mut values = [0.; 4];
+                values.copy_from_slice(&instance[start..start + 4]);
+                gl.vertex_attrib4fv_with_f32_array(location, &mut values);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::draw::Topology;
+    use nalgebra::Vector3;
+    use specs::{Builder, World};
+
+    fn batch() -> InstanceBatch {
+        InstanceBatch::new(DrawParams {
+            topology: Topology::Triangles,
+            vertex_count: 3,
+            index_count: 0,
+            index_type: None,
+        })
+    }
+
+    #[test]
+    fn instances_are_packed_in_attribute_order() {
+        let mut world = World::new();
+        let (a, b) = (world.create_entity().build(), world.create_entity().build());
+        let model = Matrix4::new_translation(&Vector3::new(1., 2., 3.));
+        let material = InstanceMaterial {
+            color: [0.1, 0.2, 0.3, 0.4],
+            reflectivity: 0.5,
+        };
+
+        let mut batch = batch();
+        assert!(batch.is_empty());
+        batch.push(a, &model, material, 0.75);
+        batch.push(b, &Matrix4::identity(), InstanceMaterial::of(None), 1.);
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.data.len(), 2 * INSTANCE_FLOATS);
+        assert_eq!(batch.entities, vec![a, b]);
+
+        let first = &batch.data[..INSTANCE_FLOATS];
+        // Column major, the translation is in the last column
+        assert_eq!(&first[12..16], &[1., 2., 3., 1.]);
+        assert_eq!(&first[16..20], &material.color);
+        assert_eq!(first[20], 0.75);
+        assert_eq!(first[21], 0.5);
+
+        let second = &batch.data[INSTANCE_FLOATS..];
+        assert_eq!(&second[16..22], &[1., 1., 1., 1., 1., 0.]);
+    }
+
+    #[test]
+    fn recoloring_only_changes_the_color() {
+        let mut world = World::new();
+        let (a, b) = (world.create_entity().build(), world.create_entity().build());
+        let mut batch = batch();
+        batch.push(a, &Matrix4::identity(), InstanceMaterial::of(None), 0.5);
+        batch.push(b, &Matrix4::identity(), InstanceMaterial::of(None), 0.5);
+
+        let recolored = batch.recolored(|entity| [entity.id() as f32, 0., 0., 1.]);
+
+        for (idx, entity) in [a, b].iter().enumerate() {
+            let before = &batch.data[idx * INSTANCE_FLOATS..(idx + 1) * INSTANCE_FLOATS];
+            let after = &recolored.data[idx * INSTANCE_FLOATS..(idx + 1) * INSTANCE_FLOATS];
+            assert_eq!(&after[16..20], &[entity.id() as f32, 0., 0., 1.]);
+            assert_eq!(&after[..16], &before[..16]);
+            assert_eq!(&after[20..], &before[20..]);
+        }
+        assert_eq!(recolored.entities, batch.entities);
+    }
+
+    #[test]
+    fn matrix_columns_take_a_location_each() {
+        let attributes = InstanceAttributes {
+            model: 3,
+            color: -1,
+            fade: 9,
+            reflectivity: 10,
+        };
+
+        assert_eq!(
+            attributes.locations(),
+            vec![
+                (3, 4, 0),
+                (4, 4, 16),
+                (5, 4, 32),
+                (6, 4, 48),
+                (9, 1, 80),
+                (10, 1, 84)
+            ]
+        );
+    }
+}
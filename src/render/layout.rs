@@ -0,0 +1,465 @@
+use std::borrow::Cow;
+use std::ops::Range;
+
+use nalgebra::Vector3;
+use web_sys::{WebGlProgram, WebGlRenderingContext as GL};
+
+use crate::canvas::is_webgl2;
+
+/// `HALF_FLOAT` is not part of WebGL1, only WebGL2 contexts accept it.
+const HALF_FLOAT: u32 = 0x140B;
+
+/// How a single component of an attribute is stored in the vertex buffer.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeType {
+    Float,
+    /// Widened to `Float` on WebGL1, which can't read half floats
+    HalfFloat,
+    Byte,
+    UnsignedByte,
+    Short,
+    UnsignedShort,
+}
+
+impl AttributeType {
+    pub fn size(self) -> usize {
+        match self {
+            AttributeType::Float => 4,
+            AttributeType::HalfFloat | AttributeType::Short | AttributeType::UnsignedShort => 2,
+            AttributeType::Byte | AttributeType::UnsignedByte => 1,
+        }
+    }
+
+    pub fn gl_type(self) -> u32 {
+        match self {
+            AttributeType::Float => GL::FLOAT,
+            AttributeType::HalfFloat => HALF_FLOAT,
+            AttributeType::Byte => GL::BYTE,
+            AttributeType::UnsignedByte => GL::UNSIGNED_BYTE,
+            AttributeType::Short => GL::SHORT,
+            AttributeType::UnsignedShort => GL::UNSIGNED_SHORT,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub name: String,
+    pub components: usize,
+    #[serde(default = "default_attribute_type")]
+    pub kind: AttributeType,
+    /// Map integer values onto [0, 1] (unsigned) or [-1, 1] (signed)
+    #[serde(default)]
+    pub normalized: bool,
+}
+
+fn default_attribute_type() -> AttributeType {
+    AttributeType::Float
+}
+
+impl VertexAttribute {
+    pub fn new<S: Into<String>>(
+        name: S,
+        components: usize,
+        kind: AttributeType,
+        normalized: bool,
+    ) -> VertexAttribute {
+        VertexAttribute {
+            name: name.into(),
+            components,
+            kind,
+            normalized,
+        }
+    }
+
+    pub fn float<S: Into<String>>(name: S, components: usize) -> VertexAttribute {
+        VertexAttribute::new(name, components, AttributeType::Float, false)
+    }
+
+    /// Size in the interleaved buffer. WebGL wants every attribute to start
+    /// on a 4 byte boundary, so smaller attributes are padded.
+    fn padded_size(&self) -> usize {
+        let size = self.components * self.kind.size();
+        (size + 3) & !3
+    }
+
+    fn write(&self, values: &[f32], out: &mut Vec<u8>) {
+        let start = out.len();
+
+        for &v in values {
+            match (self.kind, self.normalized) {
+                (AttributeType::Float, _) => out.extend_from_slice(&v.to_le_bytes()),
+                (AttributeType::HalfFloat, _) => {
+                    out.extend_from_slice(&f32_to_f16(v).to_le_bytes())
+                }
+                (AttributeType::Byte, true) => {
+                    out.push((v.max(-1.).min(1.) * 127.).round() as i8 as u8)
+                }
+                (AttributeType::Byte, false) => out.push(v as i8 as u8),
+                (AttributeType::UnsignedByte, true) => {
+                    out.push((v.max(0.).min(1.) * 255.).round() as u8)
+                }
+                (AttributeType::UnsignedByte, false) => out.push(v as u8),
+                (AttributeType::Short, true) => out.extend_from_slice(
+                    &((v.max(-1.).min(1.) * 32767.).round() as i16).to_le_bytes(),
+                ),
+                (AttributeType::Short, false) => out.extend_from_slice(&(v as i16).to_le_bytes()),
+                (AttributeType::UnsignedShort, true) => out.extend_from_slice(
+                    &((v.max(0.).min(1.) * 65535.).round() as u16).to_le_bytes(),
+                ),
+                (AttributeType::UnsignedShort, false) => {
+                    out.extend_from_slice(&(v as u16).to_le_bytes())
+                }
+            }
+        }
+
+        let padded_end = start + self.padded_size();
+        out.resize(padded_end, 0);
+    }
+}
+
+/// Describes how the vertices of a mesh are laid out in a single interleaved
+/// buffer.
+///
+/// Meshes keep their vertices as plain floats, one vertex after the other
+/// with the attributes in layout order. The layout packs them into the
+/// storage types it declares when uploading.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    attributes: Vec<VertexAttribute>,
+    offsets: Vec<usize>,
+    stride: usize,
+    floats_per_vertex: usize,
+}
+
+impl VertexLayout {
+    pub fn new(attributes: Vec<VertexAttribute>) -> VertexLayout {
+        let mut offsets = Vec::with_capacity(attributes.len());
+        let mut stride = 0;
+        for attribute in attributes.iter() {
+            offsets.push(stride);
+            stride += attribute.padded_size();
+        }
+
+        let floats_per_vertex = attributes.iter().map(|a| a.components).sum();
+
+        VertexLayout {
+            attributes,
+            offsets,
+            stride,
+            floats_per_vertex,
+        }
+    }
+
+    /// The layout as uploaded to `gl`. WebGL1 has no half float attributes,
+    /// they are widened to floats there.
+    pub fn for_context(&self, gl: &GL) -> Cow<VertexLayout> {
+        if is_webgl2(gl) {
+            Cow::Borrowed(self)
+        } else {
+            Cow::Owned(self.without_half_floats())
+        }
+    }
+
+    /// The same layout with floats in place of half floats.
+    pub fn without_half_floats(&self) -> VertexLayout {
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|attribute| match attribute.kind {
+                AttributeType::HalfFloat => VertexAttribute {
+                    kind: AttributeType::Float,
+                    ..attribute.clone()
+                },
+                _ => attribute.clone(),
+            })
+            .collect();
+
+        VertexLayout::new(attributes)
+    }
+
+    /// Just a 2D position, which is what the quad shader expects.
+    pub fn position_2d() -> VertexLayout {
+        VertexLayout::new(vec![VertexAttribute::float("a_position", 2)])
+    }
+
+    pub fn attributes(&self) -> &[VertexAttribute] {
+        &self.attributes[..]
+    }
+
+    /// Size in bytes of one packed vertex
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn floats_per_vertex(&self) -> usize {
+        self.floats_per_vertex
+    }
+
+    pub fn vertex_count(&self, vertices: &[f32]) -> usize {
+        if self.floats_per_vertex == 0 {
+            return 0;
+        }
+        vertices.len() / self.floats_per_vertex
+    }
+
+    /// Float offset of the attribute called `name` inside a vertex.
+    pub fn float_offset(&self, name: &str) -> Option<(usize, &VertexAttribute)> {
+        let mut offset = 0;
+        for attribute in self.attributes.iter() {
+            if attribute.name == name {
+                return Some((offset, attribute));
+            }
+            offset += attribute.components;
+        }
+        None
+    }
+
+    /// The positions of all vertices, padded to 3D, read from the attribute
+    /// called `name`.
+    pub fn positions(&self, vertices: &[f32], name: &str) -> Vec<Vector3<f32>> {
+        let (offset, attribute) = match self.float_offset(name) {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        let components = attribute.components.min(3);
+
+        vertices
+            .chunks(self.floats_per_vertex.max(1))
+            .filter(|vertex| vertex.len() == self.floats_per_vertex)
+            .map(|vertex| {
+                let mut position = Vector3::zeros();
+                for c in 0..components {
+                    position[c] = vertex[offset + c];
+                }
+                position
+            })
+            .collect()
+    }
+
+    /// The whole vertices touched by a range of floats.
+    pub fn vertex_range(&self, floats: Range<usize>) -> Range<usize> {
+        let fpv = self.floats_per_vertex.max(1);
+        (floats.start / fpv)..((floats.end + fpv - 1) / fpv)
+    }
+
+    pub fn pack(&self, vertices: &[f32]) -> Vec<u8> {
+        self.pack_range(vertices, 0..self.vertex_count(vertices))
+    }
+
+    /// Pack only the vertices in `range`, for partial buffer updates.
+    pub fn pack_range(&self, vertices: &[f32], range: Range<usize>) -> Vec<u8> {
+        let fpv = self.floats_per_vertex;
+        let end = range.end.min(self.vertex_count(vertices));
+        let mut out = Vec::with_capacity(end.saturating_sub(range.start) * self.stride);
+
+        for vertex in range.start..end {
+            let mut cursor = vertex * fpv;
+            for attribute in self.attributes.iter() {
+                attribute.write(&vertices[cursor..cursor + attribute.components], &mut out);
+                cursor += attribute.components;
+            }
+        }
+
+        out
+    }
+
+    /// Point the attributes of `program` at the currently bound array buffer.
+    /// Attributes the program doesn't use are skipped.
+    pub fn bind(&self, gl: &GL, program: &WebGlProgram) {
+        for (attribute, offset) in self.attributes.iter().zip(self.offsets.iter()) {
+            let location = gl.get_attrib_location(program, &attribute.name);
+            if location < 0 {
+                continue;
+            }
+
+            gl.enable_vertex_attrib_array(location as u32);
+            gl.vertex_attrib_pointer_with_i32(
+                location as u32,
+                attribute.components as i32,
+                attribute.kind.gl_type(),
+                attribute.normalized,
+                self.stride as i32,
+                *offset as i32,
+            );
+        }
+    }
+
+    /// Check the layout against the attributes the linked program actually
+    /// reads, so a mismatch shows up as a message instead of garbage on screen.
+    /// Attributes named in `external` are supplied from elsewhere, such as
+    /// per-instance data, and don't have to be in the layout.
+    pub fn validate(
+        &self,
+        gl: &GL,
+        program: &WebGlProgram,
+        external: &[&str],
+    ) -> Result<(), String> {
+        let active = gl
+            .get_program_parameter(program, GL::ACTIVE_ATTRIBUTES)
+            .as_f64()
+            .unwrap_or(0.) as u32;
+
+        for idx in 0..active {
+            let info = match gl.get_active_attrib(program, idx) {
+                Some(info) => info,
+                None => continue,
+            };
+
+            let name = info.name();
+            if name.starts_with("gl_") || external.contains(&name.as_str()) {
+                continue;
+            }
+
+            let attribute = self
+                .attributes
+                .iter()
+                .find(|a| a.name == name)
+                .ok_or_else(|| format!(r#"Attribute '{}' is missing from the layout"#, name))?;
+
+            let expected = match component_count(info.type_()) {
+                Some(count) => count,
+                None => continue,
+            };
+            if attribute.components > expected {
+                return Err(format!(
+                    r#"Attribute '{}' has {} components but the shader reads {}"#,
+                    name, attribute.components, expected
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn component_count(gl_type: u32) -> Option<usize> {
+    match gl_type {
+        GL::FLOAT => Some(1),
+        GL::FLOAT_VEC2 => Some(2),
+        GL::FLOAT_VEC3 => Some(3),
+        GL::FLOAT_VEC4 => Some(4),
+        _ => None,
+    }
+}
+
+/// Convert to IEEE 754 half precision, truncating the mantissa.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        return sign | (mantissa >> (14 - exp) as u32) as u16;
+    }
+
+    sign | ((exp as u16) << 10) | (mantissa >> 13) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f32_to_f16_converts_normal_numbers() {
+        assert_eq!(f32_to_f16(0.), 0x0000);
+        assert_eq!(f32_to_f16(-0.), 0x8000);
+        assert_eq!(f32_to_f16(1.), 0x3c00);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(-2.), 0xc000);
+        assert_eq!(f32_to_f16(65504.), 0x7bff);
+    }
+
+    #[test]
+    fn f32_to_f16_handles_special_values() {
+        assert_eq!(f32_to_f16(std::f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(std::f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_f16(std::f32::NAN) & 0x7e00, 0x7e00);
+        // Too large for half precision
+        assert_eq!(f32_to_f16(65536.), 0x7c00);
+    }
+
+    #[test]
+    fn f32_to_f16_keeps_subnormals() {
+        assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(2f32.powi(-30)), 0x0000);
+    }
+
+    #[test]
+    fn pack_interleaves_and_pads_attributes() {
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::float("a_position", 3),
+            VertexAttribute::new("a_color", 3, AttributeType::UnsignedByte, true),
+        ]);
+        assert_eq!(layout.stride(), 16);
+
+        let packed = layout.pack(&[1., 2., 3., 1., 0., 0.5]);
+        let mut expected = Vec::new();
+        for v in [1f32, 2., 3.].iter() {
+            expected.extend_from_slice(&v.to_le_bytes());
+        }
+        expected.extend_from_slice(&[255, 0, 128, 0]);
+        assert_eq!(packed, expected);
+    }
+
+    #[test]
+    fn half_floats_widen_to_floats() {
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::new("a_position", 3, AttributeType::HalfFloat, false),
+            VertexAttribute::new("a_color", 4, AttributeType::UnsignedByte, true),
+        ]);
+        let widened = layout.without_half_floats();
+
+        assert_eq!(layout.stride(), 12);
+        assert_eq!(widened.stride(), 16);
+        assert_eq!(widened.attributes()[0].kind, AttributeType::Float);
+        assert_eq!(widened.attributes()[1], layout.attributes()[1]);
+
+        let packed = widened.pack(&[1., 2., 3., 1., 0., 0.5, 0.]);
+        assert_eq!(&packed[..4], &1f32.to_le_bytes());
+        assert_eq!(packed.len(), 16);
+    }
+
+    #[test]
+    fn vertex_range_covers_whole_vertices() {
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::float("a_position", 3),
+            VertexAttribute::float("a_normal", 3),
+        ]);
+
+        assert_eq!(layout.vertex_range(0..6), 0..1);
+        assert_eq!(layout.vertex_range(4..8), 0..2);
+        assert_eq!(layout.vertex_range(12..13), 2..3);
+    }
+
+    #[test]
+    fn positions_read_the_named_attribute() {
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::float("a_normal", 3),
+            VertexAttribute::float("a_position", 2),
+        ]);
+        let positions = layout.positions(&[0., 0., 1., 4., 5., 0., 1., 0., 6., 7.], "a_position");
+
+        assert_eq!(
+            positions,
+            vec![Vector3::new(4., 5., 0.), Vector3::new(6., 7., 0.)]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use blender_mesh::BlenderMesh;
+use chal_engine::assets::Assets;
+use chal_engine::shader::ShaderKind;
+
+use crate::render::component::Mesh;
+use crate::render::draw::Indices;
+use crate::render::layout::{VertexAttribute, VertexLayout};
+
+/// Meshes that can be referred to by name, such as the levels of a
+/// `LodGroup`. Each one is uploaded once no matter how many entities use it.
+#[derive(Default)]
+pub struct MeshLibrary {
+    meshes: HashMap<String, Mesh>,
+}
+
+impl MeshLibrary {
+    pub fn new() -> MeshLibrary {
+        MeshLibrary::default()
+    }
+
+    /// Library meshes are never edited in place, their bounds are fitted
+    /// here once.
+    pub fn insert(&mut self, mut mesh: Mesh) {
+        mesh.refresh_bounds();
+        self.meshes.insert(mesh.name().to_string(), mesh);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Mesh> {
+        self.meshes.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Mesh> {
+        self.meshes.remove(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.meshes.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.meshes.keys()
+    }
+
+    /// Add a mesh from the downloaded assets under the same name. Returns
+    /// `false` when there is no such asset.
+    pub fn import(&mut self, assets: &Assets, name: &str, shader_kind: ShaderKind) -> bool {
+        match assets.get_mesh(name) {
+            Some(blender_mesh) => {
+                self.insert(Mesh::from_blender(name, blender_mesh, shader_kind));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Mesh {
+    /// Interleave the positions, normals and texture coordinates of an
+    /// exported Blender mesh. Texture coordinates are left out unless there
+    /// is one for every vertex, with them tangents are generated for normal
+    /// maps.
+    pub fn from_blender<S: Into<String>>(
+        name: S,
+        blender_mesh: &BlenderMesh,
+        shader_kind: ShaderKind,
+    ) -> Mesh {
+        let positions = &blender_mesh.vertex_positions;
+        let normals = &blender_mesh.vertex_normals;
+        let uvs = blender_mesh
+            .vertex_uvs
+            .as_ref()
+            .filter(|uvs| uvs.len() / 2 == positions.len() / 3);
+
+        let mut vertices = Vec::with_capacity(positions.len() * 3);
+        for (vertex, (position, normal)) in positions.chunks(3).zip(normals.chunks(3)).enumerate() {
+            vertices.extend_from_slice(position);
+            vertices.extend_from_slice(normal);
+            // Blender starts v at the bottom of the image, textures are
+            // uploaded from the top row
+            if let Some(uvs) = uvs {
+                vertices.extend_from_slice(&[uvs[vertex * 2], 1. - uvs[vertex * 2 + 1]]);
+            }
+        }
+
+        let mut attributes = vec![
+            VertexAttribute::float("a_position", 3),
+            VertexAttribute::float("a_normal", 3),
+        ];
+        if uvs.is_some() {
+            attributes.push(VertexAttribute::float("a_uv", 2));
+        }
+
+        Mesh::new(name, vertices, shader_kind)
+            .with_layout(VertexLayout::new(attributes))
+            .with_indices(Indices::U16(blender_mesh.vertex_position_indices.clone()))
+            .with_tangents()
+    }
+}
@@ -0,0 +1,141 @@
+use std::cmp::Ordering;
+
+use specs::{Component, VecStorage};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LodLevel {
+    /// Name of the mesh in the `MeshLibrary`
+    pub mesh: String,
+    /// Smallest screen size, as a fraction of the viewport height, at which
+    /// this level is still used
+    pub screen_size: f32,
+}
+
+/// Picks one of several meshes depending on how large the entity appears on
+/// screen. An entity with a `LodGroup` doesn't need a `Mesh`, one it has
+/// anyway is not drawn.
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct LodGroup {
+    levels: Vec<LodLevel>,
+    /// Width of the cross-fade band above each threshold, relative to the
+    /// threshold. Zero switches levels instantly.
+    pub fade_width: f32,
+}
+
+impl LodGroup {
+    /// Levels are sorted from most to least detailed. Below the smallest
+    /// threshold the entity isn't drawn at all.
+    pub fn new(mut levels: Vec<LodLevel>) -> LodGroup {
+        levels.sort_by(|a, b| {
+            b.screen_size
+                .partial_cmp(&a.screen_size)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        LodGroup {
+            levels,
+            fade_width: 0.,
+        }
+    }
+
+    pub fn with_fade_width(mut self, fade_width: f32) -> LodGroup {
+        self.fade_width = fade_width;
+        self
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        &self.levels[..]
+    }
+
+    /// Fraction of the viewport height covered by a sphere of `radius` at
+    /// `distance`, where `focal` is element (1, 1) of the projection matrix.
+    pub fn screen_size(radius: f32, distance: f32, focal: f32) -> f32 {
+        if distance <= radius {
+            return std::f32::INFINITY;
+        }
+        radius * focal / distance
+    }
+
+    /// The levels to draw with their dither fade. A fade in [0, 1] keeps that
+    /// fraction of the pixels, a fade in (1, 2] keeps the complement of
+    /// `fade - 1`, so two levels crossing over never cover the same pixel.
+    pub fn select(&self, screen_size: f32) -> Vec<(usize, f32)> {
+        let idx = match self.levels.iter().position(|l| screen_size >= l.screen_size) {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+
+        let threshold = self.levels[idx].screen_size;
+        if self.fade_width > 0. && threshold > 0. {
+            let t = (screen_size - threshold) / (threshold * self.fade_width);
+            if t < 1. {
+                let mut selected = vec![(idx, t)];
+                if idx + 1 < self.levels.len() {
+                    selected.push((idx + 1, 1. + t));
+                }
+                return selected;
+            }
+        }
+
+        vec![(idx, 1.)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group() -> LodGroup {
+        let level = |mesh: &str, screen_size| LodLevel {
+            mesh: mesh.to_string(),
+            screen_size,
+        };
+        LodGroup::new(vec![
+            level("low", 0.1),
+            level("high", 0.5),
+            level("mid", 0.25),
+        ])
+    }
+
+    #[test]
+    fn levels_are_sorted_by_detail() {
+        let names: Vec<&str> = group()
+            .levels()
+            .iter()
+            .map(|level| level.mesh.as_str())
+            .collect();
+        assert_eq!(names, vec!["high", "mid", "low"]);
+    }
+
+    #[test]
+    fn select_picks_the_level_for_the_screen_size() {
+        let group = group();
+
+        assert_eq!(group.select(std::f32::INFINITY), vec![(0, 1.)]);
+        assert_eq!(group.select(0.3), vec![(1, 1.)]);
+        assert_eq!(group.select(0.1), vec![(2, 1.)]);
+        assert!(group.select(0.05).is_empty());
+    }
+
+    #[test]
+    fn select_cross_fades_above_a_threshold() {
+        let group = group().with_fade_width(0.5);
+        let selected = group.select(0.3125);
+
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0], (1, 0.5));
+        assert_eq!(selected[1], (2, 1.5));
+    }
+
+    #[test]
+    fn nan_thresholds_do_not_panic() {
+        let level = LodLevel {
+            mesh: "broken".to_string(),
+            screen_size: std::f32::NAN,
+        };
+        let group = LodGroup::new(vec![level.clone(), level]);
+
+        assert_eq!(group.levels().len(), 2);
+    }
+}
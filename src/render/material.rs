@@ -0,0 +1,106 @@
+use specs::{Component, VecStorage};
+
+use crate::render::blend::BlendMode;
+
+/// Surface properties of a mesh.
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+#[serde(default)]
+pub struct Material {
+    /// RGBA, passed to the shader per instance
+    pub color: [f32; 4],
+    /// How the mesh is blended over what is behind it, opaque when `None`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blend: Option<BlendMode>,
+    /// Whether the mesh hides what is drawn behind it afterwards. By
+    /// default only opaque meshes do
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth_write: Option<bool>,
+    /// How much of the skybox is mirrored on the surface, from 0 to 1. Only
+    /// meshes with an `a_normal` attribute reflect. `pbr` materials reflect
+    /// by their roughness instead
+    #[serde(skip_serializing_if = "is_zero")]
+    pub reflectivity: f32,
+    /// Shade the mesh physically based, lit by the `Light`s of the scene
+    /// and the skybox. Flat `color` when `None`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pbr: Option<PbrMaterial>,
+}
+
+impl Material {
+    pub fn new(color: [f32; 4]) -> Material {
+        Material {
+            color,
+            blend: None,
+            depth_write: None,
+            reflectivity: 0.,
+            pbr: None,
+        }
+    }
+
+    pub fn writes_depth(&self) -> bool {
+        self.depth_write.unwrap_or_else(|| self.blend.is_none())
+    }
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material::new([1., 1., 1., 1.])
+    }
+}
+
+/// The metallic-roughness model. `color` of the `Material` is the albedo,
+/// and the maps are names of textures in the `TextureLibrary`, read
+/// through the `a_uv` attribute of the mesh. The maps multiply the factors,
+/// a missing map leaves them alone.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PbrMaterial {
+    /// 0 for dielectrics, 1 for metals
+    pub metallic: f32,
+    /// From 0 for a mirror to 1 for a fully diffuse surface
+    pub roughness: f32,
+    /// RGB light given off, black by default
+    pub emissive: [f32; 3],
+    /// How much of the occlusion map darkens the environment lighting
+    pub occlusion_strength: f32,
+    /// Scales the bumps of the normal map
+    pub normal_scale: f32,
+    /// Multiplies `color`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub albedo_map: Option<String>,
+    /// Tangent space normals, only used on meshes with an `a_tangent`
+    /// attribute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<String>,
+    /// Roughness in the green channel and metallic in the blue one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metallic_roughness_map: Option<String>,
+    /// Ambient occlusion in the red channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occlusion_map: Option<String>,
+    /// Multiplies `emissive`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emissive_map: Option<String>,
+}
+
+impl Default for PbrMaterial {
+    fn default() -> PbrMaterial {
+        PbrMaterial {
+            metallic: 0.,
+            roughness: 0.5,
+            emissive: [0., 0., 0.],
+            occlusion_strength: 1.,
+            normal_scale: 1.,
+            albedo_map: None,
+            normal_map: None,
+            metallic_roughness_map: None,
+            occlusion_map: None,
+            emissive_map: None,
+        }
+    }
+}
+
+fn is_zero(value: &f32) -> bool {
+    *value == 0.
+}
@@ -1,5 +1,23 @@
 mod mesh;
+pub mod blend;
+mod buffer;
+mod debug_lines;
+pub mod draw;
+pub mod fog;
+mod id_buffer;
+mod instancing;
+pub mod layout;
+pub mod library;
+pub mod lod;
+mod registry;
 pub mod component;
+pub mod material;
+mod particles;
+mod pbr;
+mod queue;
+pub mod skybox;
+pub mod tangents;
+pub mod texture;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -14,6 +32,7 @@ use web_sys::WebGlRenderingContext as GL;
 
 use crate::shader::{WebShaderSystem, WebShader};
 use crate::render::mesh::{MeshRenderOpts, NonSkinnedMesh};
+use crate::canvas::vertex_array_objects;
 use crate::engine::GLC;
 
 pub struct Renderer {
@@ -26,10 +45,7 @@ impl Renderer {
         let gl = &GLC.contexts.borrow()[0];
         let shader_sys = WebShaderSystem::new(&gl);
 
-        let oes_vao_ext = gl
-            .get_extension("OES_vertex_array_object")
-            .expect("EOS ext")
-            .expect("EOS ext");
+        let oes_vao_ext = vertex_array_objects(gl).expect("Vertex array objects");
 
         let vao_ext = VaoExtension {
             oes_vao_ext,
@@ -124,3 +140,13 @@ impl Renderer {
         Reflect::apply(&bind_vao_ext, oes_vao_ext, &args).expect("Bound VAO");
     }
 }
+
+/// Renderables are uploaded through the `Render` trait into the renderer's
+/// VAOs, their buffers go with it.
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        if let Some(gl) = GLC.contexts.borrow().get(0) {
+            component::delete_render_buffers(gl);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use chal_engine::render::Vao;
+use chal_engine::shader::Shader;
+use wasm_bindgen::JsValue;
+use web_sys::{WebGlBuffer, WebGlRenderingContext as GL};
+
+use crate::particles::{Particle, ParticleEmitter};
+use crate::render::blend::BlendMode;
+use crate::render::buffer::{self, BufferUsage, StreamBuffer};
+use crate::render::texture::{TextureCache, TextureLibrary};
+use crate::shader::WebShader;
+
+static PARTICLE_VS: &'static str = include_str!("../particle-vertex.glsl");
+static PARTICLE_FS: &'static str = include_str!("../particle-fragment.glsl");
+
+/// Floats per vertex: the center of the particle, the corner of the quad,
+/// texture coordinates, an RGBA color and the size.
+const VERTEX_FLOATS: usize = 12;
+
+/// Floats per particle, a quad of four vertices.
+const QUAD_FLOATS: usize = VERTEX_FLOATS * 4;
+
+/// Particles per draw call at most. More are split over several draws.
+const MAX_PARTICLES: usize = 4096;
+
+/// Attribute name, number of floats and offset in floats within a vertex.
+const ATTRIBUTES: [(&str, i32, i32); 5] = [
+    ("a_center", 3, 0),
+    ("a_corner", 2, 3),
+    ("a_uv", 2, 5),
+    ("a_color", 4, 7),
+    ("a_size", 1, 11),
+];
+
+const CORNERS: [[f32; 2]; 4] = [[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]];
+
+/// Particles that are drawn together.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ParticleBatchKey {
+    blend: BlendMode,
+    texture: Option<String>,
+}
+
+/// Draws the particles of every emitter as quads facing the camera,
+/// streaming their vertices anew every frame.
+pub struct ParticleRenderer {
+    shader: WebShader,
+    /// Holds the index buffer and the attribute arrays
+    pub vao: Vao<js_sys::Object>,
+    vertices: StreamBuffer,
+    /// Keeps the quads indexed into `vertices` alive
+    _indices: WebGlBuffer,
+    locations: [i32; 5],
+}
+
+impl ParticleRenderer {
+    /// Expects `vao` to be bound, it is set up for drawing particles.
+    pub fn new(gl: &GL, vao: Vao<js_sys::Object>) -> Result<ParticleRenderer, JsValue> {
+        let shader = WebShader::new(gl, PARTICLE_VS, PARTICLE_FS)?;
+
+        let mut indices = Vec::with_capacity(MAX_PARTICLES * 6);
+        for quad in 0..MAX_PARTICLES as u16 {
+            let first = quad * 4;
+            indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+        let index_buffer = buffer::create_u16_index_buffer(gl, &indices, BufferUsage::Static);
+
+        let mut locations = [-1; 5];
+        for (location, &(name, _, _)) in locations.iter_mut().zip(ATTRIBUTES.iter()) {
+            *location = gl.get_attrib_location(&shader.program, name);
+            if *location >= 0 {
+                gl.enable_vertex_attrib_array(*location as u32);
+            }
+        }
+
+        Ok(ParticleRenderer {
+            shader,
+            vao,
+            vertices: StreamBuffer::new(gl, MAX_PARTICLES * QUAD_FLOATS),
+            _indices: index_buffer,
+            locations,
+        })
+    }
+
+    /// Draw the particles of `emitters` after the opaque geometry, with one
+    /// draw per blend mode and texture. They are depth tested but don't
+    /// write depth, particles whose blending depends on the order are
+    /// sorted back to front. Expects `vao` to be bound and leaves the
+    /// program of the particles in use. Returns the number of draw calls
+    /// issued.
+    pub fn draw<'a, I>(
+        &mut self,
+        gl: &GL,
+        emitters: I,
+        textures: &mut TextureCache,
+        library: &TextureLibrary,
+        view: &mut [f32],
+        projection: &mut [f32],
+    ) -> u32
+    where
+        I: IntoIterator<Item = &'a ParticleEmitter>,
+    {
+        let mut batches: HashMap<ParticleBatchKey, Vec<(f32, [f32; QUAD_FLOATS])>> = HashMap::new();
+        for emitter in emitters {
+            if emitter.particles().is_empty() {
+                continue;
+            }
+
+            let key = ParticleBatchKey {
+                blend: emitter.blend,
+                texture: emitter.texture.clone(),
+            };
+            let quads = batches.entry(key).or_insert_with(Vec::new);
+            for particle in emitter.particles() {
+                let p = particle.position;
+                // Along the view direction, the view matrix is column major
+                let distance = -(view[2] * p.x + view[6] * p.y + view[10] * p.z + view[14]);
+                quads.push((distance, quad(emitter, particle)));
+            }
+        }
+        if batches.is_empty() {
+            return 0;
+        }
+
+        gl.use_program(Some(&self.shader.program));
+        let view_uni = self.shader.get_uniform_location(gl, "u_view");
+        let projection_uni = self.shader.get_uniform_location(gl, "u_projection");
+        let texture_uni = self.shader.get_uniform_location(gl, "u_texture");
+        let textured_uni = self.shader.get_uniform_location(gl, "u_textured");
+        gl.uniform_matrix4fv_with_f32_array(view_uni.as_ref(), false, view);
+        gl.uniform_matrix4fv_with_f32_array(projection_uni.as_ref(), false, projection);
+        gl.uniform1i(texture_uni.as_ref(), 0);
+        gl.depth_mask(false);
+
+        let mut draw_calls = 0;
+        for (key, mut quads) in batches {
+            if key.blend.is_order_dependent() {
+                quads.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+            }
+
+            // Particles with a texture that isn't loaded (yet) are dots
+            let texture = key
+                .texture
+                .as_ref()
+                .and_then(|name| textures.get(gl, library, name));
+            gl.active_texture(GL::TEXTURE0);
+            gl.bind_texture(GL::TEXTURE_2D, texture);
+            gl.uniform1i(textured_uni.as_ref(), texture.is_some() as i32);
+            key.blend.apply(gl);
+
+            let data: Vec<f32> = quads
+                .iter()
+                .flat_map(|(_, quad)| quad.iter().cloned())
+                .collect();
+            for chunk in data.chunks(MAX_PARTICLES * QUAD_FLOATS) {
+                let slice = self
+                    .vertices
+                    .push(gl, chunk)
+                    .expect("Particle chunk fits the stream buffer");
+                self.bind_attributes(gl, slice.byte_offset);
+
+                let index_count = chunk.len() / QUAD_FLOATS * 6;
+                gl.draw_elements_with_i32(GL::TRIANGLES, index_count as i32, GL::UNSIGNED_SHORT, 0);
+                draw_calls += 1;
+            }
+        }
+
+        gl.disable(GL::BLEND);
+        gl.depth_mask(true);
+
+        draw_calls
+    }
+
+    /// Point the attributes at vertices in the bound array buffer, starting
+    /// at `byte_offset`.
+    fn bind_attributes(&self, gl: &GL, byte_offset: i32) {
+        let stride = (VERTEX_FLOATS * 4) as i32;
+
+        for (&location, &(_, size, offset)) in self.locations.iter().zip(ATTRIBUTES.iter()) {
+            if location >= 0 {
+                gl.vertex_attrib_pointer_with_i32(
+                    location as u32,
+                    size,
+                    GL::FLOAT,
+                    false,
+                    stride,
+                    byte_offset + offset * 4,
+                );
+            }
+        }
+    }
+}
+
+/// The four vertices of `particle` as `emitter` looks at this point of its
+/// life.
+fn quad(emitter: &ParticleEmitter, particle: &Particle) -> [f32; QUAD_FLOATS] {
+    let life = particle.life();
+    let color = emitter
+        .color_over_life
+        .sample(life)
+        .unwrap_or([1., 1., 1., 1.]);
+    let size = emitter.size_over_life.sample(life).unwrap_or(1.);
+    let frame = emitter.atlas.frame(particle.age, particle.lifetime);
+    let [u0, v0, u1, v1] = emitter.atlas.uv_rect(frame);
+    let center = particle.position;
+
+    let mut quad = [0.; QUAD_FLOATS];
+    for (vertex, corner) in quad.chunks_mut(VERTEX_FLOATS).zip(CORNERS.iter()) {
+        // Texture rows go from the top down
+        let u = u0 + (u1 - u0) * (corner[0] + 0.5);
+        let v = v1 + (v0 - v1) * (corner[1] + 0.5);
+
+        vertex[0..3].copy_from_slice(&[center.x, center.y, center.z]);
+        vertex[3..5].copy_from_slice(corner);
+        vertex[5..7].copy_from_slice(&[u, v]);
+        vertex[7..11].copy_from_slice(&color);
+        vertex[11] = size;
+    }
+    quad
+}
@@ -0,0 +1,308 @@
+use std::f32::consts::PI;
+
+use chal_engine::shader::Shader;
+use nalgebra::{Matrix4, Vector3};
+use wasm_bindgen::JsValue;
+use web_sys::{WebGlRenderingContext as GL, WebGlTexture};
+
+use crate::light::Light;
+use crate::render::material::PbrMaterial;
+use crate::render::texture::{self, CubeMapData, TextureCache, TextureData, TextureLibrary};
+use crate::shader::WebShader;
+
+/// Lights the mesh shader takes into account, the first ones found win.
+pub const MAX_LIGHTS: usize = 4;
+
+/// Width and height of the BRDF lookup table.
+const BRDF_LUT_SIZE: u32 = 32;
+
+/// Samples per texel when integrating the BRDF or prefiltering the
+/// environment.
+const SAMPLES: u32 = 64;
+
+// Texture units of the mesh shader, the environment sits on unit 1
+const ALBEDO_UNIT: u32 = 0;
+const BRDF_LUT_UNIT: u32 = 2;
+const NORMAL_UNIT: u32 = 3;
+const METALLIC_ROUGHNESS_UNIT: u32 = 4;
+const OCCLUSION_UNIT: u32 = 5;
+const EMISSIVE_UNIT: u32 = 6;
+
+/// The textures every lit material shares: the BRDF lookup table and the
+/// stand-ins for maps a material doesn't have.
+pub struct PbrTextures {
+    brdf_lut: WebGlTexture,
+    /// Leaves the factor a map multiplies alone
+    white: WebGlTexture,
+    /// Points straight out of the surface
+    flat_normal: WebGlTexture,
+}
+
+impl PbrTextures {
+    /// Integrates the BRDF lookup table, which takes a moment.
+    pub fn new(gl: &GL) -> Result<PbrTextures, JsValue> {
+        let create = |data: &TextureData| {
+            let texture = gl
+                .create_texture()
+                .ok_or_else(|| JsValue::from_str("Could not create texture"))?;
+            texture::upload(gl, &texture, data);
+            Ok::<_, JsValue>(texture)
+        };
+        let pixel = |rgba: [u8; 4]| TextureData::new(1, 1, rgba.to_vec()).expect("1x1 texture");
+
+        Ok(PbrTextures {
+            brdf_lut: create(&brdf_lut(BRDF_LUT_SIZE))?,
+            white: create(&pixel([255, 255, 255, 255]))?,
+            flat_normal: create(&pixel([128, 128, 255, 255]))?,
+        })
+    }
+
+    /// Point the samplers of the mesh `shader`, which has to be in use, at
+    /// their texture units and bind the lookup table. `environment_lod` is
+    /// the blurriest mipmap level of the environment.
+    pub fn bind(&self, gl: &GL, shader: &WebShader, environment_lod: f32) {
+        let uniform = |name: &str| shader.get_uniform_location(gl, name);
+
+        let samplers = [
+            ("u_albedo_map", ALBEDO_UNIT),
+            ("u_brdf_lut", BRDF_LUT_UNIT),
+            ("u_normal_map", NORMAL_UNIT),
+            ("u_metallic_roughness_map", METALLIC_ROUGHNESS_UNIT),
+            ("u_occlusion_map", OCCLUSION_UNIT),
+            ("u_emissive_map", EMISSIVE_UNIT),
+        ];
+        for &(name, unit) in samplers.iter() {
+            gl.uniform1i(uniform(name).as_ref(), unit as i32);
+        }
+        gl.uniform1f(uniform("u_environment_lod").as_ref(), environment_lod);
+
+        gl.active_texture(GL::TEXTURE0 + BRDF_LUT_UNIT);
+        gl.bind_texture(GL::TEXTURE_2D, Some(&self.brdf_lut));
+        gl.active_texture(GL::TEXTURE0);
+    }
+
+    /// Set the factors of `material` and bind its maps, uploading them
+    /// first when needed. Without a material the mesh is drawn unlit.
+    pub fn bind_material(
+        &self,
+        gl: &GL,
+        shader: &WebShader,
+        material: Option<&PbrMaterial>,
+        cache: &mut TextureCache,
+        library: &TextureLibrary,
+    ) {
+        let uniform = |name: &str| shader.get_uniform_location(gl, name);
+
+        let material = match material {
+            Some(material) => material,
+            None => return set_unlit(gl, shader),
+        };
+
+        gl.uniform1i(uniform("u_lit").as_ref(), 1);
+        gl.uniform4f(
+            uniform("u_pbr_factors").as_ref(),
+            material.metallic,
+            material.roughness,
+            material.occlusion_strength,
+            material.normal_scale,
+        );
+        let [r, g, b] = material.emissive;
+        gl.uniform3f(uniform("u_emissive").as_ref(), r, g, b);
+
+        let maps = [
+            (ALBEDO_UNIT, &material.albedo_map, &self.white),
+            (NORMAL_UNIT, &material.normal_map, &self.flat_normal),
+            (
+                METALLIC_ROUGHNESS_UNIT,
+                &material.metallic_roughness_map,
+                &self.white,
+            ),
+            (OCCLUSION_UNIT, &material.occlusion_map, &self.white),
+            (EMISSIVE_UNIT, &material.emissive_map, &self.white),
+        ];
+        for &(unit, name, default) in maps.iter() {
+            // A map that isn't in the library yet is left out
+            let texture = name
+                .as_ref()
+                .and_then(|name| cache.get(gl, library, name))
+                .unwrap_or(default);
+            gl.active_texture(GL::TEXTURE0 + unit);
+            gl.bind_texture(GL::TEXTURE_2D, Some(texture));
+        }
+        gl.active_texture(GL::TEXTURE0);
+    }
+}
+
+/// Draw with the mesh `shader`, which has to be in use, without lighting.
+pub fn set_unlit(gl: &GL, shader: &WebShader) {
+    gl.uniform1i(shader.get_uniform_location(gl, "u_lit").as_ref(), 0);
+}
+
+/// Set the light uniforms of the mesh `shader`, which has to be in use, from
+/// the lights and their world transforms. Lights past `MAX_LIGHTS` are
+/// ignored.
+pub fn set_lights(gl: &GL, shader: &WebShader, lights: &[(Light, Matrix4<f32>)]) {
+    let uniform = |name: &str| shader.get_uniform_location(gl, name);
+
+    let mut positions = [0.; MAX_LIGHTS * 4];
+    let mut colors = [0.; MAX_LIGHTS * 4];
+    // Not a spot light unless the cosine of its angle is set
+    let mut spots = [-2.; MAX_LIGHTS * 4];
+
+    let count = lights.len().min(MAX_LIGHTS);
+    for (i, (light, transform)) in lights.iter().take(MAX_LIGHTS).enumerate() {
+        let column =
+            |j: usize| Vector3::new(transform[(0, j)], transform[(1, j)], transform[(2, j)]);
+        let forward = (-column(2))
+            .try_normalize(1.0e-6)
+            .unwrap_or_else(|| -Vector3::z());
+        let origin = column(3);
+
+        let (position, range) = match *light {
+            Light::Directional { .. } => ([forward.x, forward.y, forward.z, 0.], 0.),
+            Light::Point { range, .. } => ([origin.x, origin.y, origin.z, 1.], range),
+            Light::Spot { range, angle, .. } => {
+                spots[i * 4..i * 4 + 4].copy_from_slice(&[
+                    forward.x,
+                    forward.y,
+                    forward.z,
+                    angle.cos(),
+                ]);
+                ([origin.x, origin.y, origin.z, 1.], range)
+            }
+        };
+        positions[i * 4..i * 4 + 4].copy_from_slice(&position);
+
+        let [r, g, b] = light.color();
+        let intensity = light.intensity();
+        colors[i * 4..i * 4 + 4].copy_from_slice(&[
+            r * intensity,
+            g * intensity,
+            b * intensity,
+            range.max(1.0e-3),
+        ]);
+    }
+
+    gl.uniform1i(uniform("u_light_count").as_ref(), count as i32);
+    gl.uniform4fv_with_f32_array(uniform("u_light_position").as_ref(), &mut positions);
+    gl.uniform4fv_with_f32_array(uniform("u_light_color").as_ref(), &mut colors);
+    gl.uniform4fv_with_f32_array(uniform("u_light_spot").as_ref(), &mut spots);
+}
+
+/// Blur `environment` into `levels` mipmaps for the image based lighting.
+/// Level `n` is half the size of the one before and reflects off a surface
+/// with a roughness of `n / (levels - 1)`, the last one stands in for the
+/// diffuse irradiance.
+pub fn prefilter(environment: &CubeMapData, levels: u32) -> Vec<CubeMapData> {
+    // Blurred in linear space, like the light it is
+    let sample = |direction: Vector3<f32>| {
+        let [r, g, b, _] = environment.sample(direction);
+        Vector3::new(r, g, b).map(|channel| channel.powf(2.2))
+    };
+
+    (0..levels)
+        .map(|level| {
+            let size = (environment.size >> level).max(1);
+            let roughness = level as f32 / (levels - 1).max(1) as f32;
+            if level == 0 {
+                return CubeMapData::from_fn(size, |direction| environment.sample(direction));
+            }
+
+            let halfways = ggx_samples(roughness);
+            CubeMapData::from_fn(size, |direction| {
+                // The reflection straight back, viewed head on
+                let normal = direction.normalize();
+                let (tangent, bitangent) = basis(&normal);
+
+                let mut color = Vector3::zeros();
+                let mut weight = 0.;
+                for halfway in halfways.iter() {
+                    let halfway = tangent * halfway.x + bitangent * halfway.y + normal * halfway.z;
+                    let light = halfway * 2. * normal.dot(&halfway) - normal;
+                    let n_dot_l = normal.dot(&light);
+                    if n_dot_l > 0. {
+                        color += sample(light) * n_dot_l;
+                        weight += n_dot_l;
+                    }
+                }
+
+                let color = color / weight.max(1.0e-6);
+                let to_srgb = |channel: f32| channel.powf(1. / 2.2);
+                [to_srgb(color.x), to_srgb(color.y), to_srgb(color.z), 1.]
+            })
+        })
+        .collect()
+}
+
+/// The scale (red) and bias (green) of the specular reflectance of the split
+/// sum approximation, by the cosine of the view angle from left to right and
+/// the roughness from the first row to the last.
+fn brdf_lut(size: u32) -> TextureData {
+    let mut pixels = Vec::with_capacity(size as usize * size as usize * 4);
+
+    for row in 0..size {
+        let roughness = (row as f32 + 0.5) / size as f32;
+        let halfways = ggx_samples(roughness);
+        // The remapping of the geometry term for image based lighting
+        let k = roughness * roughness / 2.;
+
+        for column in 0..size {
+            let n_dot_v = (column as f32 + 0.5) / size as f32;
+            let view = Vector3::new((1. - n_dot_v * n_dot_v).sqrt(), 0., n_dot_v);
+
+            let (mut scale, mut bias) = (0., 0.);
+            for halfway in halfways.iter() {
+                let light = halfway * 2. * view.dot(halfway) - view;
+                let n_dot_l = light.z.max(0.);
+                if n_dot_l <= 0. {
+                    continue;
+                }
+
+                let n_dot_h = halfway.z.max(0.);
+                let v_dot_h = view.dot(halfway).max(0.);
+                let geometry =
+                    n_dot_v / (n_dot_v * (1. - k) + k) * n_dot_l / (n_dot_l * (1. - k) + k);
+                let visibility = geometry * v_dot_h / (n_dot_h * n_dot_v).max(1.0e-6);
+                let fresnel = (1. - v_dot_h).powi(5);
+                scale += (1. - fresnel) * visibility;
+                bias += fresnel * visibility;
+            }
+
+            let to_byte =
+                |value: f32| (value / SAMPLES as f32 * 255.).max(0.).min(255.).round() as u8;
+            pixels.extend_from_slice(&[to_byte(scale), to_byte(bias), 0, 255]);
+        }
+    }
+
+    TextureData::new(size, size, pixels).expect("BRDF lookup table")
+}
+
+/// Halfway vectors around +Z, importance sampled from the GGX distribution
+/// of `roughness` over the Hammersley sequence.
+fn ggx_samples(roughness: f32) -> Vec<Vector3<f32>> {
+    let a = roughness * roughness;
+
+    (0..SAMPLES)
+        .map(|i| {
+            let x = i as f32 / SAMPLES as f32;
+            let y = i.reverse_bits() as f32 / 4_294_967_296.;
+
+            let phi = 2. * PI * x;
+            let cos_theta = ((1. - y) / (1. + (a * a - 1.) * y)).sqrt();
+            let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+            Vector3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+        })
+        .collect()
+}
+
+/// Two directions perpendicular to `normal` and each other.
+fn basis(normal: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let up = if normal.z.abs() < 0.999 {
+        Vector3::z()
+    } else {
+        Vector3::x()
+    };
+    let tangent = up.cross(normal).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
+}
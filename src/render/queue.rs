@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use chal_engine::shader::ShaderKind;
+use nalgebra::{Matrix4, Vector3};
+use specs::Entity;
+use web_sys::WebGlRenderingContext as GL;
+
+use crate::render::blend::BlendMode;
+use crate::render::draw::{DrawParams, Topology};
+use crate::render::instancing::{InstanceBatch, InstanceMaterial};
+use crate::render::material::{Material, PbrMaterial};
+use crate::render::registry::MeshHandle;
+
+/// Blending and depth writes of a draw.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DrawState {
+    /// `None` for opaque draws
+    pub blend: Option<BlendMode>,
+    pub depth_write: bool,
+}
+
+impl DrawState {
+    /// How an entity with `material` is drawn, opaque without one.
+    pub fn of(material: Option<&Material>) -> DrawState {
+        match material {
+            Some(material) => DrawState {
+                blend: material.blend,
+                depth_write: material.writes_depth(),
+            },
+            None => DrawState::default(),
+        }
+    }
+
+    pub fn apply(self, gl: &GL) {
+        match self.blend {
+            Some(blend) => blend.apply(gl),
+            None => gl.disable(GL::BLEND),
+        }
+        gl.depth_mask(self.depth_write);
+    }
+}
+
+impl Default for DrawState {
+    fn default() -> DrawState {
+        DrawState {
+            blend: None,
+            depth_write: true,
+        }
+    }
+}
+
+/// Entities whose draws can be merged into one instanced draw.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BatchKey {
+    pub mesh: MeshHandle,
+    pub shader_kind: ShaderKind,
+    pub topology: Topology,
+    pub state: DrawState,
+    /// Index of the lit material in `RenderQueue::materials`, unlit when
+    /// `None`
+    pub material: Option<u32>,
+}
+
+/// Instance batches in the order they are drawn.
+pub type Batches = Vec<(BatchKey, InstanceBatch)>;
+
+/// An entity's share of a draw.
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub entity: Entity,
+    pub model: Matrix4<f32>,
+    pub material: InstanceMaterial,
+    pub fade: f32,
+    /// World space point a blended instance is sorted by, such as the center
+    /// of its bounds. The origin of `model` when `None`.
+    pub center: Option<Vector3<f32>>,
+}
+
+/// A blended instance, waiting to be sorted.
+struct TransparentDraw {
+    key: BatchKey,
+    params: DrawParams,
+    distance: f32,
+    instance: Instance,
+}
+
+/// Everything to draw in a frame. Opaque instances are batched by mesh,
+/// blended ones are drawn after them from back to front.
+pub struct RenderQueue {
+    eye: Vector3<f32>,
+    opaque: HashMap<BatchKey, InstanceBatch>,
+    transparent: Vec<TransparentDraw>,
+    materials: Vec<PbrMaterial>,
+}
+
+impl RenderQueue {
+    /// Blended instances are sorted by the distance of their center to
+    /// `eye`.
+    pub fn new(eye: Vector3<f32>) -> RenderQueue {
+        RenderQueue {
+            eye,
+            opaque: HashMap::new(),
+            transparent: Vec::new(),
+            materials: Vec::new(),
+        }
+    }
+
+    /// The key of `material` in this frame, equal materials share one.
+    pub fn material_key(&mut self, material: &PbrMaterial) -> u32 {
+        let index = match self.materials.iter().position(|known| known == material) {
+            Some(index) => index,
+            None => {
+                self.materials.push(material.clone());
+                self.materials.len() - 1
+            }
+        };
+
+        index as u32
+    }
+
+    /// The lit materials by key.
+    pub fn materials(&self) -> &[PbrMaterial] {
+        &self.materials[..]
+    }
+
+    pub fn push(&mut self, key: BatchKey, params: DrawParams, instance: Instance) {
+        if key.state.blend.is_none() {
+            self.opaque
+                .entry(key)
+                .or_insert_with(|| InstanceBatch::new(params))
+                .push(
+                    instance.entity,
+                    &instance.model,
+                    instance.material,
+                    instance.fade,
+                );
+            return;
+        }
+
+        let model = &instance.model;
+        let center = instance
+            .center
+            .unwrap_or_else(|| Vector3::new(model[(0, 3)], model[(1, 3)], model[(2, 3)]));
+        self.transparent.push(TransparentDraw {
+            key,
+            params,
+            distance: (center - self.eye).norm_squared(),
+            instance,
+        });
+    }
+
+    /// The opaque batches in any order, and apart from them the blended
+    /// instances from back to front. Neighbouring blended instances of the
+    /// same mesh and state are still batched together.
+    pub fn into_batches(self) -> (Batches, Batches) {
+        let opaque: Batches = self.opaque.into_iter().collect();
+        let mut batches: Batches = Vec::new();
+
+        let mut transparent = self.transparent;
+        transparent.sort_by(|a, b| {
+            b.distance
+                .partial_cmp(&a.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        for draw in transparent {
+            let same_batch = batches.last().map_or(false, |(key, _)| *key == draw.key);
+            if !same_batch {
+                batches.push((draw.key, InstanceBatch::new(draw.params)));
+            }
+
+            let (_, batch) = batches.last_mut().expect("A batch was just pushed");
+            let instance = draw.instance;
+            batch.push(
+                instance.entity,
+                &instance.model,
+                instance.material,
+                instance.fade,
+            );
+        }
+
+        (opaque, batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chal_engine::render::Vao;
+    use specs::{Builder, World};
+    use wasm_bindgen::{JsCast, JsValue};
+
+    use crate::render::registry::{MeshKey, MeshRegistry};
+
+    fn keys(count: usize, blend: Option<BlendMode>) -> Vec<BatchKey> {
+        let mut registry = MeshRegistry::new();
+        (0..count)
+            .map(|_| BatchKey {
+                mesh: registry.insert(
+                    MeshKey::unique(),
+                    None,
+                    Vao(JsValue::NULL.unchecked_into()),
+                    Vec::new(),
+                ),
+                shader_kind: ShaderKind::NonSkinnedMesh,
+                topology: Topology::Triangles,
+                state: DrawState {
+                    blend,
+                    depth_write: blend.is_none(),
+                },
+                material: None,
+            })
+            .collect()
+    }
+
+    fn params() -> DrawParams {
+        DrawParams {
+            topology: Topology::Triangles,
+            vertex_count: 3,
+            index_count: 0,
+            index_type: None,
+        }
+    }
+
+    fn instance(entity: Entity, x: f32, center: Option<Vector3<f32>>) -> Instance {
+        Instance {
+            entity,
+            model: Matrix4::new_translation(&Vector3::new(x, 0., 0.)),
+            material: InstanceMaterial::of(None),
+            fade: 1.,
+            center,
+        }
+    }
+
+    #[test]
+    fn opaque_instances_are_batched_by_key() {
+        let mut world = World::new();
+        let entities: Vec<Entity> = (0..3).map(|_| world.create_entity().build()).collect();
+        let keys = keys(2, None);
+        let mut queue = RenderQueue::new(Vector3::zeros());
+
+        queue.push(keys[0], params(), instance(entities[0], 1., None));
+        queue.push(keys[1], params(), instance(entities[1], 2., None));
+        queue.push(keys[0], params(), instance(entities[2], 3., None));
+        let (opaque, transparent) = queue.into_batches();
+
+        assert!(transparent.is_empty());
+        assert_eq!(opaque.len(), 2);
+        let shared = opaque.iter().find(|(key, _)| *key == keys[0]).unwrap();
+        assert_eq!(shared.1.entities, vec![entities[0], entities[2]]);
+    }
+
+    #[test]
+    fn blended_instances_are_sorted_by_their_centers() {
+        let mut world = World::new();
+        let entities: Vec<Entity> = (0..3).map(|_| world.create_entity().build()).collect();
+        let keys = keys(1, Some(BlendMode::Alpha));
+        let mut queue = RenderQueue::new(Vector3::zeros());
+
+        // Placed nearest, but its bounds reach out furthest
+        let far = Some(Vector3::new(10., 0., 0.));
+        queue.push(keys[0], params(), instance(entities[0], 1., far));
+        queue.push(keys[0], params(), instance(entities[1], 5., None));
+        queue.push(keys[0], params(), instance(entities[2], -3., None));
+        let (opaque, transparent) = queue.into_batches();
+
+        assert!(opaque.is_empty());
+        assert_eq!(transparent.len(), 1);
+        assert_eq!(
+            transparent[0].1.entities,
+            vec![entities[0], entities[1], entities[2]]
+        );
+    }
+
+    #[test]
+    fn blended_batches_break_where_the_key_changes() {
+        let mut world = World::new();
+        let entities: Vec<Entity> = (0..3).map(|_| world.create_entity().build()).collect();
+        let keys = keys(2, Some(BlendMode::Additive));
+        let mut queue = RenderQueue::new(Vector3::zeros());
+
+        queue.push(keys[0], params(), instance(entities[0], 3., None));
+        queue.push(keys[1], params(), instance(entities[1], 2., None));
+        queue.push(keys[0], params(), instance(entities[2], 1., None));
+        let (_, transparent) = queue.into_batches();
+
+        let order: Vec<(BatchKey, Vec<Entity>)> = transparent
+            .into_iter()
+            .map(|(key, batch)| (key, batch.entities))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (keys[0], vec![entities[0]]),
+                (keys[1], vec![entities[1]]),
+                (keys[0], vec![entities[2]]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use chal_engine::render::Vao;
+use web_sys::WebGlBuffer;
+
+use crate::render::draw::Indices;
+use crate::render::layout::VertexLayout;
+
+/// Identifies geometry that has been uploaded to the GPU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeshHandle(u32);
+
+static NEXT_UNIQUE_KEY: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies the geometry itself, so that two meshes with identical vertex
+/// data share one upload regardless of what they are called.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MeshKey {
+    /// A hash of the contents. Different geometry can share a hash, uploads
+    /// are only shared once their `Geometry` compares equal too.
+    Geometry { hash: u64, len: usize },
+    /// Geometry that is updated in place and therefore never shared
+    Unique(usize),
+}
+
+impl MeshKey {
+    /// Everything that ends up in the mesh's buffers takes part in the key:
+    /// the same floats packed with another layout are different geometry.
+    pub fn geometry(
+        vertices: &[f32],
+        layout: &VertexLayout,
+        indices: Option<&Indices>,
+    ) -> MeshKey {
+        let mut hasher = DefaultHasher::new();
+        for v in vertices {
+            v.to_bits().hash(&mut hasher);
+        }
+        layout.hash(&mut hasher);
+        indices.hash(&mut hasher);
+
+        MeshKey::Geometry {
+            hash: hasher.finish(),
+            len: vertices.len(),
+        }
+    }
+
+    pub fn unique() -> MeshKey {
+        MeshKey::Unique(NEXT_UNIQUE_KEY.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// What a shared upload was made from, kept to tell geometry with the same
+/// key apart.
+#[derive(Clone, Debug)]
+pub struct Geometry {
+    vertices: Arc<Vec<f32>>,
+    layout: VertexLayout,
+    indices: Option<Indices>,
+}
+
+impl Geometry {
+    pub fn new(
+        vertices: Arc<Vec<f32>>,
+        layout: VertexLayout,
+        indices: Option<Indices>,
+    ) -> Geometry {
+        Geometry {
+            vertices,
+            layout,
+            indices,
+        }
+    }
+
+    /// Whether this is the same geometry, with the vertices compared bit for
+    /// bit like they are hashed. Vertices shared with the upload aren't
+    /// compared at all.
+    pub fn matches(
+        &self,
+        vertices: &Arc<Vec<f32>>,
+        layout: &VertexLayout,
+        indices: Option<&Indices>,
+    ) -> bool {
+        let same_vertices = Arc::ptr_eq(&self.vertices, vertices)
+            || (self.vertices.len() == vertices.len()
+                && self
+                    .vertices
+                    .iter()
+                    .zip(vertices.iter())
+                    .all(|(a, b)| a.to_bits() == b.to_bits()));
+
+        same_vertices && self.layout == *layout && self.indices.as_ref() == indices
+    }
+}
+
+pub struct GpuMesh {
+    pub vao: Vao<js_sys::Object>,
+    pub buffers: Vec<WebGlBuffer>,
+    key: MeshKey,
+    /// `None` for geometry that is never shared
+    geometry: Option<Geometry>,
+    ref_count: usize,
+}
+
+impl GpuMesh {
+    fn holds<F>(&self, key: &MeshKey, same: F) -> bool
+    where
+        F: Fn(&Geometry) -> bool,
+    {
+        self.key == *key && self.geometry.as_ref().map_or(true, same)
+    }
+}
+
+/// Keeps track of every mesh living on the GPU and how many entities use it.
+///
+/// The registry only does the bookkeeping; creating and deleting the actual
+/// GL objects is left to the caller, which owns the context and extensions.
+#[derive(Default)]
+pub struct MeshRegistry {
+    meshes: HashMap<MeshHandle, GpuMesh>,
+    /// Every upload with the key, which only differ when their keys collide
+    handles: HashMap<MeshKey, Vec<MeshHandle>>,
+    next_handle: u32,
+}
+
+impl MeshRegistry {
+    pub fn new() -> MeshRegistry {
+        MeshRegistry::default()
+    }
+
+    /// The upload with `key` whose geometry `same` accepts.
+    pub fn lookup<F>(&self, key: &MeshKey, same: F) -> Option<MeshHandle>
+    where
+        F: Fn(&Geometry) -> bool,
+    {
+        self.handles
+            .get(key)?
+            .iter()
+            .cloned()
+            .find(|&handle| self.holds(handle, key, &same))
+    }
+
+    /// Whether `handle` is the upload `lookup` would find.
+    pub fn holds<F>(&self, handle: MeshHandle, key: &MeshKey, same: F) -> bool
+    where
+        F: Fn(&Geometry) -> bool,
+    {
+        self.meshes
+            .get(&handle)
+            .map_or(false, |mesh| mesh.holds(key, same))
+    }
+
+    pub fn get(&self, handle: MeshHandle) -> Option<&GpuMesh> {
+        self.meshes.get(&handle)
+    }
+
+    pub fn get_mut(&mut self, handle: MeshHandle) -> Option<&mut GpuMesh> {
+        self.meshes.get_mut(&handle)
+    }
+
+    /// Register freshly uploaded geometry, made from `geometry` when it may
+    /// be shared. The returned handle starts out unreferenced, call `acquire`
+    /// for every user.
+    pub fn insert(
+        &mut self,
+        key: MeshKey,
+        geometry: Option<Geometry>,
+        vao: Vao<js_sys::Object>,
+        buffers: Vec<WebGlBuffer>,
+    ) -> MeshHandle {
+        let handle = MeshHandle(self.next_handle);
+        self.next_handle += 1;
+
+        self.meshes.insert(
+            handle,
+            GpuMesh {
+                vao,
+                buffers,
+                key,
+                geometry,
+                ref_count: 0,
+            },
+        );
+        let handles = self.handles.entry(key).or_insert_with(Vec::new);
+        handles.push(handle);
+
+        handle
+    }
+
+    pub fn acquire(&mut self, handle: MeshHandle) {
+        if let Some(mesh) = self.meshes.get_mut(&handle) {
+            mesh.ref_count += 1;
+        }
+    }
+
+    /// Drop a reference to the mesh. Once nobody uses it anymore it is removed
+    /// from the registry and handed back so its GL objects can be deleted.
+    pub fn release(&mut self, handle: MeshHandle) -> Option<GpuMesh> {
+        {
+            let mesh = self.meshes.get_mut(&handle)?;
+            mesh.ref_count = mesh.ref_count.saturating_sub(1);
+            if mesh.ref_count > 0 {
+                return None;
+            }
+        }
+
+        let mesh = self.meshes.remove(&handle)?;
+        if let Some(handles) = self.handles.get_mut(&mesh.key) {
+            handles.retain(|&other| other != handle);
+            if handles.is_empty() {
+                self.handles.remove(&mesh.key);
+            }
+        }
+        Some(mesh)
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen::{JsCast, JsValue};
+
+    /// A stand-in VAO, never handed to GL.
+    fn vao() -> Vao<js_sys::Object> {
+        Vao(JsValue::NULL.unchecked_into())
+    }
+
+    fn geometry(vertices: Vec<f32>) -> Geometry {
+        Geometry::new(Arc::new(vertices), VertexLayout::position_2d(), None)
+    }
+
+    fn same(vertices: Vec<f32>) -> impl Fn(&Geometry) -> bool {
+        let vertices = Arc::new(vertices);
+        move |geometry| geometry.matches(&vertices, &VertexLayout::position_2d(), None)
+    }
+
+    #[test]
+    fn meshes_are_freed_with_their_last_user() {
+        let mut registry = MeshRegistry::new();
+        let key = MeshKey::unique();
+        let handle = registry.insert(key, None, vao(), Vec::new());
+        registry.acquire(handle);
+        registry.acquire(handle);
+
+        assert!(registry.release(handle).is_none());
+        assert_eq!(registry.len(), 1);
+        assert!(registry.release(handle).is_some());
+        assert!(registry.is_empty());
+        assert_eq!(registry.lookup(&key, |_| true), None);
+        assert!(registry.release(handle).is_none());
+    }
+
+    #[test]
+    fn equal_geometry_is_shared() {
+        let vertices = vec![0., 0., 1., 0., 0., 1.];
+        let key = MeshKey::geometry(&vertices, &VertexLayout::position_2d(), None);
+        let mut registry = MeshRegistry::new();
+        let handle = registry.insert(key, Some(geometry(vertices.clone())), vao(), Vec::new());
+
+        assert_eq!(registry.lookup(&key, same(vertices.clone())), Some(handle));
+        assert!(registry.holds(handle, &key, same(vertices)));
+        assert!(!registry.holds(handle, &MeshKey::unique(), |_| true));
+    }
+
+    #[test]
+    fn colliding_keys_keep_their_own_uploads() {
+        // Pretend both have the same key
+        let key = MeshKey::Geometry { hash: 7, len: 6 };
+        let (a, b) = (vec![0.; 6], vec![1.; 6]);
+        let mut registry = MeshRegistry::new();
+
+        let first = registry.insert(key, Some(geometry(a.clone())), vao(), Vec::new());
+        assert_eq!(registry.lookup(&key, same(b.clone())), None);
+        assert!(!registry.holds(first, &key, same(b.clone())));
+
+        let second = registry.insert(key, Some(geometry(b.clone())), vao(), Vec::new());
+        assert_eq!(registry.lookup(&key, same(a.clone())), Some(first));
+        assert_eq!(registry.lookup(&key, same(b.clone())), Some(second));
+
+        registry.acquire(first);
+        registry.release(first);
+        assert_eq!(registry.lookup(&key, same(a)), None);
+        assert_eq!(registry.lookup(&key, same(b)), Some(second));
+    }
+
+    #[test]
+    fn keys_cover_the_layout_and_indices() {
+        let vertices = [0., 0., 1., 0., 0., 1.];
+        let flat = VertexLayout::position_2d();
+        let indices = Indices::U16(vec![0, 1, 2]);
+
+        assert_eq!(
+            MeshKey::geometry(&vertices, &flat, None),
+            MeshKey::geometry(&vertices.to_vec(), &flat, None)
+        );
+        assert_ne!(
+            MeshKey::geometry(&vertices, &flat, None),
+            MeshKey::geometry(&vertices, &flat, Some(&indices))
+        );
+        assert_ne!(MeshKey::unique(), MeshKey::unique());
+    }
+
+    #[test]
+    fn vertices_are_compared_bit_for_bit() {
+        let zero = geometry(vec![0.]);
+        let layout = VertexLayout::position_2d();
+
+        assert!(zero.matches(&Arc::new(vec![0.]), &layout, None));
+        assert!(!zero.matches(&Arc::new(vec![-0.]), &layout, None));
+        assert!(!zero.matches(&Arc::new(vec![0.]), &layout, Some(&Indices::U16(vec![0]))));
+    }
+}
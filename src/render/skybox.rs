@@ -0,0 +1,283 @@
+use chal_engine::render::Vao;
+use chal_engine::shader::Shader;
+use nalgebra::{Matrix4, Vector3};
+use wasm_bindgen::JsValue;
+use web_sys::{WebGlBuffer, WebGlRenderingContext as GL, WebGlTexture};
+
+use crate::render::buffer::{self, BufferUsage};
+use crate::render::pbr;
+use crate::render::texture::{self, CubeMapData, TextureLibrary};
+use crate::shader::WebShader;
+
+static SKY_VS: &'static str = include_str!("../sky-vertex.glsl");
+static SKY_FS: &'static str = include_str!("../sky-fragment.glsl");
+
+/// Width and height in pixels of the faces of the environment cube map
+/// baked from the sky. Reflections are blurry anyway.
+const ENVIRONMENT_SIZE: u32 = 32;
+
+/// Mipmaps of the environment, from `ENVIRONMENT_SIZE` down to 1x1, each
+/// prefiltered for a rougher surface than the one before.
+pub const ENVIRONMENT_LEVELS: u32 = 6;
+
+/// What is seen where nothing else is drawn, and what reflective materials
+/// reflect.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Skybox {
+    /// A single color everywhere
+    Color { color: [f32; 3] },
+    /// A procedural sky, see `SkyGradient`
+    Gradient(SkyGradient),
+    /// A cube map from the `TextureLibrary`, by name. Nothing is drawn until
+    /// it is added
+    CubeMap { texture: String },
+}
+
+impl Skybox {
+    /// What the canvas is cleared to, the sky itself unless it is drawn
+    /// over.
+    pub fn clear_color(&self) -> [f32; 3] {
+        match self {
+            Skybox::Color { color } => *color,
+            Skybox::Gradient(gradient) => gradient.horizon,
+            Skybox::CubeMap { .. } => [0., 0., 0.],
+        }
+    }
+
+    /// Whether clearing to `clear_color` is all it takes.
+    pub fn is_color(&self) -> bool {
+        matches!(self, Skybox::Color { .. })
+    }
+
+    /// The color of a sky that isn't a cube map, in `direction`.
+    fn color_at(&self, direction: Vector3<f32>) -> [f32; 3] {
+        match self {
+            Skybox::Color { color } => *color,
+            Skybox::Gradient(gradient) => gradient.color_at(direction),
+            Skybox::CubeMap { .. } => [0., 0., 0.],
+        }
+    }
+}
+
+impl Default for Skybox {
+    fn default() -> Skybox {
+        Skybox::Color {
+            color: [0.53, 0.8, 0.98],
+        }
+    }
+}
+
+/// A sky fading from the horizon up to the zenith and down to the ground,
+/// with a sun. Colors are RGB.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SkyGradient {
+    pub zenith: [f32; 3],
+    pub horizon: [f32; 3],
+    pub ground: [f32; 3],
+    /// Towards the sun, doesn't have to be normalized
+    pub sun_direction: [f32; 3],
+    pub sun_color: [f32; 3],
+    /// Angular radius of the sun disc in radians, it glows a bit beyond
+    pub sun_size: f32,
+}
+
+impl SkyGradient {
+    fn sun_direction(&self) -> Vector3<f32> {
+        let [x, y, z] = self.sun_direction;
+        Vector3::new(x, y, z)
+            .try_normalize(1.0e-6)
+            .unwrap_or_else(Vector3::y)
+    }
+
+    /// The same as `sky-fragment.glsl`, to bake the environment cube map.
+    fn color_at(&self, direction: Vector3<f32>) -> [f32; 3] {
+        let direction = direction.normalize();
+        let (toward, amount) = if direction.y >= 0. {
+            (self.zenith, direction.y.sqrt())
+        } else {
+            (self.ground, (-direction.y).sqrt())
+        };
+
+        let sun = direction.dot(&self.sun_direction());
+        let disc = smoothstep(self.sun_size.cos(), (self.sun_size * 0.5).cos(), sun);
+        let glow = sun.max(0.).powf(64.) * 0.5;
+
+        let mut color = [0.; 3];
+        for (i, channel) in color.iter_mut().enumerate() {
+            let sky = self.horizon[i] + (toward[i] - self.horizon[i]) * amount;
+            *channel = sky + self.sun_color[i] * (disc + glow);
+        }
+        color
+    }
+}
+
+impl Default for SkyGradient {
+    fn default() -> SkyGradient {
+        SkyGradient {
+            zenith: [0.18, 0.42, 0.85],
+            horizon: [0.7, 0.85, 0.98],
+            ground: [0.35, 0.33, 0.3],
+            sun_direction: [0.4, 0.6, -0.7],
+            sun_color: [1., 0.95, 0.8],
+            sun_size: 0.03,
+        }
+    }
+}
+
+/// GLSL's `smoothstep`.
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.).min(1.);
+    t * t * (3. - 2. * t)
+}
+
+/// Draws the skybox behind everything and provides the cube map that
+/// reflective and lit materials sample.
+pub struct SkyRenderer {
+    shader: WebShader,
+    /// Holds the fullscreen triangle
+    pub vao: Vao<js_sys::Object>,
+    _vertices: WebGlBuffer,
+    /// Baked from the sky, with prefiltered mipmaps
+    environment: WebGlTexture,
+    /// The sky `environment` was last baked from, and the version of the
+    /// cube map of a cube map sky
+    baked: Option<(Skybox, Option<u32>)>,
+}
+
+impl SkyRenderer {
+    /// Expects `vao` to be bound, it is set up for drawing the sky.
+    pub fn new(gl: &GL, vao: Vao<js_sys::Object>) -> Result<SkyRenderer, JsValue> {
+        let shader = WebShader::new(gl, SKY_VS, SKY_FS)?;
+
+        // A single triangle covering the whole screen
+        let vertices =
+            buffer::create_f32_buffer(gl, &[-1., -1., 3., -1., -1., 3.], BufferUsage::Static);
+        let location = gl.get_attrib_location(&shader.program, "a_position");
+        if location >= 0 {
+            gl.enable_vertex_attrib_array(location as u32);
+            gl.vertex_attrib_pointer_with_i32(location as u32, 2, GL::FLOAT, false, 0, 0);
+        }
+
+        let environment = gl
+            .create_texture()
+            .ok_or_else(|| JsValue::from_str("Could not create texture"))?;
+
+        Ok(SkyRenderer {
+            shader,
+            vao,
+            _vertices: vertices,
+            environment,
+            baked: None,
+        })
+    }
+
+    /// The cube map reflections and image based lighting sample for
+    /// `skybox`, baked and prefiltered again when the sky changed. That
+    /// takes a moment, so a sky better not change every frame. `None` while
+    /// a cube map sky names a cube map that isn't in the library.
+    pub fn environment(
+        &mut self,
+        gl: &GL,
+        skybox: &Skybox,
+        library: &TextureLibrary,
+    ) -> Option<WebGlTexture> {
+        let version = match skybox {
+            Skybox::CubeMap { texture } => Some(library.cube_map_version(texture)?),
+            _ => None,
+        };
+        let sky = (skybox.clone(), version);
+
+        if self.baked.as_ref() != Some(&sky) {
+            let cube_map = match skybox {
+                Skybox::CubeMap { texture } => {
+                    let source = library.cube_map(texture)?;
+                    CubeMapData::from_fn(ENVIRONMENT_SIZE, |direction| source.sample(direction))
+                }
+                _ => CubeMapData::from_fn(ENVIRONMENT_SIZE, |direction| {
+                    let [r, g, b] = skybox.color_at(direction);
+                    [r, g, b, 1.]
+                }),
+            };
+            let levels = pbr::prefilter(&cube_map, ENVIRONMENT_LEVELS);
+            texture::upload_cube_map_levels(gl, &self.environment, &levels);
+            self.baked = Some(sky);
+        }
+
+        Some(self.environment.clone())
+    }
+
+    /// Draw the sky where nothing was drawn yet, after the opaque geometry.
+    /// `cube_map` is the cube map of a cube map sky. A plain color sky is
+    /// only cleared to, so nothing is drawn for it. Expects `vao` to be
+    /// bound and leaves the program of the sky in use.
+    pub fn draw(
+        &self,
+        gl: &GL,
+        skybox: &Skybox,
+        cube_map: Option<&WebGlTexture>,
+        view: &[f32],
+        projection: &[f32],
+    ) -> u32 {
+        let gradient = match skybox {
+            Skybox::Color { .. } => return 0,
+            Skybox::Gradient(gradient) => *gradient,
+            Skybox::CubeMap { .. } if cube_map.is_none() => return 0,
+            Skybox::CubeMap { .. } => SkyGradient::default(),
+        };
+
+        // The sky is infinitely far away, only the camera's rotation counts
+        let mut rotation = [0.; 16];
+        rotation.copy_from_slice(view);
+        rotation[12..15].copy_from_slice(&[0., 0., 0.]);
+        let view_projection =
+            Matrix4::from_column_slice(projection) * Matrix4::from_column_slice(&rotation);
+        let inverse = match view_projection.try_inverse() {
+            Some(inverse) => inverse,
+            None => return 0,
+        };
+        let mut inverse_array = [0.; 16];
+        inverse_array.copy_from_slice(inverse.as_slice());
+
+        gl.use_program(Some(&self.shader.program));
+        let shader = &self.shader;
+        let uniform = |name: &str| shader.get_uniform_location(gl, name);
+        gl.uniform_matrix4fv_with_f32_array(
+            uniform("u_inverse_view_projection").as_ref(),
+            false,
+            &mut inverse_array,
+        );
+        gl.uniform1i(
+            uniform("u_use_cube_map").as_ref(),
+            cube_map.is_some() as i32,
+        );
+        gl.uniform1i(uniform("u_cube_map").as_ref(), 0);
+        let colors = [
+            ("u_zenith", gradient.zenith),
+            ("u_horizon", gradient.horizon),
+            ("u_ground", gradient.ground),
+            ("u_sun_color", gradient.sun_color),
+        ];
+        for &(name, [r, g, b]) in colors.iter() {
+            gl.uniform3f(uniform(name).as_ref(), r, g, b);
+        }
+        let sun = gradient.sun_direction();
+        gl.uniform3f(uniform("u_sun_direction").as_ref(), sun.x, sun.y, sun.z);
+        gl.uniform1f(uniform("u_sun_size").as_ref(), gradient.sun_size);
+
+        gl.active_texture(GL::TEXTURE0);
+        gl.bind_texture(GL::TEXTURE_CUBE_MAP, cube_map);
+
+        // Only where the depth buffer is still cleared, the triangle sits
+        // on the far plane
+        gl.depth_func(GL::LEQUAL);
+        gl.depth_mask(false);
+        gl.draw_arrays(GL::TRIANGLES, 0, 3);
+        gl.depth_mask(true);
+        gl.depth_func(GL::LESS);
+        gl.bind_texture(GL::TEXTURE_CUBE_MAP, None);
+
+        1
+    }
+}
@@ -0,0 +1,263 @@
+//! Tangent frames for normal mapping, following the conventions of
+//! MikkTSpace, which Blender bakes normal maps in: per vertex tangents
+//! along u with the handedness of the bitangent in w, and the bitangent
+//! rebuilt in the shader as `cross(normal, tangent) * w`.
+
+use nalgebra::Vector3;
+
+use crate::render::component::Mesh;
+use crate::render::layout::{VertexAttribute, VertexLayout};
+
+/// Tangents of the vertices of `triangles`, as xyz and the handedness in w.
+/// `uvs` are texture coordinates padded to 3D, with v going down the image
+/// like the textures are uploaded. Every face corner adds the tangent of
+/// its face, flattened onto the vertex normal and weighted by the angle of
+/// the corner. Vertices without a usable tangent get any one perpendicular
+/// to their normal.
+pub fn generate(
+    positions: &[Vector3<f32>],
+    normals: &[Vector3<f32>],
+    uvs: &[Vector3<f32>],
+    triangles: &[[usize; 3]],
+) -> Vec<[f32; 4]> {
+    let count = positions.len();
+    let mut tangents = vec![Vector3::zeros(); count];
+    let mut bitangents = vec![Vector3::zeros(); count];
+
+    for &[a, b, c] in triangles {
+        if a.max(b).max(c) >= count.min(normals.len()).min(uvs.len()) {
+            continue;
+        }
+
+        let (edge1, edge2) = (positions[b] - positions[a], positions[c] - positions[a]);
+        let (du1, dv1) = (uvs[b].x - uvs[a].x, uvs[b].y - uvs[a].y);
+        let (du2, dv2) = (uvs[c].x - uvs[a].x, uvs[c].y - uvs[a].y);
+        let det = du1 * dv2 - du2 * dv1;
+        // Texture coordinates squashed into a line say nothing
+        if det.abs() < 1.0e-12 {
+            continue;
+        }
+
+        let tangent = (edge1 * dv2 - edge2 * dv1) / det;
+        // Normal maps point up the image, where v gets smaller
+        let bitangent = -(edge2 * du1 - edge1 * du2) / det;
+
+        for &(corner, previous, next) in [(a, c, b), (b, a, c), (c, b, a)].iter() {
+            let to_previous = positions[previous] - positions[corner];
+            let to_next = positions[next] - positions[corner];
+            let angle = match (
+                to_previous.try_normalize(1.0e-12),
+                to_next.try_normalize(1.0e-12),
+            ) {
+                (Some(p), Some(n)) => p.dot(&n).max(-1.).min(1.).acos(),
+                _ => continue,
+            };
+
+            let normal = match normals[corner].try_normalize(1.0e-12) {
+                Some(normal) => normal,
+                None => continue,
+            };
+            if let Some(flat) = (tangent - normal * normal.dot(&tangent)).try_normalize(1.0e-12) {
+                tangents[corner] += flat * angle;
+                bitangents[corner] += bitangent * angle;
+            }
+        }
+    }
+
+    (0..count)
+        .map(|vertex| {
+            let normal = normals
+                .get(vertex)
+                .and_then(|normal| normal.try_normalize(1.0e-12))
+                .unwrap_or_else(Vector3::z);
+            let tangent = (tangents[vertex] - normal * normal.dot(&tangents[vertex]))
+                .try_normalize(1.0e-12)
+                .unwrap_or_else(|| perpendicular(&normal));
+
+            let handedness = if normal.cross(&tangent).dot(&bitangents[vertex]) < 0. {
+                -1.
+            } else {
+                1.
+            };
+            [tangent.x, tangent.y, tangent.z, handedness]
+        })
+        .collect()
+}
+
+/// Any direction perpendicular to `normal`.
+fn perpendicular(normal: &Vector3<f32>) -> Vector3<f32> {
+    let axis = if normal.x.abs() < 0.9 {
+        Vector3::x()
+    } else {
+        Vector3::y()
+    };
+    (axis - normal * normal.dot(&axis)).normalize()
+}
+
+impl Mesh {
+    /// Add an `a_tangent` attribute for normal maps, generated from the
+    /// `a_position`, `a_normal` and `a_uv` attributes, or replace the one
+    /// the mesh has. A mesh missing any of them is returned as it is.
+    pub fn with_tangents(self) -> Mesh {
+        let layout = self.layout();
+        let vertices = self.vertices();
+        if layout.float_offset("a_normal").is_none() || layout.float_offset("a_uv").is_none() {
+            return self;
+        }
+
+        let tangents = generate(
+            &layout.positions(vertices, "a_position"),
+            &layout.positions(vertices, "a_normal"),
+            &layout.positions(vertices, "a_uv"),
+            &self.triangle_indices(),
+        );
+
+        // Append the tangent to every vertex, dropping the old one
+        let existing = layout.float_offset("a_tangent");
+        let mut attributes: Vec<VertexAttribute> = layout
+            .attributes()
+            .iter()
+            .filter(|attribute| attribute.name != "a_tangent")
+            .cloned()
+            .collect();
+        attributes.push(VertexAttribute::float("a_tangent", 4));
+
+        let floats_per_vertex = layout.floats_per_vertex();
+        let mut interleaved = Vec::with_capacity(tangents.len() * (floats_per_vertex + 4));
+        for (vertex, tangent) in vertices.chunks(floats_per_vertex).zip(tangents.iter()) {
+            match existing {
+                Some((offset, attribute)) => {
+                    interleaved.extend_from_slice(&vertex[..offset]);
+                    interleaved.extend_from_slice(&vertex[offset + attribute.components..]);
+                }
+                None => interleaved.extend_from_slice(vertex),
+            }
+            interleaved.extend_from_slice(tangent);
+        }
+
+        let mut mesh = self.with_layout(VertexLayout::new(attributes));
+        mesh.set_vertices(interleaved);
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chal_engine::shader::ShaderKind;
+
+    /// A unit quad facing +Z, as two triangles.
+    fn quad() -> (Vec<Vector3<f32>>, Vec<Vector3<f32>>, Vec<[usize; 3]>) {
+        let positions = vec![
+            Vector3::new(0., 0., 0.),
+            Vector3::new(1., 0., 0.),
+            Vector3::new(1., 1., 0.),
+            Vector3::new(0., 1., 0.),
+        ];
+        let normals = vec![Vector3::z(); 4];
+        (positions, normals, vec![[0, 1, 2], [0, 2, 3]])
+    }
+
+    fn uv(u: f32, v: f32) -> Vector3<f32> {
+        Vector3::new(u, v, 0.)
+    }
+
+    #[test]
+    fn tangents_follow_u() {
+        let (positions, normals, triangles) = quad();
+        let uvs = vec![uv(0., 1.), uv(1., 1.), uv(1., 0.), uv(0., 0.)];
+
+        for tangent in generate(&positions, &normals, &uvs, &triangles) {
+            assert_eq!(tangent, [1., 0., 0., 1.]);
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_the_handedness() {
+        let (positions, normals, triangles) = quad();
+        let uvs = vec![uv(1., 1.), uv(0., 1.), uv(0., 0.), uv(1., 0.)];
+
+        for tangent in generate(&positions, &normals, &uvs, &triangles) {
+            assert_eq!(tangent, [-1., 0., 0., -1.]);
+        }
+    }
+
+    #[test]
+    fn tangents_are_flattened_onto_the_normal() {
+        let (positions, _, triangles) = quad();
+        let normals = vec![Vector3::new(1., 0., 1.); 4];
+        let uvs = vec![uv(0., 1.), uv(1., 1.), uv(1., 0.), uv(0., 0.)];
+
+        for [x, y, z, _] in generate(&positions, &normals, &uvs, &triangles) {
+            let tangent = Vector3::new(x, y, z);
+            assert!((tangent.norm() - 1.).abs() < 1.0e-5);
+            assert!(tangent.dot(&normals[0]).abs() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn unusable_uvs_get_any_perpendicular_tangent() {
+        let (positions, normals, triangles) = quad();
+        let uvs = vec![uv(0.5, 0.5); 4];
+
+        for [x, y, z, w] in generate(&positions, &normals, &uvs, &triangles) {
+            let tangent = Vector3::new(x, y, z);
+            assert!((tangent.norm() - 1.).abs() < 1.0e-5);
+            assert!(tangent.dot(&Vector3::z()).abs() < 1.0e-5);
+            assert_eq!(w, 1.);
+        }
+    }
+
+    #[test]
+    fn triangles_past_the_vertices_are_skipped() {
+        let (positions, normals, _) = quad();
+        let uvs = vec![uv(0., 1.), uv(1., 1.), uv(1., 0.), uv(0., 0.)];
+
+        let tangents = generate(&positions, &normals, &uvs, &[[0, 1, 2], [0, 2, 7]]);
+        assert_eq!(tangents.len(), 4);
+        assert_eq!(tangents[1], [1., 0., 0., 1.]);
+    }
+
+    #[test]
+    fn meshes_get_a_tangent_attribute() {
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::float("a_position", 3),
+            VertexAttribute::float("a_normal", 3),
+            VertexAttribute::float("a_uv", 2),
+        ]);
+        #[rustfmt::skip]
+        let vertices = vec![
+            0., 0., 0., 0., 0., 1., 0., 1.,
+            1., 0., 0., 0., 0., 1., 1., 1.,
+            1., 1., 0., 0., 0., 1., 1., 0.,
+        ];
+        let mesh = Mesh::new("triangle", vertices, ShaderKind::NonSkinnedMesh)
+            .with_layout(layout)
+            .with_tangents();
+
+        assert_eq!(mesh.layout().floats_per_vertex(), 12);
+        assert_eq!(mesh.vertices()[8..12].to_vec(), vec![1., 0., 0., 1.]);
+        assert_eq!(
+            mesh.vertices()[12..20].to_vec(),
+            vec![1., 0., 0., 0., 0., 1., 1., 1.]
+        );
+
+        // Generating again replaces the tangents rather than adding more
+        let again = mesh.clone().with_tangents();
+        assert_eq!(again.layout().floats_per_vertex(), 12);
+        assert_eq!(again.vertices(), mesh.vertices());
+    }
+
+    #[test]
+    fn meshes_without_uvs_stay_as_they_are() {
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::float("a_position", 3),
+            VertexAttribute::float("a_normal", 3),
+        ]);
+        let vertices = vec![0., 0., 0., 0., 0., 1.];
+        let mesh = Mesh::new("point", vertices, ShaderKind::NonSkinnedMesh).with_layout(layout);
+
+        assert_eq!(mesh.clone().with_tangents().vertices(), mesh.vertices());
+        assert_eq!(mesh.with_tangents().layout().floats_per_vertex(), 6);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use nalgebra::Vector3;
+use web_sys::{WebGlRenderingContext as GL, WebGlTexture};
+
+/// RGBA pixels of an image, 8 bits per channel, rows from the top down.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl TextureData {
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Result<TextureData, String> {
+        let expected = width as usize * height as usize * 4;
+        if width == 0 || height == 0 || pixels.len() != expected {
+            return Err(format!(
+                "A {}x{} texture needs {} bytes of RGBA pixels, got {}",
+                width,
+                height,
+                expected,
+                pixels.len()
+            ));
+        }
+
+        Ok(TextureData {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// Bilinearly filtered color at texture coordinates (`u`, `v`), from 0
+    /// to 1. Wraps around horizontally and clamps vertically.
+    fn sample(&self, u: f32, v: f32) -> [f32; 4] {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |column: i32, row: i32| {
+            let column = column.rem_euclid(width) as usize;
+            let row = row.max(0).min(height - 1) as usize;
+            let start = (row * self.width as usize + column) * 4;
+            let mut color = [0.; 4];
+            for (channel, &byte) in color.iter_mut().zip(&self.pixels[start..start + 4]) {
+                *channel = byte as f32;
+            }
+            color
+        };
+
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let (a, b) = (texel(x0, y0), texel(x0 + 1, y0));
+        let (c, d) = (texel(x0, y0 + 1), texel(x0 + 1, y0 + 1));
+        let mut color = [0.; 4];
+        for i in 0..4 {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            color[i] = top + (bottom - top) * fy;
+        }
+        color
+    }
+}
+
+/// The six square faces of a cube map, in the order +X, -X, +Y, -Y, +Z, -Z,
+/// each `size` by `size` RGBA pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CubeMapData {
+    pub size: u32,
+    pub faces: Vec<Vec<u8>>,
+}
+
+impl CubeMapData {
+    /// Split `pixels`, holding the six faces one after the other.
+    pub fn from_faces(size: u32, pixels: &[u8]) -> Result<CubeMapData, String> {
+        let face_len = size as usize * size as usize * 4;
+        if size == 0 || pixels.len() != face_len * 6 {
+            return Err(format!(
+                "A cube map with {}x{} faces needs {} bytes of RGBA pixels, got {}",
+                size,
+                size,
+                face_len * 6,
+                pixels.len()
+            ));
+        }
+
+        Ok(CubeMapData {
+            size,
+            faces: pixels.chunks(face_len).map(|face| face.to_vec()).collect(),
+        })
+    }
+
+    /// Render a cube map with faces of `size` pixels, asking `color` for
+    /// the RGBA color in every direction.
+    pub fn from_fn<F>(size: u32, color: F) -> CubeMapData
+    where
+        F: Fn(Vector3<f32>) -> [f32; 4],
+    {
+        let faces = (0..6)
+            .map(|face| {
+                let mut pixels = Vec::with_capacity(size as usize * size as usize * 4);
+                for row in 0..size {
+                    for column in 0..size {
+                        let u = (column as f32 + 0.5) / size as f32 * 2. - 1.;
+                        let v = (row as f32 + 0.5) / size as f32 * 2. - 1.;
+                        for channel in color(face_direction(face, u, v)).iter() {
+                            pixels.push((channel.max(0.).min(1.) * 255.).round() as u8);
+                        }
+                    }
+                }
+                pixels
+            })
+            .collect();
+
+        CubeMapData { size, faces }
+    }
+
+    /// Resample a panorama in the equirectangular projection, with -Z in
+    /// the middle, longitude going around from left to right and latitude
+    /// from the zenith at the top to the nadir at the bottom.
+    pub fn from_equirectangular(panorama: &TextureData, size: u32) -> CubeMapData {
+        let to_unit = |channel: f32| channel / 255.;
+
+        CubeMapData::from_fn(size, |direction| {
+            let direction = direction.normalize();
+            let longitude = direction.x.atan2(-direction.z);
+            let latitude = direction.y.max(-1.).min(1.).asin();
+            let u = 0.5 + longitude / (2. * PI);
+            let v = 0.5 - latitude / PI;
+
+            let [r, g, b, a] = panorama.sample(u, v);
+            [to_unit(r), to_unit(g), to_unit(b), to_unit(a)]
+        })
+    }
+
+    /// The RGBA color of the pixel `direction` points at, from 0 to 1.
+    pub fn sample(&self, direction: Vector3<f32>) -> [f32; 4] {
+        let (x, y, z) = (direction.x, direction.y, direction.z);
+        let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+
+        // The inverse of `face_direction`
+        let (face, u, v) = if ax >= ay && ax >= az {
+            if x > 0. {
+                (0, -z / ax, -y / ax)
+            } else {
+                (1, z / ax, -y / ax)
+            }
+        } else if ay >= az {
+            if y > 0. {
+                (2, x / ay, z / ay)
+            } else {
+                (3, x / ay, -z / ay)
+            }
+        } else if z > 0. {
+            (4, x / az, -y / az)
+        } else {
+            (5, -x / az, -y / az)
+        };
+
+        let texel = |coordinate: f32| {
+            let texel = ((coordinate + 1.) * 0.5 * self.size as f32) as i64;
+            texel.max(0).min(self.size as i64 - 1) as usize
+        };
+        let start = (texel(v) * self.size as usize + texel(u)) * 4;
+
+        let mut color = [0.; 4];
+        for (channel, &byte) in color.iter_mut().zip(&self.faces[face][start..start + 4]) {
+            *channel = byte as f32 / 255.;
+        }
+        color
+    }
+}
+
+/// The direction a cube map is looked up with to land on face `face` at
+/// (`u`, `v`), both from -1 to 1, following the WebGL face layout.
+fn face_direction(face: usize, u: f32, v: f32) -> Vector3<f32> {
+    match face {
+        0 => Vector3::new(1., -v, -u),
+        1 => Vector3::new(-1., -v, u),
+        2 => Vector3::new(u, 1., v),
+        3 => Vector3::new(u, -1., -v),
+        4 => Vector3::new(u, -v, 1.),
+        _ => Vector3::new(-u, -v, -1.),
+    }
+}
+
+/// Images handed over from JS, by name. Uploaded to the GPU when something
+/// draws with them.
+#[derive(Default)]
+pub struct TextureLibrary {
+    textures: HashMap<String, (TextureData, u32)>,
+    cube_maps: HashMap<String, (CubeMapData, u32)>,
+}
+
+impl TextureLibrary {
+    pub fn new() -> TextureLibrary {
+        TextureLibrary::default()
+    }
+
+    /// Add a texture, replacing the one with the same name.
+    pub fn insert<S: Into<String>>(&mut self, name: S, texture: TextureData) {
+        let name = name.into();
+        let version = self.version(&name).map_or(0, |version| version + 1);
+        self.textures.insert(name, (texture, version));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TextureData> {
+        self.textures.get(name).map(|(texture, _)| texture)
+    }
+
+    /// Goes up every time the texture called `name` is replaced.
+    pub fn version(&self, name: &str) -> Option<u32> {
+        self.textures.get(name).map(|&(_, version)| version)
+    }
+
+    /// Add a cube map, replacing the one with the same name. Cube maps are
+    /// named apart from textures.
+    pub fn insert_cube_map<S: Into<String>>(&mut self, name: S, cube_map: CubeMapData) {
+        let name = name.into();
+        let version = self
+            .cube_map_version(&name)
+            .map_or(0, |version| version + 1);
+        self.cube_maps.insert(name, (cube_map, version));
+    }
+
+    pub fn cube_map(&self, name: &str) -> Option<&CubeMapData> {
+        self.cube_maps.get(name).map(|(cube_map, _)| cube_map)
+    }
+
+    pub fn cube_map_version(&self, name: &str) -> Option<u32> {
+        self.cube_maps.get(name).map(|&(_, version)| version)
+    }
+}
+
+/// The GPU copies of the textures in a `TextureLibrary`.
+#[derive(Default)]
+pub struct TextureCache {
+    textures: HashMap<String, (WebGlTexture, u32)>,
+    cube_maps: HashMap<String, (WebGlTexture, u32)>,
+}
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache::default()
+    }
+
+    /// The texture called `name`, uploaded first when it is new or was
+    /// replaced since. `None` when the library doesn't have it.
+    pub fn get(&mut self, gl: &GL, library: &TextureLibrary, name: &str) -> Option<&WebGlTexture> {
+        let version = library.version(name)?;
+        let texture = cached(gl, &mut self.textures, name, version, |texture| {
+            upload(gl, texture, library.get(name)?);
+            Some(())
+        })?;
+
+        Some(texture)
+    }
+
+    /// The cube map called `name`, uploaded first when it is new or was
+    /// replaced since.
+    pub fn cube_map(
+        &mut self,
+        gl: &GL,
+        library: &TextureLibrary,
+        name: &str,
+    ) -> Option<&WebGlTexture> {
+        let version = library.cube_map_version(name)?;
+        let texture = cached(gl, &mut self.cube_maps, name, version, |texture| {
+            upload_cube_map(gl, texture, library.cube_map(name)?);
+            Some(())
+        })?;
+
+        Some(texture)
+    }
+}
+
+/// The texture in `cache` called `name`, filled by `upload` first unless it
+/// is at `version` already.
+fn cached<'a, F>(
+    gl: &GL,
+    cache: &'a mut HashMap<String, (WebGlTexture, u32)>,
+    name: &str,
+    version: u32,
+    upload: F,
+) -> Option<&'a WebGlTexture>
+where
+    F: FnOnce(&WebGlTexture) -> Option<()>,
+{
+    let up_to_date = cache
+        .get(name)
+        .map_or(false, |&(_, uploaded)| uploaded == version);
+
+    if !up_to_date {
+        let texture = match cache.remove(name) {
+            Some((texture, _)) => texture,
+            None => gl.create_texture()?,
+        };
+        upload(&texture)?;
+        cache.insert(name.to_string(), (texture, version));
+    }
+
+    cache.get(name).map(|(texture, _)| texture)
+}
+
+/// Fill `texture` with `data`. Without mipmaps and clamped at the edges,
+/// so any size works on WebGL1.
+pub fn upload(gl: &GL, texture: &WebGlTexture, data: &TextureData) {
+    let mut pixels = data.pixels.clone();
+
+    gl.bind_texture(GL::TEXTURE_2D, Some(texture));
+    gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+        GL::TEXTURE_2D,
+        0,
+        GL::RGBA as i32,
+        data.width as i32,
+        data.height as i32,
+        0,
+        GL::RGBA,
+        GL::UNSIGNED_BYTE,
+        Some(&mut pixels[..]),
+    )
+    .expect("Texture upload");
+    set_parameters(gl, GL::TEXTURE_2D, false);
+}
+
+/// Fill the faces of the cube map `texture` with `data`.
+pub fn upload_cube_map(gl: &GL, texture: &WebGlTexture, data: &CubeMapData) {
+    upload_cube_map_levels(gl, texture, std::slice::from_ref(data));
+}
+
+/// Fill the cube map `texture` with a full chain of mipmaps, from `levels[0]`
+/// halving in size down to 1x1. Sampled trilinearly.
+pub fn upload_cube_map_levels(gl: &GL, texture: &WebGlTexture, levels: &[CubeMapData]) {
+    gl.bind_texture(GL::TEXTURE_CUBE_MAP, Some(texture));
+
+    for (level, data) in levels.iter().enumerate() {
+        for (face, pixels) in data.faces.iter().enumerate() {
+            let mut pixels = pixels.clone();
+            gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                GL::TEXTURE_CUBE_MAP_POSITIVE_X + face as u32,
+                level as i32,
+                GL::RGBA as i32,
+                data.size as i32,
+                data.size as i32,
+                0,
+                GL::RGBA,
+                GL::UNSIGNED_BYTE,
+                Some(&mut pixels[..]),
+            )
+            .expect("Cube map upload");
+        }
+    }
+    set_parameters(gl, GL::TEXTURE_CUBE_MAP, levels.len() > 1);
+}
+
+fn set_parameters(gl: &GL, target: u32, mipmapped: bool) {
+    let min_filter = if mipmapped {
+        GL::LINEAR_MIPMAP_LINEAR
+    } else {
+        GL::LINEAR
+    };
+    gl.tex_parameteri(target, GL::TEXTURE_MIN_FILTER, min_filter as i32);
+    gl.tex_parameteri(target, GL::TEXTURE_MAG_FILTER, GL::LINEAR as i32);
+    gl.tex_parameteri(target, GL::TEXTURE_WRAP_S, GL::CLAMP_TO_EDGE as i32);
+    gl.tex_parameteri(target, GL::TEXTURE_WRAP_T, GL::CLAMP_TO_EDGE as i32);
+}
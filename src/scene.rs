@@ -0,0 +1,675 @@
+//! Scenes as JSON documents.
+//!
+//! A scene lists entities with their components. Entities refer to each
+//! other by their index in the `entities` array, which is remapped to fresh
+//! entities when the scene is loaded. Every field except `version` can be
+//! left out.
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "active_camera": 0,
+//!   "entities": [
+//!     {
+//!       "transform": { "translation": [0, 2, 10] },
+//!       "camera": { "projection": { "kind": "perspective", "fov": 0.8, "near": 0.1, "far": 1000 } },
+//!       "camera_controller": { "kind": "follow", "target": 1 }
+//!     },
+//!     {
+//!       "transform": { "translation": [0, 0, 0], "rotation": [0, 0, 0, 1], "scale": [1, 1, 1] },
+//!       "mesh": { "name": "Triangle", "vertices": [0, 0, 0.5, 0.7, 1, 0] },
+//!       "material": { "color": [1, 0.5, 0, 1] },
+//!       "rigid_body": { "kind": "dynamic", "mass": 2 },
+//!       "collider": { "kind": "sphere", "radius": 0.5, "restitution": 0.3 }
+//!     },
+//!     {
+//!       "transform": { "translation": [0, 1, 0] },
+//!       "parent": { "entity": 1, "bone": "Head" },
+//!       "light": { "kind": "point", "color": [1, 1, 1], "intensity": 2, "range": 10 },
+//!       "lod_group": {
+//!         "levels": [{ "mesh": "Terrain", "screen_size": 0.1 }],
+//!         "fade_width": 0.2
+//!       }
+//!     }
+//!   ],
+//!   "skybox": { "kind": "gradient", "sun_direction": [0.4, 0.6, -0.7] },
+//!   "fog": { "distance": { "kind": "linear", "start": 200, "end": 900 } }
+//! }
+//! ```
+//!
+//! Components are in the same shape as the JavaScript component API uses.
+//! Triangle mesh and heightfield colliders name a mesh in the `MeshLibrary`,
+//! which has to be there when the scene is loaded.
+//! What the engine derives every frame, such as world transforms, bounds and
+//! skeleton poses, is not saved. Neither are the chunks of generated terrain
+//! or the live particles of particle emitters.
+
+use std::collections::HashSet;
+
+use nalgebra::Vector3;
+use specs::{Builder, Component, Entity, Join, World};
+
+use crate::camera::{
+    ActiveCamera, Camera, CameraController, FlyController, FollowController, OrbitController,
+};
+use crate::component_data::{ColliderData, MeshData, RigidBodyData, TransformData};
+use crate::hierarchy::Parent;
+use crate::light::Light;
+use crate::particles::ParticleEmitter;
+use crate::physics::collider::Collider;
+use crate::physics::RigidBody;
+use crate::render::component::Mesh;
+use crate::render::fog::Fog;
+use crate::render::library::MeshLibrary;
+use crate::render::lod::{LodGroup, LodLevel};
+use crate::render::material::Material;
+use crate::render::skybox::Skybox;
+use crate::terrain::streaming::TerrainChunk;
+use crate::terrain::GroundSnap;
+use crate::transform::{InterpolatedTransform, Transform};
+
+/// Bumped whenever the format changes in a way older scenes can't be read.
+pub const SCENE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SceneData {
+    pub version: u32,
+    /// Index of the entity to render from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_camera: Option<u32>,
+    #[serde(default)]
+    pub entities: Vec<EntityData>,
+    /// Replaces the world's skybox when loaded, left out for the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skybox: Option<Skybox>,
+    /// Replaces the world's fog when loaded, left out for none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fog: Option<Fog>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct EntityData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<TransformData>,
+    /// Render in between fixed steps, see `InterpolatedTransform`
+    #[serde(skip_serializing_if = "is_false")]
+    pub interpolated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub material: Option<Material>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lod_group: Option<LodGroupData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light: Option<Light>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<Camera>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_controller: Option<CameraControllerData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rigid_body: Option<RigidBodyData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collider: Option<ColliderData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ground_snap: Option<GroundSnap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub particle_emitter: Option<ParticleEmitter>,
+}
+
+impl EntityData {
+    /// Build the components that take work to convert from their data.
+    pub fn build(&self, library: &MeshLibrary) -> Result<BuiltComponents, String> {
+        let transform = self
+            .transform
+            .clone()
+            .map(TransformData::into_transform)
+            .transpose()?;
+        let mesh = self.mesh.clone().map(MeshData::into_mesh).transpose()?;
+        let collider = self
+            .collider
+            .clone()
+            .map(|collider| collider.into_collider(library))
+            .transpose()?;
+
+        Ok(BuiltComponents {
+            transform,
+            mesh,
+            collider,
+        })
+    }
+}
+
+/// The transform, mesh and collider of an entity, built before any entity
+/// is created so a scene is added either completely or not at all.
+#[derive(Clone, Default)]
+pub struct BuiltComponents {
+    pub transform: Option<Transform>,
+    pub mesh: Option<Mesh>,
+    pub collider: Option<Collider>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ParentData {
+    /// Index of the parent in the scene
+    pub entity: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bone: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LodGroupData {
+    pub levels: Vec<LodLevel>,
+    #[serde(default)]
+    pub fade_width: f32,
+}
+
+/// Controller settings, with any field left out taking its default.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CameraControllerData {
+    Orbit {
+        #[serde(default)]
+        target: [f32; 3],
+        distance: Option<f32>,
+        #[serde(default)]
+        yaw: f32,
+        pitch: Option<f32>,
+    },
+    Fly {
+        speed: Option<f32>,
+        #[serde(default)]
+        yaw: f32,
+        #[serde(default)]
+        pitch: f32,
+    },
+    Follow {
+        /// Index of the entity to follow
+        target: u32,
+        offset: Option<[f32; 3]>,
+        stiffness: Option<f32>,
+    },
+}
+
+impl CameraControllerData {
+    fn from_controller(
+        controller: &CameraController,
+        index_of: &dyn Fn(Entity) -> Option<u32>,
+    ) -> Option<CameraControllerData> {
+        let data = match controller {
+            CameraController::Orbit(orbit) => CameraControllerData::Orbit {
+                target: [orbit.target.x, orbit.target.y, orbit.target.z],
+                distance: Some(orbit.distance),
+                yaw: orbit.yaw,
+                pitch: Some(orbit.pitch),
+            },
+            CameraController::Fly(fly) => CameraControllerData::Fly {
+                speed: Some(fly.speed),
+                yaw: fly.yaw,
+                pitch: fly.pitch,
+            },
+            CameraController::Follow(follow) => CameraControllerData::Follow {
+                target: index_of(follow.target)?,
+                offset: Some([follow.offset.x, follow.offset.y, follow.offset.z]),
+                stiffness: Some(follow.stiffness),
+            },
+        };
+
+        Some(data)
+    }
+
+    fn to_controller(&self, entities: &[Entity]) -> CameraController {
+        match *self {
+            CameraControllerData::Orbit {
+                target,
+                distance,
+                yaw,
+                pitch,
+            } => {
+                let default = OrbitController::default();
+                CameraController::Orbit(OrbitController {
+                    target: Vector3::new(target[0], target[1], target[2]),
+                    distance: distance.unwrap_or(default.distance),
+                    yaw,
+                    pitch: pitch.unwrap_or(default.pitch),
+                    ..default
+                })
+            }
+            CameraControllerData::Fly { speed, yaw, pitch } => {
+                let default = FlyController::default();
+                CameraController::Fly(FlyController {
+                    speed: speed.unwrap_or(default.speed),
+                    yaw,
+                    pitch,
+                    ..default
+                })
+            }
+            CameraControllerData::Follow {
+                target,
+                offset,
+                stiffness,
+            } => {
+                let default = FollowController::new(entities[target as usize]);
+                CameraController::Follow(FollowController {
+                    offset: offset
+                        .map(|o| Vector3::new(o[0], o[1], o[2]))
+                        .unwrap_or(default.offset),
+                    stiffness: stiffness.unwrap_or(default.stiffness),
+                    ..default
+                })
+            }
+        }
+    }
+}
+
+impl SceneData {
+    pub fn from_json(json: &str) -> Result<SceneData, String> {
+        let scene: SceneData =
+            serde_json::from_str(json).map_err(|err| format!("Invalid scene: {}", err))?;
+
+        if scene.version != SCENE_VERSION {
+            return Err(format!(
+                "Scene version {} is not supported, expected {}",
+                scene.version, SCENE_VERSION
+            ));
+        }
+        Ok(scene)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Scenes serialize to JSON")
+    }
+
+    /// Every living entity of `world`, except for generated terrain chunks.
+    /// References to entities that are gone are left out.
+    pub fn from_world(world: &World) -> SceneData {
+        let chunks = world.read_storage::<TerrainChunk>();
+        let entities: Vec<Entity> = (&*world.entities(), !&chunks)
+            .join()
+            .map(|(entity, _)| entity)
+            .collect();
+        let index_of = |entity: Entity| {
+            entities
+                .iter()
+                .position(|&e| e == entity)
+                .map(|idx| idx as u32)
+        };
+
+        let transform = world.read_storage::<Transform>();
+        let interpolated = world.read_storage::<InterpolatedTransform>();
+        let parent = world.read_storage::<Parent>();
+        let mesh = world.read_storage::<Mesh>();
+        let material = world.read_storage::<Material>();
+        let lod_group = world.read_storage::<LodGroup>();
+        let light = world.read_storage::<Light>();
+        let camera = world.read_storage::<Camera>();
+        let controller = world.read_storage::<CameraController>();
+        let rigid_body = world.read_storage::<RigidBody>();
+        let collider = world.read_storage::<Collider>();
+        let ground_snap = world.read_storage::<GroundSnap>();
+        let particle_emitter = world.read_storage::<ParticleEmitter>();
+
+        let entity_data = entities
+            .iter()
+            .map(|&entity| EntityData {
+                transform: transform.get(entity).map(TransformData::from),
+                interpolated: interpolated.contains(entity),
+                parent: parent.get(entity).and_then(|parent| {
+                    Some(ParentData {
+                        entity: index_of(parent.entity)?,
+                        bone: parent.bone.clone(),
+                    })
+                }),
+                mesh: mesh.get(entity).map(MeshData::from),
+                material: material.get(entity).cloned(),
+                lod_group: lod_group.get(entity).map(|lod_group| LodGroupData {
+                    levels: lod_group.levels().to_vec(),
+                    fade_width: lod_group.fade_width,
+                }),
+                light: light.get(entity).cloned(),
+                camera: camera.get(entity).cloned(),
+                camera_controller: controller.get(entity).and_then(|controller| {
+                    CameraControllerData::from_controller(controller, &index_of)
+                }),
+                rigid_body: rigid_body.get(entity).map(RigidBodyData::from),
+                collider: collider.get(entity).map(ColliderData::from),
+                ground_snap: ground_snap.get(entity).cloned(),
+                particle_emitter: particle_emitter.get(entity).cloned(),
+            })
+            .collect();
+
+        let active_camera = world
+            .read_resource::<ActiveCamera>()
+            .0
+            .and_then(|entity| index_of(entity));
+        let skybox = Some(world.read_resource::<Skybox>().clone())
+            .filter(|skybox| *skybox != Skybox::default());
+        let fog = Some(*world.read_resource::<Fog>()).filter(Fog::is_enabled);
+
+        SceneData {
+            version: SCENE_VERSION,
+            active_camera,
+            entities: entity_data,
+            skybox,
+            fog,
+        }
+    }
+
+    /// Add the entities of the scene to `world`, returning them in scene
+    /// order. Nothing is added when the scene is invalid.
+    pub fn instantiate(&self, world: &mut World) -> Result<Vec<Entity>, String> {
+        let built = self.build(&world.read_resource::<MeshLibrary>())?;
+        Ok(self.instantiate_built(world, built, None))
+    }
+
+    /// Check the references and build the components of every entity for
+    /// `instantiate_built`, without touching any world.
+    pub fn build(&self, library: &MeshLibrary) -> Result<Vec<BuiltComponents>, String> {
+        self.check_references()?;
+        self.entities
+            .iter()
+            .map(|data| data.build(library))
+            .collect()
+    }
+
+    /// Create the entities with components that were built already, one
+    /// set per entity, instead of from the scene's `transform`, `mesh` and
+    /// `collider` data. `root` stands in for the data of the first entity when given.
+    /// The references have to be checked beforehand.
+    pub fn instantiate_built(
+        &self,
+        world: &mut World,
+        mut built: Vec<BuiltComponents>,
+        root: Option<&EntityData>,
+    ) -> Vec<Entity> {
+        let entities: Vec<Entity> = self
+            .entities
+            .iter()
+            .map(|_| world.create_entity().build())
+            .collect();
+
+        for (idx, ((data, &entity), built)) in self
+            .entities
+            .iter()
+            .zip(entities.iter())
+            .zip(built.drain(..))
+            .enumerate()
+        {
+            let data = match root {
+                Some(root) if idx == 0 => root,
+                _ => data,
+            };
+            if let Some(transform) = built.transform {
+                if data.interpolated {
+                    insert(world, entity, InterpolatedTransform::new(&transform));
+                }
+                insert(world, entity, transform);
+            }
+            if let Some(parent) = data.parent.as_ref() {
+                let parent = Parent {
+                    entity: entities[parent.entity as usize],
+                    bone: parent.bone.clone(),
+                };
+                insert(world, entity, parent);
+            }
+            if let Some(mesh) = built.mesh {
+                insert(world, entity, mesh);
+            }
+            if let Some(material) = data.material.clone() {
+                insert(world, entity, material);
+            }
+            if let Some(lod_group) = data.lod_group.as_ref() {
+                let lod_group = LodGroup::new(lod_group.levels.clone())
+                    .with_fade_width(lod_group.fade_width);
+                insert(world, entity, lod_group);
+            }
+            if let Some(light) = data.light {
+                insert(world, entity, light);
+            }
+            if let Some(camera) = data.camera {
+                insert(world, entity, camera);
+            }
+            if let Some(controller) = data.camera_controller.as_ref() {
+                insert(world, entity, controller.to_controller(&entities[..]));
+            }
+            if let Some(rigid_body) = data.rigid_body.clone() {
+                insert(world, entity, RigidBody::from(rigid_body));
+            }
+            if let Some(collider) = built.collider {
+                insert(world, entity, collider);
+            }
+            if let Some(ground_snap) = data.ground_snap {
+                insert(world, entity, ground_snap);
+            }
+            if let Some(particle_emitter) = data.particle_emitter.clone() {
+                insert(world, entity, particle_emitter);
+            }
+        }
+
+        if let Some(skybox) = self.skybox.clone() {
+            *world.write_resource::<Skybox>() = skybox;
+        }
+        if let Some(fog) = self.fog {
+            *world.write_resource::<Fog>() = fog;
+        }
+        if let Some(camera) = self.active_camera {
+            let camera = entities[camera as usize];
+            *world.write_resource::<ActiveCamera>() = ActiveCamera(Some(camera));
+        }
+
+        entities
+    }
+
+    /// Every entity index in the scene has to point at one of its entities,
+    /// and following the parents of any entity has to end at a root.
+    pub fn check_references(&self) -> Result<(), String> {
+        let count = self.entities.len() as u32;
+        let check = |index: u32, what: &str| {
+            if index < count {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} refers to entity {}, but the scene has {} entities",
+                    what, index, count
+                ))
+            }
+        };
+
+        if let Some(camera) = self.active_camera {
+            check(camera, "The active camera")?;
+        }
+
+        for (idx, data) in self.entities.iter().enumerate() {
+            if let Some(parent) = data.parent.as_ref() {
+                check(parent.entity, &format!("The parent of entity {}", idx))?;
+            }
+            if let Some(CameraControllerData::Follow { target, .. }) = data.camera_controller {
+                check(target, &format!("The camera controller of entity {}", idx))?;
+            }
+        }
+
+        let mut rooted = vec![false; self.entities.len()];
+        for start in 0..self.entities.len() {
+            let mut chain = HashSet::new();
+            let mut current = Some(start);
+            while let Some(idx) = current {
+                if rooted[idx] {
+                    break;
+                }
+                if !chain.insert(idx) {
+                    return Err(format!("Entity {} is its own ancestor", idx));
+                }
+                current = self.entities[idx]
+                    .parent
+                    .as_ref()
+                    .map(|parent| parent.entity as usize);
+            }
+
+            for idx in chain {
+                rooted[idx] = true;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn insert<C: Component>(world: &World, entity: Entity, component: C) {
+    world
+        .write_storage::<C>()
+        .insert(entity, component)
+        .expect("Insert into a new entity");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::UnitQuaternion;
+
+    fn empty_world() -> World {
+        let mut world = World::new();
+        world.register::<Transform>();
+        world.register::<InterpolatedTransform>();
+        world.register::<Parent>();
+        world.register::<Mesh>();
+        world.register::<Material>();
+        world.register::<LodGroup>();
+        world.register::<Light>();
+        world.register::<Camera>();
+        world.register::<CameraController>();
+        world.register::<RigidBody>();
+        world.register::<Collider>();
+        world.register::<GroundSnap>();
+        world.register::<ParticleEmitter>();
+        world.register::<TerrainChunk>();
+
+        world.add_resource(MeshLibrary::new());
+        world.add_resource(Skybox::default());
+        world.add_resource(Fog::default());
+        world.add_resource(ActiveCamera(None));
+        world
+    }
+
+    fn lod_group() -> LodGroup {
+        let level = |mesh: &str, screen_size| LodLevel {
+            mesh: mesh.to_string(),
+            screen_size,
+        };
+        LodGroup::new(vec![level("High", 0.5), level("Low", 0.1)]).with_fade_width(0.2)
+    }
+
+    #[test]
+    fn references_are_remapped_on_a_round_trip() {
+        let mut world = empty_world();
+        // Leaves a hole in the entity ids, which the scene indices close
+        let gone = world.create_entity().build();
+        let target = world
+            .create_entity()
+            .with(Transform::new(Vector3::new(1., 2., 3.)))
+            .with(lod_group())
+            .build();
+        let child = world
+            .create_entity()
+            .with(Transform::default())
+            .with(Parent::bone(target, "Head"))
+            .build();
+        let camera = world
+            .create_entity()
+            .with(Camera::perspective(0.8, 0.1, 100.))
+            .with(CameraController::Follow(FollowController::new(target)))
+            .build();
+        *world.write_resource::<ActiveCamera>() = ActiveCamera(Some(camera));
+        world.delete_entity(gone).unwrap();
+        world.maintain();
+
+        let scene = SceneData::from_json(&SceneData::from_world(&world).to_json()).unwrap();
+        assert_eq!(scene.entities.len(), 3);
+        assert_eq!(scene.active_camera, Some(2));
+        assert_eq!(scene.entities[1].parent.as_ref().unwrap().entity, 0);
+
+        let mut loaded = empty_world();
+        // Taken ids, so the loaded entities can't end up with the saved ones
+        loaded.create_entity().build();
+        loaded.create_entity().build();
+        let entities = scene.instantiate(&mut loaded).unwrap();
+        let (target, child, camera) = (entities[0], entities[1], entities[2]);
+
+        assert_eq!(
+            loaded.read_storage::<Parent>().get(child),
+            Some(&Parent::bone(target, "Head"))
+        );
+        match loaded.read_storage::<CameraController>().get(camera) {
+            Some(CameraController::Follow(follow)) => assert_eq!(follow.target, target),
+            other => panic!("Expected a follow controller, got {:?}", other),
+        }
+        assert_eq!(loaded.read_resource::<ActiveCamera>().0, Some(camera));
+        assert_eq!(
+            loaded.read_storage::<LodGroup>().get(target),
+            Some(&lod_group())
+        );
+        assert_eq!(
+            loaded
+                .read_storage::<Transform>()
+                .get(target)
+                .unwrap()
+                .translation,
+            Vector3::new(1., 2., 3.)
+        );
+    }
+
+    #[test]
+    fn parent_cycles_are_rejected() {
+        let scene = |parents: &str| {
+            SceneData::from_json(&format!(r#"{{ "version": 1, "entities": {} }}"#, parents))
+                .unwrap()
+        };
+
+        let own_parent = scene(r#"[{ "parent": { "entity": 0 } }]"#);
+        assert!(own_parent.check_references().is_err());
+
+        let cycle = scene(r#"[{}, { "parent": { "entity": 2 } }, { "parent": { "entity": 1 } }]"#);
+        assert!(cycle.check_references().is_err());
+
+        let chain = scene(r#"[{}, { "parent": { "entity": 0 } }, { "parent": { "entity": 1 } }]"#);
+        assert!(chain.check_references().is_ok());
+    }
+
+    #[test]
+    fn invalid_scenes_add_nothing() {
+        let mut world = empty_world();
+        let scene = SceneData::from_json(
+            r#"{ "version": 1, "entities": [{}, { "parent": { "entity": 1 } }] }"#,
+        )
+        .unwrap();
+
+        assert!(scene.instantiate(&mut world).is_err());
+        assert_eq!((&*world.entities()).join().count(), 0);
+    }
+
+    #[test]
+    fn rotations_that_cant_be_normalized_are_rejected() {
+        let mut world = empty_world();
+        let scene = |rotation: &str| {
+            let json = format!(
+                r#"{{ "version": 1, "entities": [{{}}, {{ "transform": {{ "rotation": {} }} }}] }}"#,
+                rotation
+            );
+            SceneData::from_json(&json).unwrap()
+        };
+
+        assert!(scene("[0, 0, 0, 0]").instantiate(&mut world).is_err());
+        assert!(scene("[0, 0, 1e-9, 0]").instantiate(&mut world).is_err());
+        assert_eq!((&*world.entities()).join().count(), 0);
+
+        scene("[0, 0, 0, 2]").instantiate(&mut world).unwrap();
+        let transforms = world.read_storage::<Transform>();
+        let rotation = (&transforms).join().next().unwrap().rotation;
+        assert_eq!(rotation, UnitQuaternion::identity());
+    }
+}
@@ -0,0 +1,291 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use specs::{Dispatcher, DispatcherBuilder, Resources, System};
+
+/// The phases of a frame, run in the order listed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Reacting to the mouse and keyboard, once per frame
+    Input,
+    /// Gameplay and physics, once per fixed step
+    Simulation,
+    /// Computing world transforms and bounds
+    Transform,
+    Animation,
+    Render,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Input,
+        Stage::Simulation,
+        Stage::Transform,
+        Stage::Animation,
+        Stage::Render,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Input => "input",
+            Stage::Simulation => "simulation",
+            Stage::Transform => "transform",
+            Stage::Animation => "animation",
+            Stage::Render => "render",
+        }
+    }
+}
+
+/// A stage collects its systems until the schedule is set up, from then on
+/// it is a dispatcher.
+enum Systems {
+    Building {
+        builder: DispatcherBuilder<'static, 'static>,
+        names: HashSet<String>,
+    },
+    Built(Dispatcher<'static, 'static>),
+}
+
+/// The systems of every stage, each stage with a dispatcher of its own.
+///
+/// Dependencies are between systems of the same stage, the stages themselves
+/// run one after the other. Systems can only be added before `setup`.
+pub struct Schedule {
+    stages: HashMap<Stage, Systems>,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        let stages = Stage::ALL
+            .iter()
+            .map(|&stage| {
+                let systems = Systems::Building {
+                    builder: DispatcherBuilder::new(),
+                    names: HashSet::new(),
+                };
+                (stage, systems)
+            })
+            .collect();
+
+        Schedule { stages }
+    }
+
+    /// Add a system that runs after the systems named in `dependencies`,
+    /// which have to be in the same stage and added before it.
+    pub fn add<S>(
+        &mut self,
+        stage: Stage,
+        system: S,
+        name: &str,
+        dependencies: &[&str],
+    ) -> Result<(), String>
+    where
+        S: for<'c> System<'c> + Send + 'static,
+    {
+        let (builder, names) = self.building(stage)?;
+
+        if names.contains(name) {
+            return Err(format!(
+                r#"A system called '{}' is already in the {} stage"#,
+                name,
+                stage.name()
+            ));
+        }
+        if let Some(missing) = dependencies.iter().find(|dep| !names.contains(**dep)) {
+            return Err(format!(
+                r#"System '{}' depends on '{}', which is not in the {} stage"#,
+                name,
+                missing,
+                stage.name()
+            ));
+        }
+
+        builder.add(system, name, dependencies);
+        names.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Add a system that has to stay on the main thread, such as anything
+    /// holding on to WebGL objects. Thread local systems run after the other
+    /// systems of their stage, in the order they were added.
+    pub fn add_thread_local<S>(&mut self, stage: Stage, system: S) -> Result<(), String>
+    where
+        S: for<'c> System<'c> + 'static,
+    {
+        let (builder, _) = self.building(stage)?;
+        builder.add_thread_local(system);
+        Ok(())
+    }
+
+    /// Systems added to `stage` after this wait for all systems before it.
+    pub fn add_barrier(&mut self, stage: Stage) -> Result<(), String> {
+        let (builder, _) = self.building(stage)?;
+        builder.add_barrier();
+        Ok(())
+    }
+
+    pub fn is_set_up(&self) -> bool {
+        self.stages.values().all(|systems| match systems {
+            Systems::Built(_) => true,
+            Systems::Building { .. } => false,
+        })
+    }
+
+    /// Build the dispatchers and let every system register the resources and
+    /// storages it uses.
+    pub fn setup(&mut self, res: &mut Resources) {
+        for stage in Stage::ALL.iter() {
+            let systems = self.stages.remove(stage).expect("Every stage has systems");
+            let mut dispatcher = match systems {
+                Systems::Building { builder, .. } => builder.build(),
+                Systems::Built(dispatcher) => dispatcher,
+            };
+
+            dispatcher.setup(res);
+            self.stages.insert(*stage, Systems::Built(dispatcher));
+        }
+    }
+
+    /// Run the systems of `stage`, setting the schedule up first if it isn't
+    /// yet. Single threaded on wasm.
+    pub fn dispatch(&mut self, stage: Stage, res: &mut Resources) {
+        if !self.is_set_up() {
+            self.setup(res);
+        }
+
+        if let Some(Systems::Built(dispatcher)) = self.stages.get_mut(&stage) {
+            dispatcher.dispatch(res);
+        }
+    }
+
+    fn building(
+        &mut self,
+        stage: Stage,
+    ) -> Result<(&mut DispatcherBuilder<'static, 'static>, &mut HashSet<String>), String> {
+        match self.stages.get_mut(&stage) {
+            Some(Systems::Building { builder, names }) => Ok((builder, names)),
+            _ => Err(format!(
+                "Systems can't be added to the {} stage once the engine is running",
+                stage.name()
+            )),
+        }
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Schedule {
+        Schedule::new()
+    }
+}
+
+/// A system that stays reachable from outside the dispatcher running it.
+pub struct Shared<S>(pub Rc<RefCell<S>>);
+
+impl<S> Shared<S> {
+    pub fn new(system: &Rc<RefCell<S>>) -> Shared<S> {
+        Shared(Rc::clone(system))
+    }
+}
+
+impl<'a, S: System<'a>> System<'a> for Shared<S> {
+    type SystemData = S::SystemData;
+
+    fn run(&mut self, data: Self::SystemData) {
+        self.0.borrow_mut().run(data);
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        self.0.borrow_mut().setup(res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::Write;
+
+    #[derive(Default)]
+    struct Order(Vec<&'static str>);
+
+    struct Record(&'static str);
+
+    impl<'a> System<'a> for Record {
+        type SystemData = Write<'a, Order>;
+
+        fn run(&mut self, mut order: Self::SystemData) {
+            order.0.push(self.0);
+        }
+    }
+
+    fn order(res: &Resources) -> Vec<&'static str> {
+        res.fetch::<Order>().0.clone()
+    }
+
+    #[test]
+    fn dependencies_run_first() {
+        let mut schedule = Schedule::new();
+        schedule.add(Stage::Simulation, Record("a"), "a", &[]).unwrap();
+        schedule.add(Stage::Simulation, Record("b"), "b", &["a"]).unwrap();
+        schedule.add(Stage::Simulation, Record("c"), "c", &["b", "a"]).unwrap();
+        let mut res = Resources::new();
+
+        schedule.dispatch(Stage::Simulation, &mut res);
+
+        assert_eq!(order(&res), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn dispatching_sets_the_schedule_up() {
+        let mut schedule = Schedule::new();
+        schedule.add(Stage::Render, Record("draw"), "draw", &[]).unwrap();
+        assert!(!schedule.is_set_up());
+
+        // `Order` only exists once the systems registered it
+        let mut res = Resources::new();
+        schedule.dispatch(Stage::Input, &mut res);
+        assert!(schedule.is_set_up());
+        assert!(order(&res).is_empty());
+
+        schedule.dispatch(Stage::Render, &mut res);
+        assert_eq!(order(&res), vec!["draw"]);
+    }
+
+    #[test]
+    fn unknown_and_later_dependencies_are_rejected() {
+        let mut schedule = Schedule::new();
+
+        let unknown = schedule.add(Stage::Input, Record("a"), "a", &["missing"]);
+        assert!(unknown.unwrap_err().contains("'missing'"));
+
+        // Dependencies have to be added first
+        schedule.add(Stage::Input, Record("b"), "b", &[]).unwrap();
+        assert!(schedule.add(Stage::Input, Record("a"), "a", &["c"]).is_err());
+        schedule.add(Stage::Input, Record("c"), "c", &[]).unwrap();
+        assert!(schedule.add(Stage::Input, Record("a"), "a", &["c"]).is_ok());
+    }
+
+    #[test]
+    fn dependencies_stay_within_their_stage() {
+        let mut schedule = Schedule::new();
+        schedule.add(Stage::Simulation, Record("physics"), "physics", &[]).unwrap();
+
+        let across = schedule.add(Stage::Render, Record("draw"), "draw", &["physics"]);
+        assert!(across.unwrap_err().contains("render stage"));
+
+        // The same name in another stage is a different system
+        assert!(schedule.add(Stage::Render, Record("physics"), "physics", &[]).is_ok());
+        assert!(schedule.add(Stage::Render, Record("physics"), "physics", &[]).is_err());
+    }
+
+    #[test]
+    fn systems_are_only_added_before_setup() {
+        let mut schedule = Schedule::new();
+        let mut res = Resources::new();
+        schedule.setup(&mut res);
+
+        assert!(schedule.add(Stage::Input, Record("late"), "late", &[]).is_err());
+        assert!(schedule.add_thread_local(Stage::Input, Record("late")).is_err());
+        assert!(schedule.add_barrier(Stage::Input).is_err());
+    }
+}
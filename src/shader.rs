@@ -7,11 +7,11 @@ use chal_engine::shader::{
 use wasm_bindgen::prelude::*;
 use web_sys::*;
 
-// static MESH_NON_SKINNED_VS: &'static str = include_str!("./mesh-non-skinned-vertex.glsl");
-// static MESH_NON_SKINNED_FS: &'static str = include_str!("./mesh-non-skinned-fragment.glsl");
+static MESH_NON_SKINNED_VS: &'static str = include_str!("./mesh-non-skinned-vertex.glsl");
+static MESH_NON_SKINNED_FS: &'static str = include_str!("./mesh-non-skinned-fragment.glsl");
 
-static MESH_NON_SKINNED_VS: &'static str = include_str!("./quad-vertex.glsl");
-static MESH_NON_SKINNED_FS: &'static str = include_str!("./quad-fragment.glsl");
+// static MESH_NON_SKINNED_VS: &'static str = include_str!("./quad-vertex.glsl");
+// static MESH_NON_SKINNED_FS: &'static str = include_str!("./quad-fragment.glsl");
 
 pub struct WebShader {
     pub program: WebGlProgram,
@@ -23,10 +23,24 @@ pub struct WebShaderSystem {
     active_program: RefCell<ShaderKind>
 }
 
+impl WebShaderSystem {
+    /// Use the active program again after drawing with a shader from outside
+    /// the system.
+    pub fn restore_program(&self, gl: &WebGlRenderingContext) {
+        let active = *self.active_program.borrow();
+        gl.use_program(Some(&self.programs.get(&active).unwrap().program));
+    }
+}
+
 impl ShaderSystem<WebGlRenderingContext, WebShader> for WebShaderSystem {
     fn new(gl: &WebGlRenderingContext) -> WebShaderSystem {
         let mut programs = HashMap::new();
 
+        // Lets the mesh shader read mipmap levels of the environment
+        // directly. Only WebGL1 offers it, WebGL2 doesn't to GLSL ES 1.0
+        // shaders, which then fall back to biasing the level.
+        gl.get_extension("EXT_shader_texture_lod").ok();
+
         let non_skinned_shader =
             WebShader::new(&gl, MESH_NON_SKINNED_VS, MESH_NON_SKINNED_FS).unwrap();
 
@@ -0,0 +1,43 @@
+// The sun disc is a tiny range of angles near a cosine of 1
+#ifdef GL_FRAGMENT_PRECISION_HIGH
+precision highp float;
+#else
+precision mediump float;
+#endif
+
+varying vec4 v_direction;
+
+uniform bool u_use_cube_map;
+uniform samplerCube u_cube_map;
+
+// Kept in sync with `SkyGradient::color_at`, which bakes the reflections
+uniform vec3 u_zenith;
+uniform vec3 u_horizon;
+uniform vec3 u_ground;
+uniform vec3 u_sun_direction;
+uniform vec3 u_sun_color;
+uniform float u_sun_size;
+
+vec3 gradient(vec3 direction) {
+  vec3 sky;
+  if (direction.y >= 0.0) {
+    sky = mix(u_horizon, u_zenith, sqrt(direction.y));
+  } else {
+    sky = mix(u_horizon, u_ground, sqrt(-direction.y));
+  }
+
+  float sun = dot(direction, u_sun_direction);
+  float disc = smoothstep(cos(u_sun_size), cos(u_sun_size * 0.5), sun);
+  float glow = pow(max(sun, 0.0), 64.0) * 0.5;
+  return sky + u_sun_color * (disc + glow);
+}
+
+void main() {
+  vec3 direction = normalize(v_direction.xyz / v_direction.w);
+
+  if (u_use_cube_map) {
+    gl_FragColor = vec4(textureCube(u_cube_map, direction).rgb, 1.0);
+  } else {
+    gl_FragColor = vec4(gradient(direction), 1.0);
+  }
+}
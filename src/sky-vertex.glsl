@@ -0,0 +1,14 @@
+// A triangle covering the screen, on the far plane
+attribute vec2 a_position;
+
+// Inverse of the projection times the view without its translation
+uniform mat4 u_inverse_view_projection;
+
+varying vec4 v_direction;
+
+void main() {
+  vec4 position = vec4(a_position, 1.0, 1.0);
+  gl_Position = position;
+  // Divided by w per fragment, interpolating the division isn't linear
+  v_direction = u_inverse_view_projection * position;
+}
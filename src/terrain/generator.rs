@@ -0,0 +1,397 @@
+use chal_engine::shader::ShaderKind;
+use nalgebra::Vector3;
+
+use crate::picking::Ray;
+use crate::render::component::Mesh;
+use crate::render::draw::Indices;
+use crate::render::layout::{VertexAttribute, VertexLayout};
+use crate::terrain::noise::{Fbm, FbmSettings};
+
+/// Highest number of quads along the side of a chunk, so a chunk still fits
+/// 16 bit indices.
+pub const MAX_CHUNK_RESOLUTION: u32 = 128;
+
+/// Everything that decides the shape of generated terrain and how much of it
+/// is around the camera.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TerrainSettings {
+    #[serde(flatten)]
+    pub noise: FbmSettings,
+    /// From the lowest possible valley to the highest possible peak is twice
+    /// this
+    pub height: f32,
+    /// Length of the side of a chunk in world units
+    pub chunk_size: f32,
+    /// Quads along the side of a chunk at full detail, a power of two
+    pub resolution: u32,
+    /// Chunks kept around the camera in every direction
+    pub view_distance: u32,
+    /// Rings of chunks around the camera at each level of detail, every
+    /// level halving the resolution of the one before
+    pub lod_distance: u32,
+    /// Chunks built per frame at most, the nearest first
+    pub builds_per_frame: u32,
+    pub color: [f32; 4],
+}
+
+impl Default for TerrainSettings {
+    fn default() -> TerrainSettings {
+        TerrainSettings {
+            noise: FbmSettings::default(),
+            height: 20.,
+            chunk_size: 64.,
+            resolution: 32,
+            view_distance: 6,
+            lod_distance: 2,
+            builds_per_frame: 2,
+            color: [0.35, 0.55, 0.25, 1.],
+        }
+    }
+}
+
+/// A chunk by its position on the grid of chunks, (0, 0) starting at the
+/// origin and going along positive X and Z.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub z: i32,
+}
+
+impl ChunkCoord {
+    pub fn new(x: i32, z: i32) -> ChunkCoord {
+        ChunkCoord { x, z }
+    }
+
+    pub fn offset(self, dx: i32, dz: i32) -> ChunkCoord {
+        ChunkCoord::new(self.x + dx, self.z + dz)
+    }
+
+    /// The square ring around `other` this chunk is in, 0 being `other`.
+    pub fn ring(self, other: ChunkCoord) -> u32 {
+        (self.x - other.x).abs().max((self.z - other.z).abs()) as u32
+    }
+
+    /// The neighbours along -X, +X, -Z and +Z, the order edges are stitched
+    /// in.
+    pub fn neighbours(self) -> [ChunkCoord; 4] {
+        [
+            self.offset(-1, 0),
+            self.offset(1, 0),
+            self.offset(0, -1),
+            self.offset(0, 1),
+        ]
+    }
+}
+
+/// Heights and chunk meshes of a terrain made of fractal noise.
+#[derive(Clone, Debug)]
+pub struct TerrainGenerator {
+    settings: TerrainSettings,
+    fbm: Fbm,
+}
+
+impl TerrainGenerator {
+    pub fn new(settings: TerrainSettings) -> Result<TerrainGenerator, String> {
+        if !settings.resolution.is_power_of_two()
+            || settings.resolution < 2
+            || settings.resolution > MAX_CHUNK_RESOLUTION
+        {
+            return Err(format!(
+                "The terrain resolution has to be a power of two from 2 to {}",
+                MAX_CHUNK_RESOLUTION
+            ));
+        }
+        if !(settings.chunk_size > 0.) {
+            return Err("Terrain chunks need a positive size".to_string());
+        }
+        if settings.noise.octaves == 0 || settings.noise.octaves > 16 {
+            return Err("Terrain noise needs 1 to 16 octaves".to_string());
+        }
+
+        let fbm = Fbm::new(settings.noise.clone());
+        Ok(TerrainGenerator { settings, fbm })
+    }
+
+    pub fn settings(&self) -> &TerrainSettings {
+        &self.settings
+    }
+
+    /// Height of the surface at (`x`, `z`), as detailed as the most detailed
+    /// chunks.
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        self.fbm.get(x, z) * self.settings.height
+    }
+
+    /// Upwards normal of the surface at (`x`, `z`). The same everywhere a
+    /// chunk edge is shared, so lighting has no seams either.
+    pub fn normal(&self, x: f32, z: f32) -> Vector3<f32> {
+        let e = self.cell_size() * 0.5;
+        let dx = self.height(x + e, z) - self.height(x - e, z);
+        let dz = self.height(x, z + e) - self.height(x, z - e);
+        Vector3::new(-dx, 2. * e, -dz).normalize()
+    }
+
+    /// Distance between vertices at full detail.
+    pub fn cell_size(&self) -> f32 {
+        self.settings.chunk_size / self.settings.resolution as f32
+    }
+
+    pub fn chunk_at(&self, x: f32, z: f32) -> ChunkCoord {
+        let size = self.settings.chunk_size;
+        ChunkCoord::new((x / size).floor() as i32, (z / size).floor() as i32)
+    }
+
+    /// The corner of the chunk with the lowest X and Z.
+    pub fn chunk_origin(&self, coord: ChunkCoord) -> Vector3<f32> {
+        let size = self.settings.chunk_size;
+        Vector3::new(coord.x as f32 * size, 0., coord.z as f32 * size)
+    }
+
+    /// The coarsest level of detail, which still has two quads per side.
+    pub fn max_lod(&self) -> u32 {
+        self.settings.resolution.trailing_zeros() - 1
+    }
+
+    /// Level of detail of chunks in `ring` around the camera.
+    pub fn lod_for_ring(&self, ring: u32) -> u32 {
+        (ring / self.settings.lod_distance.max(1)).min(self.max_lod())
+    }
+
+    /// The mesh of a chunk at `lod`, positioned relative to its origin. Edges
+    /// next to a coarser neighbour, with the levels of detail of the
+    /// neighbours in the order of `ChunkCoord::neighbours`, follow the
+    /// neighbour's vertices so no cracks open up between them.
+    pub fn chunk_mesh(&self, coord: ChunkCoord, lod: u32, neighbours: [u32; 4]) -> Mesh {
+        let quads = (self.settings.resolution >> lod) as usize;
+        let side = quads + 1;
+        let cell = self.settings.chunk_size / quads as f32;
+        let origin = self.chunk_origin(coord);
+
+        let height_at = |column: usize, row: usize| {
+            self.height(
+                origin.x + column as f32 * cell,
+                origin.z + row as f32 * cell,
+            )
+        };
+        // Vertices per quad of the neighbour along an edge, 1 when it isn't
+        // coarser
+        let ratio = |neighbour: u32| 1usize << neighbour.saturating_sub(lod);
+        let stitched = |along: usize, ratio: usize, height: &dyn Fn(usize) -> f32| {
+            let rest = along % ratio;
+            if rest == 0 {
+                return height(along);
+            }
+            let (start, end) = (height(along - rest), height(along - rest + ratio));
+            start + (end - start) * rest as f32 / ratio as f32
+        };
+
+        let mut vertices = Vec::with_capacity(side * side * 6);
+        for row in 0..side {
+            for column in 0..side {
+                let height = if column == 0 {
+                    stitched(row, ratio(neighbours[0]), &|r| height_at(0, r))
+                } else if column == quads {
+                    stitched(row, ratio(neighbours[1]), &|r| height_at(quads, r))
+                } else if row == 0 {
+                    stitched(column, ratio(neighbours[2]), &|c| height_at(c, 0))
+                } else if row == quads {
+                    stitched(column, ratio(neighbours[3]), &|c| height_at(c, quads))
+                } else {
+                    height_at(column, row)
+                };
+
+                let (x, z) = (column as f32 * cell, row as f32 * cell);
+                let normal = self.normal(origin.x + x, origin.z + z);
+                vertices.extend_from_slice(&[x, height, z, normal.x, normal.y, normal.z]);
+            }
+        }
+
+        // Counter-clockwise seen from above, split like a `Heightfield`
+        let mut indices = Vec::with_capacity(quads * quads * 6);
+        for row in 0..quads {
+            for column in 0..quads {
+                let i00 = (row * side + column) as u16;
+                let i10 = i00 + 1;
+                let i01 = i00 + side as u16;
+                let i11 = i01 + 1;
+                indices.extend_from_slice(&[i00, i01, i10, i10, i01, i11]);
+            }
+        }
+
+        let layout = VertexLayout::new(vec![
+            VertexAttribute::float("a_position", 3),
+            VertexAttribute::float("a_normal", 3),
+        ]);
+        let name = format!("terrain_chunk_{}_{}", coord.x, coord.z);
+
+        Mesh::new(name, vertices, ShaderKind::NonSkinnedMesh)
+            .with_layout(layout)
+            .with_indices(Indices::U16(indices))
+    }
+
+    /// Distance along `ray` to where it first crosses the surface, up to
+    /// `max_distance`. Marches in steps of half a cell and refines the
+    /// crossing, so very thin ridges can be stepped over.
+    pub fn raycast(&self, ray: &Ray, max_distance: f32) -> Option<f32> {
+        // The surface stays between these
+        let (low, high) = (-self.settings.height, self.settings.height);
+        let (mut start, mut end) = (0f32, max_distance);
+        if ray.direction.y.abs() > std::f32::EPSILON {
+            let t0 = (low - ray.origin.y) / ray.direction.y;
+            let t1 = (high - ray.origin.y) / ray.direction.y;
+            start = start.max(t0.min(t1));
+            end = end.min(t0.max(t1));
+        } else if ray.origin.y < low || ray.origin.y > high {
+            return None;
+        }
+
+        // Rays skimming the terrain go as far as chunks are loaded
+        if !end.is_finite() {
+            let reach = (2 * self.settings.view_distance + 1) as f32 * self.settings.chunk_size;
+            end = start + reach;
+        }
+        if start > end {
+            return None;
+        }
+
+        let above = |t: f32| {
+            let point = ray.at(t);
+            point.y - self.height(point.x, point.z)
+        };
+        let step = self.cell_size() * 0.5;
+
+        let mut t = start;
+        let mut previous = above(t);
+        while t < end {
+            let next = (t + step).min(end);
+            let current = above(next);
+            if (previous > 0.) != (current > 0.) {
+                let (mut near, mut far) = (t, next);
+                for _ in 0..16 {
+                    let middle = (near + far) * 0.5;
+                    if (above(middle) > 0.) == (previous > 0.) {
+                        near = middle;
+                    } else {
+                        far = middle;
+                    }
+                }
+                return Some(far);
+            }
+
+            t = next;
+            previous = current;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> TerrainGenerator {
+        TerrainGenerator::new(TerrainSettings {
+            chunk_size: 8.,
+            resolution: 8,
+            ..TerrainSettings::default()
+        })
+        .unwrap()
+    }
+
+    /// Vertex positions of a chunk mesh in world space, row after row.
+    fn positions(
+        generator: &TerrainGenerator,
+        coord: ChunkCoord,
+        lod: u32,
+        neighbours: [u32; 4],
+    ) -> Vec<Vector3<f32>> {
+        let mesh = generator.chunk_mesh(coord, lod, neighbours);
+        let origin = generator.chunk_origin(coord);
+        mesh.layout()
+            .positions(mesh.vertices(), "a_position")
+            .into_iter()
+            .map(|position| position + origin)
+            .collect()
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let with_resolution = |resolution| {
+            TerrainGenerator::new(TerrainSettings {
+                resolution,
+                ..TerrainSettings::default()
+            })
+        };
+
+        assert!(with_resolution(12).is_err());
+        assert!(with_resolution(1).is_err());
+        assert!(with_resolution(MAX_CHUNK_RESOLUTION * 2).is_err());
+        assert!(with_resolution(MAX_CHUNK_RESOLUTION).is_ok());
+    }
+
+    #[test]
+    fn rings_and_levels_of_detail() {
+        let center = ChunkCoord::new(2, -1);
+        assert_eq!(center.ring(center), 0);
+        assert_eq!(center.offset(-3, 1).ring(center), 3);
+        assert_eq!(center.neighbours()[1], ChunkCoord::new(3, -1));
+
+        let generator = generator();
+        assert_eq!(generator.max_lod(), 2);
+        assert_eq!(generator.lod_for_ring(1), 0);
+        assert_eq!(generator.lod_for_ring(2), 1);
+        assert_eq!(generator.lod_for_ring(100), 2);
+    }
+
+    #[test]
+    fn chunks_at_the_same_detail_share_their_edges() {
+        let generator = generator();
+        let (left, right) = (ChunkCoord::new(0, 0), ChunkCoord::new(1, 0));
+        let a = positions(&generator, left, 0, [0; 4]);
+        let b = positions(&generator, right, 0, [0; 4]);
+
+        let side = 9;
+        for row in 0..side {
+            let (edge_a, edge_b) = (a[row * side + side - 1], b[row * side]);
+            assert!((edge_a - edge_b).norm() < 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn edges_next_to_coarser_chunks_follow_them() {
+        let generator = generator();
+        let (fine, coarse) = (ChunkCoord::new(0, 0), ChunkCoord::new(1, 0));
+        let a = positions(&generator, fine, 0, [0, 1, 0, 0]);
+        let b = positions(&generator, coarse, 1, [0; 4]);
+
+        let (fine_side, coarse_side) = (9, 5);
+        for row in 0..fine_side {
+            let edge = a[row * fine_side + fine_side - 1];
+            let below = b[(row / 2) * coarse_side];
+            let expected = if row % 2 == 0 {
+                below.y
+            } else {
+                (below.y + b[(row / 2 + 1) * coarse_side].y) * 0.5
+            };
+            assert!((edge.y - expected).abs() < 1.0e-4);
+        }
+
+        // Inside the chunk the full detail stays
+        let inner = a[fine_side + 1];
+        assert!((inner.y - generator.height(inner.x, inner.z)).abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn coarser_chunks_have_fewer_triangles() {
+        let generator = generator();
+        let triangles = |lod| {
+            let mesh = generator.chunk_mesh(ChunkCoord::new(0, 0), lod, [lod; 4]);
+            mesh.triangles().len()
+        };
+
+        assert_eq!(triangles(0), 128);
+        assert_eq!(triangles(2), 8);
+    }
+}
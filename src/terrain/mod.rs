@@ -0,0 +1,213 @@
+//! The ground gameplay stands on. Either the bundled "Terrain" mesh,
+//! sampled into a heightfield so it can be asked how high it is anywhere
+//! without going through its triangles, or terrain generated from noise in
+//! chunks around the camera.
+
+pub mod generator;
+pub mod noise;
+pub mod streaming;
+
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+use specs::{Component, Join, Read, System, VecStorage, WriteStorage};
+use wasm_bindgen::prelude::*;
+
+use crate::physics::collider::{Collider, Shape};
+use crate::physics::heightfield::Heightfield;
+use crate::physics::RigidBody;
+use crate::picking::Ray;
+use crate::render::component::Mesh;
+use crate::terrain::generator::TerrainGenerator;
+use crate::transform::Transform;
+
+/// Name of the terrain mesh in the `MeshLibrary`.
+pub const TERRAIN_MESH: &str = "Terrain";
+
+/// Samples along each side of the terrain's heightfield.
+pub const TERRAIN_RESOLUTION: usize = 128;
+
+#[derive(Clone)]
+enum Surface {
+    Sampled(Arc<Heightfield>),
+    /// Endless, whether or not the chunks are loaded
+    Generated(Arc<TerrainGenerator>),
+}
+
+/// Height, normal and ray queries against the terrain, in world space. The
+/// terrain mesh sits at the origin. Without a terrain every query misses.
+#[derive(Clone, Default)]
+pub struct Terrain {
+    surface: Option<Surface>,
+}
+
+impl Terrain {
+    pub fn new() -> Terrain {
+        Terrain::default()
+    }
+
+    /// Sample `mesh` on a `resolution` by `resolution` grid. Empty when the
+    /// mesh has no triangles.
+    pub fn from_mesh(mesh: &Mesh, resolution: usize) -> Terrain {
+        Terrain {
+            surface: Heightfield::from_mesh(mesh, resolution, resolution)
+                .map(|heightfield| Surface::Sampled(Arc::new(heightfield))),
+        }
+    }
+
+    /// The terrain `generator` makes, queried at its full detail.
+    pub fn generated(generator: Arc<TerrainGenerator>) -> Terrain {
+        Terrain {
+            surface: Some(Surface::Generated(generator)),
+        }
+    }
+
+    /// The heightfield of a terrain sampled from a mesh.
+    pub fn heightfield(&self) -> Option<&Arc<Heightfield>> {
+        match self.surface.as_ref()? {
+            Surface::Sampled(heightfield) => Some(heightfield),
+            Surface::Generated(_) => None,
+        }
+    }
+
+    /// A collider for a terrain sampled from a mesh, sharing its
+    /// heightfield. Generated terrain has none.
+    pub fn collider(&self) -> Option<Collider> {
+        let heightfield = self.heightfield()?;
+        Some(Collider::new(Shape::Heightfield(Arc::clone(heightfield))))
+    }
+
+    /// Height of the ground at (`x`, `z`), `None` beyond the edges.
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        match self.surface.as_ref()? {
+            Surface::Sampled(heightfield) => heightfield.height_at(x, z),
+            Surface::Generated(generator) => Some(generator.height(x, z)),
+        }
+    }
+
+    /// Upwards normal of the ground at (`x`, `z`), `None` beyond the edges.
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vector3<f32>> {
+        match self.surface.as_ref()? {
+            Surface::Sampled(heightfield) => heightfield.normal_at(x, z),
+            Surface::Generated(generator) => Some(generator.normal(x, z)),
+        }
+    }
+
+    /// Where `ray` first hits the ground within `max_distance`.
+    pub fn raycast(&self, ray: &Ray, max_distance: f32) -> Option<TerrainHit> {
+        let distance = match self.surface.as_ref()? {
+            Surface::Sampled(heightfield) => heightfield.raycast(ray, max_distance)?,
+            Surface::Generated(generator) => generator.raycast(ray, max_distance)?,
+        };
+        let point = ray.at(distance);
+        let normal = self.normal_at(point.x, point.z).unwrap_or_else(Vector3::y);
+
+        Some(TerrainHit {
+            x: point.x,
+            y: point.y,
+            z: point.z,
+            normal_x: normal.x,
+            normal_y: normal.y,
+            normal_z: normal.z,
+            distance,
+        })
+    }
+}
+
+/// Where a ray hit the terrain.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TerrainHit {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub normal_x: f32,
+    pub normal_y: f32,
+    pub normal_z: f32,
+    /// Distance from the origin of the ray
+    pub distance: f32,
+}
+
+/// Keeps an entity standing on the terrain. The entity is put down on the
+/// ground whenever it is less than `snap_distance` above it, or below it,
+/// and left alone further up so it can jump and fall.
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+#[serde(default)]
+pub struct GroundSnap {
+    /// Height of the entity's origin above the ground, half the height of a
+    /// character whose origin is at its center
+    pub offset: f32,
+    pub snap_distance: f32,
+    #[serde(skip)]
+    grounded: bool,
+}
+
+impl GroundSnap {
+    pub fn new(offset: f32) -> GroundSnap {
+        GroundSnap {
+            offset,
+            ..GroundSnap::default()
+        }
+    }
+
+    pub fn with_snap_distance(mut self, snap_distance: f32) -> GroundSnap {
+        self.snap_distance = snap_distance;
+        self
+    }
+
+    /// Whether the entity was on the ground after the last fixed step.
+    pub fn grounded(&self) -> bool {
+        self.grounded
+    }
+}
+
+impl Default for GroundSnap {
+    fn default() -> GroundSnap {
+        GroundSnap {
+            offset: 0.,
+            snap_distance: 0.25,
+            grounded: false,
+        }
+    }
+}
+
+/// Puts entities with a `GroundSnap` down on the terrain. Runs after physics
+/// in every fixed step. Rigid bodies that are moving up are taking off and
+/// not snapped, those that are snapped lose their downwards velocity.
+pub struct GroundSnapSystem;
+
+impl<'a> System<'a> for GroundSnapSystem {
+    type SystemData = (
+        Read<'a, Terrain>,
+        WriteStorage<'a, GroundSnap>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, RigidBody>,
+    );
+
+    fn run(&mut self, (terrain, mut snaps, mut transforms, mut rigid_bodies): Self::SystemData) {
+        for (snap, transform, rigid_body) in
+            (&mut snaps, &mut transforms, (&mut rigid_bodies).maybe()).join()
+        {
+            let translation = &mut transform.translation;
+            let ground = match terrain.height_at(translation.x, translation.z) {
+                Some(height) => height + snap.offset,
+                None => {
+                    snap.grounded = false;
+                    continue;
+                }
+            };
+
+            let taking_off = rigid_body
+                .as_ref()
+                .map_or(false, |body| body.velocity.y > 0.);
+            snap.grounded = !taking_off && translation.y <= ground + snap.snap_distance;
+            if snap.grounded {
+                translation.y = ground;
+                if let Some(body) = rigid_body {
+                    body.velocity.y = body.velocity.y.max(0.);
+                }
+            }
+        }
+    }
+}
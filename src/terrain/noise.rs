@@ -0,0 +1,218 @@
+/// Classic Perlin gradient noise in 2D, shuffled by a seed.
+#[derive(Clone, Debug)]
+pub struct Perlin {
+    /// Two copies of a permutation of 0..256, so lookups don't wrap
+    permutation: Vec<u8>,
+}
+
+impl Perlin {
+    pub fn new(seed: u32) -> Perlin {
+        let mut table: Vec<u8> = (0..=255).collect();
+
+        // Fisher-Yates with a xorshift generator, zero would stay zero
+        let mut state = seed ^ 0x9e37_79b9;
+        if state == 0 {
+            state = 1;
+        }
+        for i in (1..table.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            table.swap(i, state as usize % (i + 1));
+        }
+
+        let mut permutation = table.clone();
+        permutation.extend_from_slice(&table);
+        Perlin { permutation }
+    }
+
+    /// Noise at (`x`, `y`), roughly between -1 and 1 and zero on every
+    /// integer coordinate.
+    pub fn get(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let xi = (x0 as i32 & 255) as usize;
+        let yi = (y0 as i32 & 255) as usize;
+
+        let p = &self.permutation;
+        let hash = |dx: usize, dy: usize| p[p[xi + dx] as usize + yi + dy];
+
+        let n00 = gradient(hash(0, 0), fx, fy);
+        let n10 = gradient(hash(1, 0), fx - 1., fy);
+        let n01 = gradient(hash(0, 1), fx, fy - 1.);
+        let n11 = gradient(hash(1, 1), fx - 1., fy - 1.);
+
+        let (u, v) = (fade(fx), fade(fy));
+        let bottom = n00 + u * (n10 - n00);
+        let top = n01 + u * (n11 - n01);
+        bottom + v * (top - bottom)
+    }
+}
+
+/// Smootherstep, so the noise is continuous up to its second derivative.
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+/// Dot product with one of eight gradients picked by `hash`.
+fn gradient(hash: u8, x: f32, y: f32) -> f32 {
+    match hash & 7 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x,
+        5 => -x,
+        6 => y,
+        _ => -y,
+    }
+}
+
+/// How octaves of noise are stacked into fractal Brownian motion.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct FbmSettings {
+    pub seed: u32,
+    pub octaves: u32,
+    /// Of the first octave, in waves per world unit
+    pub frequency: f32,
+    /// Frequency of each octave relative to the one before
+    pub lacunarity: f32,
+    /// Amplitude of each octave relative to the one before
+    pub gain: f32,
+}
+
+impl Default for FbmSettings {
+    fn default() -> FbmSettings {
+        FbmSettings {
+            seed: 0,
+            octaves: 5,
+            frequency: 0.02,
+            lacunarity: 2.,
+            gain: 0.5,
+        }
+    }
+}
+
+/// Octaves of Perlin noise, each finer and fainter than the one before.
+#[derive(Clone, Debug)]
+pub struct Fbm {
+    perlin: Perlin,
+    settings: FbmSettings,
+    /// Sum of the amplitudes, to bring the result back between -1 and 1
+    normalization: f32,
+}
+
+impl Fbm {
+    pub fn new(settings: FbmSettings) -> Fbm {
+        let normalization = (0..settings.octaves)
+            .map(|octave| settings.gain.powi(octave as i32))
+            .sum::<f32>()
+            .max(std::f32::EPSILON);
+
+        Fbm {
+            perlin: Perlin::new(settings.seed),
+            settings,
+            normalization,
+        }
+    }
+
+    /// Noise at (`x`, `y`), roughly between -1 and 1.
+    pub fn get(&self, x: f32, y: f32) -> f32 {
+        let mut frequency = self.settings.frequency;
+        let mut amplitude = 1.;
+        let mut sum = 0.;
+
+        for octave in 0..self.settings.octaves {
+            // Shift every octave so their zeros don't line up at the origin
+            let offset = octave as f32 * 17.31;
+            sum += amplitude
+                * self
+                    .perlin
+                    .get(x * frequency + offset, y * frequency - offset);
+            frequency *= self.settings.lacunarity;
+            amplitude *= self.settings.gain;
+        }
+
+        sum / self.normalization
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points spread over several cells, off the integer grid.
+    fn samples() -> impl Iterator<Item = (f32, f32)> {
+        (0..400).map(|i| ((i % 20) as f32 * 0.37 - 3., (i / 20) as f32 * 0.53 - 5.))
+    }
+
+    #[test]
+    fn perlin_is_zero_on_integer_coordinates() {
+        let perlin = Perlin::new(7);
+        for y in -3..3 {
+            for x in -3..3 {
+                assert_eq!(perlin.get(x as f32, y as f32), 0.);
+            }
+        }
+    }
+
+    #[test]
+    fn perlin_stays_within_one() {
+        let perlin = Perlin::new(7);
+        assert!(samples().all(|(x, y)| perlin.get(x, y).abs() <= 1.));
+        assert!(samples().any(|(x, y)| perlin.get(x, y).abs() > 0.1));
+    }
+
+    #[test]
+    fn seeds_shuffle_the_noise() {
+        let (a, b) = (Perlin::new(1), Perlin::new(1));
+        assert!(samples().all(|(x, y)| a.get(x, y) == b.get(x, y)));
+
+        let other = Perlin::new(2);
+        assert!(samples().any(|(x, y)| a.get(x, y) != other.get(x, y)));
+
+        // Zero is as good a seed as any other
+        let zero = Perlin::new(0);
+        assert!(samples().any(|(x, y)| zero.get(x, y) != 0.));
+    }
+
+    #[test]
+    fn perlin_is_continuous() {
+        let perlin = Perlin::new(3);
+        for (x, y) in samples() {
+            let step = (perlin.get(x + 1e-3, y) - perlin.get(x, y)).abs();
+            assert!(step < 1e-2, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn a_single_octave_is_scaled_perlin() {
+        let settings = FbmSettings {
+            seed: 5,
+            octaves: 1,
+            frequency: 0.25,
+            ..FbmSettings::default()
+        };
+        let (fbm, perlin) = (Fbm::new(settings), Perlin::new(5));
+
+        for (x, y) in samples() {
+            assert!((fbm.get(x, y) - perlin.get(x * 0.25, y * 0.25)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn octaves_are_normalized() {
+        let fbm = Fbm::new(FbmSettings {
+            frequency: 1.,
+            ..FbmSettings::default()
+        });
+        assert!(samples().all(|(x, y)| fbm.get(x, y).abs() <= 1.));
+
+        let silent = Fbm::new(FbmSettings {
+            octaves: 0,
+            ..FbmSettings::default()
+        });
+        assert_eq!(silent.get(1.5, 2.5), 0.);
+    }
+}
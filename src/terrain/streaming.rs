@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+use specs::{Component, Entities, Entity, NullStorage, Read, System, Write, WriteStorage};
+
+use crate::camera::ActiveCamera;
+use crate::render::component::Mesh;
+use crate::render::material::Material;
+use crate::terrain::generator::{ChunkCoord, TerrainGenerator};
+use crate::transform::Transform;
+
+/// Marks the entities of generated terrain chunks, which are owned by the
+/// `TerrainStreamingSystem` and not saved with scenes.
+#[derive(Component, Copy, Clone, Debug, Default, PartialEq)]
+#[storage(NullStorage)]
+pub struct TerrainChunk;
+
+/// A chunk that has an entity, and what its mesh was built for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct LoadedChunk {
+    entity: Entity,
+    lod: u32,
+    neighbours: [u32; 4],
+}
+
+/// The generated terrain around the camera. Without a generator nothing is
+/// generated.
+#[derive(Default)]
+pub struct TerrainChunks {
+    generator: Option<Arc<TerrainGenerator>>,
+    loaded: HashMap<ChunkCoord, LoadedChunk>,
+    /// The generator changed, every chunk has to go
+    reset: bool,
+}
+
+impl TerrainChunks {
+    pub fn new() -> TerrainChunks {
+        TerrainChunks::default()
+    }
+
+    /// Start over with `generator`, or stop generating terrain.
+    pub fn set_generator(&mut self, generator: Option<Arc<TerrainGenerator>>) {
+        self.generator = generator;
+        self.reset = true;
+    }
+}
+
+/// Creates the chunks of generated terrain that came within view distance
+/// of the active camera and deletes those that left it. Chunks further out
+/// get coarser meshes, a chunk is rebuilt when its level of detail or that
+/// of a neighbour changes. Runs at the start of the transform stage.
+pub struct TerrainStreamingSystem;
+
+impl<'a> System<'a> for TerrainStreamingSystem {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, TerrainChunks>,
+        Read<'a, ActiveCamera>,
+        WriteStorage<'a, TerrainChunk>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Mesh>,
+        WriteStorage<'a, Material>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            mut chunks,
+            active_camera,
+            mut chunk_markers,
+            mut transforms,
+            mut meshes,
+            mut materials,
+        ) = data;
+        let chunks = &mut *chunks;
+
+        if chunks.reset {
+            for (_, chunk) in chunks.loaded.drain() {
+                entities.delete(chunk.entity).ok();
+            }
+            chunks.reset = false;
+        }
+        // Deleted from outside, by loading a scene for instance
+        chunks
+            .loaded
+            .retain(|_, chunk| entities.is_alive(chunk.entity));
+
+        let generator = match chunks.generator.as_ref() {
+            Some(generator) => Arc::clone(generator),
+            None => return,
+        };
+        let settings = generator.settings();
+
+        let eye = active_camera
+            .0
+            .and_then(|camera| transforms.get(camera))
+            .map(|transform| transform.translation)
+            .unwrap_or_else(Vector3::zeros);
+        let center = generator.chunk_at(eye.x, eye.z);
+        let view_distance = settings.view_distance;
+        let lod_of = |coord: ChunkCoord| {
+            let ring = coord.ring(center);
+            if ring <= view_distance {
+                Some(generator.lod_for_ring(ring))
+            } else {
+                None
+            }
+        };
+
+        let out_of_view: Vec<ChunkCoord> = chunks
+            .loaded
+            .keys()
+            .filter(|&&coord| lod_of(coord).is_none())
+            .cloned()
+            .collect();
+        for coord in out_of_view {
+            if let Some(chunk) = chunks.loaded.remove(&coord) {
+                entities.delete(chunk.entity).ok();
+            }
+        }
+
+        // Chunks that are missing or were built for other levels of detail
+        let radius = view_distance as i32;
+        let mut pending = Vec::new();
+        for z in -radius..=radius {
+            for x in -radius..=radius {
+                let coord = center.offset(x, z);
+                let lod = generator.lod_for_ring(coord.ring(center));
+                let mut neighbours = [lod; 4];
+                for (neighbour, coord) in neighbours.iter_mut().zip(coord.neighbours().iter()) {
+                    *neighbour = lod_of(*coord).unwrap_or(lod);
+                }
+
+                let up_to_date = chunks.loaded.get(&coord).map_or(false, |chunk| {
+                    chunk.lod == lod && chunk.neighbours == neighbours
+                });
+                if !up_to_date {
+                    pending.push((x * x + z * z, coord, lod, neighbours));
+                }
+            }
+        }
+        pending.sort_by_key(|&(distance, ..)| distance);
+
+        let builds = settings.builds_per_frame.max(1) as usize;
+        for &(_, coord, lod, neighbours) in pending.iter().take(builds) {
+            let mesh = generator.chunk_mesh(coord, lod, neighbours);
+            let entity = match chunks.loaded.get(&coord) {
+                Some(chunk) => chunk.entity,
+                None => {
+                    let entity = entities.create();
+                    let transform = Transform::new(generator.chunk_origin(coord));
+                    let material = Material::new(settings.color);
+                    let inserted = chunk_markers
+                        .insert(entity, TerrainChunk)
+                        .and_then(|_| transforms.insert(entity, transform))
+                        .and_then(|_| materials.insert(entity, material));
+                    if let Err(err) = inserted {
+                        log!("Skipping terrain chunk {:?}: {}", coord, err);
+                        entities.delete(entity).ok();
+                        continue;
+                    }
+                    entity
+                }
+            };
+            // Tried again next frame
+            if let Err(err) = meshes.insert(entity, mesh) {
+                log!("Skipping terrain chunk {:?}: {}", coord, err);
+                chunks.loaded.remove(&coord);
+                entities.delete(entity).ok();
+                continue;
+            }
+
+            let chunk = LoadedChunk {
+                entity,
+                lod,
+                neighbours,
+            };
+            chunks.loaded.insert(coord, chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::generator::TerrainSettings;
+    use specs::{Builder, Join, RunNow, World};
+    use std::collections::HashSet;
+
+    fn world(view_distance: u32, builds_per_frame: u32) -> (World, Entity) {
+        let mut world = World::new();
+        world.register::<TerrainChunk>();
+        world.register::<Transform>();
+        world.register::<Mesh>();
+        world.register::<Material>();
+
+        let generator = TerrainGenerator::new(TerrainSettings {
+            chunk_size: 8.,
+            resolution: 4,
+            view_distance,
+            lod_distance: 1,
+            builds_per_frame,
+            ..TerrainSettings::default()
+        })
+        .unwrap();
+        let mut chunks = TerrainChunks::new();
+        chunks.set_generator(Some(Arc::new(generator)));
+        world.add_resource(chunks);
+
+        let camera = world.create_entity().with(Transform::default()).build();
+        world.add_resource(ActiveCamera(Some(camera)));
+        (world, camera)
+    }
+
+    fn stream(world: &mut World) {
+        TerrainStreamingSystem.run_now(&world.res);
+        world.maintain();
+    }
+
+    fn loaded(world: &World) -> HashSet<(i32, i32)> {
+        world
+            .read_resource::<TerrainChunks>()
+            .loaded
+            .keys()
+            .map(|coord| (coord.x, coord.z))
+            .collect()
+    }
+
+    fn square(center: (i32, i32), radius: i32) -> HashSet<(i32, i32)> {
+        let mut coords = HashSet::new();
+        for z in -radius..=radius {
+            for x in -radius..=radius {
+                coords.insert((center.0 + x, center.1 + z));
+            }
+        }
+        coords
+    }
+
+    fn chunk_entities(world: &World) -> usize {
+        (&*world.entities(), &world.read_storage::<TerrainChunk>())
+            .join()
+            .count()
+    }
+
+    #[test]
+    fn chunks_within_view_distance_are_loaded() {
+        let (mut world, _) = world(1, 100);
+        stream(&mut world);
+
+        assert_eq!(loaded(&world), square((0, 0), 1));
+        assert_eq!(chunk_entities(&world), 9);
+        assert_eq!(world.read_storage::<Mesh>().join().count(), 9);
+    }
+
+    #[test]
+    fn chunks_left_behind_are_unloaded() {
+        let (mut world, camera) = world(1, 100);
+        stream(&mut world);
+
+        // Two chunks along X keeps one column of the old square
+        world
+            .write_storage::<Transform>()
+            .get_mut(camera)
+            .unwrap()
+            .translation = Vector3::new(20., 0., 4.);
+        stream(&mut world);
+
+        assert_eq!(loaded(&world), square((2, 0), 1));
+        assert_eq!(chunk_entities(&world), 9);
+    }
+
+    #[test]
+    fn the_nearest_chunks_are_built_first() {
+        let (mut world, _) = world(2, 5);
+
+        stream(&mut world);
+        let first = loaded(&world);
+        assert_eq!(
+            first,
+            square((0, 0), 1)
+                .into_iter()
+                .filter(|&(x, z)| x == 0 || z == 0)
+                .collect()
+        );
+
+        // Chunks are rebuilt when their neighbours' detail changes, but all
+        // of them are there once the queue runs dry
+        for _ in 0..20 {
+            stream(&mut world);
+        }
+        assert_eq!(loaded(&world), square((0, 0), 2));
+        assert_eq!(chunk_entities(&world), 25);
+    }
+
+    #[test]
+    fn chunks_deleted_from_outside_come_back() {
+        let (mut world, _) = world(1, 100);
+        stream(&mut world);
+
+        let victim = world.read_resource::<TerrainChunks>().loaded[&ChunkCoord::new(1, 1)].entity;
+        world.delete_entity(victim).unwrap();
+        stream(&mut world);
+
+        assert_eq!(loaded(&world), square((0, 0), 1));
+        assert_eq!(chunk_entities(&world), 9);
+        assert!(!world
+            .read_resource::<TerrainChunks>()
+            .loaded
+            .values()
+            .any(|chunk| chunk.entity == victim));
+    }
+
+    #[test]
+    fn removing_the_generator_unloads_everything() {
+        let (mut world, _) = world(1, 100);
+        stream(&mut world);
+
+        world.write_resource::<TerrainChunks>().set_generator(None);
+        stream(&mut world);
+
+        assert!(loaded(&world).is_empty());
+        assert_eq!(chunk_entities(&world), 0);
+    }
+}
@@ -0,0 +1,245 @@
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// Frames longer than this are cut short, so a tab coming back from the
+/// background doesn't try to simulate the whole time it was away.
+const MAX_FRAME_TIME: f32 = 0.25;
+
+/// More fixed steps than this in a single frame and the rest is dropped,
+/// rather than falling further and further behind.
+const MAX_STEPS: u32 = 10;
+
+/// How far rendering is between the previous and the latest fixed step,
+/// from 0 to 1.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Interpolation(pub f32);
+
+/// What a frame has to do, as decided by the `Clock`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tick {
+    /// Fixed steps to simulate
+    pub steps: u32,
+    /// Scaled time since the previous frame, in seconds
+    pub dt: f32,
+    /// Time since the previous frame regardless of pause and time scale
+    pub real_dt: f32,
+    pub alpha: f32,
+}
+
+/// Turns the real time between frames into fixed simulation steps.
+#[derive(Clone, Debug)]
+pub struct Clock {
+    /// Length of a simulation step in seconds
+    pub fixed_step: f32,
+    time_scale: f32,
+    paused: bool,
+    accumulator: f32,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new(1. / 60.)
+    }
+}
+
+impl Clock {
+    pub fn new(fixed_step: f32) -> Clock {
+        Clock {
+            fixed_step,
+            time_scale: 1.,
+            paused: false,
+            accumulator: 0.,
+        }
+    }
+
+    pub fn advance(&mut self, real_dt: f32) -> Tick {
+        let real_dt = real_dt.max(0.).min(MAX_FRAME_TIME);
+        if self.paused {
+            return Tick {
+                steps: 0,
+                dt: 0.,
+                real_dt,
+                alpha: self.alpha(),
+            };
+        }
+
+        let dt = real_dt * self.time_scale;
+        self.accumulator += dt;
+
+        let mut steps = 0;
+        while self.accumulator >= self.fixed_step && steps < MAX_STEPS {
+            self.accumulator -= self.fixed_step;
+            steps += 1;
+        }
+        if steps == MAX_STEPS {
+            self.accumulator = self.accumulator.min(self.fixed_step);
+        }
+
+        Tick {
+            steps,
+            dt,
+            real_dt,
+            alpha: self.alpha(),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /// Slow motion below 1, fast forward above. Negative scales are treated
+    /// as 0.
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.max(0.);
+    }
+
+    fn alpha(&self) -> f32 {
+        (self.accumulator / self.fixed_step).min(1.)
+    }
+}
+
+/// Calls back on every animation frame of the browser until dropped.
+pub struct AnimationLoop {
+    /// The callback only holds on to itself weakly, so dropping the loop
+    /// frees it
+    callback: Rc<RefCell<Option<Closure<FnMut(f64)>>>>,
+    frame: Rc<Cell<Option<i32>>>,
+}
+
+impl AnimationLoop {
+    /// Start calling `on_frame` with the `requestAnimationFrame` timestamp in
+    /// milliseconds.
+    pub fn start<F: FnMut(f64) + 'static>(mut on_frame: F) -> AnimationLoop {
+        let callback = Rc::new(RefCell::new(None));
+        let frame = Rc::new(Cell::new(None));
+
+        let next: Weak<RefCell<Option<Closure<FnMut(f64)>>>> = Rc::downgrade(&callback);
+        let next_frame = Rc::clone(&frame);
+        let handler = move |timestamp: f64| {
+            on_frame(timestamp);
+
+            if let Some(callback) = next.upgrade() {
+                if let Some(callback) = callback.borrow().as_ref() {
+                    next_frame.set(Some(request_animation_frame(callback)));
+                }
+            }
+        };
+
+        *callback.borrow_mut() = Some(Closure::wrap(Box::new(handler) as Box<FnMut(_)>));
+        frame.set(Some(request_animation_frame(
+            callback.borrow().as_ref().unwrap(),
+        )));
+
+        AnimationLoop { callback, frame }
+    }
+}
+
+impl Drop for AnimationLoop {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            web_sys::window()
+                .unwrap()
+                .cancel_animation_frame(frame)
+                .expect("Cancel animation frame");
+        }
+        self.callback.borrow_mut().take();
+    }
+}
+
+fn request_animation_frame(callback: &Closure<FnMut(f64)>) -> i32 {
+    web_sys::window()
+        .unwrap()
+        .request_animation_frame(callback.as_ref().unchecked_ref())
+        .expect("Request animation frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_accumulate_across_frames() {
+        let mut clock = Clock::new(0.25);
+
+        let tick = clock.advance(0.125);
+        assert_eq!((tick.steps, tick.alpha), (0, 0.5));
+
+        let tick = clock.advance(0.25);
+        assert_eq!((tick.steps, tick.alpha), (1, 0.5));
+
+        let tick = clock.advance(0.125);
+        assert_eq!((tick.steps, tick.alpha), (1, 0.));
+        assert_eq!(tick.dt, 0.125);
+    }
+
+    #[test]
+    fn long_frames_are_cut_short() {
+        let mut clock = Clock::new(0.125);
+
+        let tick = clock.advance(5.);
+        assert_eq!(tick.dt, MAX_FRAME_TIME);
+        assert_eq!(tick.real_dt, MAX_FRAME_TIME);
+        assert_eq!(tick.steps, 2);
+
+        assert_eq!(clock.advance(-1.).dt, 0.);
+    }
+
+    #[test]
+    fn steps_are_capped_and_the_backlog_dropped() {
+        let mut clock = Clock::new(0.001);
+
+        let tick = clock.advance(MAX_FRAME_TIME);
+        assert_eq!(tick.steps, MAX_STEPS);
+        assert!(tick.alpha <= 1.);
+
+        // Only what is left of a single step carries over
+        assert!(clock.advance(0.).steps <= 1);
+    }
+
+    #[test]
+    fn pausing_stops_simulated_but_not_real_time() {
+        let mut clock = Clock::new(0.25);
+        clock.advance(0.125);
+        clock.pause();
+        assert!(clock.is_paused());
+
+        let tick = clock.advance(0.25);
+        assert_eq!((tick.steps, tick.dt, tick.real_dt), (0, 0., 0.25));
+        // Rendering stays where it was between steps
+        assert_eq!(tick.alpha, 0.5);
+
+        clock.resume();
+        assert_eq!(clock.advance(0.125).steps, 1);
+    }
+
+    #[test]
+    fn time_scale_stretches_simulated_time() {
+        let mut clock = Clock::new(0.25);
+        clock.set_time_scale(0.5);
+
+        let tick = clock.advance(0.25);
+        assert_eq!((tick.steps, tick.dt, tick.real_dt), (0, 0.125, 0.25));
+
+        clock.set_time_scale(4.);
+        assert_eq!(clock.advance(0.25).steps, 4);
+
+        clock.set_time_scale(-1.);
+        assert_eq!(clock.time_scale(), 0.);
+        assert_eq!(clock.advance(0.25).steps, 0);
+    }
+}
@@ -0,0 +1,103 @@
+use nalgebra::{Matrix4, UnitQuaternion, Vector3};
+use specs::{Component, Join, ReadStorage, System, VecStorage, WriteStorage};
+
+/// Placement of an entity in the world.
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn new(translation: Vector3<f32>) -> Transform {
+        Transform {
+            translation,
+            ..Transform::default()
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: UnitQuaternion<f32>) -> Transform {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: Vector3<f32>) -> Transform {
+        self.scale = scale;
+        self
+    }
+
+    /// Scale, then rotate, then translate.
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+
+    /// Blend towards `other`, `t` going from 0 (this) to 1 (`other`).
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        Transform {
+            translation: self.translation.lerp(&other.translation, t),
+            rotation: self.rotation.nlerp(&other.rotation, t),
+            scale: self.scale.lerp(&other.scale, t),
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform {
+            translation: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+            scale: Vector3::new(1., 1., 1.),
+        }
+    }
+}
+
+/// The world matrix of an entity, combining its `Transform` with those of
+/// its ancestors. Written by the `TransformPropagationSystem`.
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct GlobalTransform(pub Matrix4<f32>);
+
+impl Default for GlobalTransform {
+    fn default() -> GlobalTransform {
+        GlobalTransform(Matrix4::identity())
+    }
+}
+
+/// Renders the entity in between its last two fixed steps, instead of
+/// jumping from step to step when the frame rate doesn't match the
+/// simulation rate. Meant for entities moved by fixed step systems.
+#[derive(Component, Clone, Debug, PartialEq)]
+#[storage(VecStorage)]
+pub struct InterpolatedTransform {
+    /// The `Transform` as it was before the latest fixed step
+    pub previous: Transform,
+}
+
+impl InterpolatedTransform {
+    pub fn new(transform: &Transform) -> InterpolatedTransform {
+        InterpolatedTransform {
+            previous: transform.clone(),
+        }
+    }
+}
+
+/// Remembers where interpolated entities were. Runs at the start of every
+/// fixed step.
+pub struct TransformSnapshotSystem;
+
+impl<'a> System<'a> for TransformSnapshotSystem {
+    type SystemData = (
+        ReadStorage<'a, Transform>,
+        WriteStorage<'a, InterpolatedTransform>,
+    );
+
+    fn run(&mut self, (transform, mut interpolated): Self::SystemData) {
+        for (transform, interpolated) in (&transform, &mut interpolated).join() {
+            interpolated.previous = transform.clone();
+        }
+    }
+}